@@ -20,19 +20,121 @@ CREATE TABLE IF NOT EXISTS hashed (
     apparent_size UNSIGNED INTEGER NOT NULL,
     -- read_size is how many bytes the file contained when read
     read_size UNSIGNED INTEGER NOT NULL,
-    -- hash is the SHA-256 hash of the file, stored in binary form
-    hash BLOB NOT NULL CHECK(length(hash)=32),
-    -- hash_hex is a printable version of hash
+    -- hash is the hash of the file with the algorithm in metadata, stored in binary form,
+    -- or as lowercase hexadecimal text if --hash-storage hex was used.
+    hash BLOB NOT NULL CHECK(
+        typeof(hash)='blob' AND length(hash) IN (32, 64)
+        OR typeof(hash)='text' AND length(hash) IN (64, 128)
+    ),
+    -- hash_hex is a printable version of hash, always in uppercase
     hash_hex TEXT NOT NULL GENERATED ALWAYS
-        AS (hex(hash)) VIRTUAL
+        AS (CASE typeof(hash) WHEN 'text' THEN upper(hash) ELSE hex(hash) END) VIRTUAL,
+    -- sampled_blocks is 0 if the whole file was hashed,
+    -- otherwise the number of blocks that were hashed with --sample.
+    -- Such hashes only say the files are probably identical,
+    -- and are never grouped with files that were hashed differently.
+    -- read_size is then the apparent size, as that decides which blocks are read.
+    sampled_blocks UNSIGNED INTEGER NOT NULL DEFAULT 0,
+    -- device and inode are the numbers used by --inode-cache to find moved files.
+    -- They are NULL on platforms without them, and for files hashed by older versions.
+    -- They are stored as signed integers, so big numbers will appear negative.
+    device INTEGER,
+    inode INTEGER,
+    -- tag is the --tag of the scan that hashed the file, or NULL if none was given
+    tag TEXT,
+    -- normalized is 0 if the file was hashed as is, otherwise the sum of
+    -- 1 if CRLF and CR line endings were replaced by LF due to --normalize-text, and
+    -- 2 if it was hashed with --strip-bom, which leaves out any byte order mark at the start
+    -- (read_size is then without it).
+    -- Files are never grouped with files that were hashed differently.
+    normalized UNSIGNED INTEGER NOT NULL DEFAULT 0,
+    -- root is the path of the root in roots that the file was found under,
+    -- if --record-root was used, otherwise NULL.
+    root BLOB,
+    -- created is when the file was created, in the same format as modified,
+    -- or NULL if the platform or file system doesn't record it.
+    created TEXT CHECK(length(created)=19)
 ) WITHOUT ROWID; -- should be faster as long as path is printable and not too long
 
 CREATE UNIQUE INDEX IF NOT EXISTS hashed_path ON hashed (path ASC);
 CREATE INDEX IF NOT EXISTS hashed_dir ON hashed (printable_dir ASC);
 CREATE INDEX IF NOT EXISTS hashed_name ON hashed (printable_name);
 CREATE INDEX IF NOT EXISTS hashed_hash ON hashed (hash);
+CREATE INDEX IF NOT EXISTS hashed_inode ON hashed (device, inode);
+CREATE INDEX IF NOT EXISTS hashed_hash_hex ON hashed (hash_hex, read_size, sampled_blocks);
+
+-- duplicates has one row for each group of identical files,
+-- for querying the database without decopy.
+-- (Changing it requires a migration that drops it.)
+CREATE VIEW IF NOT EXISTS duplicates AS
+    SELECT hash_hex,
+        read_size,
+        sampled_blocks,
+        normalized,
+        COUNT(*) AS files,
+        (COUNT(*)-1) * read_size AS wasted,
+        group_concat(printable_path, char(10)) AS paths
+    FROM hashed
+    GROUP BY hash_hex, read_size, sampled_blocks, normalized
+    HAVING COUNT(*) > 1;
+
+-- dirs has the directories read by the previous scan with --fast-incremental,
+-- which are not read again if modified is unchanged.
+CREATE TABLE IF NOT EXISTS dirs (
+    path BLOB PRIMARY KEY NOT NULL,
+    -- modified is the modification time of the directory itself, which changes when
+    -- files are added, removed or renamed, but not when their content is changed.
+    modified TEXT NOT NULL CHECK(length(modified)=19),
+    -- signature is a SHA-256 hash of the paths of the files and subdirectories,
+    -- and the size and modification time of the files,
+    -- to detect if the files have been changed in the database since then.
+    signature BLOB NOT NULL CHECK(length(signature)=32)
+) WITHOUT ROWID;
+
+-- metadata has settings that apply to the whole database:
+-- hash_algorithm is the lowercase name of the hash function used for all files,
+-- and hash_length is the length of the hashes in bytes.
+CREATE TABLE IF NOT EXISTS metadata (
+    key TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL
+) WITHOUT ROWID;
 
 CREATE TABLE IF NOT EXISTS roots (
     path BLOB PRIMARY KEY NOT NULL,
     printable_path TEXT NOT NULL
 ) WITHOUT ROWID;
+
+-- history has every version of every file that has been hashed,
+-- to see which files change often.
+-- The history of files is deleted when they are pruned or forgotten.
+CREATE TABLE IF NOT EXISTS history (
+    path BLOB NOT NULL,
+    modified TEXT NOT NULL,
+    -- hash_hex is the uppercase hexadecimal hash, regardless of how hashed stores it
+    hash_hex TEXT NOT NULL,
+    -- same as in hashed
+    sampled_blocks UNSIGNED INTEGER NOT NULL,
+    PRIMARY KEY (path, modified, hash_hex, sampled_blocks)
+) WITHOUT ROWID;
+
+-- chunks has the content-defined chunks of files hashed with --cdc (experimental),
+-- which are found where the content matches a pattern, so that identical regions
+-- of different files have chunks with the same hash even if they are at different offsets.
+-- file_hash is the hash_hex of the whole file, so files with the same content share chunks,
+-- and there can be chunks of files that have since been changed,
+-- until some file is pruned or forgotten.
+-- Files that are sampled or have normalized line endings are not chunked.
+-- hash is the SHA-256 of the chunk, whatever hash_algorithm in metadata is.
+CREATE TABLE IF NOT EXISTS chunks (
+    file_hash TEXT NOT NULL,
+    offset UNSIGNED INTEGER NOT NULL,
+    length UNSIGNED INTEGER NOT NULL,
+    hash BLOB NOT NULL CHECK(length(hash)=32),
+    PRIMARY KEY (file_hash, offset)
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS chunks_hash ON chunks (hash);
+
+CREATE TRIGGER IF NOT EXISTS hashed_history AFTER INSERT ON hashed BEGIN
+    INSERT OR IGNORE INTO history (path, modified, hash_hex, sampled_blocks)
+        VALUES (new.path, new.modified, new.hash_hex, new.sampled_blocks);
+END;
@@ -13,26 +13,45 @@
  * If not, see <https://www.gnu.org/licenses/>.
  */
 
+//! A pool of byte buffers of varying sizes, with a limit on the total memory they use.
+//!
+//! Nothing here is specific to decopy except that waiting and unexpected reallocations are
+//! reported through a `ThreadInfo`, which can be created with `ThreadInfo::new()`.
+
 use crate::multimap::BTreeMultiMap;
 use crate::thread_info::*;
 
 use std::fmt::{self, Debug, Formatter};
-use std::sync::{Condvar, Mutex, TryLockError};
+use std::sync::{Condvar, Mutex, MutexGuard, TryLockError};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+type BufferMap = BTreeMultiMap<u32, Box<[u8]>>;
+
 /// Stores unused `Box<[u8]>` buffers so that they don't need to be re-allocated or re-initialized,
 /// and makes them available to any thread.
 ///
 /// Buffers of any size can be returned, up to a limit set at construction time.
 /// That limit is itself limitied to maximum 4 GiB, and as a sanity check, minimum 512 bytes.
+///
+/// The buffers handed out are not necessarily of the requested size:
+/// Requests are first clamped to between `MIN_BUFFER_SIZE` (or 1/128 of the biggest buffer size
+/// if that is more) and the biggest buffer size, and then a buffer that is between 90% and 200%
+/// of that might be reused. The contents of reused buffers are not cleared.
+///
+/// When all memory is in use by buffers that haven't been returned, `get_buffer()` blocks until
+/// another thread returns one or the limit is raised, while `try_get_buffer()` returns `None`.
+/// Waiting threads are not served in any particular order, so a thread asking for a big buffer
+/// can be starved by threads asking for small ones.
 pub struct AvailableBuffers {
     /// A map used as a multimap:
     /// The second u32 in the key is used as a counter to allow having multiple boxes of the same size.
-    map: Mutex<BTreeMultiMap<u32, Box<[u8]>>>,
+    map: Mutex<BufferMap>,
     starving: Condvar,
     /// Tracks size of buffers given out plus currently in the map.
     current_buffers_size: AtomicUsize,
     max_buffers_size: usize,
+    /// The limit currently in effect, which can be lowered below `max_buffers_size` at runtime.
+    limit: AtomicUsize,
     max_single_buffer: u32,
 }
 
@@ -55,6 +74,7 @@ impl Debug for AvailableBuffers {
             .field("starving", &self.starving)
             .field("current_buffers_size", &self.current_buffers_size.load(Ordering::Relaxed))
             .field("max_buffers_size", &self.max_buffers_size)
+            .field("limit", &self.limit.load(Ordering::Relaxed))
             .field("max_single_buffer", &self.max_single_buffer)
             .finish()
     }
@@ -77,6 +97,7 @@ impl AvailableBuffers {
             starving: Condvar::new(),
             current_buffers_size: AtomicUsize::new(0),
             max_buffers_size,
+            limit: AtomicUsize::new(max_buffers_size),
             max_single_buffer: max_single_buffer_size as u32,
         })
     }
@@ -88,7 +109,7 @@ impl AvailableBuffers {
             thread_info: &ThreadInfo,
     ) -> Box<[u8]> {
         if buffer.capacity() > self.max_single_buffer as usize {
-            thread_info.log_message(format!("vec of size {} has too big capacity {}",
+            thread_info.log_message(Info, format!("vec of size {} has too big capacity {}",
                     buffer.len(),
                     buffer.capacity(),
             ));
@@ -99,7 +120,7 @@ impl AvailableBuffers {
         }
         let extra_capacity = buffer.capacity() - buffer.len();
         if extra_capacity > 0 {
-            thread_info.log_message(format!("vec of size {} has extra capacity {}",
+            thread_info.log_message(Info, format!("vec of size {} has extra capacity {}",
                     buffer.len(),
                     extra_capacity,
             ));
@@ -109,76 +130,171 @@ impl AvailableBuffers {
         buffer.into_boxed_slice()
     }
 
+    fn clamp_size(&self,  requested_size: usize) -> usize {
+        requested_size.clamp(
+                Self::MIN_BUFFER_SIZE.max(self.max_single_buffer as usize/128),
+                self.max_single_buffer as usize,
+        )
+    }
+
+    /// Get a buffer of roughly the requested size, waiting for one to be returned if there
+    /// isn't enough memory available.
+    ///
+    /// An empty buffer is returned if `requested_size` is zero.
     pub fn get_buffer(&self,  requested_size: usize,  thread_info: &ThreadInfo) -> Box<[u8]> {
         if requested_size == 0 {
             return Box::default();
         }
-        let requested_size = requested_size.clamp(
-                Self::MIN_BUFFER_SIZE.max(self.max_single_buffer as usize/128),
-                self.max_single_buffer as usize,
-        );
-        let key = requested_size as u32;
+        let requested_size = self.clamp_size(requested_size);
         let mut map = self.map.lock().unwrap();
         loop {
-            // see if there is something big enough
-            if let Some((&next, _)) = map.range(key..).next() {
-                let buffer = map.remove_last(next).unwrap();
-                if buffer.len() <= requested_size * 2 {
-                    return buffer;
+            map = match self.take_buffer(map, requested_size, thread_info) {
+                Ok(buffer) => return buffer,
+                Err(map) => {
+                    thread_info.set_state(WaitingForMemory);
+                    self.starving.wait(map).unwrap()
                 }
-                // too big (this might deprive others of memory)
-                let release = buffer.len() - requested_size;
-                self.current_buffers_size.fetch_sub(release, Ordering::Relaxed);
-                drop(map);
-                let mut to_shrink = buffer.into_vec();
-                to_shrink.truncate(requested_size);
-                to_shrink.shrink_to_fit();
-                return self.check_capacity(to_shrink, requested_size, thread_info)
-            }
-            // see if there is something slightly too small
-            if let Some((&smaller, _)) = map.range(..key).last() {
-                if smaller >= (key*9)/10 {
-                    return map.remove_last(smaller).unwrap();
-                } 
-            }
-            // see if there is enough free space
-            let unallocated = self.max_buffers_size as isize
-                - self.current_buffers_size.load(Ordering::Relaxed) as isize;
-            if (requested_size as isize) <= unallocated {
-                // mutex prevents any other thread from allocating
-                self.current_buffers_size.fetch_add(requested_size, Ordering::Relaxed);
-                drop(map);
-                return self.check_capacity(vec![0u8; requested_size], requested_size, thread_info);
+            };
+        }
+    }
+
+    /// Get a buffer of roughly the requested size if there is enough memory available,
+    /// without waiting.
+    pub fn try_get_buffer(&self,  requested_size: usize,  thread_info: &ThreadInfo)
+    -> Option<Box<[u8]>> {
+        if requested_size == 0 {
+            return Some(Box::default());
+        }
+        let requested_size = self.clamp_size(requested_size);
+        let map = self.map.lock().unwrap();
+        return self.take_buffer(map, requested_size, thread_info).ok();
+    }
+
+    /// Reuse, allocate or grow a buffer, or give back the lock if there isn't enough memory.
+    fn take_buffer<'a>(&self,
+            mut map: MutexGuard<'a, BufferMap>,
+            requested_size: usize,
+            thread_info: &ThreadInfo,
+    ) -> Result<Box<[u8]>, MutexGuard<'a, BufferMap>> {
+        let key = requested_size as u32;
+        // see if there is something big enough
+        if let Some((&next, _)) = map.range(key..).next() {
+            let buffer = map.remove_last(next).unwrap();
+            if buffer.len() <= requested_size * 2 {
+                return Ok(buffer);
             }
-            // see if there is a buffer that can be grown within the limit.
-            let need_to_release = requested_size as isize - unallocated;
-            if let Some((&remove, _)) = map.range(need_to_release as u32..).next() {
-                let to_grow = map.remove_last(remove).unwrap();
-                let increase = requested_size - to_grow.len();
-                self.current_buffers_size.fetch_add(increase, Ordering::Relaxed);
-                drop(map);
-                let mut to_grow = to_grow.into_vec();
-                to_grow.resize(requested_size, 0);
-                return self.check_capacity(to_grow, requested_size, thread_info);
+            // too big (this might deprive others of memory)
+            let release = buffer.len() - requested_size;
+            self.current_buffers_size.fetch_sub(release, Ordering::Relaxed);
+            drop(map);
+            let mut to_shrink = buffer.into_vec();
+            to_shrink.truncate(requested_size);
+            to_shrink.shrink_to_fit();
+            return Ok(self.check_capacity(to_shrink, requested_size, thread_info));
+        }
+        // see if there is something slightly too small
+        if let Some((&smaller, _)) = map.range(..key).last() {
+            if smaller >= (key*9)/10 {
+                return Ok(map.remove_last(smaller).unwrap());
             }
-            // wait
-            thread_info.set_state(WaitingForMemory);
-            map = self.starving.wait(map).unwrap();
-        };
+        }
+        // see if there is enough free space
+        let unallocated = self.limit.load(Ordering::Relaxed) as isize
+            - self.current_buffers_size.load(Ordering::Relaxed) as isize;
+        if (requested_size as isize) <= unallocated {
+            // mutex prevents any other thread from allocating
+            self.current_buffers_size.fetch_add(requested_size, Ordering::Relaxed);
+            drop(map);
+            return Ok(self.check_capacity(vec![0u8; requested_size], requested_size, thread_info));
+        }
+        // see if there is a buffer that can be grown within the limit.
+        let need_to_release = requested_size as isize - unallocated;
+        if let Some((&remove, _)) = map.range(need_to_release as u32..).next() {
+            let to_grow = map.remove_last(remove).unwrap();
+            let increase = requested_size - to_grow.len();
+            self.current_buffers_size.fetch_add(increase, Ordering::Relaxed);
+            drop(map);
+            let mut to_grow = to_grow.into_vec();
+            to_grow.resize(requested_size, 0);
+            return Ok(self.check_capacity(to_grow, requested_size, thread_info));
+        }
+        return Err(map);
     }
 
+    /// Make a buffer available for reuse, and wake up threads waiting for one.
+    ///
+    /// Buffers smaller than `MIN_BUFFER_SIZE` or bigger than `max_single_buffer_size()` are
+    /// dropped, as are all buffers while more memory than the limit is in use.
+    /// Memory is accounted for by the length of buffers, so a buffer that has been resized
+    /// since it was handed out must be returned with `return_resized()`.
     pub fn return_buffer(&self,  buffer: Box<[u8]>) {
+        let length = buffer.len();
+        self.return_resized(buffer, length);
+    }
+
+    /// Return a buffer that had the length `handed_out` when it was handed out,
+    /// so that all of that is released even if the buffer has been shrunk.
+    pub fn return_resized(&self,  buffer: Box<[u8]>,  handed_out: usize) {
         // reject trying to add too small or too big buffers
         if buffer.len() < Self::MIN_BUFFER_SIZE  ||  buffer.len() > self.max_single_buffer as usize {
+            // but stop counting it, so that the memory can be used by other buffers
+            if handed_out > 0 {
+                self.release(handed_out);
+                self.starving.notify_all();
+            }
             return;
         }
+        // count it as what it is now
+        if buffer.len() < handed_out {
+            self.release(handed_out - buffer.len());
+        } else {
+            self.current_buffers_size.fetch_add(buffer.len() - handed_out, Ordering::Relaxed);
+        }
         let size = buffer.len() as u32;
         let mut map = self.map.lock().unwrap();
-        map.insert(size, buffer);
+        if self.current_buffers_size.load(Ordering::Relaxed) > self.limit.load(Ordering::Relaxed) {
+            // the limit has been lowered, so free it
+            self.current_buffers_size.fetch_sub(buffer.len(), Ordering::Relaxed);
+        } else {
+            map.insert(size, buffer);
+        }
         drop(map);
         self.starving.notify_all();
     }
 
+    /// Subtract from the size of buffers, without wrapping if it wasn't counted.
+    fn release(&self,  size: usize) {
+        let _ = self.current_buffers_size.fetch_update(Ordering::Relaxed, Ordering::Relaxed,
+                |current| Some(current.saturating_sub(size))
+        );
+    }
+
+    /// Change how much memory the buffers can use,
+    /// clamped to between `max_single_buffer_size()` and `max_memory_usage()`.
+    ///
+    /// When lowered, unused buffers are freed until the total is within the new limit,
+    /// and buffers in use are freed when returned until then.
+    /// Returns the new limit.
+    pub fn set_limit(&self,  limit: usize) -> usize {
+        let limit = limit.clamp(self.max_single_buffer as usize, self.max_buffers_size);
+        let mut map = self.map.lock().unwrap();
+        self.limit.store(limit, Ordering::Relaxed);
+        while self.current_buffers_size.load(Ordering::Relaxed) > limit {
+            let Some((&size, _)) = map.last_key_value() else {
+                break;
+            };
+            let buffer = map.remove_last(size).unwrap();
+            self.current_buffers_size.fetch_sub(buffer.len(), Ordering::Relaxed);
+        }
+        drop(map);
+        self.starving.notify_all();
+        return limit;
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
     #[allow(dead_code)]
     pub fn current_buffers_size(&self) -> usize {
         self.current_buffers_size.load(Ordering::Relaxed)
@@ -194,3 +310,83 @@ impl AvailableBuffers {
         self.max_single_buffer as usize
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    #[test]
+    fn lowering_limit_frees_buffers() {
+        let buffers = AvailableBuffers::new(8192, 1024).unwrap();
+        let info = ThreadInfo::new("test".to_string(), mpsc::channel().0);
+        let taken = (0..8).map(|_| buffers.get_buffer(1024, &info) ).collect::<Vec<_>>();
+        assert_eq!(buffers.current_buffers_size(), 8192);
+        let mut taken = taken.into_iter();
+        for buffer in taken.by_ref().take(4) {
+            buffers.return_buffer(buffer);
+        }
+
+        assert_eq!(buffers.set_limit(2048), 2048);
+        assert_eq!(buffers.current_buffers_size(), 4096);
+        for buffer in taken {
+            buffers.return_buffer(buffer);
+        }
+        assert_eq!(buffers.current_buffers_size(), 2048);
+
+        assert_eq!(buffers.set_limit(100), 1024);
+        assert_eq!(buffers.current_buffers_size(), 1024);
+        assert_eq!(buffers.set_limit(usize::MAX), 8192);
+    }
+
+    #[test]
+    fn try_get_doesnt_wait() {
+        let buffers = AvailableBuffers::new(2048, 1024).unwrap();
+        let info = ThreadInfo::new("test".to_string(), mpsc::channel().0);
+        let first = buffers.try_get_buffer(1024, &info).unwrap();
+        let second = buffers.try_get_buffer(1024, &info).unwrap();
+        assert!(buffers.try_get_buffer(1024, &info).is_none());
+        assert_eq!(buffers.try_get_buffer(0, &info).unwrap().len(), 0);
+        buffers.return_buffer(first);
+        assert_eq!(buffers.try_get_buffer(1000, &info).unwrap().len(), 1024);
+        drop(second);
+    }
+
+    #[test]
+    fn return_unexpected_sizes() {
+        let buffers = AvailableBuffers::new(4096, 1024).unwrap();
+        let info = ThreadInfo::new("test".to_string(), mpsc::channel().0);
+        // too small or too big to keep
+        buffers.return_buffer(vec![0; 100].into_boxed_slice());
+        buffers.return_buffer(vec![0; 2000].into_boxed_slice());
+        assert!(format!("{:?}", buffers).contains("{empty}"));
+        // an odd size that wasn't handed out is still reused if close enough
+        buffers.return_buffer(vec![7; 700].into_boxed_slice());
+        assert_eq!(&buffers.get_buffer(750, &info)[..], &[7; 700][..]);
+        assert_eq!(buffers.get_buffer(600, &info).len(), 600);
+    }
+
+    #[test]
+    fn rejected_buffers_are_not_counted() {
+        let buffers = AvailableBuffers::new(2048, 1024).unwrap();
+        let info = ThreadInfo::new("test".to_string(), mpsc::channel().0);
+        let mut taken = buffers.get_buffer(1024, &info).into_vec();
+        let other = buffers.get_buffer(1024, &info);
+        assert!(buffers.try_get_buffer(1024, &info).is_none());
+        // shrunk after an error
+        taken.truncate(100);
+        buffers.return_resized(taken.into_boxed_slice(), 1024);
+        assert_eq!(buffers.current_buffers_size(), 1024);
+        buffers.return_buffer(Box::default());
+        buffers.return_buffer(other);
+        // only the other one is left in the pool
+        assert_eq!(buffers.current_buffers_size(), 1024);
+        assert!(format!("{:?}", buffers).contains("{1 buffers between 1024 and 1024 bytes"));
+        // shrunk but still big enough to keep
+        let mut taken = buffers.get_buffer(1024, &info).into_vec();
+        taken.truncate(600);
+        buffers.return_resized(taken.into_boxed_slice(), 1024);
+        assert_eq!(buffers.current_buffers_size(), 600);
+    }
+}
@@ -0,0 +1,90 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Measure whether threads updating their own `ThreadInfo` slow each other down,
+//! by comparing with counters that are packed together and thus share cache lines.
+//!
+//! Run with `cargo run --release --bin false_sharing [threads [updates per thread]]`.
+
+#![allow(
+    clippy::uninlined_format_args, // I don't like mixing code into strings
+    clippy::needless_return, // `foo` after `foo.frob();` looks like unfinished
+    clippy::match_ref_pats, // keep it explicit
+    clippy::redundant_pattern_matching, // keep it explicit, and possibly faster
+    clippy::len_zero, clippy::comparison_to_empty, // ! is easy to miss
+)]
+
+use decopy_scan::thread_info::{ThreadInfo, create_info_array};
+
+use std::{env, hint::black_box, mem, process::exit, thread};
+use std::sync::{atomic::{AtomicUsize, Ordering}, mpsc};
+use std::time::{Duration, Instant};
+
+fn time_threads<F: Fn(usize)+Sync>(threads: usize,  work: F) -> Duration {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for i in 0..threads {
+            let work = &work;
+            scope.spawn(move || work(i) );
+        }
+    });
+    return start.elapsed();
+}
+
+fn parse_arg(arg: Option<String>,  default: usize) -> usize {
+    match arg.map(|arg| arg.parse::<usize>() ) {
+        None => default,
+        Some(Ok(n)) if n > 0 => n,
+        Some(_) => {
+            eprintln!("Usage: false_sharing [threads [updates per thread]]");
+            exit(1);
+        }
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let cpus = thread::available_parallelism().map_or(4, usize::from);
+    let threads = parse_arg(args.next(), cpus);
+    let updates = parse_arg(args.next(), 10_000_000);
+
+    let (log_channel, _) = mpsc::channel();
+    let infos = create_info_array("bench", threads, log_channel);
+    let padded = time_threads(threads, |i| {
+        for _ in 0..updates {
+            infos[i].add_bytes(black_box(1));
+        }
+    });
+    assert!(infos.iter().all(|info| info.processed_bytes() == updates ));
+
+    let counters = (0..threads).map(|_| AtomicUsize::new(0) ).collect::<Vec<_>>();
+    let packed = time_threads(threads, |i| {
+        for _ in 0..updates {
+            counters[i].fetch_add(black_box(1), Ordering::Relaxed);
+        }
+    });
+    assert!(counters.iter().all(|counter| counter.load(Ordering::Relaxed) == updates ));
+
+    println!("ThreadInfo is {} bytes with alignment {}",
+            mem::size_of::<ThreadInfo>(),
+            mem::align_of::<ThreadInfo>(),
+    );
+    println!("{} threads doing {} updates each:", threads, updates);
+    println!("  in ThreadInfo:    {:?}", padded);
+    println!("  packed together:  {:?} ({:.1}x slower)",
+            packed,
+            packed.as_secs_f64() / padded.as_secs_f64(),
+    );
+}
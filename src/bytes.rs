@@ -190,10 +190,10 @@ impl FromStr for Bytes {
             "" => return Err("missing unit"),
             _ => return Err("unrecognized unit"),
         };
-        if number << shift != number.rotate_left(shift) {
-            return Err("overflow");
+        match number.checked_mul(1u64 << shift) {
+            Some(bytes) => Ok(Bytes(bytes)),
+            None => Err("overflow"),
         }
-        Ok(Bytes(number << shift))
     }
 }
 
@@ -237,6 +237,18 @@ mod tests {
         Bytes::from_str("2QB").unwrap_err();
     }
 
+    #[test]
+    fn from_str_overflow() {
+        assert_eq!(Bytes::from_str("15EB"), Ok(Bytes(15 << 60)));
+        assert_eq!(Bytes::from_str("16383PB"), Ok(Bytes(16383 << 50)));
+        assert_eq!(Bytes::from_str("18446744073709551615B"), Ok(Bytes(u64::MAX)));
+        assert_eq!(Bytes::from_str("16EB"), Err("overflow"));
+        assert_eq!(Bytes::from_str("16384PB"), Err("overflow"));
+        assert_eq!(Bytes::from_str("17179869184EB"), Err("overflow"));
+        assert_eq!(Bytes::from_str("18446744073709551616B"), Err("overflow"));
+        assert_eq!(Bytes::from_str("18014398509481984K"), Err("overflow"));
+    }
+
     #[test]
     fn to_string() {
         assert_eq!(Bytes(0).to_string(), "0B");
@@ -0,0 +1,161 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Content-defined chunking for `--cdc`, which splits files where the content matches a pattern
+//! instead of at fixed offsets, so that inserting or removing bytes only changes the chunks
+//! around the change, and identical regions of different files can be found.
+//!
+//! The boundaries are found with a gear hash, a rolling hash of the last 64 bytes,
+//! and each chunk is then hashed with SHA-256.
+
+use sha2::{Digest, Sha256};
+
+/// Chunks are never smaller than this, except at the end of a file.
+pub const MIN_CHUNK_SIZE: usize = 16*1024;
+/// A boundary is forced here if the content doesn't have one.
+pub const MAX_CHUNK_SIZE: usize = 256*1024;
+/// The top 16 bits, which depend on more bytes than the lower ones,
+/// giving chunks of 64 KiB on average after the minimum size.
+const BOUNDARY_MASK: u64 = 0xffff << 48;
+
+/// Random numbers for each byte value, generated with splitmix64.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    return table;
+}
+static GEAR: [u64; 256] = gear_table();
+
+/// A part of a file.
+#[derive(Clone, Debug, PartialEq,Eq,Hash)]
+pub struct Chunk {
+    pub offset: u64,
+    pub length: u64,
+    pub hash: [u8; 32],
+}
+
+/// Splits the content of a file into chunks as it's read.
+#[derive(Clone, Default)]
+pub struct Chunker {
+    gear: u64,
+    /// Offset of the current chunk.
+    start: u64,
+    /// Bytes in the current chunk so far.
+    length: usize,
+    hasher: Sha256,
+    chunks: Vec<Chunk>,
+}
+
+impl Chunker {
+    /// Add the next bytes of the file.
+    pub fn update(&mut self,  data: &[u8]) {
+        let mut from = 0;
+        for (i, &byte) in data.iter().enumerate() {
+            self.gear = (self.gear << 1).wrapping_add(GEAR[byte as usize]);
+            self.length += 1;
+            if (self.length >= MIN_CHUNK_SIZE && self.gear & BOUNDARY_MASK == 0)
+            || self.length >= MAX_CHUNK_SIZE {
+                self.hasher.update(&data[from..=i]);
+                self.end_chunk();
+                from = i + 1;
+            }
+        }
+        self.hasher.update(&data[from..]);
+    }
+
+    fn end_chunk(&mut self) {
+        let hash = <[u8; 32]>::from(self.hasher.finalize_reset());
+        let length = self.length as u64;
+        self.chunks.push(Chunk { offset: self.start, length, hash });
+        self.start += length;
+        self.length = 0;
+        self.gear = 0;
+    }
+
+    /// Get the chunks, including a last one that ends at the end of the file.
+    ///
+    /// Empty files have no chunks.
+    pub fn finish(mut self) -> Vec<Chunk> {
+        if self.length != 0 {
+            self.end_chunk();
+        }
+        return self.chunks;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic data without repeating patterns.
+    fn pseudo_random(length: usize,  seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..length).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8
+        }).collect()
+    }
+
+    fn chunks(data: &[u8],  part_size: usize) -> Vec<Chunk> {
+        let mut chunker = Chunker::default();
+        for part in data.chunks(part_size) {
+            chunker.update(part);
+        }
+        chunker.finish()
+    }
+
+    #[test]
+    fn chunks_cover_the_content() {
+        let data = pseudo_random(3_000_000, 1);
+        let big_parts = chunks(&data, 100_000);
+        assert!(big_parts.len() > 3_000_000 / MAX_CHUNK_SIZE);
+        let mut offset = 0;
+        for chunk in &big_parts {
+            assert_eq!(chunk.offset, offset);
+            let end = (offset + chunk.length) as usize;
+            assert!(chunk.length as usize <= MAX_CHUNK_SIZE);
+            assert!(chunk.length as usize >= MIN_CHUNK_SIZE || end == data.len());
+            assert_eq!(chunk.hash[..], Sha256::digest(&data[offset as usize..end])[..]);
+            offset += chunk.length;
+        }
+        assert_eq!(offset, data.len() as u64);
+        assert_eq!(chunks(&data, 4096), big_parts);
+        assert!(Chunker::default().finish().is_empty());
+    }
+
+    #[test]
+    fn insertion_only_changes_nearby_chunks() {
+        let original = pseudo_random(3_000_000, 2);
+        let mut changed = original.clone();
+        changed.splice(1_000_000..1_000_000, *b"inserted");
+        let hashes = |data: &[u8]| {
+            chunks(data, 65536).into_iter().map(|chunk| chunk.hash ).collect::<Vec<_>>()
+        };
+        let (original, changed) = (hashes(&original), hashes(&changed));
+        let different = changed.iter().filter(|hash| !original.contains(hash) ).count();
+        assert!(different <= 2, "{} of {} chunks changed", different, changed.len());
+    }
+}
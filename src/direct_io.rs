@@ -0,0 +1,146 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Reading files without filling the page cache with data that won't be used again.
+//!
+//! On Linux files are opened with `O_DIRECT`, which requires the buffer address,
+//! the file offset and the read length to be aligned to the block size of the device.
+//! Where that isn't possible, the cached pages are dropped after reading instead.
+//! Files can also be read through the cache with hints that they are read sequentially and
+//! only once.
+//! On other platforms this does nothing.
+
+use std::fs;
+use std::io::{self, Read};
+#[cfg(target_os="linux")]
+use std::os::unix::{fs::OpenOptionsExt, io::AsRawFd};
+use std::path::Path;
+
+/// Alignment used for O_DIRECT, which is a multiple of all common block sizes.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Open a file for reading with O_DIRECT if the file system supports it.
+///
+/// Returns whether O_DIRECT is used.
+#[cfg(target_os="linux")]
+pub fn open_direct(path: &Path) -> io::Result<(fs::File, bool)> {
+    match fs::OpenOptions::new().read(true).custom_flags(libc::O_DIRECT).open(path) {
+        Ok(file) => Ok((file, true)),
+        Err(e) if is_unaligned(&e) => Ok((fs::File::open(path)?, false)),
+        Err(e) => Err(e),
+    }
+}
+#[cfg(not(target_os="linux"))]
+pub fn open_direct(path: &Path) -> io::Result<(fs::File, bool)> {
+    Ok((fs::File::open(path)?, false))
+}
+
+/// Whether an error from opening or reading with O_DIRECT means that it cannot be used.
+pub fn is_unaligned(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::InvalidInput
+}
+
+/// Continue reading an opened file through the page cache.
+#[cfg(target_os="linux")]
+pub fn disable_direct(file: &fs::File) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    // SAFETY: fcntl() with these commands only reads and changes the flags of an open descriptor
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags == -1  ||  libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    return Ok(());
+}
+#[cfg(not(target_os="linux"))]
+pub fn disable_direct(_: &fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Read into the aligned part of `buffer`, and then move what was read to the start of it.
+///
+/// Fails with the same error as unaligned reads if `buffer` is too small to contain an aligned
+/// block.
+pub fn read_direct(file: &mut fs::File,  buffer: &mut [u8]) -> io::Result<usize> {
+    let start = buffer.as_ptr().align_offset(DIRECT_IO_ALIGNMENT).min(buffer.len());
+    let usable = (buffer.len() - start) / DIRECT_IO_ALIGNMENT * DIRECT_IO_ALIGNMENT;
+    if usable == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is too small to align"));
+    }
+    let length = file.read(&mut buffer[start..start+usable])?;
+    buffer.copy_within(start..start+length, 0);
+    return Ok(length);
+}
+
+/// How big a buffer to request for reading `size` bytes with `read_direct()`.
+pub fn direct_buffer_size(size: usize) -> usize {
+    let blocks = size.div_ceil(DIRECT_IO_ALIGNMENT);
+    // one extra block to be able to align the start
+    return (blocks + 1).saturating_mul(DIRECT_IO_ALIGNMENT);
+}
+
+/// Tell the OS that the cached contents of a file won't be needed again.
+#[cfg(target_os="linux")]
+pub fn drop_from_cache(file: &fs::File) {
+    // SAFETY: only gives advice about an open descriptor, and failure is harmless
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+}
+#[cfg(not(target_os="linux"))]
+pub fn drop_from_cache(_: &fs::File) {}
+
+/// Tell the OS that a file will be read from start to end, so that it can read further ahead.
+#[cfg(target_os="linux")]
+pub fn advise_sequential(file: &fs::File) {
+    // SAFETY: only gives advice about an open descriptor, and failure is harmless
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+    }
+}
+#[cfg(not(target_os="linux"))]
+pub fn advise_sequential(_: &fs::File) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn read_direct_moves_to_start() {
+        let path = std::env::temp_dir().join(format!("decopy-direct-{}", std::process::id()));
+        let content = (0..10_000u32).map(|n| n as u8 ).collect::<Vec<u8>>();
+        fs::File::create(&path).unwrap().write_all(&content).unwrap();
+        let (mut file, _) = open_direct(&path).unwrap();
+        let mut buffer = vec![0u8; direct_buffer_size(content.len())];
+        let mut read = Vec::new();
+        loop {
+            let length = match read_direct(&mut file, &mut buffer) {
+                Err(e) if is_unaligned(&e) => {
+                    disable_direct(&file).unwrap();
+                    continue;
+                }
+                result => result.unwrap(),
+            };
+            if length == 0 {
+                break;
+            }
+            read.extend_from_slice(&buffer[..length]);
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read, content);
+    }
+}
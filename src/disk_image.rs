@@ -0,0 +1,182 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Scanning the files in disk images, by mounting them read-only.
+//!
+//! This runs `mount -o loop,ro` on Linux, which usually requires root.
+//! Each image is mounted in a new directory that only the current user can access,
+//! as a predictable path in the shared temporary directory could be replaced by a symlink
+//! to make root mount the image somewhere else.
+//!
+//! Mounted images are remembered so that they can be unmounted whatever way the program exits,
+//! which is why all exits after mounting must go through `exit()`.
+
+use crate::shared::PrintablePath;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(target_os="linux")]
+use std::process::Command;
+use std::process;
+use std::sync::Mutex;
+
+/// Images that are mounted and not yet unmounted.
+static MOUNTED: Mutex<Vec<MountedImage>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+struct MountedImage {
+    mount_point: PathBuf,
+}
+
+impl MountedImage {
+    /// Unmount and remove the directory, or with `lazy`,
+    /// detach it now and let the kernel unmount it when it is no longer in use.
+    fn unmount(self,  lazy: bool) -> Result<(), String> {
+        run_umount(&self.mount_point, lazy).map_err(|e| {
+            format!("Cannot unmount {}: {}", PrintablePath::from(self.mount_point.as_path()), e)
+        })?;
+        let _ = fs::remove_dir(&self.mount_point);
+        return Ok(());
+    }
+}
+
+/// Mount the image read-only in a new private directory, and get that directory.
+///
+/// The image stays mounted until `unmount_all()` or `exit()`.
+pub fn mount(image: &Path) -> Result<PathBuf, String> {
+    let printable = PrintablePath::from(image);
+    let image = fs::canonicalize(image)
+            .map_err(|e| format!("Cannot find {}: {}", printable, e) )?;
+    let mount_point = private_dir(&image)
+            .map_err(|e| format!("Cannot create directory to mount {} in: {}", printable, e) )?;
+    if let Err(e) = run_mount(&image, &mount_point) {
+        let _ = fs::remove_dir(&mount_point);
+        return Err(format!("Cannot mount {}: {}\n\
+                            This usually requires root, so mount it yourself and scan it instead.",
+                printable,
+                e,
+        ));
+    }
+    MOUNTED.lock().unwrap().push(MountedImage { mount_point: mount_point.clone() });
+    return Ok(mount_point);
+}
+
+/// Unmount all mounted images, and print any errors.
+///
+/// With `lazy`, images that are still in use are detached, and unmounted when no longer used.
+pub fn unmount_all(lazy: bool) {
+    // the lock can be poisoned by a panic in another thread, but the list is still valid
+    let mounted = std::mem::take(&mut *MOUNTED.lock().unwrap_or_else(|e| e.into_inner() ));
+    for image in mounted {
+        if let Err(e) = image.unmount(lazy) {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+/// Exit the process after unmounting any mounted images.
+///
+/// Other threads might still be reading files in them, so they are unmounted lazily.
+pub fn exit(code: i32) -> ! {
+    unmount_all(true);
+    process::exit(code);
+}
+
+/// Create a directory with a random name that only the current user can access,
+/// named after the image so that it's recognizable.
+#[cfg(target_os="linux")]
+fn private_dir(image: &Path) -> io::Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let mut template = std::env::temp_dir().join("decopy-").into_os_string().into_vec();
+    if let Some(name) = image.file_name() {
+        template.extend_from_slice(name.as_bytes());
+        template.push(b'-');
+    }
+    template.extend_from_slice(b"XXXXXX\0");
+    // SAFETY: the template is NUL-terminated, paths cannot contain NUL,
+    // and mkdtemp() only replaces the Xs
+    let created = unsafe { libc::mkdtemp(template.as_mut_ptr().cast()) };
+    if created.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    template.pop();
+    return Ok(PathBuf::from(OsString::from_vec(template)));
+}
+#[cfg(not(target_os="linux"))]
+fn private_dir(_: &Path) -> io::Result<PathBuf> {
+    let message = "mounting disk images is only supported on Linux";
+    Err(io::Error::new(io::ErrorKind::Unsupported, message))
+}
+
+/// Run a command and turn failure into the error it printed.
+#[cfg(target_os="linux")]
+fn run(command: &mut Command) -> Result<(), String> {
+    let output = command.output().map_err(|e| e.to_string() )?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match stderr.trim() {
+            "" => output.status.to_string(),
+            message => message.to_string(),
+        });
+    }
+    return Ok(());
+}
+
+#[cfg(target_os="linux")]
+fn run_mount(image: &Path,  mount_point: &Path) -> Result<(), String> {
+    run(Command::new("mount").arg("-o").arg("loop,ro").arg(image).arg(mount_point))
+}
+#[cfg(target_os="linux")]
+fn run_umount(mount_point: &Path,  lazy: bool) -> Result<(), String> {
+    let mut command = Command::new("umount");
+    if lazy {
+        command.arg("--lazy");
+    }
+    run(command.arg(mount_point))
+}
+
+#[cfg(not(target_os="linux"))]
+fn run_mount(_: &Path,  _: &Path) -> Result<(), String> {
+    Err("mounting disk images is only supported on Linux".to_string())
+}
+#[cfg(not(target_os="linux"))]
+fn run_umount(_: &Path,  _: bool) -> Result<(), String> {
+    Err("mounting disk images is only supported on Linux".to_string())
+}
+
+#[cfg(all(test, target_os="linux"))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn mount_points_are_private_and_unique() {
+        let a = private_dir(Path::new("/images/disk.img")).unwrap();
+        let b = private_dir(Path::new("/images/disk.img")).unwrap();
+        assert_ne!(a, b);
+        let name = a.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("decopy-disk.img-") && name.len() == "decopy-disk.img-".len() + 6,
+                "{}", name
+        );
+        let metadata = fs::symlink_metadata(&a).unwrap();
+        assert!(metadata.is_dir());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+        fs::remove_dir(a).unwrap();
+        fs::remove_dir(b).unwrap();
+    }
+}
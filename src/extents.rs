@@ -0,0 +1,114 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Finding where the contents of files are stored on disk, to detect files that already share
+//! storage because they are hard links or reflinks (copy-on-write copies).
+//!
+//! This uses the FIEMAP ioctl on Linux, and is not supported on other platforms.
+
+use std::io;
+use std::path::Path;
+
+/// A contiguous part of a file: the offset in the file, the position on disk, and the length.
+pub type Extent = (u64, u64, u64);
+
+#[cfg(target_os="linux")]
+mod fiemap {
+    use super::Extent;
+
+    use std::fs;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    /// _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+    /// Write out delayed allocations first, so that they have a position.
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    /// Flags that mean the position on disk is not known or not meaningful.
+    const FIEMAP_EXTENT_NO_POSITION: u32 = 0x2 /*UNKNOWN*/ | 0x4 /*DELALLOC*/ | 0x8 /*ENCODED*/
+            | 0x100 /*NOT_ALIGNED*/ | 0x200 /*DATA_INLINE*/ | 0x400 /*DATA_TAIL*/;
+    const EXTENTS_PER_CALL: usize = 32;
+
+    #[repr(C)]
+    #[derive(Clone,Copy, Default)]
+    struct FiemapExtent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [FiemapExtent; EXTENTS_PER_CALL],
+    }
+
+    pub fn physical_extents(path: &Path) -> io::Result<Vec<Extent>> {
+        let file = fs::File::open(path)?;
+        let mut extents = Vec::new();
+        let mut fiemap = Fiemap::default();
+        let mut start = 0;
+        loop {
+            fiemap.start = start;
+            fiemap.length = u64::MAX - start;
+            fiemap.flags = FIEMAP_FLAG_SYNC;
+            fiemap.extent_count = EXTENTS_PER_CALL as u32;
+            // SAFETY: the struct has the layout the kernel expects,
+            // and room for the number of extents in extent_count
+            let result = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut fiemap) };
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let mapped = &fiemap.extents[..fiemap.mapped_extents as usize];
+            for extent in mapped {
+                if extent.flags & FIEMAP_EXTENT_NO_POSITION != 0 {
+                    return Err(io::Error::new(io::ErrorKind::Unsupported, "unknown position"));
+                }
+                extents.push((extent.logical, extent.physical, extent.length));
+                if extent.flags & FIEMAP_EXTENT_LAST != 0 {
+                    return Ok(extents);
+                }
+            }
+            match mapped.last() {
+                Some(last) => start = last.logical + last.length,
+                None => return Ok(extents),
+            }
+        }
+    }
+}
+
+/// Get the positions on disk of the contents of a file.
+///
+/// Files that are empty or only stored in metadata have no extents,
+/// and parts of files that have not been written out yet produce an error.
+#[cfg(target_os="linux")]
+pub fn physical_extents(path: &Path) -> io::Result<Vec<Extent>> {
+    fiemap::physical_extents(path)
+}
+#[cfg(not(target_os="linux"))]
+pub fn physical_extents(_: &Path) -> io::Result<Vec<Extent>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "only supported on Linux"))
+}
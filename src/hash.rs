@@ -13,80 +13,407 @@
  * If not, see <https://www.gnu.org/licenses/>.
  */
 
+use crate::chunking::Chunker;
+use crate::output::seconds;
 use crate::shared::*;
 use crate::thread_info::*;
 
+use std::{fs, io::{self, Read}, path::Path};
 use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
-use sha2::{Sha256, Digest};
+use sha2::{Sha256, Sha512, Digest};
 
+/// Hash functions files can be hashed with, selected with --hash-algorithm.
+///
+/// A database only has hashes from one of them, as recorded in its metadata table.
+///
+/// Each file is hashed by one thread from start to end, except that BLAKE3 can hash the parts
+/// of big files on several threads with --parallel-file, as it is a tree hash.
+#[derive(Clone,Copy, Default, Debug, PartialEq,Eq, clap::ValueEnum)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Name stored in the database, in lowercase.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Length of the hash in bytes.
+    pub fn length(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+            HashAlgorithm::Blake3 => 32,
+        }
+    }
+}
+
+/// The state of any of the hash functions, which is reused for all files a thread hashes.
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    /// Boxed because it is much bigger than the others.
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+
+    pub fn update(&mut self,  content: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(content),
+            Hasher::Sha512(hasher) => hasher.update(content),
+            Hasher::Blake3(hasher) => {
+                hasher.update(content);
+            }
+        }
+    }
+
+    /// Hash content on several threads if the algorithm supports that, for --parallel-file.
+    ///
+    /// The hash is the same as with `update()`.
+    pub fn update_parallel(&mut self,  content: &[u8]) {
+        match self {
+            Hasher::Blake3(hasher) => {
+                hasher.update_rayon(content);
+            }
+            _ => self.update(content),
+        }
+    }
+
+    /// Get the hash of everything since the previous reset, and start over.
+    pub fn finalize_reset(&mut self) -> FileHash {
+        match self {
+            Hasher::Sha256(hasher) => FileHash::from(<[u8; 32]>::from(hasher.finalize_reset())),
+            Hasher::Sha512(hasher) => FileHash::from(<[u8; 64]>::from(hasher.finalize_reset())),
+            Hasher::Blake3(hasher) => {
+                let hash = FileHash::from(*hasher.finalize().as_bytes());
+                hasher.reset();
+                hash
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        match self {
+            Hasher::Sha256(hasher) => Digest::reset(hasher),
+            Hasher::Sha512(hasher) => Digest::reset(hasher),
+            Hasher::Blake3(hasher) => {
+                hasher.reset();
+            }
+        }
+    }
+}
+
+/// The state of hashing one file, which is updated with each part of it as they are read.
+///
+/// Hashes the content as is unless `first_part()` decides otherwise.
+#[derive(Clone, Default)]
+struct FileHashing {
+    /// How many bytes of the file have been hashed, including any byte order mark.
+    position: u64,
+    normalize: Option<NormalizeLineEndings>,
+    /// How the content is changed before hashing, as `NORMALIZED_*` bits.
+    normalized: u8,
+    /// Length of the byte order mark left out with --strip-bom.
+    bom: usize,
+    chunker: Option<Chunker>,
+    /// Hash on several threads, with --parallel-file.
+    parallel: bool,
+}
+
+impl FileHashing {
+    /// Decide from the first part of a file whether to normalize it,
+    /// for --strip-bom and --normalize-text.
+    fn first_part(&mut self,  part: &[u8],  shared: &Shared,  sampled: bool) {
+        // sampled blocks are hashed as is, as there is no line ending between them
+        if sampled {
+            return;
+        }
+        // assume that files without NUL bytes at the start are text
+        let text = !part.contains(&0);
+        // UTF-16 text has NUL bytes, but then it should have a BOM
+        // (which is assumed to be within the first part)
+        if shared.strip_bom {
+            self.bom = bom_length(part);
+        }
+        if shared.strip_bom && (text || self.bom != 0) {
+            self.normalized |= NORMALIZED_BOM;
+            self.chunker = None;
+        }
+        if shared.normalize_text && text {
+            self.normalize = Some(NormalizeLineEndings::default());
+            self.normalized |= NORMALIZED_LINE_ENDINGS;
+            // the chunks would not be of the content that was hashed
+            self.chunker = None;
+        }
+    }
+
+    /// Hash the next part of the file.
+    fn update(&mut self,  hasher: &mut Hasher,  part: &[u8]) {
+        if let Some(ref mut chunker) = self.chunker {
+            chunker.update(part);
+        }
+        let content = match self.position {
+            0 => &part[self.bom..],
+            _ => part,
+        };
+        match self.normalize {
+            Some(ref mut normalize) => normalize.update(hasher, content),
+            None if self.parallel => hasher.update_parallel(content),
+            None => hasher.update(content),
+        }
+        self.position += part.len() as u64;
+    }
+}
+
+fn hash_reader(mut reader: impl Read,  hasher: &mut Hasher,  buffer: &mut [u8])
+-> io::Result<FileHash> {
+    assert!(!buffer.is_empty(), "cannot read into an empty buffer");
+    let mut hashing = FileHashing::default();
+    loop {
+        match reader.read(buffer) {
+            Ok(0) => return Ok(hasher.finalize_reset()),
+            Ok(length) => hashing.update(hasher, &buffer[..length]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Replaces CRLF and lone CR line endings with LF while hashing, for --normalize-text.
+///
+/// A CR at the end of one part and an LF at the start of the next is one line ending.
+#[derive(Clone,Copy, Default, Debug)]
+struct NormalizeLineEndings {
+    after_cr: bool,
+}
+
+impl NormalizeLineEndings {
+    fn update(&mut self,  hasher: &mut Hasher,  mut part: &[u8]) {
+        if part.is_empty() {
+            return;
+        }
+        if self.after_cr && part[0] == b'\n' {
+            part = &part[1..];
+        }
+        self.after_cr = false;
+        while let Some(cr) = part.iter().position(|&b| b == b'\r' ) {
+            hasher.update(&part[..cr]);
+            hasher.update(b"\n");
+            part = &part[cr+1..];
+            match part.first() {
+                Some(b'\n') => part = &part[1..],
+                Some(_) => {},
+                None => self.after_cr = true,
+            }
+        }
+        hasher.update(part);
+    }
+}
+
+/// Get the length of the UTF-8 or UTF-16 byte order mark at the start of `content`, if any.
+fn bom_length(content: &[u8]) -> usize {
+    if content.starts_with(&[0xef, 0xbb, 0xbf]) {
+        3
+    } else if content.starts_with(&[0xff, 0xfe]) || content.starts_with(&[0xfe, 0xff]) {
+        2
+    } else {
+        0
+    }
+}
+
+/// Hash a single file on the current thread, without any of the threads and queues.
+///
+/// The file is read into `buffer`, which can be reused between calls to avoid allocating.
+/// `AvailableBuffers::get_buffer()` can be used to get one.
+pub fn hash_path(path: &Path,  algorithm: HashAlgorithm,  buffer: &mut [u8])
+-> io::Result<Vec<u8>> {
+    let file = fs::File::open(path)?;
+    let hash = hash_reader(file, &mut Hasher::new(algorithm), buffer)?;
+    return Ok(hash.to_vec());
+}
+
+/// Hash the parts of a file as they are read, and send the result to the storer.
+///
+/// If reading fails nothing is sent, even if no bytes were read,
+/// so that the file is not stored with the hash of an empty file.
+///
+/// A file that is interrupted is hashed from the start by the next scan:
+/// The hashers of the sha2 and blake3 crates cannot save and restore their state,
+/// and the hash of the whole file cannot be computed from the hashes of its --cdc chunks.
 fn hash_file(
-        file: UnreadFile,  parts: mpsc::Receiver<FilePart>,
-        hasher: &mut sha2::Sha256,  thread_info: &ThreadInfo,
-        buffers: &AvailableBuffers,  hashed_tx: &mpsc::Sender<HashedFile>,
+        file: UnreadFile,  parts: mpsc::Receiver<FilePart>,  skip: Arc<AtomicBool>,
+        hasher: &mut Hasher,  thread_info: &ThreadInfo,
+        shared: &Shared,  hashed_tx: &mpsc::Sender<HashedFile>,
 ) {
-    let mut position = 0;
+    let mut started = Instant::now();
+    let sampled = is_sampled(file.size, shared.sample_blocks);
+    let mut hashing = FileHashing {
+        // only the whole content can be found in other files, so don't chunk samples
+        chunker: match shared.cdc && !sampled && shared.range.is_none() {
+            true => Some(Chunker::default()),
+            false => None,
+        },
+        parallel: !sampled && shared.parallel_file.is_some_and(|min| file.size >= min ),
+        ..FileHashing::default()
+    };
 
     for part in parts.into_iter() {
         match part {
+            // keep receiving until the reader notices, as it panics if the channel is closed
+            FilePart::Chunk{buffer, ..} if skip.load(Ordering::Relaxed) => {
+                shared.buffers.return_buffer(buffer);
+            }
             FilePart::Chunk{buffer, length} => {
-                if position == 0 {
+                if hashing.position == 0 {
+                    started = Instant::now();
                     thread_info.set_state(Hashing);
                     thread_info.set_working_on(Some(file.path.clone()));
+                    thread_info.start_file(match shared.range {
+                        Some(range) => range.length(),
+                        None if sampled => SAMPLE_BLOCK_SIZE * shared.sample_blocks as u64,
+                        None => file.size,
+                    });
+                    hashing.first_part(&buffer[..length], shared, sampled);
                 }
-                hasher.update(&buffer[..length]);
+                hashing.update(hasher, &buffer[..length]);
                 thread_info.add_bytes(length);
-                position += length as u64;
-                buffers.return_buffer(buffer);
+                shared.buffers.return_buffer(buffer);
             },
             FilePart::Error(e) => {
-                thread_info.log_message(format!("{} got IO error after {} of {} bytes: {}",
+                thread_info.log_message(Error, format!("{} got IO error after {} of {} bytes: {}",
                         file.path,
-                        position,
+                        hashing.position,
                         file.size,
                         e
                 ));
                 hasher.reset();
+                shared.finish_file(&skip);
+                shared.read_separately(shared.inode_failed(&file));
                 return;
             },
         }
     }
-
-    let hash_result = hasher.finalize_reset();
-    let Ok(hash) = <[u8; 32]>::try_from(&hash_result[..]) else {
-        panic!("hash has length {}, not 32 as explected", hash_result.len());
-    };
-    if position != file.size {
-        thread_info.log_message(format!(
-                "{} has apparent size {:?} ({}) but {:?} was read",
+    let FileHashing { position, normalized, bom, chunker, .. } = hashing;
+    shared.finish_file(&skip);
+    if skip.load(Ordering::Relaxed) {
+        thread_info.log_message(Info, format!("Skipped {} after hashing {} of {} bytes",
                 file.path,
-                Bytes(file.size),
-                Bytes(file.size),
-                Bytes(position),
+                position,
+                file.size,
         ));
+        hasher.reset();
+        // other paths to the same file are skipped too
+        shared.inode_failed(&file);
+        return;
+    }
+
+    let hash = hasher.finalize_reset();
+    let sampled_blocks = match is_sampled(file.size, shared.sample_blocks) {
+        true => shared.sample_blocks,
+        false => 0,
+    };
+    // a range that was not read completely is an error
+    if sampled_blocks == 0 && shared.range.is_none() {
+        shared.size_mismatches.record(&file, position);
+        if position != file.size {
+            thread_info.log_message(Warning, format!(
+                    "{} has apparent size {:?} ({}) but {:?} was read",
+                    file.path,
+                    Bytes(file.size),
+                    Bytes(file.size),
+                    Bytes(position),
+            ));
+        }
+    }
+    shared.hashed.add(position);
+    if shared.events.is_enabled() {
+        let event = shared.events.event("file_hashed")
+                .string("path", file.path.as_str())
+                .number("bytes", position)
+                .number("duration", seconds(started.elapsed()));
+        shared.send_event(event, thread_info);
     }
-    hashed_tx.send(HashedFile {
+    let hashed = HashedFile {
             path: file.path,
             modified: file.modified,
+            created: file.created,
             apparent_size: file.size,
-            read_size: position,
+            // the offsets sampled depend on the size, so store it even though less was read,
+            // and leave out the BOM so that copies without one are grouped with it
+            read_size: if sampled_blocks == 0 {position - bom as u64} else {file.size},
             hash,
-    }).unwrap();
+            sampled_blocks,
+            inode: file.inode,
+            normalized,
+            chunks: chunker.map_or_else(Vec::new, Chunker::finish),
+            range: shared.range,
+    };
+    let others = match &shared.seen_inodes {
+        Some(seen) => seen.hashed(&hashed),
+        None => Vec::new(),
+    };
+    hashed_tx.send(hashed).unwrap();
+    for other in others {
+        shared.hashed.add(other.apparent_size);
+        hashed_tx.send(other).unwrap();
+    }
+}
+
+/// Receive the rest of a file without hashing it,
+/// as the reader might be waiting for the buffers, and panics if the channel is closed.
+fn skip_file(parts: mpsc::Receiver<FilePart>,  shared: &Shared) {
+    for part in parts {
+        if let FilePart::Chunk{buffer, ..} = part {
+            shared.buffers.return_buffer(buffer);
+        }
+    }
 }
 
 pub fn hash_files(shared: Arc<Shared>,  thread_info: &ThreadInfo) {
     let hashed_tx = shared.finished.lock().unwrap().clone();
-    let mut hasher = Sha256::new();
+    let mut hasher = Hasher::new(shared.hash_algorithm);
     let mut lock = shared.to_hash.lock().unwrap();
+    lock.running_hashers += 1;
 
     loop {
-        if lock.stop_now {
-            thread_info.set_state(Quit);
+        if lock.too_many_hashers(lock.running_hashers) {
+            lock.running_hashers -= 1;
+            thread_info.set_state(Parked);
             thread_info.set_working_on(None);
-            break;
-        } else if let Some((path, rx)) = lock.queue.pop() {
+            while lock.too_many_hashers(lock.running_hashers+1) {
+                lock = shared.hasher_unparker.wait(lock).unwrap();
+            }
+            lock.running_hashers += 1;
+        }
+        if let Some((path, rx, skip)) = lock.queue.pop() {
+            let stop_now = lock.stop_now;
             drop(lock);
-            hash_file(path, rx, &mut hasher, thread_info, &shared.buffers, &hashed_tx);
+            match stop_now {
+                true => {
+                    skip_file(rx, &shared);
+                    shared.finish_file(&skip);
+                }
+                false => hash_file(path, rx, skip, &mut hasher, thread_info, &shared, &hashed_tx),
+            }
             lock = shared.to_hash.lock().unwrap();
         } else if lock.stop_when_empty {
             thread_info.set_state(Quit);
@@ -99,3 +426,190 @@ pub fn hash_files(shared: Arc<Shared>,  thread_info: &ThreadInfo) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, process, thread};
+    use std::time::Duration;
+
+    fn hash_parts(parts: Vec<FilePart>) -> Option<HashedFile> {
+        hash_parts_with(parts, |_| {})
+    }
+
+    fn hash_parts_with(parts: Vec<FilePart>,  configure: impl FnOnce(&mut Shared))
+    -> Option<HashedFile> {
+        let buffers = AvailableBuffers::new(1<<20, 1<<16).unwrap();
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        let mut shared = Shared::new(buffers, hashed_tx.clone());
+        configure(&mut shared);
+        let file = UnreadFile {
+            path: Arc::new(PrintablePath::from(Path::new("/file"))),
+            modified: PrintableTime::default(),
+            created: None,
+            size: 0,
+            inode: None,
+        };
+        let (parts_tx, parts_rx) = mpsc::channel();
+        for part in parts {
+            parts_tx.send(part).unwrap();
+        }
+        drop(parts_tx);
+        let (log_tx, _log_rx) = mpsc::channel();
+        let info = ThreadInfo::new("test".to_string(), log_tx);
+        let mut hasher = Hasher::new(shared.hash_algorithm);
+        hash_file(file, parts_rx, Arc::default(), &mut hasher, &info, &shared, &hashed_tx);
+        return hashed_rx.try_recv().ok();
+    }
+
+    #[test]
+    fn park_hashers() {
+        let buffers = AvailableBuffers::new(1<<20, 1<<16).unwrap();
+        let (hashed_tx, _hashed_rx) = mpsc::channel();
+        let shared = Arc::new(Shared::new(buffers, hashed_tx));
+        let (log_tx, _log_rx) = mpsc::channel();
+        let info = (0..2)
+                .map(|i| ThreadInfo::new(format!("hasher {}", i), log_tx.clone()) )
+                .collect::<Arc<[ThreadInfo]>>();
+        let threads = (0..2).map(|i| {
+            let (shared, info) = (shared.clone(), info.clone());
+            thread::spawn(move || hash_files(shared, &info[i]) )
+        }).collect::<Vec<_>>();
+        let states = || info.iter().map(ThreadInfo::state).collect::<Vec<_>>();
+        let wait_until_parked = |parked: usize| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while states().iter().filter(|&&state| state == Parked ).count() != parked {
+                assert!(Instant::now() < deadline, "states are {:?}", states());
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+
+        shared.to_hash.lock().unwrap().max_hashers = Some(1);
+        shared.hasher_waker.notify_all();
+        wait_until_parked(1);
+        assert_eq!(shared.to_hash.lock().unwrap().running_hashers, 1);
+        shared.to_hash.lock().unwrap().max_hashers = Some(2);
+        shared.hasher_unparker.notify_one();
+        wait_until_parked(0);
+        assert_eq!(shared.to_hash.lock().unwrap().running_hashers, 2);
+
+        // parked threads quit at the end of the scan
+        shared.to_hash.lock().unwrap().max_hashers = Some(1);
+        shared.hasher_waker.notify_all();
+        wait_until_parked(1);
+        shared.to_hash.lock().unwrap().stop_when_empty = true;
+        shared.hasher_waker.notify_all();
+        shared.hasher_unparker.notify_all();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(states(), [Quit, Quit]);
+    }
+
+    #[test]
+    fn error_is_not_stored_as_empty() {
+        let error = io::Error::other("first read failed");
+        assert_eq!(hash_parts(vec![FilePart::Error(error)]), None);
+
+        let error = io::Error::other("later read failed");
+        let chunk = FilePart::Chunk{buffer: vec![1; 1024].into_boxed_slice(), length: 1024};
+        assert_eq!(hash_parts(vec![chunk, FilePart::Error(error)]), None);
+
+        let empty = hash_parts(Vec::new()).expect("empty file is stored");
+        assert_eq!(empty.read_size, 0);
+        assert_eq!(empty.hash[..], Sha256::digest([])[..]);
+    }
+
+    fn parts(chunks: &[&[u8]]) -> Vec<FilePart> {
+        chunks.iter()
+            .map(|chunk| FilePart::Chunk{buffer: chunk.to_vec().into(), length: chunk.len()} )
+            .collect()
+    }
+
+    #[test]
+    fn normalize_line_endings_across_parts() {
+        let normalize = |shared: &mut Shared| shared.normalize_text = true;
+        let lf = hash_parts_with(parts(&[b"a\nb\n\nc\n"]), normalize).unwrap();
+        assert_eq!(lf.normalized, NORMALIZED_LINE_ENDINGS);
+        assert_eq!(lf.hash[..], Sha256::digest(b"a\nb\n\nc\n")[..]);
+        let crlf = hash_parts_with(parts(&[b"a\r", b"\nb\r\n\r", b"\nc\r"]), normalize).unwrap();
+        assert_eq!(crlf.hash, lf.hash);
+        assert_eq!(crlf.read_size, 10);
+        let cr = hash_parts_with(parts(&[b"a\rb\r", b"\r", b"c\r"]), normalize).unwrap();
+        assert_eq!(cr.hash, lf.hash);
+
+        let binary = hash_parts_with(parts(&[b"a\r\n\0"]), normalize).unwrap();
+        assert_eq!(binary.normalized, 0);
+        assert_eq!(binary.hash[..], Sha256::digest(b"a\r\n\0")[..]);
+        assert_eq!(hash_parts(parts(&[b"a\r\n"])).unwrap().normalized, 0);
+    }
+
+    #[test]
+    fn strip_byte_order_mark() {
+        let strip = |shared: &mut Shared| shared.strip_bom = true;
+        let plain = hash_parts_with(parts(&[b"text\n"]), strip).unwrap();
+        assert_eq!(plain.normalized, NORMALIZED_BOM);
+        assert_eq!(plain.hash[..], Sha256::digest(b"text\n")[..]);
+        let utf8 = hash_parts_with(parts(&[b"\xef\xbb\xbftext\n"]), strip).unwrap();
+        assert_eq!((utf8.hash, utf8.read_size, utf8.normalized), (plain.hash, 5, NORMALIZED_BOM));
+        // only at the start
+        let later = hash_parts_with(parts(&[b"text", b"\xef\xbb\xbf\n"]), strip).unwrap();
+        assert_ne!(later.hash, plain.hash);
+
+        let utf16 = hash_parts_with(parts(&[b"\xff\xfet\0"]), strip).unwrap();
+        assert_eq!((utf16.read_size, utf16.normalized), (2, NORMALIZED_BOM));
+        assert_eq!(utf16.hash[..], Sha256::digest(b"t\0")[..]);
+        let binary = hash_parts_with(parts(&[b"\0\xef\xbb\xbf"]), strip).unwrap();
+        assert_eq!(binary.normalized, 0);
+        assert_eq!(binary.hash[..], Sha256::digest(b"\0\xef\xbb\xbf")[..]);
+    }
+
+    #[test]
+    fn other_algorithms() {
+        let with = |algorithm| move |shared: &mut Shared| shared.hash_algorithm = algorithm;
+        let sha512 = hash_parts_with(parts(&[b"ab", b"c"]), with(HashAlgorithm::Sha512)).unwrap();
+        assert_eq!(sha512.hash[..], Sha512::digest(b"abc")[..]);
+        let blake = hash_parts_with(parts(&[b"ab", b"c"]), with(HashAlgorithm::Blake3)).unwrap();
+        assert_eq!(blake.hash[..], blake3::hash(b"abc").as_bytes()[..]);
+
+        // the hasher is reused for the next file
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Sha512, HashAlgorithm::Blake3] {
+            let mut hasher = Hasher::new(algorithm);
+            hasher.update(b"abc");
+            let first = hasher.finalize_reset();
+            assert_eq!(first.len(), algorithm.length());
+            hasher.update(b"abc");
+            assert_eq!(hasher.finalize_reset(), first);
+        }
+    }
+
+    #[test]
+    fn parallel_blake3() {
+        let content = (0..3_000_001u32).map(|i| (i*7 % 251) as u8 ).collect::<Vec<u8>>();
+        let parallel = |shared: &mut Shared| {
+            shared.hash_algorithm = HashAlgorithm::Blake3;
+            shared.parallel_file = Some(0);
+        };
+        let (first, second) = content.split_at(1<<20);
+        let hashed = hash_parts_with(parts(&[first, second]), parallel).unwrap();
+        assert_eq!(hashed.hash[..], blake3::hash(&content).as_bytes()[..]);
+    }
+
+    #[test]
+    fn hash_single_path() {
+        let path = env::temp_dir().join(format!("decopy-hash-path-{}", process::id()));
+        fs::write(&path, "hello").unwrap();
+        // smaller than the file to require multiple reads
+        let mut buffer = [0; 2];
+        let hash = hash_path(&path, HashAlgorithm::Sha256, &mut buffer).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut hex = String::new();
+        crate::report::write_hex(&hash, &mut hex);
+        assert_eq!(hex, "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+        assert_eq!(hash.len(), HashAlgorithm::Sha256.length());
+
+        let missing = hash_path(&path, HashAlgorithm::Sha256, &mut buffer);
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,58 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The scanner as a library, for embedding it or hashing single files with `hash_path()`.
+
+#![allow(
+    clippy::uninlined_format_args, // I don't like mixing code into strings
+    clippy::needless_return, // `foo` after `foo.frob();` looks like unfinished
+    clippy::match_ref_pats, // keep it explicit
+    clippy::redundant_pattern_matching, // keep it explicit, and possibly faster
+    clippy::len_zero, clippy::comparison_to_empty, // ! is easy to miss
+)]
+
+extern crate arc_swap;
+extern crate clap;
+extern crate fxhash;
+#[cfg(target_os="linux")]
+extern crate libc;
+#[macro_use]
+extern crate rusqlite;
+extern crate sha2;
+
+// parts that could be stand-alone libraries
+pub mod bytes;
+mod multimap;
+pub mod path_decoding;
+pub mod time;
+
+// parts that are more specific to this program
+pub mod thread_info;
+pub mod available_buffers;
+pub mod direct_io;
+pub mod extents;
+pub mod reflink;
+pub mod open_files;
+pub mod pseudo_fs;
+pub mod disk_image;
+pub mod memory_pressure;
+pub mod shared;
+pub mod read;
+pub mod chunking;
+pub mod hash;
+pub mod storage;
+pub mod report;
+pub mod output;
+pub mod live_duplicates;
@@ -0,0 +1,168 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Keeping track of the biggest groups of duplicates while scanning, for --live-duplicates.
+//!
+//! Only files hashed in the current scan are seen, so groups can be smaller than in the
+//! final report.
+
+use crate::output::ResultSink;
+use crate::shared::*;
+
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use fxhash::FxBuildHasher;
+
+/// What files must have in common to be duplicates, like the grouping in the database.
+type GroupKey = (FileHash, u64, u16, u8);
+
+/// A group of files with identical content hashed so far.
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub struct LiveGroup {
+    pub files: u64,
+    pub size: u64,
+    /// The first file hashed.
+    pub example: Arc<PrintablePath>,
+}
+
+impl LiveGroup {
+    /// How many bytes could be freed by removing all but one of the files.
+    pub fn wasted(&self) -> u64 {
+        (self.files - 1) * self.size
+    }
+}
+
+#[derive(Default)]
+struct State {
+    groups: HashMap<GroupKey, LiveGroup, FxBuildHasher>,
+    /// Groups with more than one file, by the wasted space when they were pushed.
+    ///
+    /// Entries are not updated when a group grows, but a new one is pushed,
+    /// and outdated entries are skipped and removed when reading the biggest groups.
+    biggest: BinaryHeap<(u64, GroupKey)>,
+    /// Number of groups with more than one file.
+    duplicated: usize,
+}
+
+impl State {
+    /// Prevent outdated entries from growing the heap without bounds.
+    fn remove_outdated(&mut self) {
+        if self.biggest.len() > 4 * self.duplicated + 64 {
+            let groups = &self.groups;
+            self.biggest.retain(|(wasted, key)| groups[key].wasted() == *wasted );
+        }
+    }
+}
+
+/// Receives hashed files as a sink, and can be read from the UI thread through a clone.
+#[derive(Clone, Default)]
+pub struct LiveDuplicates {
+    state: Arc<Mutex<State>>,
+}
+
+impl LiveDuplicates {
+    pub fn new() -> Self {
+        LiveDuplicates::default()
+    }
+
+    /// Get up to `n` groups where most space could be freed, biggest first.
+    pub fn biggest(&self,  n: usize) -> Vec<LiveGroup> {
+        let mut state = self.state.lock().unwrap();
+        let mut biggest = Vec::<(u64, GroupKey)>::with_capacity(n);
+        while biggest.len() < n {
+            let Some((wasted, key)) = state.biggest.pop() else {
+                break;
+            };
+            // a group can have several outdated entries, but only one current
+            if state.groups[&key].wasted() == wasted {
+                biggest.push((wasted, key));
+            }
+        }
+        let groups = biggest.iter().map(|(_, key)| state.groups[key].clone() ).collect();
+        state.biggest.extend(biggest);
+        return groups;
+    }
+}
+
+impl ResultSink for LiveDuplicates {
+    fn add(&mut self,  file: &HashedFile) -> io::Result<()> {
+        let key = (file.hash, file.read_size, file.sampled_blocks, file.normalized);
+        let mut state = self.state.lock().unwrap();
+        let group = state.groups.entry(key).or_insert_with(|| LiveGroup {
+            files: 0,
+            size: file.read_size,
+            example: file.path.clone(),
+        });
+        group.files += 1;
+        let (files, wasted) = (group.files, group.wasted());
+        if files == 2 {
+            state.duplicated += 1;
+        }
+        if wasted > 0 {
+            state.biggest.push((wasted, key));
+            state.remove_outdated();
+        }
+        return Ok(());
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn biggest_groups_first() {
+        let live = LiveDuplicates::new();
+        let mut sink = live.clone();
+        let mut add = |path: &str,  hash: u8,  size: u64| {
+            sink.add(&HashedFile {
+                path: Arc::new(PrintablePath::from(Path::new(path))),
+                modified: PrintableTime::default(),
+                created: None,
+                apparent_size: size,
+                read_size: size,
+                hash: [hash; 32].into(),
+                sampled_blocks: 0,
+                inode: None,
+                normalized: 0,
+                chunks: Vec::new(),
+                range: None,
+            }).unwrap();
+        };
+        add("/a", 1, 100);
+        add("/b", 2, 30);
+        assert_eq!(live.biggest(3), Vec::new());
+        add("/c", 2, 30);
+        add("/d", 1, 100);
+        add("/e", 2, 30);
+        add("/f", 2, 30);
+        let wasted = |groups: Vec<LiveGroup>| {
+            groups.iter().map(|group| (group.example.to_string(), group.wasted()) )
+                    .collect::<Vec<_>>()
+        };
+        assert_eq!(wasted(live.biggest(3)), [("/a".to_string(), 100), ("/b".to_string(), 90)]);
+        // reading doesn't remove groups
+        assert_eq!(live.biggest(1).len(), 1);
+        add("/g", 1, 100);
+        assert_eq!(wasted(live.biggest(1)), [("/a".to_string(), 200)]);
+    }
+}
@@ -21,45 +21,39 @@
     clippy::len_zero, clippy::comparison_to_empty, // ! is easy to miss
 )]
 
-extern crate arc_swap;
 extern crate clap;
-extern crate fxhash;
+extern crate decopy_scan;
 #[cfg(target_os="linux")]
 extern crate ioprio;
 extern crate is_terminal;
-#[macro_use]
-extern crate rusqlite;
-extern crate sha2;
 extern crate term_size;
 extern crate thread_priority;
 
-// parts that could be stand-alone libraries
-mod bytes;
-mod multimap;
-mod path_decoding;
-mod time;
-
-// parts that are more specific to this program
-mod thread_info;
-mod available_buffers;
-mod shared;
-mod read;
-mod hash;
-mod storage;
-
-use bytes::*;
-use path_decoding::*;
-use hash::*;
-use read::*;
-use shared::*;
-use storage::Sqlite;
-use thread_info::*;
-
-use std::{fmt::Write, fs, path::PathBuf, process::exit, str::FromStr, thread};
-use std::io::{Write as ioWrite, stderr};
+use decopy_scan::bytes::*;
+use decopy_scan::disk_image::{self, exit};
+use decopy_scan::path_decoding::*;
+use decopy_scan::hash::*;
+use decopy_scan::live_duplicates::LiveDuplicates;
+use decopy_scan::memory_pressure::*;
+use decopy_scan::open_files::OpenForWriting;
+use decopy_scan::output::*;
+use decopy_scan::read::*;
+use decopy_scan::reflink::{hard_links, replace_with_reflink, same_content};
+use decopy_scan::extents::physical_extents;
+use decopy_scan::report::*;
+use decopy_scan::shared::*;
+use decopy_scan::storage::{AutoVacuum, DEFAULT_BUSY_TIMEOUT, Durability, HashStorage, Sqlite,
+                           StorageOptions, valid_page_size};
+use decopy_scan::thread_info::*;
+
+use std::{env, fmt::Write, fs, path::{Path, PathBuf}, str::FromStr, thread};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Write as ioWrite, stderr, stdin, stdout};
 use std::num::{NonZeroU16, NonZeroU64};
 use std::sync::{Arc, mpsc};
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 use clap::Parser;
 use is_terminal::IsTerminal;
@@ -67,6 +61,24 @@ use thread_priority::{ThreadBuilder, ThreadPriority};
 #[cfg(unix)]
 use thread_priority::unix::{NormalThreadSchedulePolicy, ThreadSchedulePolicy};
 
+/// Deleted files are only pruned from the database without --prune if there are at most this many,
+/// as more might mean that the roots are wrong or a disk isn't mounted.
+const PRUNE_WITHOUT_ASKING: usize = 1000;
+
+/// Set by the Ctrl-C handler, and checked every frame and after storing.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Set when there is nothing left to store, so that Ctrl-C exits right away.
+static EXIT_ON_INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+/// Where to find the files hashed by previous scans.
+#[derive(Clone,Copy, Debug, PartialEq,Eq, clap::ValueEnum)]
+enum PreviousLookup {
+    /// Load the files under the roots into memory before scanning, which is fastest.
+    Memory,
+    /// Query the database for each file found, which uses less memory for millions of files.
+    Database,
+}
+
 #[derive(Clone,Copy, Debug)]
 struct Rate(Duration);
 impl FromStr for Rate {
@@ -95,37 +107,1657 @@ impl FromStr for Rate {
     }
 }
 
+/// A duration back in time, such as 90d, 12h or 30m.
+#[derive(Clone,Copy, Debug)]
+struct Age(Duration);
+impl FromStr for Age {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Age, String> {
+        let (number, unit_secs) = if let Some(days) = s.strip_suffix('d') {
+            (days, 24*60*60)
+        } else if let Some(hours) = s.strip_suffix('h') {
+            (hours, 60*60)
+        } else if let Some(minutes) = s.strip_suffix('m') {
+            (minutes, 60)
+        } else {
+            return Err("missing unit (d, h or m)".to_string());
+        };
+        match u64::from_str(number.trim_end()) {
+            Ok(number) => match number.checked_mul(unit_secs) {
+                Some(secs) => Ok(Age(Duration::from_secs(secs))),
+                None => Err("too long".to_string()),
+            },
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+impl Age {
+    /// Get the time this long before `now`.
+    fn before(self,  now: SystemTime) -> PrintableTime {
+        PrintableTime::from(now).saturating_sub(self.0).clamp_to_yyyy()
+    }
+}
+
+/// What --strict does when something is wrong.
+#[derive(Clone,Copy, Debug, PartialEq,Eq, clap::ValueEnum)]
+enum Strict {
+    /// Finish the scan, but exit with an error.
+    Exit,
+    /// Stop the scan at the first warning or error.
+    Abort,
+}
+
 #[derive(Parser, Debug)]
 #[command(arg_required_else_help=true, author, version, about, long_about=None)]
 struct Args {
     #[arg(short, long)]
     database: Option<PathBuf>,
+    /// Show more about each file in reports, such as when it was created
+    #[arg(short, long)]
+    verbose: bool,
+    /// Show paths in the duplicate report and --dir-pairs relative to the current directory
+    ///
+    /// Paths that are not under it are shown in full.
+    #[arg(long)]
+    cwd_relative: bool,
+    /// How to store hashes in the database
+    #[arg(long, value_enum, default_value_t=HashStorage::Blob)]
+    hash_storage: HashStorage,
+    /// Which hash function to hash files with
+    ///
+    /// A database only has hashes from one of them, so this must be the same as when it was
+    /// created, also for modes that only read it.
+    /// Chunks from --cdc are always hashed with SHA-256,
+    /// and --format sha256sum can only be used with sha256.
+    #[arg(long, value_enum, default_value_t=HashAlgorithm::Sha256)]
+    hash_algorithm: HashAlgorithm,
+    /// Whether to flush the database to disk before exiting,
+    /// for when the results are used to delete files
+    #[arg(long, value_enum, default_value_t=Durability::Fast, requires="database")]
+    durability: Durability,
+    /// How many seconds to wait for other programs that are writing to the database
+    #[arg(long, value_name="SECONDS", requires="database",
+          default_value_t=DEFAULT_BUSY_TIMEOUT.as_secs() as u32)]
+    busy_timeout: u32,
+    /// The page size of a new database, a power of two from 512 to 65536 bytes
+    ///
+    /// Bigger pages can make big databases smaller and faster to scan.
+    /// This only takes effect when the database is created.
+    #[arg(long, value_name="BYTES", requires="database")]
+    db_page_size: Option<u32>,
+    /// Whether a new database shrinks when files are removed from it
+    ///
+    /// This only takes effect when the database is created.
+    #[arg(long, value_enum, value_name="MODE", requires="database")]
+    db_auto_vacuum: Option<AutoVacuum>,
+    /// Where to look for the files hashed by previous scans
+    ///
+    /// database doesn't work with --rehash or --fast-incremental.
+    #[arg(long, value_enum, default_value_t=PreviousLookup::Memory, requires="database")]
+    previous_lookup: PreviousLookup,
     #[arg(short, long, value_name="NUMBER_OF_IO_THREADS", default_value_t=NonZeroU16::new(2).unwrap())]
     io_threads: NonZeroU16,
+    /// Read directories on this many separate threads, leaving files to the IO threads
+    ///
+    /// By default the IO threads read both.
+    /// One or two directory threads can help when listing directories is slow,
+    /// such as on network file systems, so that reading files doesn't wait for it.
+    #[arg(long, value_name="NUMBER_OF_DIRECTORY_THREADS")]
+    dir_threads: Option<NonZeroU16>,
     #[arg(short='t', long, value_name="NUBMER_OF_HASHER_THREADS", default_value_t=NonZeroU16::new(4).unwrap())]
     hasher_threads: NonZeroU16,
     #[arg(short='b', long, default_value_t=Bytes::new(1<<20))]
     max_buffer_size: Bytes,
+    /// Read files this much at a time, instead of in buffers as big as the rest of the file
+    ///
+    /// It's still limited by --max-buffer-size.
+    /// By default small files get small buffers, so that more of them fit in memory,
+    /// while big files are read in buffers of the max size.
+    /// A fixed size means buffers can be reused without resizing them,
+    /// which can be faster when most files are bigger than it.
+    #[arg(long, value_name="BYTES")]
+    read_chunk: Option<Bytes>,
     #[arg(short, long, value_name="MAX_MEMORY_USAGE_OF_BUFFERS", default_value_t=Bytes::new(1<<30))]
     max_buffers_memory: Bytes,
+    /// Stop reading more files after roughly this many bytes have been hashed
+    ///
+    /// Files that are being read when the limit is reached are still hashed and stored,
+    /// but files that were not reached are not removed from the database.
+    #[arg(long, value_name="BYTES")]
+    max_bytes: Option<Bytes>,
     #[arg(short, long, value_name="RATE")]
     refresh_rate: Option<Rate>,
-    #[arg(required = true)]
+    /// Start more hasher threads when hashing is the bottleneck, up to this number (experimental)
+    ///
+    /// Threads that were started are parked when there is nothing for them to hash,
+    /// but never fewer than --hasher-threads.
+    #[arg(long, value_name="MAX_HASHER_THREADS")]
+    auto_scale: Option<NonZeroU16>,
+    /// Hash files of at least this size on all cores, instead of one hasher thread per file
+    ///
+    /// Only BLAKE3 can combine the hashes of separate parts of a file, so this requires
+    /// --hash-algorithm blake3. Each part that is read is split between the threads,
+    /// so a bigger --max-buffer-size makes it more effective.
+    #[arg(long, value_name="SIZE")]
+    parallel_file: Option<Bytes>,
+    /// Only hash files that were last modified longer ago than this, such as 90d, 12h or 30m
+    #[arg(long, value_name="AGE")]
+    older_than: Option<Age>,
+    /// Only hash files that were modified more recently than this, such as 90d, 12h or 30m
+    #[arg(long, value_name="AGE")]
+    newer_than: Option<Age>,
+    /// Skip files and directories that sort before this path, to continue an interrupted scan
+    ///
+    /// Deleted files are not pruned from the database when this is used.
+    #[arg(long, value_name="PATH")]
+    resume_from: Option<PathBuf>,
+    /// Store LABEL with the files hashed in this scan, to be able to filter reports by it
+    ///
+    /// Files that are unchanged since a previous scan keep the tag they were hashed with.
+    #[arg(long, value_name="LABEL")]
+    tag: Option<String>,
+    /// Store which of the roots each hashed file was found under in the database
+    #[arg(long)]
+    record_root: bool,
+    /// Hash the files under the roots that are in the database again, even if unchanged,
+    /// instead of looking for new files
+    ///
+    /// Files that no longer exist are pruned.
+    #[arg(long, requires="database", conflicts_with="inode_cache")]
+    rehash: bool,
+    /// Prune deleted files from the database even if there are more than 1000 of them
+    #[arg(long, requires="database")]
+    prune: bool,
+    /// Print the deleted files instead of pruning them from the database
+    #[arg(long, requires="database", conflicts_with="prune")]
+    prune_dry_run: bool,
+    /// Only hash files owned by this user, given as id, or as name on Linux
+    #[arg(long, value_name="USER")]
+    owner: Option<String>,
+    /// Only hash files owned by this group, given as id, or as name on Linux
+    #[arg(long, value_name="GROUP")]
+    group: Option<String>,
+    /// Don't read directories whose modification time is unchanged since the previous scan
+    /// with this option, and assume the files in them are unchanged too
+    ///
+    /// Changing the content of a file doesn't change the modification time of its directory,
+    /// so such changes will not be detected. The same applies to files that were skipped
+    /// by --older-than, --newer-than, --owner or --group in the previous scan.
+    #[arg(long, requires="database", conflicts_with="rehash")]
+    fast_incremental: bool,
+    /// Reuse hashes of moved or renamed files that have the same device, inode, size
+    /// and modification time as a previously hashed file
+    #[arg(long)]
+    inode_cache: bool,
+    /// Read files that other processes have open for writing last, and skip them if still open
+    ///
+    /// This looks through the open files of all processes regularly, which can be slow,
+    /// and only works on Linux. Files opened by other users are only seen when running as root.
+    #[arg(long)]
+    skip_open_files: bool,
+    /// Also scan directories on pseudo-filesystems such as /proc and /sys
+    ///
+    /// These are skipped by default on Linux, as their files are not stored anywhere,
+    /// and some of them like /proc/kcore make reading hang or fail.
+    #[arg(long)]
+    allow_pseudofs: bool,
+    /// Remember every path queued, to never read a file or directory twice in one scan
+    ///
+    /// Overlapping roots are already removed, so this is only needed if paths can still be
+    /// reached twice, and it costs memory for every file and directory until the scan is done.
+    /// This also reads hard links to the same file only once,
+    /// which is always done when scanning several roots.
+    #[arg(long)]
+    dedup_traversal: bool,
+    /// Only read files once another file with the same size is found
+    ///
+    /// Files that are alone with their size cannot have duplicates, so this saves reading them,
+    /// but they are then not stored in the database.
+    /// Files given as roots and files changed since the previous scan are always read.
+    #[arg(long, conflicts_with_all=["normalize_text", "strip_bom", "range", "cdc"])]
+    skip_unique_sizes: bool,
+    /// Log why each file was or wasn't hashed, such as being new, unchanged or excluded
+    ///
+    /// This is a line per file, so it's only useful for finding out why an incremental scan
+    /// did or didn't skip some files.
+    #[arg(long, conflicts_with="summary_only")]
+    explain: bool,
+    /// Lower the memory limit for buffers while Linux reports that memory is scarce
+    #[arg(long)]
+    adaptive_memory: bool,
+    /// Only count the files and bytes under the roots, without hashing anything
+    #[arg(long, conflicts_with="rehash")]
+    count_only: bool,
+    /// Count files before hashing them, to be able to show progress
+    #[arg(long)]
+    prescan: bool,
+    /// Show how many threads are in each state instead of one line per thread
+    #[arg(long, conflicts_with="detailed_ui")]
+    compact_ui: bool,
+    /// Show one line per thread even if they don't fit in the terminal
+    #[arg(long)]
+    detailed_ui: bool,
+    /// Show the N groups of duplicates where most space could be freed while scanning,
+    /// when there is room for them in the terminal
+    ///
+    /// Only files hashed in this scan are included.
+    #[arg(long, value_name="N", conflicts_with="summary_only")]
+    live_duplicates: Option<NonZeroU16>,
+    /// Only show messages that are at least this serious
+    #[arg(long, value_enum, default_value_t=LogLevel::Info)]
+    log_level: LogLevel,
+    /// Start log messages with when they happened (in UTC)
+    #[arg(long)]
+    log_timestamps: bool,
+    /// Append errors to this file instead of showing them with the other messages
+    #[arg(long, value_name="FILE")]
+    error_log: Option<PathBuf>,
+    /// Treat warnings as errors, and exit with an error if there were any
+    ///
+    /// Warnings are logged when the size of a file read differs from its size in the directory
+    /// listing (such as when it's modified while read), when the modification time of a file
+    /// is not available and its creation time is used instead,
+    /// and on Windows when a path is not valid Unicode and cannot be looked up in the database.
+    /// Errors are logged when a file or directory cannot be opened or read.
+    #[arg(long, value_enum, value_name="WHEN", num_args=0..=1, require_equals=true,
+            default_missing_value="exit")]
+    strict: Option<Strict>,
+    /// Only hash N blocks of 64 KiB from bigger files, to quickly find probable duplicates
+    #[arg(long, value_name="N")]
+    sample: Option<NonZeroU16>,
+    /// Hash text files as if all line endings were LF, so that CRLF copies are duplicates
+    ///
+    /// Files without NUL bytes at the start are treated as text.
+    /// Their hashes are only compared with other normalized hashes,
+    /// and files already in the database are not rehashed unless changed or --rehash is used.
+    #[arg(long, conflicts_with="sample")]
+    normalize_text: bool,
+    /// Leave out UTF-8 and UTF-16 byte order marks at the start of text files when hashing
+    ///
+    /// This makes files saved with and without a BOM duplicates.
+    /// Like with --normalize-text, these hashes are only compared with other hashes made with
+    /// this option, and files already in the database are not rehashed unless changed
+    /// or --rehash is used.
+    #[arg(long, conflicts_with="sample")]
+    strip_bom: bool,
+    /// Also split files into chunks where the content matches a pattern, and store their hashes,
+    /// to find identical regions in different files with --shared-chunks (experimental)
+    ///
+    /// Inserting or removing bytes only changes the chunks around the change.
+    /// Sampled files and files with normalized line endings are not chunked.
+    /// The chunks are hashed with SHA-256 whatever --hash-algorithm is.
+    #[arg(long)]
+    cdc: bool,
+    /// Only hash bytes START up to END of every file, such as to compare headers
+    ///
+    /// END is exclusive, and both can have a unit, such as 4K:8K.
+    /// Files smaller than END are not hashed but logged as errors.
+    /// As the hashes are not of whole files, this cannot be used with a database
+    /// or --format sha256sum.
+    #[arg(long, value_name="START:END",
+            conflicts_with_all=["database", "sample", "normalize_text", "strip_bom", "cdc"])]
+    range: Option<ByteRange>,
+    /// Read files with O_DIRECT to not evict other data from the page cache (Linux only)
+    ///
+    /// Files on file systems that don't support it are removed from the cache after reading.
+    #[arg(long)]
+    direct_io: bool,
+    /// Tell the OS that files are read sequentially and only once (Linux only)
+    #[arg(long)]
+    fadvise: bool,
+    /// Write progress as JSON lines to this already open file descriptor
+    /// (or to this file path on Windows), for programs wrapping this one
+    #[arg(long, value_name="N")]
+    progress_fd: Option<String>,
+    /// Write timestamped lifecycle events as JSON lines to this file, for log ingestion
+    ///
+    /// The events are scan_start, dir_entered, file_hashed, commit and scan_end,
+    /// and each has the seconds since the scan started and the name of the thread.
+    #[arg(long, value_name="FILE")]
+    events_ndjson: Option<PathBuf>,
+    /// Also write files in this format as they are hashed and stored, json or sha256sum
+    ///
+    /// They are written to FILE if given, where - is stdout, and otherwise to --output or stdout.
+    /// This can be repeated to write several formats or files, but only one can write to stdout.
+    #[arg(long, value_name="FORMAT[:FILE]", group="results")]
+    format: Vec<FormatTarget>,
+    /// Also write a line in this format to stdout for each file as it is hashed and stored
+    ///
+    /// The placeholders are {hash}, {size}, {size:human}, {modified}, {path}, {name} and {dir},
+    /// and {{ and }} are literal braces.
+    #[arg(long, value_name="FORMAT", group="results")]
+    template: Option<Template>,
+    /// Write --format without a FILE, or --template, to this file instead of stdout
+    ///
+    /// If it's a named pipe, each file is written as soon as it's stored,
+    /// for another program to process the results while scanning.
+    #[arg(long, value_name="FILE", requires="results")]
+    output: Option<PathBuf>,
+    /// After scanning, write every file in the database to this file as a JSON array
+    ///
+    /// Each file is an object with its path, modification time, sizes and hash,
+    /// and paths that cannot be shown exactly have `path_is_lossy` set.
+    #[arg(long, value_name="FILE", conflicts_with_all=[
+            "merge_db", "forget", "hash_stdin_per_line", "is_duplicate", "report_only",
+    ])]
+    export_json: Option<PathBuf>,
+    /// Print the resolved roots and settings, then exit without scanning
+    #[arg(long)]
+    print_plan: bool,
+    /// Print groups of identical files when done
+    #[arg(long)]
+    report: bool,
+    /// How to order the files in each group of duplicates
+    ///
+    /// The first file is the one kept by --reflink-duplicates and marked by --survivor-marker,
+    /// and files that are equal by this are ordered by path.
+    #[arg(long, value_enum, value_name="BY", default_value_t=GroupOrder::Path)]
+    group_order: GroupOrder,
+    /// Start the line of the first file in each group of duplicates with this
+    #[arg(long, value_name="STRING")]
+    survivor_marker: Option<String>,
+    /// Only print the groups of identical files and totals when done, without showing progress
+    ///
+    /// Log messages are counted but only errors are shown, even with --log-level.
+    #[arg(long, conflicts_with="results")]
+    summary_only: bool,
+    /// Replace duplicates with reflinks of the first file in their group, after comparing them
+    ///
+    /// Reflinks share storage until one of the files is modified, so unlike hard links they stay
+    /// independent files. This only works on Linux, on file systems that support it such as btrfs
+    /// and XFS. Files that cannot be reflinked are left as they are.
+    #[arg(long)]
+    reflink_duplicates: bool,
+    /// Only print which files --reflink-duplicates would replace, and how much that would free
+    #[arg(long, requires="reflink_duplicates")]
+    dry_run: bool,
+    /// Compare the content of the files in every group of duplicates, and report any that
+    /// differ despite having the same hash
+    ///
+    /// This should never find anything with SHA-256, so any collision points to a bug.
+    /// Exits with status 1 if any are found. Files that have been modified since they were hashed,
+    /// and groups that were sampled or normalized, are skipped.
+    #[arg(long, conflicts_with="range")]
+    collision_audit: bool,
+    /// Only include files with this tag in reports
+    #[arg(long, value_name="LABEL")]
+    filter_tag: Option<String>,
+    /// Only consider files with the same tag as duplicates
+    #[arg(long)]
+    group_within_tag: bool,
+    /// Mark files in reports that are hard links or reflinks of another file in the group,
+    /// and don't count them as wasted space (Linux only)
+    #[arg(long)]
+    detect_reflinks: bool,
+    /// Only report groups of duplicates where at least this much space could be freed,
+    /// and show the groups where most can be freed first
+    #[arg(long, value_name="BYTES")]
+    min_waste: Option<Bytes>,
+    /// Don't count files smaller than this as space that could be freed,
+    /// but still list them in reports
+    #[arg(long, value_name="BYTES")]
+    waste_min_size: Option<Bytes>,
+    /// Print the N pairs of directories that share the most identical data when done
+    #[arg(long, value_name="N")]
+    dir_pairs: Option<usize>,
+    /// Print the N files whose content has changed the most times between scans when done
+    #[arg(long, value_name="N")]
+    churn: Option<usize>,
+    /// Print how many files under the roots were added, removed or modified compared to
+    /// another database when done, such as a copy of --database from before the scan
+    #[arg(long, value_name="DB")]
+    diff_against: Option<PathBuf>,
+    /// Also print the added, removed and modified files with --diff-against
+    #[arg(long, requires="diff_against")]
+    list_diff: bool,
+    /// Print the total size of each directory under the roots when done, like du,
+    /// and how much of it is files with copies elsewhere
+    #[arg(long)]
+    du: bool,
+    /// Only print directories up to this many levels below the roots with --du
+    #[arg(long, value_name="LEVELS", requires="du")]
+    max_depth: Option<usize>,
+    /// Print the files under the roots whose content is not in this database when done
+    ///
+    /// Files are compared by hash and size, to find what hasn't been backed up or ingested.
+    #[arg(long, value_name="DB")]
+    only_new_vs: Option<PathBuf>,
+    /// Print the N biggest groups of directories that contain files with the same names
+    /// when done, regardless of the content of the files
+    #[arg(long, value_name="N")]
+    same_structure: Option<usize>,
+    /// Print the N pairs of different files with the most content in identical chunks
+    /// from --cdc when done
+    #[arg(long, value_name="N")]
+    shared_chunks: Option<usize>,
+    /// Group files with identical hash as duplicates even if they have different sizes
+    #[arg(long)]
+    ignore_size_in_grouping: bool,
+    /// Only consider files with the same name as duplicates
+    #[arg(long, conflicts_with="different_name")]
+    same_name: bool,
+    /// Only show duplicates where some of the files have a different name
+    #[arg(long)]
+    different_name: bool,
+    /// Don't scan, but report duplicates across --database and these databases
+    ///
+    /// The databases are only read, so they must have been upgraded by a scan with this version.
+    /// Files are identified by their path, so if several databases have a file with the same path,
+    /// only the one from the last database is reported, with a warning of how many there are.
+    #[arg(long, value_name="DATABASE", conflicts_with="roots")]
+    merge_db: Vec<PathBuf>,
+    /// Don't scan, but report the duplicates already in --database, biggest waste first
+    ///
+    /// The other report options such as --dir-pairs and --min-waste still apply.
+    #[arg(long, requires="database", conflicts_with_all=[
+            "roots", "roots_dir", "image", "merge_db", "forget", "hash_stdin_per_line",
+            "is_duplicate",
+    ])]
+    report_only: bool,
+    /// Don't scan, but remove this file or directory and everything in it from the database
+    #[arg(long, value_name="PATH", requires="database", conflicts_with_all=["roots", "merge_db"])]
+    forget: Vec<PathBuf>,
+    /// Don't scan, but hash this file and print the files in the database with the same content
+    ///
+    /// Exits with status 0 if there are any, 1 if there are none, and 2 if the file cannot be
+    /// hashed. The file itself is not printed even if it is in the database.
+    #[arg(long, value_name="PATH", requires="database", conflicts_with_all=[
+            "roots", "roots_dir", "image", "merge_db", "forget", "hash_stdin_per_line",
+    ])]
+    is_duplicate: Option<PathBuf>,
+    /// Also scan every directory or symlink to a directory in this directory
+    ///
+    /// Other entries are skipped with a warning.
+    #[arg(long, value_name="DIR", conflicts_with_all=["merge_db", "forget"])]
+    roots_dir: Vec<PathBuf>,
+    /// Also scan the files in this disk image, by mounting it read-only (Linux only)
+    ///
+    /// This usually requires root. The image is mounted in a new private directory under
+    /// the temporary directory, and unmounted when done, so the files are only for reports.
+    /// They are stored and reported under the path of the image, as if it was a directory,
+    /// and every file in it is hashed again by each scan. Scanning a directory that contains
+    /// the image without --image removes them from the database.
+    #[arg(long, value_name="FILE",
+            conflicts_with_all=["merge_db", "forget", "hash_stdin_per_line", "reflink_duplicates"])]
+    image: Vec<PathBuf>,
+    /// Don't scan, but check files listed on stdin in the format of sha256sum
+    ///
+    /// Use --hash-algorithm to check the output of sha512sum or b3sum.
+    /// Prints OK, FAILED or MISSING for each line like `sha256sum -c`,
+    /// and exits with status 1 if any file doesn't match or cannot be read.
+    /// Lines that cannot be parsed are skipped with a warning, unless --strict is given.
+    #[arg(long, conflicts_with_all=[
+            "roots", "roots_dir", "merge_db", "forget", "database", "sample", "normalize_text",
+            "strip_bom",
+    ])]
+    hash_stdin_per_line: bool,
+    /// Read commands from stdin during the scan, to skip files that take too long
+    ///
+    /// An empty line or `skip` skips the file that has been read and hashed for the longest,
+    /// and `skip PATH` skips that file. Skipped files are not stored in the database.
+    #[arg(long, conflicts_with="hash_stdin_per_line")]
+    control_stdin: bool,
+    #[arg(required_unless_present_any=[
+            "merge_db", "forget", "roots_dir", "image", "hash_stdin_per_line", "is_duplicate",
+            "report_only",
+    ])]
     roots: Vec<PathBuf>,
 }
 
+fn start_hasher(shared: &Arc<Shared>,  hasher_info: &Arc<[ThreadInfo]>,  i: usize)
+-> thread::JoinHandle<()> {
+    let shared = shared.clone();
+    let hasher_info = hasher_info.clone();
+    let builder = ThreadBuilder::default()
+            .name(hasher_info[i].name())
+            .priority(ThreadPriority::Min);
+    #[cfg(unix)]
+    let builder = builder.policy(
+            ThreadSchedulePolicy::Normal(NormalThreadSchedulePolicy::Batch)
+    );
+    builder.spawn(move |priority_result| {
+        if let Err(e) = priority_result {
+            eprintln!("Failed lowering thread priority: {:?}", e);
+        }
+        let info = &hasher_info[i];
+        hash_files(shared, info)
+    }).unwrap()
+}
+
+/// Whether hashing can't keep up with reading.
+///
+/// This is the case if there are more files waiting to be hashed than there are hasher threads,
+/// and most IO threads are waiting for buffers to be returned or have nothing to do.
+fn need_more_hashers(shared: &Shared,  io_info: &[ThreadInfo],  running_hashers: usize) -> bool {
+    let waiting_files = shared.to_hash.lock().unwrap().queue.len();
+    let waiting_io = io_info.iter()
+            .filter(|info| matches!(info.state(), WaitingForMemory | Idle) )
+            .count();
+    waiting_files > running_hashers  &&  waiting_io*2 > io_info.len()
+}
+
+/// Whether hashing easily keeps up with reading,
+/// as there are no files waiting to be hashed and more than one hasher thread has nothing to do.
+fn need_fewer_hashers(shared: &Shared,  hasher_info: &[ThreadInfo]) -> bool {
+    let waiting_files = shared.to_hash.lock().unwrap().queue.len();
+    let idle_hashers = hasher_info.iter().filter(|info| info.state() == Idle ).count();
+    waiting_files == 0  &&  idle_hashers > 1
+}
+
+/// Canonicalize the roots, and remove duplicates and roots that are inside other roots,
+/// so that no file is hashed twice even if the roots are reached through symlinks.
+///
+/// Roots that cannot be canonicalized, such as symlinks that form a cycle, are an error.
+/// Messages about removed roots are appended to `notes`.
+fn resolve_roots(paths: &[PathBuf],  notes: &mut String) -> Result<Vec<Arc<PrintablePath>>, String> {
+    let mut roots = Vec::<Arc<PrintablePath>>::with_capacity(paths.len());
+    for dir_path in paths {
+        let dir_path = fs::canonicalize(dir_path).map_err(|e| {
+            format!("Cannot canonicalize {}: {}", PrintablePath::from(dir_path.as_path()), e)
+        })?;
+        let printable = Arc::new(PrintablePath::from(dir_path));
+        if !roots.contains(&printable) {
+            roots.push(printable);
+        }
+    }
+    let all = roots.clone();
+    roots.retain(|root| {
+        let outer = all.iter().find(|other| other != &root && root.starts_with(other.as_path()) );
+        if let Some(outer) = outer {
+            writeln!(notes, "{} is inside {}, so it is not scanned separately", root, outer).unwrap();
+        }
+        outer.is_none()
+    });
+    return Ok(roots);
+}
+
+/// Mount the --image files and add them to the roots, or exit if any cannot be mounted.
+///
+/// Returns the mount points with the absolute path of the image,
+/// which their files are stored under.
+/// They must be unmounted with `disk_image::unmount_all()`, or by exiting with `exit()`.
+fn mount_images(images: &[PathBuf],  roots: &mut Vec<Arc<PrintablePath>>)
+-> Vec<(Arc<PrintablePath>, Arc<PrintablePath>)> {
+    let mut mounted = Vec::new();
+    for image in images {
+        match disk_image::mount(image) {
+            Ok(mount_point) => {
+                eprintln!("Mounted {} at {}",
+                        PrintablePath::from(image.as_path()),
+                        PrintablePath::from(mount_point.as_path()),
+                );
+                let root = fs::canonicalize(&mount_point).unwrap_or(mount_point);
+                let root = Arc::new(PrintablePath::from(root));
+                let image = fs::canonicalize(image).unwrap_or_else(|_| image.clone() );
+                roots.push(root.clone());
+                mounted.push((root, Arc::new(PrintablePath::from(image))));
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(2);
+            }
+        }
+    }
+    return mounted;
+}
+
+/// Get the directories and symlinks to directories in a --roots-dir, sorted by name.
+fn roots_in_dir(dir: &Path,  notes: &mut String) -> Result<Vec<PathBuf>, String> {
+    let printable = PrintablePath::from(dir);
+    let entries = fs::read_dir(dir).map_err(|e| format!("Cannot open {}: {}", printable, e) )?;
+    let mut roots = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Cannot read {}: {}", printable, e) )?;
+        let path = entry.path();
+        // follows symlinks
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => roots.push(path),
+            Ok(_) => {
+                let path = PrintablePath::from(path);
+                writeln!(notes, "Warning: {} is not a directory, skipping it", path).unwrap();
+            }
+            Err(e) => {
+                let path = PrintablePath::from(path);
+                writeln!(notes, "Warning: Cannot resolve {}: {}, skipping it", path, e).unwrap();
+            }
+        }
+    }
+    roots.sort();
+    return Ok(roots);
+}
+
+/// Get the id of a user or group given as id or name, for --owner and --group.
+///
+/// `kind` is "user" or "group", and decides which the name is looked up as.
+/// Exits if the platform doesn't have owners or the name is not found.
+fn resolve_id(arg: &str,  kind: &str) -> u32 {
+    if cfg!(not(unix)) {
+        eprintln!("Filtering by owner is only supported on Unix");
+        exit(2);
+    }
+    if let Ok(id) = arg.parse() {
+        return id;
+    }
+    #[cfg(target_os="linux")]
+    let found = match kind {
+        "user" => user_id(arg),
+        _ => group_id(arg),
+    };
+    #[cfg(not(target_os="linux"))]
+    let found = Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+            "names are only supported on Linux, use the numeric id instead",
+    ));
+    match found {
+        Ok(Some(id)) => return id,
+        Ok(None) => eprintln!("There is no {} named {}", kind, arg),
+        Err(e) => eprintln!("Cannot look up {} {}: {}", kind, arg, e),
+    }
+    exit(2);
+}
+
+fn find_duplicates(args: &Args,  storage: &Sqlite) -> Vec<DuplicateGroup> {
+    let options = GroupingOptions {
+        ignore_size: args.ignore_size_in_grouping,
+        names: match (args.same_name, args.different_name) {
+            (true, _) => NameFilter::Same,
+            (false, true) => NameFilter::Different,
+            (false, false) => NameFilter::Any,
+        },
+        tag: args.filter_tag.clone(),
+        within_tag: args.group_within_tag,
+        min_waste: args.min_waste.map_or(0, Bytes::as_u64),
+        waste_min_size: args.waste_min_size.map_or(0, Bytes::as_u64),
+    };
+    let mut groups = storage.find_duplicate_groups(&options);
+    sort_groups(&mut groups, args.group_order);
+    if args.detect_reflinks {
+        detect_shared_storage(&mut groups);
+        // files that share storage don't waste any
+        retain_wasteful(&mut groups, options.min_waste);
+    }
+    return groups;
+}
+
+/// Whether anything about the hashed files is written, apart from to the database.
+fn prints_results(args: &Args) -> bool {
+    !args.format.is_empty() || args.template.is_some() || args.events_ndjson.is_some()
+    || args.report || args.dir_pairs.is_some() || args.churn.is_some()
+    || args.same_structure.is_some() || args.shared_chunks.is_some()
+    || args.diff_against.is_some() || args.du
+    || args.only_new_vs.is_some() || args.summary_only || args.reflink_duplicates
+    || args.collision_audit
+}
+
+/// Compare every file in each group with the first, for --collision-audit.
+///
+/// Returns how many files have the same hash as the first but different content.
+fn audit_collisions(groups: &[DuplicateGroup]) -> usize {
+    let mut out = stdout().lock();
+    let (mut compared, mut skipped, mut collisions) = (0, 0, 0);
+    let unchanged = |file: &DuplicateEntry| match fs::metadata(file.path.as_path()) {
+        Ok(metadata) => metadata.len() == file.read_size
+                && metadata.modified().ok().map(PrintableTime::from) == Some(file.modified),
+        Err(_) => false,
+    };
+    for group in groups {
+        // these are not expected to be byte-for-byte identical
+        if group.sampled_blocks != 0 || group.normalized != 0 {
+            skipped += group.files.len();
+            continue;
+        }
+        let original = &group.files[0];
+        if !unchanged(original) {
+            eprintln!("Not comparing with {}, as it has been changed", original.path);
+            skipped += group.files.len();
+            continue;
+        }
+        for file in &group.files[1..] {
+            if !unchanged(file) {
+                eprintln!("Not comparing {}, as it has been changed", file.path);
+                skipped += 1;
+                continue;
+            }
+            match same_content(original.path.as_path(), file.path.as_path()) {
+                Ok(true) => compared += 1,
+                Ok(false) => {
+                    let mut hash = String::new();
+                    write_hex(&group.hash, &mut hash);
+                    let result = writeln!(out, "Collision: {} and {} both have hash {}",
+                            original.path,
+                            file.path,
+                            hash,
+                    );
+                    if let Err(e) = result {
+                        eprintln!("Error printing collisions: {}", e);
+                        exit(1);
+                    }
+                    compared += 1;
+                    collisions += 1;
+                }
+                Err(e) => {
+                    eprintln!("Cannot compare {} with {}: {}", file.path, original.path, e);
+                    skipped += 1;
+                }
+            }
+        }
+    }
+    let result = writeln!(out, "compared {} files, skipped {}, found {} collisions",
+            compared,
+            skipped,
+            collisions,
+    );
+    if let Err(e) = result {
+        eprintln!("Error printing collisions: {}", e);
+        exit(1);
+    }
+    return collisions;
+}
+
+/// Replace the other files in each group with reflinks of the first, for --reflink-duplicates.
+///
+/// Files that already share storage with it, that have other hard links which would keep using
+/// storage, or that turn out to differ are skipped,
+/// and files that cannot be replaced are reported but don't stop the others.
+/// Stops after the current file on Ctrl-C. Returns how many failed.
+fn reflink_duplicates(groups: &[DuplicateGroup],  dry_run: bool) -> usize {
+    let mut out = stdout().lock();
+    let (mut replaced, mut freed, mut failed) = (0, 0, 0);
+    'groups: for group in groups {
+        let original = group.files[0].path.as_path();
+        let original_extents = physical_extents(original).ok()
+                .filter(|extents| !extents.is_empty() );
+        for file in &group.files[1..] {
+            if INTERRUPTED.load(Ordering::Relaxed) {
+                break 'groups;
+            }
+            let duplicate = file.path.as_path();
+            if original_extents.is_some() && physical_extents(duplicate).ok() == original_extents {
+                continue;
+            }
+            // before comparing, so that it is not replaced if it is changed after that
+            let before = match fs::symlink_metadata(duplicate) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    eprintln!("Cannot get metadata of {}: {}", file.path, e);
+                    failed += 1;
+                    continue;
+                }
+            };
+            if hard_links(&before) > 1 {
+                eprintln!("Not reflinking {}, as it has other hard links", file.path);
+                continue;
+            }
+            match same_content(original, duplicate) {
+                Ok(true) => {}
+                Ok(false) => {
+                    eprintln!("Not reflinking {}, as it has been changed", file.path);
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Cannot compare {} with {}: {}", file.path, group.files[0].path, e);
+                    failed += 1;
+                    continue;
+                }
+            }
+            let result = match dry_run {
+                true => writeln!(out, "Would reflink {} to {}", file.path, group.files[0].path),
+                false => match replace_with_reflink(original, duplicate, &before) {
+                    Ok(true) => writeln!(out, "Reflinked {} to {}", file.path, group.files[0].path),
+                    Ok(false) => {
+                        eprintln!("Not reflinking {}, as it has been changed", file.path);
+                        continue;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                        // not a failure, as it's only an optimization
+                        eprintln!("Not reflinking {}: {}", file.path, e);
+                        continue;
+                    }
+                    Err(e) => {
+                        eprintln!("Cannot reflink {} to {}: {}", file.path, group.files[0].path, e);
+                        failed += 1;
+                        continue;
+                    }
+                },
+            };
+            if let Err(e) = result {
+                eprintln!("Error printing reflinked files: {}", e);
+                exit(1);
+            }
+            replaced += 1;
+            freed += file.read_size;
+        }
+    }
+    let summary = match dry_run {
+        true => format!("would reflink {} files, freeing {:#}", replaced, Bytes::new(freed)),
+        false => format!("reflinked {} files, freeing {:#}", replaced, Bytes::new(freed)),
+    };
+    if let Err(e) = writeln!(out, "{}", summary) {
+        eprintln!("Error printing reflinked files: {}", e);
+        exit(1);
+    }
+    if failed != 0 {
+        eprintln!("{} files could not be reflinked", failed);
+    }
+    return failed;
+}
+
+/// Print the groups if `report`, and then the directory pairs if asked for, or exit on failure.
+fn print_duplicate_reports(args: &Args,  groups: &[DuplicateGroup],  report: bool) {
+    let relative;
+    let groups = match args.cwd_relative {
+        true => match env::current_dir() {
+            Ok(cwd) => {
+                relative = RelativePaths::new(cwd).groups(groups);
+                &relative
+            }
+            Err(e) => {
+                eprintln!("Cannot get the current directory, so showing absolute paths: {}", e);
+                groups
+            }
+        },
+        false => groups,
+    };
+    let mut out = stdout().lock();
+    let mut result = Ok(());
+    if report {
+        if let Some(range) = args.range {
+            result = writeln!(out, "Only bytes {} of the files were compared.", range);
+        }
+        let marker = args.survivor_marker.as_deref();
+        result = result.and_then(|()| print_duplicates(groups, args.verbose, marker, &mut out) );
+    }
+    if let (Some(n), Ok(())) = (args.dir_pairs, &result) {
+        let mut pairs = directory_pairs(groups);
+        pairs.truncate(n);
+        result = print_directory_pairs(&pairs, &mut out);
+    }
+    if let Err(e) = result {
+        eprintln!("Error printing duplicates: {}", e);
+        exit(1);
+    }
+}
+
+/// Report duplicates across --database and all --merge-db databases, without scanning.
+fn report_merged(args: &Args) {
+    let (_, hashed_rx) = mpsc::channel();
+    let (messages, _) = mpsc::channel();
+    let options = StorageOptions { hash_algorithm: args.hash_algorithm, ..Default::default() };
+    let mut combined = Sqlite::new_in_memory(options, hashed_rx, messages);
+    for path in args.database.iter().chain(&args.merge_db) {
+        match combined.merge_from(path) {
+            Ok((files, replaced)) => {
+                let path = PrintablePath::from(path.as_path());
+                eprintln!("{} files from {}", files, path);
+                if replaced != 0 {
+                    eprintln!("Warning: {} files from {} have the same path as files from \
+                               an earlier database, and replace them in the report",
+                            replaced,
+                            path,
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(2);
+            }
+        }
+    }
+    let groups = find_duplicates(args, &combined);
+    // a report of duplicates is the point of merging
+    print_duplicate_reports(args, &groups, args.report || args.dir_pairs.is_none());
+}
+
+/// Report the duplicates in --database without scanning, for --report-only.
+fn report_only(args: &Args) {
+    let path = args.database.as_ref().unwrap();
+    let (_, hashed_rx) = mpsc::channel();
+    let (messages, log_messages) = mpsc::channel();
+    let options = StorageOptions {
+        hash_algorithm: args.hash_algorithm,
+        busy_timeout: Duration::from_secs(args.busy_timeout.into()),
+        ..Default::default()
+    };
+    let storage = Sqlite::open(path, options, hashed_rx, messages);
+    let mut groups = find_duplicates(args, &storage);
+    groups.sort_by_key(|group| Reverse(group.wasted()) );
+    while let Ok(message) = log_messages.try_recv() {
+        eprintln!("{}", message);
+    }
+    print_duplicate_reports(args, &groups, args.report || args.dir_pairs.is_none());
+}
+
+/// Remove the --forget paths from the database, without scanning.
+fn forget(args: &Args) {
+    let path = args.database.as_ref().unwrap();
+    let (_, hashed_rx) = mpsc::channel();
+    let (messages, log_messages) = mpsc::channel();
+    let options = StorageOptions {
+        hash_algorithm: args.hash_algorithm,
+        busy_timeout: Duration::from_secs(args.busy_timeout.into()),
+        ..Default::default()
+    };
+    let mut storage = Sqlite::open(path, options, hashed_rx, messages);
+    for prefix in &args.forget {
+        // the path has probably been deleted, so canonicalizing can fail
+        let absolute = env::current_dir().map(|dir| dir.join(prefix) ).unwrap_or_else(|e| {
+            eprintln!("Cannot get current directory: {}", e);
+            exit(1);
+        });
+        let prefix = PrintablePath::from(fs::canonicalize(&absolute).unwrap_or(absolute));
+        let removed = storage.forget(&prefix);
+        println!("removed {} files under {}", removed, prefix);
+    }
+    while let Ok(message) = log_messages.try_recv() {
+        eprintln!("{}", message);
+    }
+}
+
+/// Print the copies of the --is-duplicate file in the database, and exit with whether there
+/// were any.
+fn is_duplicate(args: &Args,  path: &Path) -> ! {
+    let printable = PrintablePath::from(path);
+    let path = fs::canonicalize(path).unwrap_or_else(|e| {
+        eprintln!("Cannot find {}: {}", printable, e);
+        exit(2);
+    });
+    let mut buffer = vec![0; args.max_buffer_size.to_usize_saturating()];
+    let hashed = fs::metadata(&path).and_then(|metadata| {
+        let hash = hash_path(&path, args.hash_algorithm, &mut buffer)?;
+        Ok((hash, metadata.len()))
+    });
+    let (hash, size) = hashed.unwrap_or_else(|e| {
+        eprintln!("Cannot hash {}: {}", printable, e);
+        exit(2);
+    });
+
+    let (_, hashed_rx) = mpsc::channel();
+    let (messages, log_messages) = mpsc::channel();
+    let db_path = args.database.as_ref().unwrap();
+    let options = StorageOptions {
+        hash_algorithm: args.hash_algorithm,
+        busy_timeout: Duration::from_secs(args.busy_timeout.into()),
+        ..Default::default()
+    };
+    let storage = Sqlite::open(db_path, options, hashed_rx, messages);
+    let copies = storage.find_copies(&hash, size, &PrintablePath::from(path));
+    for copy in &copies {
+        println!("{}", copy);
+    }
+    while let Ok(message) = log_messages.try_recv() {
+        eprintln!("{}", message);
+    }
+    exit(if copies.is_empty() {1} else {0});
+}
+
+fn send_event(events: &mut ProgressEvents,  event: JsonLine,  display: &mut String) {
+    if let Err(e) = events.write(event) {
+        writeln!(display, "Cannot write progress event, so stopping them: {}", e).unwrap();
+    }
+}
+
+/// Where log messages end up, and whether any of them were errors.
+struct LogOutput {
+    error_file: Option<fs::File>,
+    errors: usize,
+    /// Warnings turned into errors by --strict.
+    warnings: usize,
+    strict: Option<Strict>,
+    min_level: LogLevel,
+    timestamps: bool,
+}
+
+impl LogOutput {
+    fn write(&mut self,  mut message: LogMessage,  display: &mut String) {
+        if message.level == Warning && self.strict.is_some() {
+            message.level = Error;
+            self.warnings += 1;
+        } else if message.level == Error {
+            self.errors += 1;
+        }
+        if message.level < self.min_level {
+            return;
+        }
+        let time = match self.timestamps {
+            true => format!("{} ", message.time),
+            false => String::new(),
+        };
+        let mut written = false;
+        if message.level == Error {
+            if let Some(ref mut file) = self.error_file {
+                match writeln!(file, "{}{}", time, message) {
+                    Ok(()) => written = true,
+                    Err(e) => {
+                        writeln!(display, "Cannot write to error log: {}", e).unwrap();
+                        self.error_file = None;
+                    }
+                }
+            }
+        }
+        if !written {
+            writeln!(display, "{}{}", time, message).unwrap();
+        }
+        if message.level == Error && self.strict == Some(Strict::Abort) {
+            eprint!("{}", display);
+            eprintln!("Stopping because of --strict=abort");
+            exit(1);
+        }
+    }
+}
+
+/// Find the fastest and slowest of the threads that are hashing, if at least two are and they
+/// don't hash equally fast.
+///
+/// `rates` is bytes per second for each hasher thread, or `None` if it isn't hashing.
+fn fastest_and_slowest(rates: &[Option<u64>]) -> Option<(usize, usize)> {
+    let hashing = || rates.iter().enumerate().filter_map(|(i, rate)| Some((i, (*rate)?)) );
+    let (fastest, max) = hashing().max_by_key(|&(_, rate)| rate )?;
+    let (slowest, min) = hashing().min_by_key(|&(_, rate)| rate )?;
+    return (max != min).then_some((fastest, slowest));
+}
+
+/// Write how many of the threads are in each state, on one line.
+fn write_state_counts(display: &mut String,  name: &str,  threads: &[ThreadInfo]) {
+    let mut counts = [0usize; Quit as usize + 1];
+    for thread in threads {
+        counts[thread.state() as usize] += 1;
+    }
+    write!(display, "{:10}", name).unwrap();
+    let mut separator = "";
+    for (state, &count) in counts.iter().enumerate() {
+        if count != 0 {
+            let state = ThreadState::try_from(state).unwrap();
+            write!(display, "{}{} {:?}", separator, count, state).unwrap();
+            separator = ", ";
+        }
+    }
+    display.push('\n');
+}
+
+/// Show percentage of bytes hashed and estimated time remaining.
+fn write_progress(display: &mut String,  done: u64,  total: u64,  elapsed: Duration) {
+    if total == 0 || done >= total {
+        return;
+    }
+    write!(display, "{}% of {:#}", done*100/total, Bytes(total)).unwrap();
+    if done > 0 {
+        let remaining = elapsed.as_secs_f64() * (total - done) as f64 / done as f64;
+        let remaining = remaining as u64;
+        write!(display, ", ETA {}:{:02}:{:02}",
+                remaining / 3600,
+                (remaining / 60) % 60,
+                remaining % 60,
+        ).unwrap();
+    }
+    display.push_str(", ");
+}
+
+/// Handle commands from --control-stdin until stdin is closed.
+///
+/// This doesn't use `Shared`, so that it doesn't need to be stopped at the end of the scan.
+fn read_commands(in_progress: &InProgress,  log_channel: mpsc::Sender<LogMessage>) {
+    for line in stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let message = match line.trim_start() {
+            "" | "skip" => match in_progress.skip_oldest() {
+                Some(path) => format!("Skipping {}", path),
+                None => "No file to skip".to_string(),
+            },
+            command => match command.strip_prefix("skip ") {
+                Some(path) if in_progress.skip(&PrintablePath::from(Path::new(path))) => {
+                    format!("Skipping {}", path)
+                }
+                Some(path) => format!("Cannot skip {}, as it is not being read or hashed", path),
+                None => format!("Unknown command {:?}, expected skip [PATH]", command),
+            },
+        };
+        if log_channel.send(LogMessage::new(Info, message)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Convert a path read from stdin.
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+}
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    String::from_utf8(bytes.to_vec()).ok().map(PathBuf::from)
+}
+
+/// Hash the files listed in sha256sum format on stdin, and print whether each still matches,
+/// for --hash-stdin-per-line.
+///
+/// The database is not used.
+fn verify_checksums(args: &Args) {
+    let mut expected = Vec::<(FileHash, Arc<PrintablePath>)>::new();
+    let mut improper = 0;
+    let mut stdin = stdin().lock();
+    let mut line = Vec::new();
+    for line_number in 1.. {
+        line.clear();
+        match stdin.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Cannot read stdin: {}", e);
+                exit(2);
+            }
+        }
+        let parsed = parse_checksum_line(&line).and_then(|(hash, path)| {
+            Some((hash, path_from_bytes(&path)?))
+        });
+        match parsed {
+            Some((hash, path)) => expected.push((hash, Arc::new(PrintablePath::from(path)))),
+            None => {
+                eprintln!("Line {} is not a hash followed by a path", line_number);
+                improper += 1;
+            }
+        }
+    }
+    drop(stdin);
+
+    let (log_channel, log_messages) = mpsc::channel::<LogMessage>();
+    let io_info = create_info_array("io", u16::from(args.io_threads).into(), log_channel.clone());
+    let hasher_info = create_info_array(
+            "hasher",
+            u16::from(args.hasher_threads).into(),
+            log_channel,
+    );
+    let buffers = AvailableBuffers::new(
+            args.max_buffers_memory.to_usize_saturating(),
+            args.max_buffer_size.to_usize_saturating(),
+    ).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(2);
+    });
+    let (complete_tx, complete_rx) = mpsc::channel::<HashedFile>();
+    let mut shared = Shared::new(buffers, complete_tx);
+    shared.direct_io = args.direct_io;
+    shared.fadvise = args.fadvise;
+    shared.hash_algorithm = args.hash_algorithm;
+    shared.parallel_file = args.parallel_file.map(Bytes::as_u64);
+    shared.read_chunk = args.read_chunk.map(Bytes::to_usize_saturating);
+
+    // follow symlinks like sha256sum, and leave files that don't exist out of the queue
+    let mut missing = HashSet::new();
+    let mut queued = HashSet::new();
+    let mut to_read = shared.to_read.lock().unwrap();
+    for (_, path) in &expected {
+        if missing.contains(path) || !queued.insert(path.clone()) {
+            continue;
+        }
+        match fs::metadata(path.as_path()) {
+            Ok(metadata) if metadata.is_file() => to_read.push(ToRead::File(UnreadFile {
+                path: path.clone(),
+                modified: metadata.modified().map(PrintableTime::from).unwrap_or_default(),
+                created: None,
+                size: metadata.len(),
+                inode: None,
+            })),
+            Ok(_) => eprintln!("{} is not a file", path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                queued.remove(path);
+                missing.insert(path.clone());
+            }
+            Err(e) => eprintln!("Cannot get metadata of {}: {}", path, e),
+        }
+    }
+    drop(to_read);
+    let shared = Arc::new(shared);
+
+    let hasher_threads = (0..hasher_info.len())
+        .map(|i| start_hasher(&shared, &hasher_info, i) )
+        .collect::<Vec<_>>();
+    let io_threads = (0..io_info.len()).map(|i| {
+        let shared = shared.clone();
+        let io_info = io_info.clone();
+        let builder = thread::Builder::new().name(io_info[i].name().to_string());
+        builder.spawn(move || read_files(shared, &io_info[i], ReadKind::Any) ).unwrap()
+    }).collect::<Vec<_>>();
+    for thread in io_threads {
+        thread.join().unwrap();
+    }
+    shared.to_hash.lock().unwrap().stop_when_empty = true;
+    shared.hasher_waker.notify_all();
+    for thread in hasher_threads {
+        thread.join().unwrap();
+    }
+    drop(Arc::try_unwrap(shared).expect("drop the last reference to shared"));
+    while let Ok(message) = log_messages.try_recv() {
+        eprintln!("{}", message);
+    }
+
+    let hashes = complete_rx.into_iter()
+        .map(|file| (file.path, file.hash) )
+        .collect::<HashMap<_, _>>();
+    let (mut failed, mut unreadable) = (0, 0);
+    let mut stdout = std::io::BufWriter::new(stdout().lock());
+    for (hash, path) in &expected {
+        let result = match hashes.get(path) {
+            Some(actual) if actual == hash => "OK",
+            Some(_) => {
+                failed += 1;
+                "FAILED"
+            }
+            None if missing.contains(path) => "MISSING",
+            None => {
+                unreadable += 1;
+                "FAILED open or read"
+            }
+        };
+        writeln!(stdout, "{}: {}", path, result).unwrap();
+    }
+    stdout.flush().unwrap();
+    drop(stdout);
+
+    let missing = expected.iter().filter(|(_, path)| missing.contains(path) ).count();
+    if failed != 0 {
+        eprintln!("{} files did not match", failed);
+    }
+    if missing != 0 {
+        eprintln!("{} files are missing", missing);
+    }
+    if unreadable != 0 {
+        eprintln!("{} files could not be read", unreadable);
+    }
+    let strict_fail = improper != 0 && args.strict.is_some();
+    if failed != 0  ||  missing != 0  ||  unreadable != 0  ||  strict_fail {
+        exit(1);
+    }
+}
+
+/// Print what would be done, for `--print-plan`.
+fn print_plan(args: &Args,  roots: &[Arc<PrintablePath>],  filters: &Filters) {
+    println!("roots:");
+    for root in roots {
+        println!("  {}", root);
+    }
+    for image in &args.image {
+        let image = fs::canonicalize(image).unwrap_or_else(|_| image.clone() );
+        println!("  {} (image mounted read-only in a new temporary directory)",
+                PrintablePath::from(image.as_path()),
+        );
+    }
+    match args.database {
+        Some(ref path) => println!("database: {}", PrintablePath::from(path.as_path())),
+        None => println!("database: in-memory (results are not saved)"),
+    }
+    println!("hash algorithm: {}", args.hash_algorithm.name());
+    if args.report_only {
+        println!("mode: report duplicates in the database without scanning");
+    }
+    if let Some(ref path) = args.is_duplicate {
+        println!("mode: print copies of {} in the database", PrintablePath::from(path.as_path()));
+    }
+    if args.rehash {
+        println!("mode: hash files in the database again");
+    }
+    println!("hash storage: {:?}", args.hash_storage);
+    println!("durability: {:?}", args.durability);
+    if let Some(ref path) = args.database {
+        println!("wait for other programs writing to {}: {} seconds",
+                PrintablePath::from(path.as_path()),
+                args.busy_timeout,
+        );
+    }
+    if let Some(page_size) = args.db_page_size {
+        println!("page size of new database: {}", page_size);
+    }
+    if let Some(auto_vacuum) = args.db_auto_vacuum {
+        println!("auto_vacuum of new database: {:?}", auto_vacuum);
+    }
+    println!("only print summary: {}", if args.summary_only {"yes"} else {"no"});
+    println!("reflink duplicates: {}", match (args.reflink_duplicates, args.dry_run) {
+        (true, true) => "dry run",
+        (true, false) => "yes",
+        (false, _) => "no",
+    });
+    println!("collision audit: {}", if args.collision_audit {"yes"} else {"no"});
+    println!("paths in reports: {}", if args.cwd_relative {"relative"} else {"absolute"});
+    if let Some(ref path) = args.export_json {
+        println!("export: all files in the database as JSON to {}",
+                PrintablePath::from(path.as_path()),
+        );
+    }
+    println!("look up previously hashed files in: {:?}", args.previous_lookup);
+    println!("prune deleted files: {}", match (args.prune_dry_run, args.prune) {
+        (true, _) => "no, only list them".to_string(),
+        (false, true) => "yes".to_string(),
+        (false, false) => format!("if at most {}", PRUNE_WITHOUT_ASKING),
+    });
+    match args.tag {
+        Some(ref tag) => println!("tag: {}", tag),
+        None => println!("tag: none"),
+    }
+    println!("record root of files: {}", if args.record_root {"yes"} else {"no"});
+    println!("IO threads: {}", args.io_threads);
+    if let Some(dir_threads) = args.dir_threads {
+        println!("directory threads: {}", dir_threads);
+    }
+    match args.auto_scale {
+        Some(max) => {
+            println!("hasher threads: {} to {}", args.hasher_threads, max.max(args.hasher_threads));
+        },
+        None => println!("hasher threads: {}", args.hasher_threads),
+    }
+    match args.parallel_file {
+        Some(min) => println!("hash files on all cores: if at least {:#}", min),
+        None => println!("hash files on all cores: no"),
+    }
+    let min_buffer_size = AvailableBuffers::MIN_BUFFER_SIZE as u64;
+    let min_buffer_size = min_buffer_size.max(args.max_buffer_size.as_u64()/128);
+    match args.read_chunk {
+        Some(chunk) => println!("buffer size: {:#}",
+                Bytes(chunk.as_u64().clamp(min_buffer_size, args.max_buffer_size.as_u64())),
+        ),
+        None => println!("buffer size: {:#} to {:#}", Bytes(min_buffer_size), args.max_buffer_size),
+    }
+    println!("max memory used by buffers: {:#}{}",
+            args.max_buffers_memory,
+            if args.adaptive_memory {", lowered under memory pressure"} else {""},
+    );
+    match args.refresh_rate {
+        Some(rate) => println!("refresh interval: {:?}", rate.0),
+        None => println!("refresh interval: 100ms if stderr is a terminal, otherwise 1s"),
+    }
+    if let Some(n) = args.live_duplicates {
+        println!("show while scanning: the {} biggest groups of duplicates", n);
+    }
+    match args.sample {
+        Some(blocks) => println!("sample: {} blocks of {:#} from bigger files",
+                blocks,
+                Bytes(SAMPLE_BLOCK_SIZE),
+        ),
+        None => println!("sample: no, hash whole files"),
+    }
+    match args.max_bytes {
+        Some(max) => println!("stop after hashing: {:#}", max),
+        None => println!("stop after hashing: everything"),
+    }
+    println!("normalize line endings: {}", if args.normalize_text {"yes"} else {"no"});
+    println!("strip byte order marks: {}", if args.strip_bom {"yes"} else {"no"});
+    println!("content-defined chunks: {}", if args.cdc {"yes"} else {"no"});
+    match args.range {
+        Some(range) => println!("range: only hash bytes {} of files", range),
+        None => println!("range: no, hash whole files"),
+    }
+    println!("reuse hashes of moved files: {}", if args.inode_cache {"yes"} else {"no"});
+    println!("defer files open for writing: {}", if args.skip_open_files {"yes"} else {"no"});
+    println!("remember queued paths: {}", if args.dedup_traversal {"yes"} else {"no"});
+    println!("skip files with unique sizes: {}", if args.skip_unique_sizes {"yes"} else {"no"});
+    println!("explain decisions: {}", if args.explain {"yes"} else {"no"});
+    println!("skip pseudo-filesystems: {}", if args.allow_pseudofs {"no"} else {"yes"});
+    println!("count files first: {}", if args.prescan {"yes"} else {"no"});
+    println!("filters: {}", filters);
+    if args.report || (args.database.is_none() && !prints_results(args)) {
+        let mut report = String::from("report: duplicates with same hash");
+        if !args.ignore_size_in_grouping {
+            report.push_str(" and size");
+        }
+        if args.same_name {
+            report.push_str(" and name");
+        } else if args.different_name {
+            report.push_str(", where some have different names");
+        }
+        if args.group_within_tag {
+            report.push_str(" and tag");
+        }
+        if let Some(ref tag) = args.filter_tag {
+            write!(&mut report, ", only files tagged {}", tag).unwrap();
+        }
+        if let Some(min) = args.min_waste {
+            write!(&mut report, ", where at least {:#} could be freed", min).unwrap();
+        }
+        if let Some(min) = args.waste_min_size {
+            write!(&mut report, ", not counting files smaller than {:#} as waste", min).unwrap();
+        }
+        write!(&mut report, ", ordered by {:?}", args.group_order).unwrap();
+        if let Some(ref marker) = args.survivor_marker {
+            write!(&mut report, ", first file marked with {}", marker).unwrap();
+        }
+        println!("{}", report);
+    }
+    if let Some(n) = args.dir_pairs {
+        println!("report: top {} directory pairs", n);
+    }
+    if let Some(n) = args.churn {
+        println!("report: top {} most changed files", n);
+    }
+    if let Some(n) = args.same_structure {
+        println!("report: top {} groups of directories with the same file names", n);
+    }
+    if let Some(n) = args.shared_chunks {
+        println!("report: top {} pairs of files with identical chunks", n);
+    }
+    if args.du {
+        match args.max_depth {
+            Some(depth) => println!("report: size of directories up to {} levels down", depth),
+            None => println!("report: size of all directories"),
+        }
+    }
+    if let Some(ref reference) = args.only_new_vs {
+        println!("report: files not in {}", PrintablePath::from(reference.as_path()));
+    }
+    if let Some(ref other) = args.diff_against {
+        println!("report: {} from {}",
+                if args.list_diff {"changed files"} else {"number of changed files"},
+                PrintablePath::from(other.as_path()),
+        );
+    }
+}
+
+/// Traverse the roots on the current thread and print how many files and bytes they contain,
+/// for --count-only.
+///
+/// The database is not used, so files hashed by previous scans are included.
+fn count_files(roots: &[Arc<PrintablePath>],  filters: Filters) {
+    let (log_channel, log_messages) = mpsc::channel::<LogMessage>();
+    let info = create_info_array("io", 1, log_channel);
+    // no buffers are needed when no files are read
+    let min_buffers = AvailableBuffers::MIN_BUFFER_SIZE;
+    let buffers = AvailableBuffers::new(min_buffers, min_buffers).unwrap();
+    let mut shared = Shared::new(buffers, mpsc::channel().0);
+    shared.filters = filters;
+    let mut to_read = shared.to_read.lock().unwrap();
+    for root in roots {
+        to_read.push(ToRead::Directory(root.clone()));
+    }
+    drop(to_read);
+
+    let start = Instant::now();
+    prescan(&shared, &info[0]);
+    let mut errors = 0;
+    while let Ok(message) = log_messages.try_recv() {
+        if message.level == Error {
+            errors += 1;
+        }
+        eprintln!("{}", message);
+    }
+    println!("{} files with {:#} found in {:.1} seconds",
+            shared.prescanned.files(),
+            Bytes(shared.prescanned.bytes()),
+            start.elapsed().as_secs_f32(),
+    );
+    if errors != 0 {
+        eprintln!("{} files or directories could not be read", errors);
+        exit(1);
+    }
+}
+
 fn main() {
     let args = Args::parse();
+    let mut format_targets = HashSet::new();
+    for format in &args.format {
+        let target = match format.is_stdout(args.output.as_deref()) {
+            true => Path::new("-"),
+            false => format.target.as_deref().or(args.output.as_deref()).unwrap(),
+        };
+        if !format_targets.insert(target) {
+            match target == Path::new("-") {
+                true => eprintln!("Only one --format can write to stdout"),
+                false => eprintln!("Several --format write to {}", PrintablePath::from(target)),
+            }
+            exit(2);
+        }
+    }
+    if args.read_chunk == Some(Bytes::new(0)) {
+        eprintln!("--read-chunk cannot be 0");
+        exit(2);
+    }
+    if args.db_page_size.is_some_and(|size| !valid_page_size(size) ) {
+        eprintln!("--db-page-size must be a power of two from 512 to 65536");
+        exit(2);
+    }
+    let sha256sum = args.format.iter().any(|format| format.format == ResultFormat::Sha256sum );
+    if args.range.is_some() && sha256sum {
+        eprintln!("--format sha256sum cannot be used with --range, \
+                   as the hashes are not of whole files");
+        exit(2);
+    }
+    if args.hash_algorithm != HashAlgorithm::Sha256 && sha256sum {
+        eprintln!("--format sha256sum cannot be used with --hash-algorithm {}, \
+                   as the hashes would not be SHA-256",
+                args.hash_algorithm.name(),
+        );
+        exit(2);
+    }
+    if args.parallel_file.is_some() && args.hash_algorithm != HashAlgorithm::Blake3 {
+        eprintln!("--parallel-file requires --hash-algorithm blake3, \
+                   as the other algorithms can only hash a file from start to end");
+        exit(2);
+    }
+    if args.previous_lookup == PreviousLookup::Database && (args.rehash || args.fast_incremental) {
+        eprintln!("--previous-lookup database cannot be used with --rehash or --fast-incremental");
+        exit(2);
+    }
+
+    // check root directories
+    let mut notes = String::new();
+    let mut roots = args.roots.clone();
+    for dir in &args.roots_dir {
+        let in_dir = roots_in_dir(dir, &mut notes).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1);
+        });
+        roots.extend(in_dir);
+    }
+    let mut absolute_roots = resolve_roots(&roots, &mut notes).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+    eprint!("{}", notes);
 
-    let (log_channel, log_messages) = mpsc::channel::<String>();
+    // the path might have been deleted since the interrupted scan, so canonicalizing can fail
+    let resume_from = args.resume_from.as_ref().map(|path| {
+        let absolute = env::current_dir().map(|dir| dir.join(path) ).unwrap_or_else(|e| {
+            eprintln!("Cannot get current directory: {}", e);
+            exit(1);
+        });
+        Arc::new(PrintablePath::from(fs::canonicalize(&absolute).unwrap_or(absolute)))
+    });
+
+    // resolve relative times once so that all threads use the same limits
+    let now = SystemTime::now();
+    let filters = Filters {
+        modified_before: args.older_than.map(|age| age.before(now) ),
+        modified_after: args.newer_than.map(|age| age.before(now) ),
+        resume_from,
+        owner: args.owner.as_deref().map(|owner| resolve_id(owner, "user") ),
+        group: args.group.as_deref().map(|group| resolve_id(group, "group") ),
+    };
+
+    if args.print_plan {
+        print_plan(&args, &absolute_roots, &filters);
+        return;
+    }
+    if !args.merge_db.is_empty() {
+        report_merged(&args);
+        return;
+    }
+    if !args.forget.is_empty() {
+        forget(&args);
+        return;
+    }
+    if let Some(ref path) = args.is_duplicate {
+        is_duplicate(&args, path);
+    }
+    if args.report_only {
+        report_only(&args);
+        return;
+    }
+    if args.hash_stdin_per_line {
+        verify_checksums(&args);
+        return;
+    }
+    // every exit after this must go through exit() to unmount the images
+    let images = mount_images(&args.image, &mut absolute_roots);
+    // the roots as stored in the database, where files in images are under the image
+    let stored_roots = absolute_roots.iter().map(|root| {
+        match images.iter().find(|(mount_point, _)| mount_point == root ) {
+            Some((_, image)) => image.clone(),
+            None => root.clone(),
+        }
+    }).collect::<Vec<_>>();
+    if args.count_only {
+        count_files(&absolute_roots, filters);
+        disk_image::unmount_all(false);
+        return;
+    }
+
+    let error_file = args.error_log.as_ref().map(|path| {
+        fs::OpenOptions::new().create(true).append(true).open(path).unwrap_or_else(|e| {
+            eprintln!("Cannot open error log {}: {}", path.display(), e);
+            exit(2);
+        })
+    });
+    let mut log_output = LogOutput {
+        error_file,
+        errors: 0,
+        warnings: 0,
+        strict: args.strict,
+        min_level: match (args.summary_only, args.explain) {
+            (true, _) => args.log_level.max(Error),
+            (false, true) => args.log_level.min(Explain),
+            (false, false) => args.log_level,
+        },
+        timestamps: args.log_timestamps,
+    };
+    let progress_output = args.progress_fd.as_ref().map(|arg| {
+        open_progress_output(arg).unwrap_or_else(|e| {
+            eprintln!("Cannot use --progress-fd {}: {}", arg, e);
+            exit(2);
+        })
+    });
+    let mut events = ProgressEvents::new(progress_output);
+    let lifecycle_events = args.events_ndjson.as_ref().map(|path| {
+        fs::File::create(path).unwrap_or_else(|e| {
+            eprintln!("Cannot create {}: {}", PrintablePath::from(path.as_path()), e);
+            exit(2);
+        })
+    });
+    let (log_channel, log_messages) = mpsc::channel::<LogMessage>();
     let io_info = create_info_array(
             "io",
             u16::from(args.io_threads).into(),
             log_channel.clone()
     );
+    let dir_info = create_info_array(
+            "dir",
+            args.dir_threads.map_or(0, |threads| u16::from(threads).into() ),
+            log_channel.clone()
+    );
+    let max_hasher_threads = match args.auto_scale {
+        Some(max) => max.max(args.hasher_threads),
+        None => args.hasher_threads,
+    };
     let hasher_info = create_info_array(
             "hasher",
-            u16::from(args.hasher_threads).into(),
+            u16::from(max_hasher_threads).into(),
             log_channel.clone()
     );
 
@@ -139,26 +1771,183 @@ fn main() {
 
     let (complete_tx, complete_rx) = mpsc::channel::<HashedFile>();
     let mut shared = Shared::new(buffers, complete_tx);
+    shared.filters = filters;
+    shared.sample_blocks = args.sample.map_or(0, u16::from);
+    shared.normalize_text = args.normalize_text;
+    shared.strip_bom = args.strip_bom;
+    shared.explain = args.explain;
+    shared.cdc = args.cdc;
+    shared.range = args.range;
+    shared.direct_io = args.direct_io;
+    shared.fadvise = args.fadvise;
+    shared.hash_algorithm = args.hash_algorithm;
+    shared.read_chunk = args.read_chunk.map(Bytes::to_usize_saturating);
+    shared.events = Arc::new(EventLog::new(lifecycle_events));
+    let storage_options = StorageOptions {
+        hash_storage: args.hash_storage,
+        durability: args.durability,
+        busy_timeout: Duration::from_secs(args.busy_timeout.into()),
+        page_size: args.db_page_size,
+        auto_vacuum: args.db_auto_vacuum,
+        tag: args.tag.clone(),
+        hash_algorithm: args.hash_algorithm,
+        record_roots: match args.record_root {
+            true => stored_roots.clone(),
+            false => Vec::new(),
+        },
+        images: images.clone(),
+    };
+    let control_log = log_channel.clone();
     let mut storage = match args.database {
-        Some(ref path) => Sqlite::open(path, complete_rx, log_channel),
-        None => Sqlite::new_in_memory(complete_rx, log_channel),
+        Some(ref path) => Sqlite::open(path, storage_options, complete_rx, log_channel),
+        None => Sqlite::new_in_memory(storage_options, complete_rx, log_channel),
+    };
+    storage.set_events(shared.events.clone());
+    // files deleted from the image since the last time would not be pruned otherwise
+    for (_, image) in &images {
+        storage.forget_image_contents(image);
+    }
+    let mut sinks = Vec::<Box<dyn ResultSink>>::new();
+    let open = |target: Option<&Path>| -> Box<dyn std::io::Write + Send> {
+        match target.or(args.output.as_deref()) {
+            Some(path) if path != Path::new("-") => open_result_output(path).unwrap_or_else(|e| {
+                eprintln!("Cannot open {}: {}", PrintablePath::from(path), e);
+                exit(2);
+            }),
+            _ => Box::new(std::io::BufWriter::new(stdout())),
+        }
     };
+    let mut outputs = Vec::<Box<dyn ResultSink>>::new();
+    for format in &args.format {
+        outputs.push(Box::new(FormatSink::new(open(format.target.as_deref()), format.format)));
+    }
+    if let Some(ref template) = args.template {
+        outputs.push(Box::new(TemplateSink::new(open(None), template.clone())));
+    }
+    for sink in outputs {
+        // don't stall storing because of a slow pipe
+        let sink = BackgroundSink::new(sink, 10_000).unwrap_or_else(|e| {
+            eprintln!("Cannot start output thread: {}", e);
+            exit(2);
+        });
+        sinks.push(Box::new(sink));
+    }
+    let live_duplicates = args.live_duplicates.map(|_| LiveDuplicates::new() );
+    if let Some(ref live) = live_duplicates {
+        sinks.push(Box::new(live.clone()));
+    }
+    storage.set_sink(TeeSink::new(sinks));
 
-    // check root directories and add them to queue
+    // add root directories to queue
+    // without a database or any output, the results would be thrown away,
+    // so print the duplicates that were found
+    let report = args.report || args.summary_only
+            || (args.database.is_none() && !prints_results(&args));
+    if args.database.is_none() && !prints_results(&args) {
+        eprintln!("No --database given, so the results will not be saved,");
+        eprintln!("but duplicates are printed when done.");
+        eprintln!("Use --database FILE to keep them, or --format to write them to stdout.");
+    } else if args.database.is_none() && !args.summary_only {
+        // an empty in-memory database will not have anything under the roots
+        eprintln!("No --database given, so files hashed in previous runs will not be skipped.");
+    }
+    let lookup_in_database = args.previous_lookup == PreviousLookup::Database;
+    if lookup_in_database {
+        match storage.lookup_previous(&absolute_roots, shared.sample_blocks) {
+            Ok(lookup) => shared.previously_read.use_lookup(Box::new(lookup)),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(2);
+            }
+        }
+    }
     let mut to_read = shared.to_read.lock().unwrap();
-    let absolute_roots = args.roots.into_iter().map(|dir_path| {
-        let dir_path = fs::canonicalize(&dir_path).unwrap_or_else(|e| {
-            eprintln!("Cannot canoniicalize {}: {}", PrintablePath::from(dir_path), e);
-            exit(1);
-        });
-        let printable = Arc::new(PrintablePath::from(dir_path));
-        storage.get_previously_read(&printable, &mut shared.previously_read);
-        to_read.queue.push(ToRead::Directory(printable.clone()));
-        printable
-    }).collect::<Vec<_>>();
+    to_read.separate_dirs = args.dir_threads.is_some();
+    for root in &absolute_roots {
+        if args.database.is_some() && !lookup_in_database {
+            storage.get_previously_read(root, shared.sample_blocks, &mut shared.previously_read);
+        }
+        if !args.rehash {
+            to_read.push(ToRead::Directory(root.clone()));
+        }
+    }
+    if args.rehash {
+        let recorded = shared.previously_read.paths().cloned().map(ToRead::Recorded);
+        to_read.queue.extend(recorded);
+    }
     drop(to_read);
+    if args.inode_cache && args.database.is_some() {
+        storage.get_inode_cache(&mut shared.inode_cache);
+    }
+    if args.fast_incremental {
+        let mut dirs = Vec::new();
+        for root in &absolute_roots {
+            storage.get_previous_dirs(root, &mut dirs);
+        }
+        shared.previous_dirs = PreviousDirs::new(dirs, &shared.previously_read);
+        shared.fast_incremental = true;
+    }
+    if args.allow_pseudofs {
+        shared.pseudo_filesystems = None;
+    }
+    if args.dedup_traversal {
+        shared.queued_paths = Some(QueuedPaths::default());
+    }
+    if args.dedup_traversal || absolute_roots.len() > 1 {
+        shared.seen_inodes = Some(SeenInodes::default());
+    }
+    if args.control_stdin {
+        shared.in_progress = Some(Arc::new(InProgress::default()));
+    }
+    if args.skip_unique_sizes {
+        shared.size_groups = Some(SizeGroups::default());
+    }
+    if args.skip_open_files {
+        match OpenForWriting::new() {
+            Ok(open) => shared.open_for_writing = Some(open),
+            Err(e) => {
+                eprintln!("Cannot find open files: {}", e);
+                exit(2);
+            }
+        }
+    }
+
+    if args.prescan {
+        eprintln!("Counting files...");
+        prescan(&shared, &io_info[0]);
+        // files can be deleted before the scan finds them again, and should then be pruned
+        shared.previously_read.forget_found();
+        eprintln!("Found {} files with {:#} to hash",
+                shared.prescanned.files(),
+                Bytes(shared.prescanned.bytes()),
+        );
+    }
+    if shared.events.is_enabled() {
+        let event = shared.events.event("scan_start")
+                .strings("roots", absolute_roots.iter().map(|root| root.as_str() ));
+        if let Err(e) = shared.events.write(event) {
+            eprintln!("Cannot write event, so stopping them: {}", e);
+        }
+    }
     let shared = Arc::new(shared);
 
+    // Let the UI loop stop the scan on Ctrl-C, so that what was hashed is stored
+    let mut interrupted_at = None::<Instant>;
+    let handler = ctrlc::set_handler(move || {
+        if EXIT_ON_INTERRUPT.load(Ordering::Relaxed) {
+            eprintln!("\nInterrupted");
+            exit(130);
+        } else if interrupted_at.is_some_and(|at| at.elapsed() < Duration::from_secs(2) ) {
+            eprintln!("\nInterrupted twice, exiting without storing what has been hashed");
+            exit(130);
+        }
+        interrupted_at = Some(Instant::now());
+        INTERRUPTED.store(true, Ordering::Relaxed);
+    });
+    if let Err(e) = handler {
+        eprintln!("Cannot handle Ctrl-C, so interrupting will lose what was hashed: {}", e);
+    }
+
     // start storer thread
     let storer = thread::Builder::new().name("storer".to_string()).spawn(move || {
         storage.save_hashed(Duration::from_secs(2));
@@ -176,39 +1965,41 @@ fn main() {
     }
 
     // start hasher threads
-    let mut hasher_threads = Vec::with_capacity(u16::from(args.hasher_threads).into());
-    for i in 0..hasher_info.len() {
-        let shared = shared.clone();
-        let hasher_info = hasher_info.clone();
-        let builder = ThreadBuilder::default()
-                .name(hasher_info[i].name())
-                .priority(ThreadPriority::Min);
-        #[cfg(unix)]
-        let builder = builder.policy(
-                ThreadSchedulePolicy::Normal(NormalThreadSchedulePolicy::Batch)
-        );
-        let thread = builder.spawn(move |priority_result| {
-            if let Err(e) = priority_result {
-                eprintln!("Failed lowering thread priority: {:?}", e);
-            }
-            let info = &hasher_info[i];
-            hash_files(shared, info)
-        }).unwrap();
-        hasher_threads.push((thread, 0usize));
+    let mut hasher_threads = Vec::with_capacity(hasher_info.len());
+    for i in 0..u16::from(args.hasher_threads).into() {
+        hasher_threads.push((start_hasher(&shared, &hasher_info, i), 0usize));
     }
 
     // start IO threads
     let mut io_threads = Vec::with_capacity(u16::from(args.io_threads).into());
+    let separate_dirs = !dir_info.is_empty();
     for i in 0..io_info.len() {
         let shared = shared.clone();
         let io_info = io_info.clone();
         let builder = thread::Builder::new().name(io_info[i].name().to_string());
         let thread = builder.spawn(move || {
             let info = &io_info[i];
-            read_files(shared, info);
+            read_files(shared, info, if separate_dirs {ReadKind::Files} else {ReadKind::Any});
         }).unwrap();
         io_threads.push((thread, 0usize));
     }
+    let mut dir_threads = Vec::with_capacity(dir_info.len());
+    for i in 0..dir_info.len() {
+        let shared = shared.clone();
+        let dir_info = dir_info.clone();
+        let builder = thread::Builder::new().name(dir_info[i].name().to_string());
+        let thread = builder.spawn(move || {
+            read_files(shared, &dir_info[i], ReadKind::Directories);
+        }).unwrap();
+        dir_threads.push(thread);
+    }
+
+    if let Some(in_progress) = shared.in_progress.clone() {
+        let log_channel = control_log.clone();
+        let builder = thread::Builder::new().name("control".to_string());
+        // not joined, as it waits for stdin
+        builder.spawn(move || read_commands(&in_progress, log_channel) ).unwrap();
+    }
 
     let is_terminal = stderr().is_terminal();
     let interval = match args.refresh_rate {
@@ -216,21 +2007,81 @@ fn main() {
         None if is_terminal => Duration::from_millis(100),
         None => Duration::from_secs(1),
     };
-    let terminal_width = match term_size::dimensions_stderr() {
-        Some((width, _height)) => width,
+    let (terminal_width, terminal_height) = match term_size::dimensions_stderr() {
+        Some(dimensions) => dimensions,
         None => {
             if is_terminal {
                 eprintln!("Cannot get terminal size of stderr despite it being a terminal");
             }
-            !0
+            (!0, !0)
         }
     };
+    let max_buffer_size = args.max_buffer_size.as_u64();
+    // for finding the most recently started file in compact mode
+    let mut prev_working_on = vec![None; io_info.len()+dir_info.len()+hasher_info.len()];
+    let mut latest_started = None::<Arc<PrintablePath>>;
 
     // buffer output but also allow lookback
-    let mut display = String::new();
+    // Reserve room for a line per thread and the status lines up front,
+    // so that frames don't grow it (clear() keeps the capacity).
+    let line_length = terminal_width.min(512) + 32;
+    let lines = io_info.len() + dir_info.len() + hasher_info.len() + 8;
+    let mut display = String::with_capacity(lines * line_length);
+    let roots = absolute_roots.iter().map(|root| root.as_str() );
+    send_event(&mut events, JsonLine::new("start").strings("roots", roots), &mut display);
     let mut prev = Instant::now();
+    let start = prev;
+    let mut adaptive_memory = args.adaptive_memory;
+    let mut total_hashed = 0u64;
+    let mut stopped_early = false;
+    let mut interrupted = false;
+    let mut next_pressure_check = start;
+    let mut running_hashers = hasher_threads.len();
+    let mut next_parking = start;
     loop {
         let now = Instant::now();
+        if running_hashers < hasher_info.len()
+        && need_more_hashers(&shared, &io_info, running_hashers) {
+            running_hashers += 1;
+            shared.to_hash.lock().unwrap().max_hashers = Some(running_hashers);
+            if running_hashers > hasher_threads.len() {
+                let i = hasher_threads.len();
+                writeln!(&mut display, "starting {}", hasher_info[i].name()).unwrap();
+                hasher_threads.push((start_hasher(&shared, &hasher_info, i), 0usize));
+            } else {
+                writeln!(&mut display, "unparking a hasher thread").unwrap();
+                shared.hasher_unparker.notify_one();
+            }
+            // don't park it again right away
+            next_parking = now + Duration::from_secs(1);
+        } else if running_hashers > u16::from(args.hasher_threads).into() && now >= next_parking
+        && need_fewer_hashers(&shared, &hasher_info[..hasher_threads.len()]) {
+            running_hashers -= 1;
+            writeln!(&mut display, "parking a hasher thread").unwrap();
+            shared.to_hash.lock().unwrap().max_hashers = Some(running_hashers);
+            // idle threads park when woken
+            shared.hasher_waker.notify_all();
+            next_parking = now + Duration::from_secs(1);
+        }
+
+        if adaptive_memory && now >= next_pressure_check {
+            next_pressure_check = now + Duration::from_secs(1);
+            match read_memory_pressure() {
+                Ok(pressure) => if let Some(limit) = adjust_to_pressure(pressure, &shared.buffers) {
+                    writeln!(&mut display, "memory pressure is {:.1}%, buffer limit is now {:#}",
+                            pressure,
+                            Bytes::from(limit),
+                    ).unwrap();
+                },
+                Err(e) => {
+                    writeln!(&mut display, "Cannot read {}: {}, so not adapting memory usage",
+                            PRESSURE_FILE,
+                            e,
+                    ).unwrap();
+                    adaptive_memory = false;
+                }
+            }
+        }
         let mut read = 0;
         for (info, (_, prev_read)) in io_info.iter().zip(&mut io_threads) {
             let current = info.processed_bytes();
@@ -238,22 +2089,96 @@ fn main() {
             *prev_read = current;
         }
         let mut hashed = 0;
+        let frame_seconds = (now-prev).as_secs_f64().max(0.001);
+        let mut hasher_rates = Vec::with_capacity(hasher_threads.len());
         for (info, (_, prev_hashed)) in hasher_info.iter().zip(&mut hasher_threads) {
             let current = info.processed_bytes();
-            hashed += (current - *prev_hashed) as u64;
+            let delta = (current - *prev_hashed) as u64;
+            hashed += delta;
             *prev_hashed = current;
+            let rate = (delta as f64 / frame_seconds) as u64;
+            hasher_rates.push((info.state() == Hashing).then_some(rate));
+        }
+        let hasher_extremes = fastest_and_slowest(&hasher_rates);
+        total_hashed += hashed;
+        if args.max_bytes.is_some_and(|max| total_hashed >= max.as_u64() ) && !stopped_early {
+            writeln!(&mut display, "hashed {:#}, stopping", Bytes(total_hashed)).unwrap();
+            shared.to_read.lock().unwrap().stop_now = true;
+            stopped_early = true;
+        }
+        if INTERRUPTED.load(Ordering::Relaxed) && !interrupted {
+            writeln!(&mut display,
+                    "interrupted, storing what has been hashed (press Ctrl-C again to exit now)",
+            ).unwrap();
+            // files that have been queued for hashing are skipped too
+            shared.to_read.lock().unwrap().stop_now = true;
+            shared.to_hash.lock().unwrap().stop_now = true;
+            shared.reader_waker.notify_all();
+            shared.hasher_waker.notify_all();
+            shared.hasher_unparker.notify_all();
+            interrupted = true;
         }
 
         // print logs (these are not erased, and will be visible in scrollback)
         while let Ok(message) = log_messages.try_recv() {
-            display.push_str(&message);
-            display.push('\n');
+            log_output.write(message, &mut display);
         }
 
-        if is_terminal {
+        let mut status_lines = 0;
+        let all_threads = io_info.iter()
+                .chain(dir_info.iter())
+                .chain(&hasher_info[..hasher_threads.len()]);
+        let readers = io_info.len() + dir_info.len();
+        let compact = args.compact_ui
+                || (!args.detailed_ui && readers+hasher_threads.len()+1 >= terminal_height);
+        if args.summary_only {
+            // no progress
+        } else if is_terminal && compact {
+            for (thread, prev) in all_threads.zip(&mut prev_working_on) {
+                let current = thread.working_on();
+                let changed = match (&current, &*prev) {
+                    (Some(current), Some(prev)) => !Arc::ptr_eq(current, prev),
+                    (current, _) => current.is_some(),
+                };
+                if changed {
+                    latest_started = current.clone();
+                }
+                *prev = current;
+            }
+            write_state_counts(&mut display, "io", &io_info);
+            if !dir_info.is_empty() {
+                write_state_counts(&mut display, "dir", &dir_info);
+                status_lines += 1;
+            }
+            write_state_counts(&mut display, "hasher", &hasher_info[..hasher_threads.len()]);
+            display.push_str("latest    ");
+            if let Some(ref path) = latest_started {
+                path.display_within(&mut display, terminal_width);
+            }
+            display.push('\n');
+            status_lines += 3;
+        } else if is_terminal {
             // display state of each thread
-            for thread in io_info.iter().chain(hasher_info.iter()) {
+            for (i, thread) in all_threads.enumerate() {
                 write!(&mut display, "{:10} {:?}", thread.name(), thread.state()).unwrap();
+                let (done, size) = thread.file_progress();
+                // smaller files are hashed in one go
+                if thread.state() == Hashing && size > max_buffer_size {
+                    write!(&mut display, " ({}%)", done.min(size) * 100 / size).unwrap();
+                }
+                let hasher = i.checked_sub(readers);
+                if let Some(rate) = hasher.and_then(|hasher| hasher_rates[hasher] ) {
+                    write!(&mut display, " {:#}/s", Bytes::new(rate)).unwrap();
+                    match hasher_extremes {
+                        Some((fastest, _)) if hasher == Some(fastest) => {
+                            display.push_str(" (fastest)");
+                        }
+                        Some((_, slowest)) if hasher == Some(slowest) => {
+                            display.push_str(" (slowest)");
+                        }
+                        _ => {}
+                    }
+                }
                 thread.view_working_on(|path| {
                     if let Some(path) = path {
                         display.push(' ');
@@ -261,17 +2186,56 @@ fn main() {
                     }
                 });
                 display.push('\n');
+                status_lines += 1;
+            }
+        }
+        if let (Some(live), true) = (&live_duplicates, is_terminal) {
+            // leave room for the rate line and the line that is being written on
+            let room = terminal_height.saturating_sub(status_lines + 3);
+            let n = usize::from(args.live_duplicates.unwrap().get()).min(room);
+            for group in live.biggest(n) {
+                write!(&mut display, "{:#} in {} copies of ",
+                        Bytes::new(group.wasted()),
+                        group.files,
+                ).unwrap();
+                group.example.display_within(&mut display, terminal_width);
+                display.push('\n');
+                status_lines += 1;
             }
         }
 
-        if is_terminal || now >= prev + interval {
+        let refresh = is_terminal || now >= prev + interval;
+        if refresh {
+            if events.is_enabled() {
+                let seconds = (now-prev).as_secs_f64().max(0.001);
+                let event = JsonLine::new("progress")
+                    .number("elapsed_seconds", (now-start).as_secs_f64())
+                    .number("files_discovered", shared.discovered.files())
+                    .number("bytes_discovered", shared.discovered.bytes())
+                    .number("files_hashed", shared.hashed.files())
+                    .number("bytes_hashed", shared.hashed.bytes())
+                    .number("read_bytes_per_second", (read as f64 / seconds) as u64)
+                    .number("hash_bytes_per_second", (hashed as f64 / seconds) as u64)
+                    .optional_number("total_bytes",
+                            args.prescan.then(|| shared.prescanned.bytes() ),
+                    );
+                send_event(&mut events, event, &mut display);
+            }
             read = read*(now-prev).as_micros() as u64/1_000_000;
             hashed = hashed*(now-prev).as_micros() as u64/1_000_000;
             prev = now;
-            writeln!(&mut display,
-                    "reading {:#}/s, hashing {:#}/s, buffer memory allocated: {:#}",
+        }
+        if refresh && !args.summary_only {
+            write!(&mut display, "reading {:#}/s, hashing {:#}/s, ",
                     Bytes::new(read),
                     Bytes::new(hashed),
+            ).unwrap();
+            if args.prescan {
+                let total = shared.prescanned.bytes();
+                let done = hasher_info.iter().map(|info| info.processed_bytes() as u64 ).sum();
+                write_progress(&mut display, done, total, now - start);
+            }
+            writeln!(&mut display, "buffer memory allocated: {:#}",
                     Bytes::from(shared.buffers.current_buffers_size()),
             ).unwrap();
         }
@@ -283,54 +2247,384 @@ fn main() {
         display.clear();
 
         let lock = shared.to_read.lock().unwrap();
-        if (lock.queue.is_empty() && lock.working == 0) || lock.stop_now {
+        if (lock.is_empty() && lock.working == 0) || lock.stop_now {
             break;
         }
         drop(lock);
 
         // prepare the next frame
-        if is_terminal {
+        if is_terminal && !args.summary_only {
             // go to beginning of line n up, and erase to end of screen
-            write!(&mut display, "\u{1b}[{}F\u{1b}[0J", io_info.len()+hasher_info.len()+1).unwrap();
+            write!(&mut display, "\u{1b}[{}F\u{1b}[0J", status_lines+1).unwrap();
         }
 
         if let Some(deadline_in) = interval.checked_sub(now.elapsed()) {
             if let Ok(message) = log_messages.recv_timeout(deadline_in) {
-                display.push_str(&message);
-                display.push('\n');
+                log_output.write(message, &mut display);
             }
         } // else continue without sleeping
     }
 
-    // tell hashers they can stop now
-    let mut lock = shared.to_hash.lock().unwrap();
-    lock.stop_when_empty = true;
-    drop(lock);
+    // print logs from before shutting down first
+    while let Ok(message) = log_messages.try_recv() {
+        log_output.write(message, &mut display);
+    }
+    stderr().write_all(display.as_bytes()).unwrap();
+    display.clear();
 
     shared.reader_waker.notify_all();
-    for (thread, _) in io_threads {
-        eprintln!("joining reader");
+    for (info, (thread, _)) in io_info.iter().zip(io_threads) {
+        if args.log_level <= Verbose {
+            eprintln!("joining {}", info.name());
+        }
+        thread.join().unwrap();
+    }
+    for (info, thread) in dir_info.iter().zip(dir_threads) {
+        if args.log_level <= Verbose {
+            eprintln!("joining {}", info.name());
+        }
         thread.join().unwrap();
     }
 
+    // tell hashers they can stop now, after the readers have queued their last file
+    shared.to_hash.lock().unwrap().stop_when_empty = true;
     shared.hasher_waker.notify_all();
-    for (thread, _) in hasher_threads {
-        eprintln!("joining hasher");
+    shared.hasher_unparker.notify_all();
+    for (info, (thread, _)) in hasher_info.iter().zip(hasher_threads) {
+        if args.log_level <= Verbose {
+            eprintln!("joining {}", info.name());
+        }
         thread.join().unwrap();
     }
 
-    let read = Arc::try_unwrap(shared)
-        .expect("drop the last reference to shared")
-        .previously_read;
+    let shared = Arc::try_unwrap(shared).expect("drop the last reference to shared");
+    // let the storer thread finish
+    drop(shared.finished);
+    if let Some(summary) = shared.size_mismatches.summary() {
+        display.push_str(&summary);
+        display.push('\n');
+    }
+    if shared.vanished.files() != 0 {
+        writeln!(&mut display, "{} files vanished during scan", shared.vanished.files()).unwrap();
+    }
+    if let Some(ref sizes) = shared.size_groups {
+        let (files, bytes) = sizes.unique();
+        writeln!(&mut display,
+                "{} files with {:#} were not read, as no other file has the same size",
+                files,
+                Bytes::new(bytes),
+        ).unwrap();
+    }
+    if shared.split_reads.bytes() > shared.hashed.bytes()/2 && !args.summary_only
+    && !interrupted {
+        writeln!(&mut display,
+                "{} files with {:#} of the {:#} hashed were bigger than --max-buffer-size {:#}, \
+                 increasing it might make reading faster",
+                shared.split_reads.files(),
+                Bytes::new(shared.split_reads.bytes()),
+                Bytes::new(shared.hashed.bytes()),
+                args.max_buffer_size,
+        ).unwrap();
+    }
+    let read = shared.previously_read;
     let mut storage = storer.join().expect("join storer thread");
-    storage.store_roots(&absolute_roots);
-    storage.prune(&read);
+    storage.store_roots(&stored_roots);
+    if stopped_early {
+        display.push_str("Not pruning deleted files, as --max-bytes stopped the scan early\n");
+    } else if interrupted {
+        display.push_str("Not pruning deleted files, as the scan was interrupted\n");
+    } else if shared.filters.resume_from.is_none() {
+        let mut not_found = read.get_not_found();
+        // files in an image under a root are stored under the image, which is not a directory
+        not_found.retain(|path| {
+            !images.iter().any(|(_, image)| path.as_path().starts_with(image.as_path()) )
+        });
+        if args.prune_dry_run {
+            let mut paths = not_found.iter().map(|path| path.as_str() ).collect::<Vec<_>>();
+            paths.sort_unstable();
+            let mut out = stdout().lock();
+            let result = paths.iter().try_for_each(|path| writeln!(out, "{}", path) );
+            if let Err(e) = result.and_then(|()| out.flush() ) {
+                eprintln!("Error printing deleted files: {}", e);
+                exit(1);
+            }
+            writeln!(&mut display, "would prune {} files", not_found.len()).unwrap();
+        } else if not_found.len() > PRUNE_WITHOUT_ASKING && !args.prune {
+            writeln!(&mut display,
+                    "Not pruning {} deleted files without --prune, as that is many. \
+                     Use --prune-dry-run to see them.",
+                    not_found.len(),
+            ).unwrap();
+        } else {
+            storage.prune(&not_found);
+        }
+        if args.fast_incremental {
+            let mut dirs = shared.scanned_dirs.into_inner().unwrap();
+            // images are always scanned fully, as their files are not stored by the scanned path
+            dirs.retain(|dir| {
+                let path = dir.path.as_path();
+                !images.iter().any(|(mount_point, _)| path.starts_with(mount_point.as_path()) )
+            });
+            storage.store_dirs(&stored_roots, &dirs);
+        }
+    } else {
+        display.push_str("Not pruning deleted files, as --resume-from skipped some directories\n");
+    }
 
     // print any remaining logs
     while let Ok(message) = log_messages.try_recv() {
-        display.push_str(&message);
-        display.push('\n');
+        log_output.write(message, &mut display);
+    }
+    if log_output.errors != 0 {
+        writeln!(display, "{} files or directories could not be read", log_output.errors).unwrap();
+    }
+    if log_output.warnings != 0 {
+        writeln!(display, "{} warnings were treated as errors", log_output.warnings).unwrap();
     }
     stderr().write_all(display.as_bytes()).unwrap();
     display.clear();
+    // a partial scan is not worth reporting on
+    EXIT_ON_INTERRUPT.store(true, Ordering::Relaxed);
+    if interrupted || INTERRUPTED.load(Ordering::Relaxed) {
+        eprintln!("Stored what was hashed before being interrupted, skipping reports");
+        exit(130);
+    }
+
+    if shared.events.is_enabled() {
+        let event = shared.events.event("scan_end")
+                .number("files_hashed", shared.hashed.files())
+                .number("bytes_hashed", shared.hashed.bytes())
+                .number("errors", log_output.errors);
+        if let Err(e) = shared.events.write(event) {
+            writeln!(display, "Cannot write event, so stopping them: {}", e).unwrap();
+        }
+    }
+    let mut done = JsonLine::new("done")
+        .number("elapsed_seconds", start.elapsed().as_secs_f64())
+        .number("files_hashed", shared.hashed.files())
+        .number("bytes_hashed", shared.hashed.bytes())
+        .number("errors", log_output.errors);
+    let (mut reflink_failures, mut collisions) = (0, 0);
+    if report || args.dir_pairs.is_some() || events.is_enabled() || args.reflink_duplicates
+    || args.collision_audit {
+        let groups = find_duplicates(&args, &storage);
+        done = done.number("duplicate_groups", groups.len())
+            .number("wasted_bytes", groups.iter().map(DuplicateGroup::wasted).sum::<u64>());
+        print_duplicate_reports(&args, &groups, report);
+        if args.reflink_duplicates {
+            // let the file being replaced finish
+            EXIT_ON_INTERRUPT.store(false, Ordering::Relaxed);
+            reflink_failures = reflink_duplicates(&groups, args.dry_run);
+            EXIT_ON_INTERRUPT.store(true, Ordering::Relaxed);
+            if INTERRUPTED.load(Ordering::Relaxed) {
+                exit(130);
+            }
+        }
+        if args.collision_audit {
+            collisions = audit_collisions(&groups);
+        }
+    }
+    if args.summary_only {
+        let result = writeln!(stdout().lock(), "Hashed {} files with {:#} in {:.1}s, {} errors",
+                shared.hashed.files(),
+                Bytes::new(shared.hashed.bytes()),
+                start.elapsed().as_secs_f64(),
+                log_output.errors,
+        );
+        if let Err(e) = result {
+            eprintln!("Error printing summary: {}", e);
+            exit(1);
+        }
+    }
+    send_event(&mut events, done, &mut display);
+    eprint!("{}", display);
+    if let Some(n) = args.churn {
+        if let Err(e) = print_churn(&storage.find_churn(n), &mut stdout().lock()) {
+            eprintln!("Error printing changed files: {}", e);
+            exit(1);
+        }
+    }
+    if let Some(n) = args.same_structure {
+        let groups = storage.find_same_structure(n);
+        if let Err(e) = print_same_structure(&groups, &mut stdout().lock()) {
+            eprintln!("Error printing directories: {}", e);
+            exit(1);
+        }
+    }
+    if let Some(n) = args.shared_chunks {
+        let pairs = storage.find_shared_chunks(n);
+        if let Err(e) = print_shared_chunks(&pairs, &mut stdout().lock()) {
+            eprintln!("Error printing files with shared chunks: {}", e);
+            exit(1);
+        }
+    }
+    if let Some(ref other) = args.diff_against {
+        let diff = storage.diff_against(other, &stored_roots).unwrap_or_else(|e| {
+            eprintln!("Cannot compare with {}: {}", PrintablePath::from(other.as_path()), e);
+            exit(2);
+        });
+        if let Err(e) = print_diff(&diff, args.list_diff, &mut stdout().lock()) {
+            eprintln!("Error printing differences: {}", e);
+            exit(1);
+        }
+    }
+    if let Some(ref path) = args.export_json {
+        let exported = open_result_output(path).and_then(|mut out| {
+            let files = storage.export_json(&mut out)?;
+            out.flush()?;
+            Ok(files)
+        });
+        match exported {
+            Ok(files) => eprintln!("exported {} files", files),
+            Err(e) => {
+                eprintln!("Cannot export to {}: {}", PrintablePath::from(path.as_path()), e);
+                exit(1);
+            }
+        }
+    }
+    if args.du {
+        let dirs = storage.directory_sizes(&stored_roots, args.max_depth);
+        if let Err(e) = print_dir_sizes(&dirs, &mut stdout().lock()) {
+            eprintln!("Error printing directory sizes: {}", e);
+            exit(1);
+        }
+    }
+    if let Some(ref reference) = args.only_new_vs {
+        let (new, checked) = storage.find_new_files(reference, &stored_roots).unwrap_or_else(|e| {
+            eprintln!("Cannot compare with {}: {}", PrintablePath::from(reference.as_path()), e);
+            exit(2);
+        });
+        if let Err(e) = print_new_files(&new, checked, &mut stdout().lock()) {
+            eprintln!("Error printing new files: {}", e);
+            exit(1);
+        }
+    }
+    disk_image::unmount_all(false);
+    if log_output.errors != 0 || log_output.warnings != 0 || reflink_failures != 0
+    || collisions != 0 {
+        exit(1);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    /// Create an empty directory for a test, which is not removed if the test fails.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("decopy-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        return fs::canonicalize(dir).unwrap();
+    }
+
+    #[test]
+    fn fastest_and_slowest_hasher() {
+        assert_eq!(fastest_and_slowest(&[]), None);
+        assert_eq!(fastest_and_slowest(&[Some(5), None]), None);
+        assert_eq!(fastest_and_slowest(&[Some(5), None, Some(5)]), None);
+        assert_eq!(fastest_and_slowest(&[Some(1), None, Some(9), Some(5)]), Some((2, 0)));
+    }
+
+    #[test]
+    fn nested_roots_through_symlinks() {
+        let dir = test_dir("nested-roots");
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/file"), "content").unwrap();
+        symlink(dir.join("a/b"), dir.join("to_b")).unwrap();
+        symlink(dir.join("a"), dir.join("a/b/loop")).unwrap();
+
+        let paths = [dir.join("to_b"), dir.join("a/b/loop"), dir.join("a")];
+        let mut notes = String::new();
+        let roots = resolve_roots(&paths, &mut notes).unwrap();
+        assert_eq!(roots, [Arc::new(PrintablePath::from(dir.join("a")))]);
+        assert_eq!(notes.lines().count(), 1);
+
+        // the symlink back to a must not be followed
+        let buffers = AvailableBuffers::new(1<<20, 1<<16).unwrap();
+        let shared = Shared::new(buffers, mpsc::channel().0);
+        shared.to_read.lock().unwrap().push(ToRead::Directory(roots[0].clone()));
+        let (log_channel, _) = mpsc::channel();
+        prescan(&shared, &ThreadInfo::new("test".to_string(), log_channel));
+        assert_eq!(shared.prescanned.files(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn roots_from_dir_of_symlinks() {
+        let dir = test_dir("roots-dir");
+        fs::create_dir_all(dir.join("trees/a")).unwrap();
+        fs::create_dir_all(dir.join("scan.d/c")).unwrap();
+        fs::write(dir.join("scan.d/file"), "content").unwrap();
+        symlink(dir.join("trees/a"), dir.join("scan.d/b")).unwrap();
+        symlink(dir.join("missing"), dir.join("scan.d/broken")).unwrap();
+
+        let mut notes = String::new();
+        let roots = roots_in_dir(&dir.join("scan.d"), &mut notes).unwrap();
+        assert_eq!(roots, [dir.join("scan.d/b"), dir.join("scan.d/c")]);
+        assert_eq!(notes.lines().count(), 2);
+        let roots = resolve_roots(&roots, &mut notes).unwrap();
+        assert_eq!(roots[0], Arc::new(PrintablePath::from(dir.join("trees/a"))));
+        assert!(roots_in_dir(&dir.join("missing"), &mut notes).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn report_only_reads_no_files() {
+        let dir = test_dir("report-only");
+        let db_path = dir.join("hashes.sqlite");
+        // the stored hashes and times don't match the files, so reading them would change them
+        fs::write(dir.join("a"), "changed").unwrap();
+        fs::write(dir.join("b"), "changed too").unwrap();
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        let options = StorageOptions::default();
+        let mut db = Sqlite::open(&db_path, options.clone(), hashed_rx, mpsc::channel().0);
+        for name in ["a", "b", "deleted"] {
+            hashed_tx.send(HashedFile {
+                path: Arc::new(PrintablePath::from(dir.join(name))),
+                modified: PrintableTime::default(),
+                created: None,
+                apparent_size: 10,
+                read_size: 10,
+                hash: [1; 32].into(),
+                sampled_blocks: 0,
+                inode: None,
+                normalized: 0,
+                chunks: Vec::new(),
+                range: None,
+            }).unwrap();
+        }
+        drop(hashed_tx);
+        db.save_hashed(Duration::from_secs(1));
+        drop(db);
+
+        let args = Args::parse_from([
+                "decopy-scan", "--report-only", "--database", db_path.to_str().unwrap(),
+        ]);
+        report_only(&args);
+
+        let db = Sqlite::open(&db_path, options, mpsc::channel().1, mpsc::channel().0);
+        let groups = db.find_duplicate_groups(&GroupingOptions::default());
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].hash, [1; 32]);
+        assert_eq!(groups[0].files.len(), 3);
+        assert!(groups[0].files.iter().all(|file| file.modified == PrintableTime::default() ));
+        drop(db);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn symlink_cycle_root_is_rejected() {
+        let dir = test_dir("cycle-root");
+        symlink(dir.join("x"), dir.join("y")).unwrap();
+        symlink(dir.join("y"), dir.join("x")).unwrap();
+        let result = resolve_roots(&[dir.join("x")], &mut String::new());
+        assert!(result.is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,89 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Lower the memory limit for buffers while the system is short on memory,
+//! using the pressure stall information Linux provides.
+
+use crate::available_buffers::AvailableBuffers;
+
+use std::{fs, io};
+
+pub const PRESSURE_FILE: &str = "/proc/pressure/memory";
+
+/// Lower the limit when more than this percentage of time was spent waiting for memory ...
+const HIGH_PRESSURE: f32 = 10.0;
+/// ... and raise it again when below this.
+const LOW_PRESSURE: f32 = 1.0;
+
+/// Get the percentage of the last ten seconds where some tasks were waiting for memory,
+/// from the contents of /proc/pressure/memory.
+pub fn parse_memory_pressure(contents: &str) -> Option<f32> {
+    let line = contents.lines().find(|line| line.starts_with("some ") )?;
+    let avg10 = line.split(' ').find_map(|field| field.strip_prefix("avg10=") )?;
+    avg10.parse().ok()
+}
+
+pub fn read_memory_pressure() -> io::Result<f32> {
+    let contents = fs::read_to_string(PRESSURE_FILE)?;
+    parse_memory_pressure(&contents).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "unrecognized format")
+    })
+}
+
+/// Halve the buffer limit under high pressure, and double it when the pressure has subsided.
+///
+/// Returns the new limit if it changed.
+pub fn adjust_to_pressure(pressure: f32,  buffers: &AvailableBuffers) -> Option<usize> {
+    let current = buffers.limit();
+    let wanted = if pressure > HIGH_PRESSURE {
+        current / 2
+    } else if pressure < LOW_PRESSURE {
+        current.saturating_mul(2)
+    } else {
+        return None;
+    };
+    let new = buffers.set_limit(wanted);
+    match new != current {
+        true => Some(new),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let contents = "some avg10=12.50 avg60=3.00 avg300=0.50 total=123456\n\
+                        full avg10=1.00 avg60=0.00 avg300=0.00 total=456\n";
+        assert_eq!(parse_memory_pressure(contents), Some(12.5));
+        assert_eq!(parse_memory_pressure("full avg10=1.00\n"), None);
+    }
+
+    #[test]
+    fn adjust() {
+        let buffers = AvailableBuffers::new(8192, 1024).unwrap();
+        assert_eq!(adjust_to_pressure(50.0, &buffers), Some(4096));
+        assert_eq!(adjust_to_pressure(5.0, &buffers), None);
+        assert_eq!(adjust_to_pressure(50.0, &buffers), Some(2048));
+        assert_eq!(adjust_to_pressure(50.0, &buffers), Some(1024));
+        assert_eq!(adjust_to_pressure(50.0, &buffers), None);
+        assert_eq!(adjust_to_pressure(0.0, &buffers), Some(2048));
+        assert_eq!(adjust_to_pressure(0.0, &buffers), Some(4096));
+        assert_eq!(adjust_to_pressure(0.0, &buffers), Some(8192));
+        assert_eq!(adjust_to_pressure(0.0, &buffers), None);
+    }
+}
@@ -0,0 +1,186 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Finding files that other processes have open for writing, to not hash them while they change.
+//!
+//! This looks through the open file descriptors of all processes in /proc on Linux,
+//! and is not supported on other platforms.
+//! Processes of other users can only be seen when running as root,
+//! so this is a best-effort check.
+
+use crate::shared::UnreadFile;
+
+use std::collections::HashSet;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use fxhash::FxBuildHasher;
+
+/// How old the list of open files can get before it is updated.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Device and inode numbers of files that are open for writing.
+type OpenFiles = HashSet<(u64, u64), FxBuildHasher>;
+
+#[cfg(target_os="linux")]
+fn find_open_for_writing() -> io::Result<OpenFiles> {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    let mut files = OpenFiles::default();
+    for process in fs::read_dir("/proc")? {
+        let Ok(process) = process else {
+            continue;
+        };
+        let name = process.file_name();
+        if !name.to_str().is_some_and(|name| name.bytes().all(|b| b.is_ascii_digit() ) ) {
+            continue; // not a process
+        }
+        // processes can exit at any time, and those of other users cannot be inspected
+        let Ok(descriptors) = fs::read_dir(process.path().join("fdinfo")) else {
+            continue;
+        };
+        for descriptor in descriptors {
+            let Ok(descriptor) = descriptor else {
+                continue;
+            };
+            let Ok(info) = fs::read_to_string(descriptor.path()) else {
+                continue;
+            };
+            let flags = info.lines()
+                .find_map(|line| line.strip_prefix("flags:") )
+                .and_then(|flags| i32::from_str_radix(flags.trim(), 8).ok() );
+            if flags.is_none_or(|flags| flags & libc::O_ACCMODE == libc::O_RDONLY ) {
+                continue;
+            }
+            let link = process.path().join("fd").join(descriptor.file_name());
+            if let Ok(metadata) = fs::metadata(link) {
+                if metadata.is_file() {
+                    files.insert((metadata.dev(), metadata.ino()));
+                }
+            }
+        }
+    }
+    return Ok(files);
+}
+#[cfg(not(target_os="linux"))]
+fn find_open_for_writing() -> io::Result<OpenFiles> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "only supported on Linux"))
+}
+
+/// Files that were open for writing recently, and the files deferred because of that.
+#[derive(Debug)]
+pub struct OpenForWriting {
+    open: ArcSwap<OpenFiles>,
+    /// When `open` was last updated, and locked while it is being updated.
+    updated: Mutex<Instant>,
+    deferred: Mutex<Vec<UnreadFile>>,
+    /// Set when the deferred files are queued again at the end of the scan.
+    retrying: AtomicBool,
+}
+
+impl OpenForWriting {
+    /// Find the files that are open now, or fail if that is not supported.
+    pub fn new() -> io::Result<Self> {
+        Ok(OpenForWriting {
+            open: ArcSwap::from_pointee(find_open_for_writing()?),
+            updated: Mutex::new(Instant::now()),
+            deferred: Mutex::new(Vec::new()),
+            retrying: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether the file was open for writing when last checked.
+    ///
+    /// The list is updated when it's older than `REFRESH_INTERVAL`, by the first thread to notice,
+    /// while other threads keep using the old list instead of waiting for it.
+    pub fn is_open(&self,  inode: (u64, u64)) -> bool {
+        if let Ok(mut updated) = self.updated.try_lock() {
+            if updated.elapsed() >= REFRESH_INTERVAL {
+                self.refresh(&mut updated);
+            }
+        }
+        return self.open.load().contains(&inode);
+    }
+
+    fn refresh(&self,  updated: &mut Instant) {
+        // keep the old list if the processes cannot be read anymore
+        if let Ok(files) = find_open_for_writing() {
+            self.open.store(Arc::new(files));
+        }
+        *updated = Instant::now();
+    }
+
+    /// Whether files that are still open should be skipped instead of deferred.
+    pub fn is_retrying(&self) -> bool {
+        self.retrying.load(Ordering::Relaxed)
+    }
+
+    /// Read the file when everything else has been read.
+    pub fn defer(&self,  file: UnreadFile) {
+        self.deferred.lock().unwrap().push(file);
+    }
+
+    /// Get the deferred files to try reading them again, once.
+    pub fn retry(&self) -> Vec<UnreadFile> {
+        if self.retrying.swap(true, Ordering::Relaxed) {
+            return Vec::new();
+        }
+        // the files might have been closed since the list was updated
+        self.refresh(&mut self.updated.lock().unwrap());
+        return std::mem::take(&mut self.deferred.lock().unwrap());
+    }
+}
+
+#[cfg(all(test, target_os="linux"))]
+mod tests {
+    use super::*;
+
+    use std::{env, fs, process};
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn finds_files_open_for_writing() {
+        let path = env::temp_dir().join(format!("decopy-open-{}", process::id()));
+        let writing = fs::File::create(&path).unwrap();
+        let metadata = writing.metadata().unwrap();
+        let inode = (metadata.dev(), metadata.ino());
+        assert!(find_open_for_writing().unwrap().contains(&inode));
+        drop(writing);
+        let reading = fs::File::open(&path).unwrap();
+        assert!(!find_open_for_writing().unwrap().contains(&inode));
+        drop(reading);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn old_list_is_used_while_updating() {
+        let open = OpenForWriting::new().unwrap();
+        let path = env::temp_dir().join(format!("decopy-open-later-{}", process::id()));
+        let writing = fs::File::create(&path).unwrap();
+        let metadata = writing.metadata().unwrap();
+        let inode = (metadata.dev(), metadata.ino());
+        let mut updated = open.updated.lock().unwrap();
+        *updated -= REFRESH_INTERVAL;
+        assert!(!open.is_open(inode));
+        drop(updated);
+        assert!(open.is_open(inode));
+        drop(writing);
+        fs::remove_file(&path).unwrap();
+    }
+}
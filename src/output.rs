@@ -0,0 +1,932 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Machine-readable output for programs wrapping decopy.
+
+use crate::bytes::Bytes;
+use crate::report::{DuplicateGroup, parse_hex, write_hex};
+use crate::shared::{FileHash, HashedFile, PrintablePath};
+
+use std::borrow::Cow;
+use std::fmt::{Display, Write as fmtWrite};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{MAIN_SEPARATOR, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Increased whenever a field is removed or changes meaning.
+/// Fields might be added without changing it.
+///
+/// Every JSON line has it as the `version` field, and every JSON output starts with
+/// a `header` line from `header_line()`, except --export-json which is a plain array.
+pub const JSON_VERSION: u32 = 1;
+
+/// The first line written to every JSON output, with the version of decopy that wrote it.
+pub fn header_line() -> JsonLine {
+    JsonLine::new("header").string("decopy_version", env!("CARGO_PKG_VERSION"))
+}
+
+/// Write a string with quotes and the necessary escapes.
+pub fn write_json_string(s: &str,  out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\0'..='\x1f' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Builds a JSON object on a single line, starting with the version and event type.
+#[derive(Clone, Debug)]
+pub struct JsonLine {
+    line: String,
+}
+
+impl JsonLine {
+    pub fn new(event: &str) -> Self {
+        let mut line = format!("{{\"version\":{},\"event\":", JSON_VERSION);
+        write_json_string(event, &mut line);
+        JsonLine { line }
+    }
+
+    /// Start an object without the version and event type, for JSON that isn't a stream of events.
+    pub fn plain() -> Self {
+        JsonLine { line: String::from("{") }
+    }
+
+    fn key(&mut self,  key: &str) {
+        if !self.line.ends_with('{') {
+            self.line.push(',');
+        }
+        write_json_string(key, &mut self.line);
+        self.line.push(':');
+    }
+
+    /// Add a field with a value that must be a valid JSON number.
+    pub fn number(mut self,  key: &str,  value: impl Display) -> Self {
+        self.key(key);
+        write!(&mut self.line, "{}", value).unwrap();
+        self
+    }
+
+    /// Add a field with a number, or null if `None`.
+    pub fn optional_number(self,  key: &str,  value: Option<impl Display>) -> Self {
+        match value {
+            Some(value) => self.number(key, value),
+            None => self.null(key),
+        }
+    }
+
+    pub fn boolean(mut self,  key: &str,  value: bool) -> Self {
+        self.key(key);
+        self.line.push_str(if value {"true"} else {"false"});
+        self
+    }
+
+    pub fn null(mut self,  key: &str) -> Self {
+        self.key(key);
+        self.line.push_str("null");
+        self
+    }
+
+    pub fn string(mut self,  key: &str,  value: &str) -> Self {
+        self.key(key);
+        write_json_string(value, &mut self.line);
+        self
+    }
+
+    pub fn strings<'a>(mut self,  key: &str,  values: impl IntoIterator<Item=&'a str>) -> Self {
+        self.key(key);
+        self.line.push('[');
+        for (i, value) in values.into_iter().enumerate() {
+            if i != 0 {
+                self.line.push(',');
+            }
+            write_json_string(value, &mut self.line);
+        }
+        self.line.push(']');
+        self
+    }
+
+    /// Get the object, with a trailing newline.
+    pub fn finish(mut self) -> String {
+        self.line.push_str("}\n");
+        self.line
+    }
+}
+
+/// Open where progress events are written:
+/// An already open file descriptor on unix, and a file path on other platforms.
+pub fn open_progress_output(arg: &str) -> io::Result<File> {
+    #[cfg(unix)] {
+        use std::os::unix::io::FromRawFd;
+        let fd = arg.parse::<i32>().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid file descriptor: {}", e))
+        })?;
+        if fd < 3 {
+            let message = "file descriptor must not be stdin, stdout or stderr";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        // The fd is given to this process for writing to, and nothing else uses it.
+        let file = unsafe { File::from_raw_fd(fd) };
+        if let Err(e) = file.metadata() {
+            // don't close a file descriptor that wasn't open
+            std::mem::forget(file);
+            return Err(e);
+        }
+        Ok(file)
+    }
+    #[cfg(not(unix))] {
+        File::create(arg)
+    }
+}
+
+/// Writes `header_line()` before the first line.
+#[derive(Debug)]
+struct WithHeader<W: Write> {
+    out: W,
+    started: bool,
+}
+
+impl<W: Write> WithHeader<W> {
+    fn new(out: W) -> Self {
+        WithHeader { out, started: false }
+    }
+
+    fn write_line(&mut self,  line: JsonLine) -> io::Result<()> {
+        if !self.started {
+            self.out.write_all(header_line().finish().as_bytes())?;
+            self.started = true;
+        }
+        self.out.write_all(line.finish().as_bytes())
+    }
+}
+
+/// Writes one event per line, and stops writing after the first error.
+#[derive(Debug)]
+pub struct ProgressEvents {
+    out: Option<WithHeader<File>>,
+}
+
+impl ProgressEvents {
+    pub fn new(out: Option<File>) -> Self {
+        ProgressEvents { out: out.map(WithHeader::new) }
+    }
+
+    /// Returns an error the first time writing fails, so that it can be reported once.
+    pub fn write(&mut self,  event: JsonLine) -> io::Result<()> {
+        if let Some(ref mut out) = self.out {
+            if let Err(e) = out.write_line(event) {
+                self.out = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.out.is_some()
+    }
+}
+
+/// Lifecycle events for log ingestion, written by any thread as they happen.
+///
+/// Each event has the seconds since the log was created (from a monotonic clock)
+/// and the name of the thread that wrote it.
+#[derive(Debug)]
+pub struct EventLog {
+    out: Mutex<Option<WithHeader<File>>>,
+    start: Instant,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        EventLog::new(None)
+    }
+}
+
+impl EventLog {
+    pub fn new(out: Option<File>) -> Self {
+        EventLog { out: Mutex::new(out.map(WithHeader::new)), start: Instant::now() }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.out.lock().unwrap().is_some()
+    }
+
+    /// Start an event with the current time and thread.
+    pub fn event(&self,  event: &str) -> JsonLine {
+        let thread = thread::current();
+        JsonLine::new(event)
+            .number("time", seconds(self.start.elapsed()))
+            .string("thread", thread.name().unwrap_or("unnamed"))
+    }
+
+    /// Returns an error the first time writing fails, so that it can be reported once.
+    pub fn write(&self,  event: JsonLine) -> io::Result<()> {
+        let mut out = self.out.lock().unwrap();
+        if let Some(ref mut file) = *out {
+            if let Err(e) = file.write_line(event) {
+                *out = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Somewhere hashed files are written to as they are stored.
+pub trait ResultSink: Send {
+    fn add(&mut self,  file: &HashedFile) -> io::Result<()>;
+    /// Called after the last file.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+impl<S: ResultSink + ?Sized> ResultSink for Box<S> {
+    fn add(&mut self,  file: &HashedFile) -> io::Result<()> {
+        (**self).add(file)
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+/// Whether the path is a named pipe or socket, which can only be written to from start to end.
+pub fn is_stream(path: &Path) -> bool {
+    #[cfg(unix)] {
+        use std::os::unix::fs::FileTypeExt;
+        std::fs::metadata(path).is_ok_and(|metadata| {
+            metadata.file_type().is_fifo() || metadata.file_type().is_socket()
+        })
+    }
+    #[cfg(not(unix))] {
+        let _ = path;
+        false
+    }
+}
+
+/// Open a file for `--format` or `--template` to write to instead of stdout.
+///
+/// A named pipe is opened once another process opens it for reading,
+/// and every line is then written as soon as it's ready, so that the reader gets it immediately.
+pub fn open_result_output(path: &Path) -> io::Result<Box<dyn Write + Send>> {
+    let file = File::create(path)?;
+    if is_stream(path) {
+        Ok(Box::new(io::LineWriter::new(file)))
+    } else {
+        Ok(Box::new(io::BufWriter::new(file)))
+    }
+}
+
+/// Formats `--format` can write hashed files in.
+#[derive(Clone,Copy, Debug, PartialEq,Eq, clap::ValueEnum)]
+pub enum ResultFormat {
+    /// One JSON object per line, with the same version field and header as --progress-fd events.
+    Json,
+    /// The hash and path of each file, like the output of sha256sum.
+    ///
+    /// Only for SHA-256 hashes, as the format doesn't say which hash function was used.
+    Sha256sum,
+}
+
+/// A `--format` and where to write it, parsed from `FORMAT[:FILE]`.
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub struct FormatTarget {
+    pub format: ResultFormat,
+    /// `-` for stdout, or `None` for the default.
+    pub target: Option<PathBuf>,
+}
+
+impl FormatTarget {
+    /// Whether this writes to stdout, if `default` is where formats without a file are written.
+    pub fn is_stdout(&self,  default: Option<&Path>) -> bool {
+        self.target.as_deref().or(default).is_none_or(|path| path == Path::new("-") )
+    }
+}
+
+impl FromStr for FormatTarget {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (format, target) = match s.split_once(':') {
+            Some((format, "")) => return Err(format!("no file after {}:", format)),
+            Some((format, target)) => (format, Some(PathBuf::from(target))),
+            None => (s, None),
+        };
+        let format = <ResultFormat as clap::ValueEnum>::from_str(format, true)?;
+        return Ok(FormatTarget { format, target });
+    }
+}
+
+/// Writes each hashed file as a line in one of the `ResultFormat`s.
+#[derive(Debug)]
+pub struct FormatSink<W: Write> {
+    out: W,
+    format: ResultFormat,
+    line: String,
+    /// Whether the JSON header has been written.
+    started: bool,
+}
+
+impl<W: Write> FormatSink<W> {
+    pub fn new(out: W,  format: ResultFormat) -> Self {
+        FormatSink { out, format, line: String::new(), started: false }
+    }
+
+    fn add_header(&mut self) {
+        if self.format == ResultFormat::Json && !self.started {
+            self.line.push_str(&header_line().finish());
+        }
+        self.started = true;
+    }
+}
+
+impl<W: Write + Send> ResultSink for FormatSink<W> {
+    fn add(&mut self,  file: &HashedFile) -> io::Result<()> {
+        self.line.clear();
+        self.add_header();
+        let mut hash = String::with_capacity(file.hash.len()*2);
+        write_hex(&file.hash, &mut hash);
+        match self.format {
+            ResultFormat::Json => {
+                let mut line = JsonLine::new("hashed")
+                    .string("path", file.path.as_str())
+                    .string("modified", &file.modified.to_string())
+                    .number("size", file.read_size)
+                    .string("hash", &hash)
+                    .number("sampled_blocks", file.sampled_blocks);
+                if let Some(range) = file.range {
+                    line = line.string("range", &range.to_string());
+                }
+                self.line.push_str(&line.finish());
+            }
+            ResultFormat::Sha256sum => {
+                writeln!(&mut self.line, "{}  {}", hash, file.path).unwrap();
+            }
+        }
+        self.out.write_all(self.line.as_bytes())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // the header is written even if there were no files
+        self.line.clear();
+        self.add_header();
+        self.out.write_all(self.line.as_bytes())?;
+        self.out.flush()
+    }
+}
+
+/// A part of a `--template`.
+#[derive(Clone, Debug, PartialEq,Eq)]
+enum TemplatePart {
+    Text(String),
+    Hash,
+    /// The number of bytes read, or human-readable with `{size:human}`.
+    Size{human: bool},
+    Modified,
+    Path,
+    /// The last part of the path.
+    Name,
+    /// The path of the directory, with trailing separator.
+    Dir,
+}
+
+/// A line format with `{placeholder}`s that are replaced with information about each file.
+///
+/// `{{` and `}}` are literal braces.
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub struct Template {
+    parts: Vec<TemplatePart>,
+}
+
+impl FromStr for Template {
+    type Err = String;
+    fn from_str(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = template;
+        while let Some(brace) = rest.find(['{', '}']) {
+            text.push_str(&rest[..brace]);
+            let after = &rest[brace+1..];
+            if rest[brace..].starts_with("{{") || rest[brace..].starts_with("}}") {
+                text.push_str(&rest[brace..brace+1]);
+                rest = &after[1..];
+                continue;
+            } else if rest[brace..].starts_with('}') {
+                return Err(format!("unmatched }} at position {}", template.len()-rest.len()+brace));
+            }
+            let Some(end) = after.find('}') else {
+                return Err(format!("unterminated {{{}", after));
+            };
+            let part = match &after[..end] {
+                "hash" => TemplatePart::Hash,
+                "size" => TemplatePart::Size{human: false},
+                "size:human" => TemplatePart::Size{human: true},
+                "modified" => TemplatePart::Modified,
+                "path" => TemplatePart::Path,
+                "name" => TemplatePart::Name,
+                "dir" => TemplatePart::Dir,
+                unknown => return Err(format!(
+                        "unknown placeholder {{{}}}, expected one of {{hash}}, {{size}}, \
+                         {{size:human}}, {{modified}}, {{path}}, {{name}} or {{dir}}",
+                        unknown,
+                )),
+            };
+            if !text.is_empty() {
+                parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+            }
+            parts.push(part);
+            rest = &after[end+1..];
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            parts.push(TemplatePart::Text(text));
+        }
+        return Ok(Template { parts });
+    }
+}
+
+impl Template {
+    /// Append the expanded template for a file to `out`, without a newline.
+    pub fn expand(&self,  file: &HashedFile,  out: &mut String) {
+        let path = file.path.as_str();
+        let name = Path::new(path).file_name().map_or("", |name| name.to_str().unwrap() );
+        for part in &self.parts {
+            match part {
+                TemplatePart::Text(text) => out.push_str(text),
+                TemplatePart::Hash => write_hex(&file.hash, out),
+                TemplatePart::Size{human: false} => {
+                    write!(out, "{}", file.read_size).unwrap();
+                }
+                TemplatePart::Size{human: true} => {
+                    write!(out, "{:#}", Bytes(file.read_size)).unwrap();
+                }
+                TemplatePart::Modified => write!(out, "{:?}", file.modified).unwrap(),
+                TemplatePart::Path => out.push_str(path),
+                TemplatePart::Name => out.push_str(name),
+                TemplatePart::Dir => out.push_str(&path[..path.len()-name.len()]),
+            }
+        }
+    }
+}
+
+/// Writes each hashed file as a line from a `Template`.
+#[derive(Debug)]
+pub struct TemplateSink<W: Write> {
+    out: W,
+    template: Template,
+    line: String,
+}
+
+impl<W: Write> TemplateSink<W> {
+    pub fn new(out: W,  template: Template) -> Self {
+        TemplateSink { out, template, line: String::new() }
+    }
+}
+
+impl<W: Write + Send> ResultSink for TemplateSink<W> {
+    fn add(&mut self,  file: &HashedFile) -> io::Result<()> {
+        self.line.clear();
+        self.template.expand(file, &mut self.line);
+        self.line.push('\n');
+        self.out.write_all(self.line.as_bytes())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Parse a line in the format of sha256sum into the expected hash and the path.
+///
+/// The hash can also be 64 bytes, as from sha512sum.
+/// A `*` before the path, which marks that the file was read in binary mode, is ignored.
+/// The path is everything after that, including any whitespace, except the newline.
+/// If the line starts with a backslash, backslashes and newlines in the path are escaped.
+pub fn parse_checksum_line(line: &[u8]) -> Option<(FileHash, Cow<'_, [u8]>)> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let (escaped, line) = match line.strip_prefix(b"\\") {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let hex_length = line.iter().position(|&byte| byte == b' ' )?;
+    if line.len() <= hex_length+2  ||  !matches!(line[hex_length+1], b' ' | b'*') {
+        return None;
+    }
+    let hex = std::str::from_utf8(&line[..hex_length]).ok()?;
+    let hash = FileHash::new(&parse_hex(hex)?)?;
+    let path = &line[hex_length+2..];
+    if !escaped {
+        return Some((hash, Cow::Borrowed(path)));
+    }
+    let mut unescaped = Vec::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte != b'\\' {
+            unescaped.push(byte);
+            continue;
+        }
+        unescaped.push(match bytes.next()? {
+            b'\\' => b'\\',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            _ => return None,
+        });
+    }
+    return Some((hash, Cow::Owned(unescaped)));
+}
+
+/// Runs another sink on a separate thread, so that a slow sink only stalls storing files
+/// when `capacity` files are waiting to be written.
+#[derive(Debug)]
+pub struct BackgroundSink {
+    tx: Option<mpsc::SyncSender<HashedFile>>,
+    thread: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl BackgroundSink {
+    pub fn new(mut sink: impl ResultSink + 'static,  capacity: usize) -> io::Result<Self> {
+        let (tx, rx) = mpsc::sync_channel::<HashedFile>(capacity);
+        let thread = thread::Builder::new().name("output".to_string()).spawn(move || {
+            for file in rx {
+                sink.add(&file)?;
+            }
+            sink.finish()
+        })?;
+        Ok(BackgroundSink { tx: Some(tx), thread: Some(thread) })
+    }
+
+    /// Get the error that stopped the thread.
+    fn join(&mut self) -> io::Result<()> {
+        self.tx = None;
+        match self.thread.take() {
+            Some(thread) => thread.join().expect("join output thread"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ResultSink for BackgroundSink {
+    fn add(&mut self,  file: &HashedFile) -> io::Result<()> {
+        match self.tx {
+            Some(ref tx) => match tx.send(file.clone()) {
+                Ok(()) => Ok(()),
+                // the thread has stopped
+                Err(_) => self.join(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.join()
+    }
+}
+
+/// Writes to several sinks, and stops writing to those that fail.
+#[derive(Default)]
+pub struct TeeSink {
+    sinks: Vec<Box<dyn ResultSink>>,
+}
+
+impl TeeSink {
+    pub fn new(sinks: Vec<Box<dyn ResultSink>>) -> Self {
+        TeeSink { sinks }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+}
+
+impl ResultSink for TeeSink {
+    /// Returns the first error, after writing to all sinks.
+    fn add(&mut self,  file: &HashedFile) -> io::Result<()> {
+        let mut result = Ok(());
+        self.sinks.retain_mut(|sink| match sink.add(file) {
+            Ok(()) => true,
+            Err(e) => {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+                false
+            }
+        });
+        result
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for mut sink in self.sinks.drain(..) {
+            if let Err(e) = sink.finish() {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl std::fmt::Debug for TeeSink {
+    fn fmt(&self,  fmtr: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmtr.debug_struct("TeeSink")
+            .field("sinks", &self.sinks.len())
+            .finish()
+    }
+}
+
+/// Shows paths relative to a directory, for --cwd-relative.
+///
+/// Paths that are not under the directory are shown as they are instead of with `../`,
+/// as those are hard to read.
+#[derive(Clone, Debug)]
+pub struct RelativePaths {
+    base: PathBuf,
+}
+
+impl RelativePaths {
+    pub fn new(base: PathBuf) -> Self {
+        RelativePaths { base }
+    }
+
+    /// Get the part of an absolute path after the base directory, or the path if not under it.
+    pub fn relative(&self,  path: &PrintablePath) -> PrintablePath {
+        // compares components, so a base that isn't valid UTF-8 works too
+        match path.as_path().strip_prefix(&self.base) {
+            Ok(relative) if relative.as_os_str().is_empty() => PrintablePath::from(Path::new(".")),
+            Ok(relative) => PrintablePath::from(relative),
+            Err(_) => path.clone(),
+        }
+    }
+
+    /// Make the paths and directories of the files in the groups relative.
+    pub fn groups(&self,  groups: &[DuplicateGroup]) -> Vec<DuplicateGroup> {
+        let mut groups = groups.to_vec();
+        for file in groups.iter_mut().flat_map(|group| &mut group.files ) {
+            let path = self.relative(&file.path);
+            if path.as_path().is_relative() {
+                file.dir = match path.as_path().parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => {
+                        format!("{}{}", PrintablePath::from(parent), MAIN_SEPARATOR)
+                    }
+                    _ => format!(".{}", MAIN_SEPARATOR),
+                };
+            }
+            file.path = path;
+        }
+        return groups;
+    }
+}
+
+/// Format a duration as seconds for `JsonLine::number()`.
+pub fn seconds(duration: Duration) -> impl Display {
+    format!("{:.6}", duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_line() {
+        let line = JsonLine::new("test")
+            .number("n", 1.5)
+            .optional_number("none", None::<u64>)
+            .string("path", "/a \"b\"\\\n\u{1}")
+            .strings("list", ["x", "y"])
+            .finish();
+        assert_eq!(line, concat!(
+            r#"{"version":1,"event":"test","n":1.5,"none":null,"#,
+            r#""path":"/a \"b\"\\\n\u0001","list":["x","y"]}"#,
+            "\n",
+        ));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn relative_paths() {
+        let relative = RelativePaths::new(PathBuf::from("/home/user"));
+        let path = |path: &str| PrintablePath::from(Path::new(path));
+        assert_eq!(relative.relative(&path("/home/user/a/b")).as_str(), "a/b");
+        assert_eq!(relative.relative(&path("/home/user")).as_str(), ".");
+        assert_eq!(relative.relative(&path("/home/username")).as_str(), "/home/username");
+        assert_eq!(relative.relative(&path("/tmp/a")).as_str(), "/tmp/a");
+    }
+
+    /// Fails after writing `limit` bytes.
+    struct Limited(Vec<u8>, usize);
+    impl Write for Limited {
+        fn write(&mut self,  buf: &[u8]) -> io::Result<usize> {
+            if self.0.len() + buf.len() > self.1 {
+                return Err(io::Error::other("full"));
+            }
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parse_format_target() {
+        let parsed = "json:report.json".parse::<FormatTarget>().unwrap();
+        assert_eq!(parsed.format, ResultFormat::Json);
+        assert_eq!(parsed.target, Some(PathBuf::from("report.json")));
+        assert!(!parsed.is_stdout(None));
+        let parsed = "sha256sum".parse::<FormatTarget>().unwrap();
+        assert_eq!((parsed.format, parsed.target.is_none()), (ResultFormat::Sha256sum, true));
+        assert!(parsed.is_stdout(None));
+        assert!(!parsed.is_stdout(Some(Path::new("out"))));
+        assert!("json:-".parse::<FormatTarget>().unwrap().is_stdout(Some(Path::new("out"))));
+        assert!("json:".parse::<FormatTarget>().is_err());
+        assert!("text:out".parse::<FormatTarget>().is_err());
+    }
+
+    #[test]
+    fn tee_sink() {
+        use crate::shared::{PrintablePath, PrintableTime};
+        use std::{path::Path, sync::Arc};
+        let file = HashedFile {
+            path: Arc::new(PrintablePath::from(Path::new("/a b"))),
+            modified: PrintableTime::default(),
+            created: None,
+            apparent_size: 3,
+            read_size: 3,
+            hash: [0xab; 32].into(),
+            sampled_blocks: 0,
+            inode: None,
+            normalized: 0,
+            chunks: Vec::new(),
+            range: None,
+        };
+        let line = format!("{}  /a b\n", "ab".repeat(32));
+        let mut tee = TeeSink::new(vec![
+            Box::new(FormatSink::new(Limited(Vec::new(), 1000), ResultFormat::Json)),
+            Box::new(FormatSink::new(Limited(Vec::new(), line.len()), ResultFormat::Sha256sum)),
+        ]);
+        tee.add(&file).unwrap();
+        assert_eq!(tee.sinks.len(), 2);
+        assert!(tee.add(&file).is_err());
+        assert_eq!(tee.sinks.len(), 1);
+        tee.add(&file).unwrap();
+        tee.finish().unwrap();
+        assert!(tee.is_empty());
+
+        let mut sink = FormatSink::new(Vec::new(), ResultFormat::Sha256sum);
+        sink.add(&file).unwrap();
+        assert_eq!(String::from_utf8(sink.out).unwrap(), line);
+        let mut sink = FormatSink::new(Vec::new(), ResultFormat::Json);
+        sink.add(&file).unwrap();
+        sink.finish().unwrap();
+        let json = String::from_utf8(sink.out).unwrap();
+        let lines = json.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(r#"{"version":1,"event":"header","decopy_version":""#));
+        assert!(lines[1].starts_with(r#"{"version":1,"event":"hashed","path":"/a b","#));
+        assert!(lines[1].ends_with(",\"sampled_blocks\":0}"));
+        let mut empty = FormatSink::new(Vec::new(), ResultFormat::Json);
+        empty.finish().unwrap();
+        assert_eq!(String::from_utf8(empty.out).unwrap(), header_line().finish());
+    }
+
+    #[test]
+    fn checksum_lines() {
+        let hash = "0f".repeat(32);
+        let parse = |line: String| parse_checksum_line(line.as_bytes()).map(|(hash, path)| {
+            (hash, String::from_utf8(path.into_owned()).unwrap())
+        });
+        let expected = FileHash::from([0x0f; 32]);
+        assert_eq!(parse(format!("{}  a b \n", hash)), Some((expected, "a b ".to_string())));
+        assert_eq!(parse(format!("{} * a", hash.to_uppercase())).unwrap().1, " a");
+        assert_eq!(parse(format!("\\{}  a\\\\b\\nc", hash)).unwrap().1, "a\\b\nc");
+        assert_eq!(parse(format!("{}  a\\nb", hash)).unwrap().1, "a\\nb");
+        assert_eq!(parse(format!("\\{}  a\\b", hash)), None);
+        assert_eq!(parse(format!("{}  ", hash)), None);
+        assert_eq!(parse(format!("{} a", hash)), None);
+        assert_eq!(parse(format!("{}  a", &hash[2..])), None);
+        let sha512 = parse(format!("{}{}  a", hash, hash)).unwrap();
+        assert_eq!(sha512, (FileHash::from([0x0f; 64]), "a".to_string()));
+    }
+
+    #[test]
+    fn template() {
+        use crate::shared::{PrintablePath, PrintableTime};
+        use std::{path::Path, sync::Arc};
+        let file = HashedFile {
+            path: Arc::new(PrintablePath::from(Path::new("/dir/a b"))),
+            modified: PrintableTime::default(),
+            created: None,
+            apparent_size: 2048,
+            read_size: 2048,
+            hash: [0xab; 32].into(),
+            sampled_blocks: 0,
+            inode: None,
+            normalized: 0,
+            chunks: Vec::new(),
+            range: None,
+        };
+        let expand = |template: &str| {
+            let mut line = String::new();
+            template.parse::<Template>().unwrap().expand(&file, &mut line);
+            line
+        };
+        assert_eq!(expand("{hash} {size} {path}"), format!("{} 2048 /dir/a b", "ab".repeat(32)));
+        assert_eq!(expand("{{{name}}} in {dir}: {size:human}"), "{a b} in /dir/: 2.0 KB");
+        assert_eq!(expand("{modified}"), format!("{:?}", PrintableTime::default()));
+        assert_eq!(expand("}}plain"), "}plain");
+        assert!("{hash".parse::<Template>().is_err());
+        assert!("{size:raw}".parse::<Template>().is_err());
+        assert!("{unknown}".parse::<Template>().is_err());
+        assert!("a}b".parse::<Template>().is_err());
+    }
+
+    #[test]
+    #[cfg(target_os="linux")]
+    fn stream_through_named_pipe() {
+        use crate::shared::{PrintablePath, PrintableTime};
+        use std::{ffi::CString, io::{BufRead, BufReader}, path::Path, sync::Arc};
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::env::temp_dir().join(format!("decopy-fifo-{}", std::process::id()));
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        // SAFETY: the path is a valid C string
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        assert!(is_stream(&path));
+        assert!(!is_stream(&std::env::temp_dir()));
+
+        let reader = thread::spawn({
+            let path = path.clone();
+            move || BufReader::new(File::open(path).unwrap())
+        });
+        let out = open_result_output(&path).unwrap();
+        let mut lines = reader.join().unwrap().lines();
+        let mut sink = TemplateSink::new(out, "{path}".parse().unwrap());
+        for name in ["/a", "/b"] {
+            let file = HashedFile {
+                path: Arc::new(PrintablePath::from(Path::new(name))),
+                modified: PrintableTime::default(),
+                created: None,
+                apparent_size: 0,
+                read_size: 0,
+                hash: [0; 32].into(),
+                sampled_blocks: 0,
+                inode: None,
+                normalized: 0,
+                chunks: Vec::new(),
+                range: None,
+            };
+            sink.add(&file).unwrap();
+            // arrives before the sink is finished
+            assert_eq!(lines.next().unwrap().unwrap(), name);
+        }
+        sink.finish().unwrap();
+        drop(sink);
+        assert!(lines.next().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn event_log() {
+        let path = std::env::temp_dir().join(format!("decopy-events-{}", std::process::id()));
+        let log = EventLog::new(Some(File::create(&path).unwrap()));
+        assert!(log.is_enabled());
+        log.write(log.event("first").number("bytes", 3)).unwrap();
+        thread::scope(|scope| {
+            thread::Builder::new().name("other".to_string())
+                .spawn_scoped(scope, || log.write(log.event("second")).unwrap() )
+                .unwrap();
+        });
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines = written.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], header_line().finish().trim_end());
+        assert!(lines[1].starts_with(r#"{"version":1,"event":"first","time":0."#));
+        assert!(lines[1].contains(r#","thread":""#));
+        assert!(lines[1].ends_with(r#","bytes":3}"#));
+        assert!(lines[2].ends_with(r#","thread":"other"}"#));
+        assert!(!EventLog::default().is_enabled());
+    }
+}
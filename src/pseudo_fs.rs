@@ -0,0 +1,114 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Detecting virtual file systems such as /proc and /sys, which are not worth scanning
+//! and contain files like /proc/kcore that cannot be read normally.
+//!
+//! This checks the file system type with statfs() on Linux, and finds nothing on other platforms.
+//! /dev is usually a devtmpfs, which can't be told apart from tmpfs,
+//! but the device files in it are not read anyway.
+
+use std::collections::HashMap;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use fxhash::FxBuildHasher;
+
+/// Magic numbers of pseudo-filesystems from linux/magic.h, and their names.
+#[cfg(target_os="linux")]
+const PSEUDO_FILESYSTEMS: &[(u32, &str)] = &[
+    (0x0000_9fa0, "proc"),
+    (0x6265_6572, "sysfs"),
+    (0x0000_1cd1, "devpts"),
+    (0x6462_6720, "debugfs"),
+    (0x7472_6163, "tracefs"),
+    (0x7363_6673, "securityfs"),
+    (0x0027_e0eb, "cgroup"),
+    (0x6367_7270, "cgroup2"),
+    (0x6165_676c, "pstore"),
+    (0xcafe_4a11, "bpf"),
+    (0x6265_6570, "configfs"),
+    (0xde5e_81e4, "efivarfs"),
+    (0x6573_5543, "fusectl"),
+    (0x1980_0202, "mqueue"),
+    (0x4249_4e4d, "binfmt_misc"),
+    (0xf97c_ff8c, "selinuxfs"),
+];
+
+/// Get the name of the pseudo-filesystem `dir` is on, or `None` if it's on a normal one.
+#[cfg(target_os="linux")]
+fn pseudo_filesystem(dir: &Path) -> io::Result<Option<&'static str>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    // SAFETY: statfs() only writes to the struct, and the path is NUL-terminated
+    let fs_type = unsafe {
+        let mut stat = std::mem::zeroed::<libc::statfs>();
+        if libc::statfs(path.as_ptr(), &mut stat) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // f_type is signed and 32-bit on some architectures, but the magic numbers are not
+        stat.f_type as u32
+    };
+    let name = PSEUDO_FILESYSTEMS.iter().find(|&&(magic, _)| magic == fs_type );
+    return Ok(name.map(|&(_, name)| name ));
+}
+#[cfg(not(target_os="linux"))]
+fn pseudo_filesystem(_: &Path) -> io::Result<Option<&'static str>> {
+    Ok(None)
+}
+
+/// Remembers which devices have pseudo-filesystems, to only check each once.
+#[derive(Debug, Default)]
+pub struct PseudoFilesystems {
+    devices: Mutex<HashMap<u64, Option<&'static str>, FxBuildHasher>>,
+}
+
+impl PseudoFilesystems {
+    /// Get the name of the pseudo-filesystem a directory is on,
+    /// or `None` if it's on a normal file system or cannot be checked.
+    pub fn check(&self,  dir: &Path) -> Option<&'static str> {
+        #[cfg(unix)]
+        let device = match dir.symlink_metadata() {
+            Ok(metadata) => metadata.dev(),
+            Err(_) => return None, // reading the directory will fail too
+        };
+        #[cfg(not(unix))]
+        let device = 0;
+        if let Some(&name) = self.devices.lock().unwrap().get(&device) {
+            return name;
+        }
+        let name = pseudo_filesystem(dir).unwrap_or(None);
+        self.devices.lock().unwrap().insert(device, name);
+        return name;
+    }
+}
+
+#[cfg(all(test, target_os="linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_proc() {
+        let pseudo = PseudoFilesystems::default();
+        assert_eq!(pseudo.check(Path::new("/proc")), Some("proc"));
+        assert_eq!(pseudo.check(Path::new("/proc/self")), Some("proc"));
+        assert_eq!(pseudo.check(&std::env::temp_dir()), None);
+    }
+}
@@ -13,19 +13,165 @@
  * If not, see <https://www.gnu.org/licenses/>.
  */
 
+use crate::direct_io::*;
 use crate::shared::*;
 use crate::thread_info::*;
 
-use std::{fs, io::Read};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
 use std::sync::{Arc, mpsc};
+use std::sync::atomic::Ordering;
 
-fn read_dir(dir_path: Arc<PrintablePath>,  shared: &Shared,  thread_info: &ThreadInfo) {
+/// Get the information needed to read a file from its metadata.
+///
+/// Returns `None` if the modification time is not available, after logging an error.
+fn unread_file(path: Arc<PrintablePath>,  metadata: &fs::Metadata,  thread_info: &ThreadInfo)
+-> Option<UnreadFile> {
+    let modified = match metadata.modified() {
+        Ok(modified) => modified,
+        Err(e) => match metadata.created() {
+            Ok(created) => {
+                thread_info.log_message(Warning, format!(
+                        "Cannot get modification time for {}: {}, using creation time",
+                        path,
+                        e,
+                ));
+                created
+            },
+            Err(_) => {
+                thread_info.log_message(Error, format!(
+                        "Cannot get modification or creation time for {}: {}",
+                        path,
+                        e,
+                ));
+                return None;
+            },
+        },
+    };
+    let modified = PrintableTime::from(modified).clamp_to_yyyy();
+    // not supported by all platforms and file systems
+    let created = metadata.created().ok().map(|created| {
+        PrintableTime::from(created).clamp_to_yyyy()
+    });
+
+    #[cfg(unix)]
+    let inode = Some((metadata.dev(), metadata.ino()));
+    #[cfg(not(unix))]
+    let inode = None;
+    return Some(UnreadFile { path, modified, created, size: metadata.len(), inode });
+}
+
+/// Log why a file is or isn't hashed, with --explain.
+fn explain(path: &PrintablePath,  decision: fmt::Arguments,
+        shared: &Shared,  thread_info: &ThreadInfo,
+) {
+    if shared.explain {
+        thread_info.log_message(Explain, format!("{}: {}", path, decision));
+    }
+}
+
+/// With --skip-unique-sizes, queue the file that is waiting for another file with the same size,
+/// now that one which will be stored has been found.
+fn found_size(size: u64,  shared: &Shared,  thread_info: &ThreadInfo) {
+    let waiting = shared.size_groups.as_ref().and_then(|sizes| sizes.found(size) );
+    if let Some(waiting) = waiting {
+        let decision = format_args!("hashed (another file has the same size)");
+        queue_file(waiting, decision, shared, thread_info);
+    }
+}
+
+/// Queue a file to be read, unless another path to the same inode has already been queued.
+fn queue_file(unread: UnreadFile,  decision: fmt::Arguments,
+        shared: &Shared,  thread_info: &ThreadInfo,
+) {
+    let path = unread.path.clone();
+    let claimed = match &shared.seen_inodes {
+        Some(seen) => seen.claim(unread),
+        None => Claimed::First(unread),
+    };
+    match claimed {
+        Claimed::First(unread) => {
+            explain(&path, decision, shared, thread_info);
+            shared.discovered.add(unread.size);
+            shared.queue_to_read(ToRead::File(unread));
+        }
+        Claimed::Waiting => {
+            let decision = format_args!("not read (another path to a file being read)");
+            explain(&path, decision, shared, thread_info);
+        }
+        Claimed::Hashed(hashed) => {
+            let decision = format_args!("not read (another path to a hashed file)");
+            explain(&path, decision, shared, thread_info);
+            // reached through another root or a hard link
+            shared.hashed.add(hashed.apparent_size);
+            shared.finished.lock().unwrap().send(hashed).unwrap();
+        }
+    }
+}
+
+/// Queue the entries of a directory, or only count the files if `count_only` is true.
+fn read_dir(dir_path: Arc<PrintablePath>,
+        shared: &Shared,  thread_info: &ThreadInfo,
+        count_only: bool,
+) {
     thread_info.set_state(Opening);
     thread_info.set_working_on(Some(dir_path.clone()));
+    // the prescan is before the scan starts, and the scan enters the directory again
+    if shared.events.is_enabled() && !count_only {
+        let event = shared.events.event("dir_entered").string("path", dir_path.as_str());
+        shared.send_event(event, thread_info);
+    }
+    let pseudo = shared.pseudo_filesystems.as_ref()
+            .and_then(|pseudo| pseudo.check(dir_path.as_path()) );
+    if let Some(fs_type) = pseudo {
+        thread_info.log_message(Info, format!(
+                "Skipping {} as it is on a {} pseudo-filesystem, use --allow-pseudofs to scan it",
+                dir_path,
+                fs_type,
+        ));
+        return;
+    }
+    let dir_modified = match shared.fast_incremental {
+        true => fs::metadata(dir_path.as_path()).and_then(|metadata| metadata.modified() ).ok(),
+        false => None,
+    };
+    let dir_modified = dir_modified.map(|modified| PrintableTime::from(modified).clamp_to_yyyy() );
+    let unchanged = dir_modified.and_then(|modified| {
+        shared.previous_dirs.unchanged(&dir_path, modified)
+    });
+    if let Some((previous, children)) = unchanged {
+        for child in children {
+            match child {
+                ToRead::File(file) => {
+                    shared.previously_read.check_unchanged(file);
+                    if !count_only {
+                        let decision = format_args!("skipped (the directory is unchanged)");
+                        explain(&file.path, decision, shared, thread_info);
+                    }
+                }
+                // the prescan must not mark paths as queued, as that would skip them in the scan
+                ToRead::Directory(dir) if shared.filters.accepts_dir(dir) && (count_only
+                || shared.queued_paths.as_ref().is_none_or(|queued| queued.first_time(dir) )) => {
+                    shared.queue_to_read(child.clone());
+                }
+                _ => {}
+            }
+        }
+        if !count_only {
+            shared.scanned_dirs.lock().unwrap().push(previous.clone());
+        }
+        return;
+    }
+    // what will be in the database after this scan, if --fast-incremental
+    let mut contents = Vec::<(Arc<PrintablePath>, Option<(u64, PrintableTime)>)>::new();
+
     let entries = match fs::read_dir(dir_path.as_path()) {
         Ok(entries) => entries,
         Err(e) => {
-            thread_info.log_message(format!("Cannot open {}: {}", dir_path, e));
+            thread_info.log_message(Error, format!("Cannot open {}: {}", dir_path, e));
             return;
         }
     };
@@ -34,18 +180,26 @@ fn read_dir(dir_path: Arc<PrintablePath>,  shared: &Shared,  thread_info: &Threa
         let entry = match entry {
             Ok(entry) => entry,
             Err(e) => {
-                thread_info.log_message(format!("Error getting entry from {}: {}", dir_path, e));
+                let message = format!("Error getting entry from {}: {}", dir_path, e);
+                thread_info.log_message(Error, message);
                 continue;
             }
         };
         let mut entry_path = dir_path.to_path_buf();
         entry_path.push(entry.path());
         let entry_path = Arc::new(PrintablePath::from(entry_path));
+        if let (Some(queued), false) = (&shared.queued_paths, count_only) {
+            if !queued.first_time(&entry_path) {
+                thread_info.log_message(Verbose, format!("{} has already been queued", entry_path));
+                continue;
+            }
+        }
 
         let file_type = match entry.file_type() {
             Ok(typ) => typ,
             Err(e) => {
-                thread_info.log_message(format!("Error getting type of {}: {}", entry_path, e));
+                let message = format!("Error getting type of {}: {}", entry_path, e);
+                thread_info.log_message(Error, message);
                 continue;
             }
         };
@@ -53,96 +207,359 @@ fn read_dir(dir_path: Arc<PrintablePath>,  shared: &Shared,  thread_info: &Threa
             let metadata = match entry.metadata() {
                 Ok(metadata) => metadata,
                 Err(e) => {
-                    thread_info.log_message(format!("Error getting metadata of {}: {}",
+                    thread_info.log_message(Error, format!("Error getting metadata of {}: {}",
                             entry_path,
                             e,
                     ));
                     continue;
                 }
             };
-            let modified = match metadata.modified() {
-                Ok(modified) => modified,
-                Err(e) => match metadata.created() {
-                    Ok(created) => {
-                        thread_info.log_message(format!(
-                                "Cannot get modification time for {}: {}, using creation time",
-                                entry_path,
-                                e,
-                        ));
-                        created
-                    },
-                    Err(_) => {
-                        thread_info.log_message(format!(
-                                "Cannot get modification or creation time for {}: {}",
-                                entry_path,
-                                e,
-                        ));
-                        continue;
-                    },
-                },
+            #[cfg(unix)]
+            let owned = shared.filters.accepts_owner(metadata.uid(), metadata.gid());
+            #[cfg(not(unix))]
+            let owned = true;
+            let Some(unread) = unread_file(entry_path, &metadata, thread_info) else {
+                continue;
             };
-            let modified = PrintableTime::from(modified).clamp_to_yyyy();
-
-            let unread = UnreadFile { path: entry_path, modified, size: metadata.len(), };
-            if shared.previously_read.check_unchanged(&unread) {
+            // check_previous() must be called even for filtered files to not prune them
+            let previous = shared.previously_read.check_previous(&unread);
+            let unchanged = previous == Some((unread.modified, unread.size));
+            let rejected = shared.filters.rejects(&unread)
+                    .or((!owned).then_some("not owned by --owner or --group"));
+            if dir_modified.is_some() && (unchanged || rejected.is_none()) {
+                contents.push((unread.path.clone(), Some((unread.size, unread.modified))));
+            }
+            if unchanged || rejected.is_some() {
+                match rejected {
+                    _ if count_only => {}
+                    Some(filter) => {
+                        let decision = format_args!("excluded ({})", filter);
+                        explain(&unread.path, decision, shared, thread_info);
+                    }
+                    None => {
+                        let decision = format_args!("skipped (same size and modification time)");
+                        explain(&unread.path, decision, shared, thread_info);
+                        found_size(unread.size, shared, thread_info);
+                    }
+                }
+                continue;
+            }
+            let cached = shared.inode_cache.get(&unread);
+            if count_only {
+                if cached.is_none() {
+                    shared.prescanned.add(unread.size);
+                }
+                continue;
+            } else if let Some(cached) = cached {
+                let decision = format_args!("not read (moved: a hashed file has the same inode)");
+                explain(&unread.path, decision, shared, thread_info);
+                // moved or renamed since the previous scan
+                shared.finished.lock().unwrap().send(HashedFile {
+                        path: unread.path,
+                        modified: unread.modified,
+                        created: unread.created,
+                        apparent_size: unread.size,
+                        read_size: cached.read_size,
+                        hash: cached.hash,
+                        sampled_blocks: 0,
+                        inode: unread.inode,
+                        normalized: cached.normalized,
+                        chunks: Vec::new(),
+                        range: None,
+                }).unwrap();
+                shared.hashed.add(cached.apparent_size);
+                found_size(unread.size, shared, thread_info);
                 continue;
             }
-            ToRead::File(unread)
+            // changed files are always read, to not keep the outdated hash
+            if let (Some(sizes), None) = (&shared.size_groups, previous) {
+                // claimed only when read, as other paths to the inode would wait for it otherwise
+                let path = unread.path.clone();
+                let ready = sizes.add(unread);
+                if ready.is_empty() {
+                    let decision = format_args!("not read yet (no other file has its size)");
+                    explain(&path, decision, shared, thread_info);
+                }
+                for unread in ready {
+                    let decision = match unread.path == path {
+                        true => format_args!("hashed (new)"),
+                        false => format_args!("hashed (another file has the same size)"),
+                    };
+                    queue_file(unread, decision, shared, thread_info);
+                }
+                continue;
+            }
+            let decision = match previous {
+                Some(_) => "changed size or modification time",
+                None => "new",
+            };
+            let size = unread.size;
+            queue_file(unread, format_args!("hashed ({})", decision), shared, thread_info);
+            found_size(size, shared, thread_info);
+            continue;
         } else if file_type.is_dir() {
+            if !shared.filters.accepts_dir(&entry_path) {
+                continue;
+            }
+            if dir_modified.is_some() {
+                contents.push((entry_path.clone(), None));
+            }
             ToRead::Directory(entry_path)
         } else if file_type.is_symlink() {
             continue;
         } else {
             let file_type = if file_type.is_symlink() {"symlink"} else {"special file"};
-            thread_info.log_message(format!("{} is a {}, skipping.", entry_path, file_type));
+            thread_info.log_message(Info, format!("{} is a {}, skipping.", entry_path, file_type));
             continue;
         };
 
-        let mut lock = shared.to_read.lock().unwrap();
-        lock.queue.push(to_read);
-        drop(lock);
-        shared.reader_waker.notify_one();
+        shared.queue_to_read(to_read);
+    }
+
+    if let (Some(modified), false) = (dir_modified, count_only) {
+        let signature = dir_signature(contents.iter().map(|(path, file)| (path.as_str(), *file) ));
+        let dir = ScannedDir { path: dir_path, modified, signature };
+        shared.scanned_dirs.lock().unwrap().push(dir);
+    }
+}
+
+/// Read only the given offsets and lengths of a file,
+/// which are the blocks from `sample_offsets()` or the --range.
+fn read_parts(file: &mut fs::File,  file_info: UnreadFile,  parts: Vec<(u64, u64)>,
+        shared: &Shared,  thread_info: &ThreadInfo,
+) {
+    let (tx, rx) = mpsc::channel();
+    let skip = shared.start_file(&file_info.path);
+    let mut lock = shared.to_hash.lock().unwrap();
+    lock.queue.push((file_info, rx, skip.clone()));
+    drop(lock);
+    shared.hasher_waker.notify_one();
+
+    for (offset, length) in parts {
+        if skip.load(Ordering::Relaxed) {
+            return;
+        }
+        thread_info.set_state(Reading);
+        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
+            tx.send(FilePart::Error(e)).unwrap();
+            return;
+        }
+        // buffers might be smaller than a block
+        let mut remaining = length;
+        while remaining > 0 {
+            let mut buffer = shared.buffers.get_buffer(buffer_request(remaining), thread_info);
+            let wanted = buffer_request(remaining).min(buffer.len());
+            match file.read(&mut buffer[..wanted]) {
+                Err(e) => {
+                    shared.buffers.return_buffer(buffer);
+                    tx.send(FilePart::Error(e)).unwrap();
+                    return;
+                }
+                Ok(0) if shared.range.is_some() => {
+                    shared.buffers.return_buffer(buffer);
+                    let message = "file ended before the end of the range";
+                    tx.send(FilePart::Error(io::Error::new(io::ErrorKind::UnexpectedEof, message)))
+                        .unwrap();
+                    return;
+                }
+                Ok(0) => {
+                    // file has shrunk, which the hasher will detect
+                    shared.buffers.return_buffer(buffer);
+                    return;
+                }
+                Ok(length) => {
+                    tx.send(FilePart::Chunk{buffer, length}).unwrap();
+                    thread_info.add_bytes(length);
+                    remaining -= length as u64;
+                }
+            }
+        }
+    }
+}
+
+/// Hash a file from the database again, for --rehash.
+///
+/// Files that no longer exist are not marked as found, so that they are pruned.
+fn reread_file(path: Arc<PrintablePath>,  shared: &Shared,  thread_info: &ThreadInfo) {
+    thread_info.set_state(Opening);
+    thread_info.set_working_on(Some(path.clone()));
+    let metadata = match fs::symlink_metadata(path.as_path()) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            thread_info.log_message(Error, format!("Error getting metadata of {}: {}", path, e));
+            return;
+        }
+    };
+    let Some(unread) = unread_file(path, &metadata, thread_info) else {
+        return;
+    };
+    // marks it as found, but it's read even if unchanged
+    shared.previously_read.check_unchanged(&unread);
+    #[cfg(unix)]
+    let owned = shared.filters.accepts_owner(metadata.uid(), metadata.gid());
+    #[cfg(not(unix))]
+    let owned = true;
+    if shared.filters.accepts(&unread) && owned {
+        explain(&unread.path, format_args!("hashed (--rehash)"), shared, thread_info);
+        shared.discovered.add(unread.size);
+        read_file(unread, shared, thread_info);
+    }
+}
+
+/// Convert how much remains to be read to a buffer size.
+///
+/// Files can be bigger than `usize` on 32-bit platforms, but buffers are limited anyway.
+fn buffer_request(remaining: u64) -> usize {
+    usize::try_from(remaining).unwrap_or(usize::MAX)
+}
+
+/// How much remains to be read of a file after reading `length` bytes,
+/// or the maximum buffer size if the file has grown, to continue until its end.
+fn remaining_after(remaining: u64,  length: usize,  max_buffer_size: usize) -> u64 {
+    match remaining.checked_sub(length as u64) {
+        Some(remaining) => remaining,
+        None => max_buffer_size as u64,
     }
 }
 
 fn read_file(file_info: UnreadFile,  shared: &Shared,  thread_info: &ThreadInfo) {
     thread_info.set_state(Opening);
     thread_info.set_working_on(Some(file_info.path.clone()));
-    let mut file = match fs::File::open(file_info.path.as_path()) {
-        Ok(file) => file,
+    if let (Some(open), Some(inode)) = (&shared.open_for_writing, file_info.inode) {
+        if open.is_open(inode) && open.is_retrying() {
+            thread_info.log_message(Warning, format!(
+                    "Skipped {} as it is still open for writing",
+                    file_info.path,
+            ));
+            // other paths to the same file are skipped too
+            shared.inode_failed(&file_info);
+            return;
+        } else if open.is_open(inode) {
+            thread_info.log_message(Info, format!(
+                    "{} is open for writing, so reading it last",
+                    file_info.path,
+            ));
+            open.defer(file_info);
+            return;
+        }
+    }
+    if let Some(range) = shared.range.filter(|range| range.end > file_info.size ) {
+        thread_info.log_message(Error, format!(
+                "{} is only {} bytes, so it doesn't contain the range {}",
+                file_info.path,
+                file_info.size,
+                range,
+        ));
+        shared.inode_failed(&file_info);
+        return;
+    }
+    let sampled = is_sampled(file_info.size, shared.sample_blocks);
+    // the sample offsets and range are not aligned for O_DIRECT
+    let opened = match shared.direct_io && !sampled && shared.range.is_none() {
+        true => open_direct(file_info.path.as_path()),
+        false => fs::File::open(file_info.path.as_path()).map(|file| (file, false) ),
+    };
+    let (mut file, mut direct) = match opened {
+        Ok(opened) => opened,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            // deleted since the directory was read, which is expected when files are being moved
+            let message = format!("{} was deleted before it was read", file_info.path);
+            thread_info.log_message(Info, message);
+            shared.previously_read.vanished(&file_info.path);
+            shared.vanished.add(file_info.size);
+            shared.read_separately(shared.inode_failed(&file_info));
+            return;
+        }
         Err(e) => {
-            thread_info.log_message(format!("Cannot open {}: {}", file_info.path, e));
+            thread_info.log_message(Error, format!("Cannot open {}: {}", file_info.path, e));
+            shared.read_separately(shared.inode_failed(&file_info));
             return;
         }
     };
+    let parts = match (sampled, shared.range) {
+        (_, Some(range)) => Some(vec![(range.start, range.length())]),
+        (true, None) => Some(sample_offsets(file_info.size, shared.sample_blocks)
+                .into_iter()
+                .map(|offset| (offset, SAMPLE_BLOCK_SIZE) )
+                .collect()),
+        (false, None) => None,
+    };
+    if let Some(parts) = parts {
+        read_parts(&mut file, file_info, parts, shared, thread_info);
+        if shared.direct_io || shared.fadvise {
+            drop_from_cache(&file);
+        }
+        return;
+    }
+    if shared.fadvise && !direct {
+        advise_sequential(&file);
+    }
 
-    let mut remaining_size = usize::try_from(file_info.size)
-            .unwrap_or(shared.buffers.max_single_buffer_size());
-    let mut buffer = shared.buffers.get_buffer(remaining_size, thread_info);
+    let mut remaining_size = file_info.size;
+    if file_info.size > shared.buffers.max_single_buffer_size() as u64 {
+        shared.split_reads.add(file_info.size);
+    }
+    let buffer_size = |remaining: u64,  direct: bool| {
+        // with --read-chunk the size is fixed, except for the read that should find the end
+        let size = match shared.read_chunk {
+            Some(chunk) if remaining != 0 => chunk,
+            _ => buffer_request(remaining),
+        };
+        match direct {
+            true => direct_buffer_size(size),
+            false => size,
+        }
+    };
+    // Reading into an empty buffer returns 0 like at the end of the file,
+    // which would make files that have content despite an apparent size of 0
+    // (such as in /proc) look empty.
+    let mut buffer = shared.buffers.get_buffer(
+            buffer_size(remaining_size.max(1), direct),
+            thread_info,
+    );
 
     let (tx, rx) = mpsc::channel();
+    let skip = shared.start_file(&file_info.path);
     // delay inserting until after first read
-    let mut insert = Some((file_info, rx));
+    let mut insert = Some((file_info, rx, skip.clone()));
     let mut incomplete = true;
 
     while incomplete {
         thread_info.set_state(Reading);
-        match file.read(&mut buffer) {
+        let result = match direct {
+            true => read_direct(&mut file, &mut buffer),
+            false => file.read(&mut buffer),
+        };
+        match result {
+            Err(e) if direct && is_unaligned(&e) => {
+                // the tail of the file or the buffer is not aligned, so read the rest normally
+                direct = false;
+                if let Err(e) = disable_direct(&file) {
+                    tx.send(FilePart::Error(e)).unwrap();
+                    incomplete = false;
+                }
+            }
             Err(e) => {
                 tx.send(FilePart::Error(e)).unwrap();
                 incomplete = false;
             }
             Ok(0) => {
+                // also on the first read of an empty file: closing the channel without an error
+                // tells the hasher that it got the whole file
                 incomplete = false;
             }
             Ok(length) => {
                 tx.send(FilePart::Chunk{buffer, length}).unwrap();
                 thread_info.add_bytes(length);
-                remaining_size = match remaining_size.checked_sub(length) {
-                    Some(remaining) => remaining,
-                    None => shared.buffers.max_single_buffer_size(),
-                };
-                buffer = shared.buffers.get_buffer(remaining_size, thread_info);
+                remaining_size = remaining_after(
+                        remaining_size,
+                        length,
+                        shared.buffers.max_single_buffer_size(),
+                );
+                let size = buffer_size(remaining_size, direct);
+                buffer = shared.buffers.get_buffer(size, thread_info);
             }
         }
         // now insert it
@@ -152,11 +569,21 @@ fn read_file(file_info: UnreadFile,  shared: &Shared,  thread_info: &ThreadInfo)
             drop(lock);
             shared.hasher_waker.notify_one();
         }
+        if skip.load(Ordering::Relaxed) {
+            // the hasher will log it
+            break;
+        }
     }
     shared.buffers.return_buffer(buffer);
+    if (shared.direct_io || shared.fadvise) && !direct {
+        drop_from_cache(&file);
+    }
 }
 
-pub fn read_files(shared: Arc<Shared>, thread_info: &ThreadInfo) {
+/// Read files and directories from the queue until it is empty and no other thread is working.
+///
+/// `kind` limits which entries this thread takes, when directories have their own threads.
+pub fn read_files(shared: Arc<Shared>,  thread_info: &ThreadInfo,  kind: ReadKind) {
     let mut lock = shared.to_read.lock().unwrap();
 
     loop {
@@ -164,18 +591,33 @@ pub fn read_files(shared: Arc<Shared>, thread_info: &ThreadInfo) {
             thread_info.set_state(Quit);
             thread_info.set_working_on(None);
             break;
-        } else if let Some(to_read) = lock.queue.pop() {
+        } else if let Some(to_read) = lock.pop(kind) {
             lock.working += 1;
             drop(lock);
 
             match to_read {
                 ToRead::File(file) => read_file(file, &shared, thread_info),
-                ToRead::Directory(path) => read_dir(path, &shared, thread_info),
+                ToRead::Directory(path) => read_dir(path, &shared, thread_info, false),
+                ToRead::Recorded(path) => reread_file(path, &shared, thread_info),
             }
 
             lock = shared.to_read.lock().unwrap();
             lock.working -= 1;
-        } else if lock.working == 0 {
+            if lock.is_empty() && lock.working == 0 {
+                // the others are waiting for more to read, so wake them to quit
+                shared.reader_waker.notify_all();
+            }
+        } else if lock.is_empty() && lock.working == 0 {
+            if let Some(ref open) = shared.open_for_writing {
+                let deferred = open.retry();
+                if !deferred.is_empty() {
+                    for file in deferred {
+                        lock.push(ToRead::File(file));
+                    }
+                    shared.reader_waker.notify_all();
+                    continue;
+                }
+            }
             thread_info.set_state(Quit);
             thread_info.set_working_on(None);
             break;
@@ -186,3 +628,206 @@ pub fn read_files(shared: Arc<Shared>, thread_info: &ThreadInfo) {
         }
     }
 }
+
+/// Traverse the queued directories on the current thread, without reading any files,
+/// to find how many files and bytes will need to be hashed.
+///
+/// The totals are stored in `shared.prescanned`, and the queue is restored afterwards.
+pub fn prescan(shared: &Shared,  thread_info: &ThreadInfo) {
+    let mut lock = shared.to_read.lock().unwrap();
+    let roots = (lock.queue.clone(), lock.dirs.clone());
+    while let Some(to_read) = lock.pop(ReadKind::Any) {
+        drop(lock);
+        match to_read {
+            ToRead::Directory(path) => read_dir(path, shared, thread_info, true),
+            ToRead::File(file) => shared.prescanned.add(file.size),
+            ToRead::Recorded(path) => if let Ok(metadata) = fs::symlink_metadata(path.as_path()) {
+                shared.prescanned.add(metadata.len());
+            },
+        }
+        lock = shared.to_read.lock().unwrap();
+    }
+    (lock.queue, lock.dirs) = roots;
+    thread_info.set_state(Idle);
+    thread_info.set_working_on(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::available_buffers::AvailableBuffers;
+    use crate::hash::hash_files;
+
+    use std::{env, process};
+    use std::path::Path;
+
+    use sha2::{Digest, Sha256};
+
+    /// Read and hash files with the given apparent sizes on the current thread.
+    fn read_and_hash(files: &[(&Path, u64)],  configure: impl FnOnce(&mut Shared))
+    -> Vec<HashedFile> {
+        let buffers = AvailableBuffers::new(1<<20, 1<<16).unwrap();
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        let mut shared = Shared::new(buffers, hashed_tx);
+        configure(&mut shared);
+        let shared = Arc::new(shared);
+        for &(path, size) in files {
+            shared.queue_to_read(ToRead::File(UnreadFile {
+                path: Arc::new(PrintablePath::from(path)),
+                modified: PrintableTime::default(),
+                created: None,
+                size,
+                inode: None,
+            }));
+        }
+        let (log_tx, _log_rx) = mpsc::channel();
+        let info = ThreadInfo::new("test".to_string(), log_tx);
+        read_files(shared.clone(), &info, ReadKind::Any);
+        shared.to_hash.lock().unwrap().stop_when_empty = true;
+        hash_files(shared.clone(), &info);
+        drop(shared);
+        return hashed_rx.into_iter().collect();
+    }
+
+    #[test]
+    fn empty_and_unreadable_files() {
+        let dir = env::temp_dir().join(format!("decopy-read-empty-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty");
+        fs::write(&empty, "").unwrap();
+        let empty_hash = FileHash::from(<[u8; 32]>::from(Sha256::digest([])));
+
+        let hashed = read_and_hash(&[(&empty, 0)], |_| {});
+        assert_eq!(hashed.len(), 1);
+        assert_eq!((hashed[0].read_size, hashed[0].hash), (0, empty_hash));
+
+        // truncated after the size was read
+        let hashed = read_and_hash(&[(&empty, 100)], |_| {});
+        assert_eq!(hashed.len(), 1);
+        assert_eq!((hashed[0].apparent_size, hashed[0].read_size), (100, 0));
+        assert_eq!(hashed[0].hash, empty_hash);
+
+        // opening a directory as a file succeeds, but reading it fails
+        let hashed = read_and_hash(&[(&dir, 0)], |_| {});
+        assert!(hashed.is_empty(), "stored {:?}", hashed);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prescan_with_dedup_traversal() {
+        let dir = env::temp_dir().join(format!("decopy-prescan-dedup-{}", process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("sub/b"), "b").unwrap();
+        let buffers = AvailableBuffers::new(1<<20, 1<<16).unwrap();
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        let mut shared = Shared::new(buffers, hashed_tx);
+        shared.queued_paths = Some(QueuedPaths::default());
+        let shared = Arc::new(shared);
+        shared.queue_to_read(ToRead::Directory(Arc::new(PrintablePath::from(dir.as_path()))));
+        let (log_tx, _log_rx) = mpsc::channel();
+        let info = ThreadInfo::new("test".to_string(), log_tx);
+        prescan(&shared, &info);
+        assert_eq!(shared.prescanned.files(), 2);
+        read_files(shared.clone(), &info, ReadKind::Any);
+        shared.to_hash.lock().unwrap().stop_when_empty = true;
+        hash_files(shared.clone(), &info);
+        drop(shared);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(hashed_rx.into_iter().count(), 2);
+    }
+
+    #[test]
+    fn hard_links_with_unique_sizes() {
+        let dir = env::temp_dir().join(format!("decopy-hard-links-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), "linked").unwrap();
+        fs::hard_link(dir.join("a"), dir.join("b")).unwrap();
+        fs::write(dir.join("c"), "unique size").unwrap();
+        let buffers = AvailableBuffers::new(1<<20, 1<<16).unwrap();
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        let mut shared = Shared::new(buffers, hashed_tx);
+        shared.seen_inodes = Some(SeenInodes::default());
+        shared.size_groups = Some(SizeGroups::default());
+        let shared = Arc::new(shared);
+        shared.queue_to_read(ToRead::Directory(Arc::new(PrintablePath::from(dir.as_path()))));
+        let (log_tx, _log_rx) = mpsc::channel();
+        let info = ThreadInfo::new("test".to_string(), log_tx);
+        read_files(shared.clone(), &info, ReadKind::Any);
+        shared.to_hash.lock().unwrap().stop_when_empty = true;
+        hash_files(shared.clone(), &info);
+        drop(shared);
+        fs::remove_dir_all(&dir).unwrap();
+        // both paths are stored, but the file is only read once
+        let mut hashed = hashed_rx.into_iter().collect::<Vec<_>>();
+        hashed.sort_by(|a, b| a.path.cmp(&b.path) );
+        let names = hashed.iter().map(|file| file.path.as_path().file_name().unwrap() );
+        assert_eq!(names.collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(hashed[0].hash, hashed[1].hash);
+    }
+
+    #[test]
+    fn hash_range() {
+        let dir = env::temp_dir().join(format!("decopy-read-range-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        fs::write(&file, "header and content").unwrap();
+        let range = "0:6".parse::<ByteRange>().unwrap();
+
+        let hashed = read_and_hash(&[(&file, 18)], |shared| shared.range = Some(range));
+        assert_eq!(hashed.len(), 1);
+        assert_eq!((hashed[0].read_size, hashed[0].range), (6, Some(range)));
+        assert_eq!(hashed[0].hash[..], Sha256::digest("header")[..]);
+
+        // beyond the apparent size
+        let hashed = read_and_hash(&[(&file, 18)], |shared| shared.range = "10:20".parse().ok());
+        assert!(hashed.is_empty(), "stored {:?}", hashed);
+        // truncated after the size was read
+        let hashed = read_and_hash(&[(&file, 100)], |shared| shared.range = "10:20".parse().ok());
+        assert!(hashed.is_empty(), "stored {:?}", hashed);
+
+        assert_eq!("1K:2K".parse(), Ok(ByteRange { start: 1024, end: 2048 }));
+        assert!("6:6".parse::<ByteRange>().is_err());
+        assert!("6".parse::<ByteRange>().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fixed_read_chunk() {
+        let dir = env::temp_dir().join(format!("decopy-read-chunk-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (small, big) = (dir.join("small"), dir.join("big"));
+        let content = (0..100_000u32).map(|n| n as u8 ).collect::<Vec<u8>>();
+        fs::write(&small, &content[..10]).unwrap();
+        fs::write(&big, &content).unwrap();
+
+        let files = [(small.as_path(), 10), (big.as_path(), content.len() as u64)];
+        let mut hashed = read_and_hash(&files, |shared| shared.read_chunk = Some(4096) );
+        hashed.sort_by_key(|file| file.read_size );
+        assert_eq!(hashed.len(), 2);
+        assert_eq!(hashed[0].hash[..], Sha256::digest(&content[..10])[..]);
+        assert_eq!(hashed[1].hash[..], Sha256::digest(&content)[..]);
+        assert_eq!(hashed[1].read_size, content.len() as u64);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remaining_size_of_files_bigger_than_usize_on_32_bit() {
+        let max_buffer_size = 1<<28;
+        let size = (1u64<<32) + 3 * max_buffer_size as u64 + 1;
+        // read in the biggest buffers until the empty request that finds the end
+        let (mut remaining, mut reads, mut read) = (size, 0, 0u64);
+        while buffer_request(remaining) != 0 {
+            let length = buffer_request(remaining).min(max_buffer_size);
+            remaining = remaining_after(remaining, length, max_buffer_size);
+            read += length as u64;
+            reads += 1;
+        }
+        assert_eq!((read, reads), (size, 16 + 3 + 1));
+        // files that grow are read until the end
+        assert_eq!(remaining_after(10, 20, max_buffer_size), max_buffer_size as u64);
+    }
+}
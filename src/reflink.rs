@@ -0,0 +1,274 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Replacing duplicate files with reflinks (copy-on-write copies) of another file,
+//! so that they share storage until one of them is modified.
+//!
+//! This uses the FICLONE ioctl on Linux, which btrfs and XFS support,
+//! and is not supported on other platforms.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Make `target` have the same content as `source` by sharing its storage.
+#[cfg(target_os="linux")]
+fn clone_file(source: &File,  target: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    /// _IOW(0x94, 9, int)
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+    // SAFETY: FICLONE only takes the source descriptor as argument
+    let result = unsafe { libc::ioctl(target.as_raw_fd(), FICLONE, source.as_raw_fd()) };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(());
+}
+#[cfg(not(target_os="linux"))]
+fn clone_file(_: &File,  _: &File) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "only supported on Linux"))
+}
+
+/// Give `target` the owner, group and extended attributes of `source`, which includes ACLs.
+///
+/// Fails with `Unsupported` if any of them cannot be set, such as the owner when not root.
+#[cfg(target_os="linux")]
+fn copy_owner_and_xattrs(source: &File,  source_metadata: &fs::Metadata,  target: &File)
+-> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    let cannot_keep = |what: &str,  e: io::Error| {
+        io::Error::new(io::ErrorKind::Unsupported, format!("cannot keep its {}: {}", what, e))
+    };
+    // SAFETY: only takes integers
+    let result = unsafe {
+        libc::fchown(target.as_raw_fd(), source_metadata.uid(), source_metadata.gid())
+    };
+    if result == -1 {
+        return Err(cannot_keep("owner", io::Error::last_os_error()));
+    }
+
+    // SAFETY: the buffers are as long as the lengths passed,
+    // and names are NUL-terminated by the kernel
+    let get_names = |buffer: &mut Vec<u8>| unsafe {
+        libc::flistxattr(source.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len())
+    };
+    let mut names = Vec::new();
+    let length = match get_names(&mut names) {
+        -1 => {
+            let e = io::Error::last_os_error();
+            // the file system doesn't have any
+            if e.raw_os_error() == Some(libc::ENOTSUP) {
+                return Ok(());
+            }
+            return Err(cannot_keep("extended attributes", e));
+        }
+        length => length as usize,
+    };
+    names.resize(length, 0);
+    if length != 0 && get_names(&mut names) != length as isize {
+        // changed since getting the length
+        let e = io::Error::other("they changed while being copied");
+        return Err(cannot_keep("extended attributes", e));
+    }
+    let mut value = Vec::new();
+    for name in names.split_inclusive(|&b| b == 0 ) {
+        // SAFETY: as above
+        let get_value = |value: &mut Vec<u8>| unsafe {
+            libc::fgetxattr(source.as_raw_fd(), name.as_ptr().cast(),
+                    value.as_mut_ptr().cast(), value.len(),
+            )
+        };
+        value.clear();
+        let length = get_value(&mut value);
+        if length != -1 {
+            value.resize(length as usize, 0);
+        }
+        if length == -1 || get_value(&mut value) != length {
+            return Err(cannot_keep("extended attributes", io::Error::last_os_error()));
+        }
+        // SAFETY: as above
+        let result = unsafe {
+            libc::fsetxattr(target.as_raw_fd(), name.as_ptr().cast(),
+                    value.as_ptr().cast(), value.len(), 0,
+            )
+        };
+        if result == -1 {
+            return Err(cannot_keep("extended attributes", io::Error::last_os_error()));
+        }
+    }
+    return Ok(());
+}
+#[cfg(not(target_os="linux"))]
+fn copy_owner_and_xattrs(_: &File,  _: &fs::Metadata,  _: &File) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "only supported on Linux"))
+}
+
+/// Get the number of hard links to a file, which is 1 on platforms where that isn't available.
+pub fn hard_links(metadata: &fs::Metadata) -> u64 {
+    #[cfg(unix)]
+    return std::os::unix::fs::MetadataExt::nlink(metadata);
+    #[cfg(not(unix))]
+    return 1;
+}
+
+/// Check that a file is the same one and hasn't been modified,
+/// by comparing the size, modification time and inode.
+fn unchanged(before: &fs::Metadata,  now: &fs::Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if (before.dev(), before.ino()) != (now.dev(), now.ino()) {
+            return false;
+        }
+    }
+    return before.len() == now.len() && before.modified().ok() == now.modified().ok();
+}
+
+/// Read two files to check that they have exactly the same content.
+pub fn same_content(a: &Path,  b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let (mut a_buffer, mut b_buffer) = (vec![0; 64*1024], vec![0; 64*1024]);
+    loop {
+        let length = a.read(&mut a_buffer)?;
+        if length == 0 {
+            // b must also be at the end
+            return Ok(b.read(&mut b_buffer[..1])? == 0);
+        }
+        match b.read_exact(&mut b_buffer[..length]) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+            Ok(()) if a_buffer[..length] != b_buffer[..length] => return Ok(false),
+            Ok(()) => {}
+        }
+    }
+}
+
+/// Replace `duplicate` with a reflink of `original`, keeping its owner, permissions,
+/// extended attributes and modification time.
+///
+/// The reflink is created next to it and then renamed over it, so that the duplicate is never
+/// lost or incomplete.
+/// The content is not compared; use `same_content()` for that first, after getting `before`,
+/// the metadata of the duplicate. If the duplicate has changed since then, it is not replaced,
+/// and `Ok(false)` is returned.
+/// Fails with `Unsupported` if the owner or extended attributes cannot be kept.
+pub fn replace_with_reflink(original: &Path,  duplicate: &Path,  before: &fs::Metadata)
+-> io::Result<bool> {
+    let source = File::open(original)?;
+    let old = File::open(duplicate)?;
+    let mut temporary_name = OsString::from(".");
+    temporary_name.push(duplicate.file_name().unwrap_or_default());
+    temporary_name.push(".decopy-reflink");
+    let temporary = duplicate.with_file_name(temporary_name);
+    let target = fs::OpenOptions::new().write(true).create_new(true).open(&temporary)?;
+    // set the owner first, as changing it clears setuid bits
+    let result = clone_file(&source, &target)
+        .and_then(|()| copy_owner_and_xattrs(&old, before, &target) )
+        .and_then(|()| target.set_permissions(before.permissions()) )
+        .and_then(|()| target.set_modified(before.modified()?) )
+        .and_then(|()| fs::symlink_metadata(duplicate) )
+        .and_then(|now| match unchanged(before, &now) {
+            true => fs::rename(&temporary, duplicate).map(|()| true ),
+            false => Ok(false),
+        });
+    if !matches!(result, Ok(true)) {
+        let _ = fs::remove_file(&temporary);
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, process};
+
+    #[test]
+    fn compare_and_reflink() {
+        let dir = env::temp_dir().join(format!("decopy-reflink-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name);
+        let content = (0..100_000u32).map(|n| n as u8 ).collect::<Vec<u8>>();
+        fs::write(path("original"), &content).unwrap();
+        fs::write(path("copy"), &content).unwrap();
+        let mut changed = content.clone();
+        changed[99_999] ^= 1;
+        fs::write(path("changed"), &changed).unwrap();
+        fs::write(path("shorter"), &content[..99_999]).unwrap();
+        assert!(same_content(&path("original"), &path("copy")).unwrap());
+        assert!(!same_content(&path("original"), &path("changed")).unwrap());
+        assert!(!same_content(&path("original"), &path("shorter")).unwrap());
+
+        let before = fs::metadata(path("copy")).unwrap();
+        // tmpfs and ext4 don't support reflinks, and then nothing should change
+        if replace_with_reflink(&path("original"), &path("copy"), &before).is_ok() {
+            let after = fs::metadata(path("copy")).unwrap();
+            assert_eq!(after.modified().unwrap(), before.modified().unwrap());
+        }
+        // changed after comparing
+        let before = fs::metadata(path("shorter")).unwrap();
+        fs::write(path("shorter"), &content).unwrap();
+        assert!(!replace_with_reflink(&path("original"), &path("shorter"), &before)
+                .unwrap_or(false));
+        assert_eq!(fs::read(path("shorter")).unwrap(), content);
+        assert!(!path(".shorter.decopy-reflink").exists());
+        assert_eq!(fs::read(path("copy")).unwrap(), content);
+        assert!(!path(".copy.decopy-reflink").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os="linux")]
+    fn keep_extended_attributes() {
+        let dir = env::temp_dir().join(format!("decopy-xattr-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::write(&a, "a").unwrap();
+        fs::write(&b, "b").unwrap();
+        let c_path = |path: &Path| {
+            let path = path.to_str().unwrap().to_string() + "\0";
+            path.into_bytes()
+        };
+        let (name, value) = (b"user.decopy\0", b"value");
+        // SAFETY: the strings are NUL-terminated and the value is as long as passed
+        let set = unsafe {
+            libc::setxattr(c_path(&a).as_ptr().cast(), name.as_ptr().cast(),
+                    value.as_ptr().cast(), value.len(), 0,
+            )
+        };
+        // not all file systems support user attributes
+        if set == 0 {
+            let (a_file, b_file) = (File::open(&a).unwrap(), File::open(&b).unwrap());
+            copy_owner_and_xattrs(&a_file, &a_file.metadata().unwrap(), &b_file).unwrap();
+            let mut copied = [0u8; 16];
+            // SAFETY: as above
+            let length = unsafe {
+                libc::getxattr(c_path(&b).as_ptr().cast(), name.as_ptr().cast(),
+                        copied.as_mut_ptr().cast(), copied.len(),
+                )
+            };
+            assert_eq!(&copied[..length.max(0) as usize], value);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
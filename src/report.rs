@@ -0,0 +1,544 @@
+/* Copyright 2023 Torbjørn Birch Moltu
+ *
+ * This file is part of Decopy.
+ * Decopy is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software Foundation,
+ * either version 3 of the License, or (at your option) any later version.
+ *
+ * Decopy is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+ * without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ * See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with Decopy.
+ * If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::extents::physical_extents;
+use crate::shared::*;
+
+use std::collections::HashMap;
+use std::fmt::Write as fmtWrite;
+use std::io::{self, Write};
+
+#[derive(Clone,Copy, Default, Debug, PartialEq,Eq)]
+pub enum NameFilter {
+    #[default]
+    Any,
+    /// Only group files that also have the same name.
+    Same,
+    /// Only show groups where not all files have the same name.
+    Different,
+}
+
+/// Which files are considered duplicates of each other.
+#[derive(Clone, Default, Debug)]
+pub struct GroupingOptions {
+    /// Group only by hash, not by (hash, read_size).
+    ///
+    /// Two files can only have the same hash but different sizes if one of them was truncated
+    /// while being read, so this is unsafe unless the database is known to be consistent.
+    pub ignore_size: bool,
+    pub names: NameFilter,
+    /// Only include files with this tag.
+    pub tag: Option<String>,
+    /// Only group files that have the same tag.
+    pub within_tag: bool,
+    /// Leave out groups where less than this many bytes could be freed,
+    /// and put the groups where most can be freed first.
+    ///
+    /// 0 includes all groups, ordered by hash.
+    pub min_waste: u64,
+    /// Files smaller than this don't count as wasted space, but are still listed.
+    pub waste_min_size: u64,
+}
+
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub struct DuplicateEntry {
+    pub path: PrintablePath,
+    /// The printable parent directory, with trailing separator.
+    pub dir: String,
+    pub modified: PrintableTime,
+    /// When the file was created, if known.
+    pub created: Option<PrintableTime>,
+    pub read_size: u64,
+    pub tag: Option<String>,
+    /// Whether the contents are stored in the same place as an earlier file in the group,
+    /// as set by `detect_shared_storage()`.
+    pub shares_storage: bool,
+}
+
+/// Files with identical content.
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub struct DuplicateGroup {
+    pub hash: Vec<u8>,
+    /// 0 unless the files were only sampled, and thus only probably identical.
+    pub sampled_blocks: u16,
+    /// How the content was changed before hashing, as `NORMALIZED_*` bits.
+    pub normalized: u8,
+    pub files: Vec<DuplicateEntry>,
+    /// Files smaller than this don't count as wasted space, from `GroupingOptions`.
+    pub waste_min_size: u64,
+}
+
+impl DuplicateGroup {
+    /// Size of the biggest file in the group.
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|file| file.read_size ).max().unwrap_or(0)
+    }
+
+    /// How many bytes could be freed by only keeping one (the biggest) file in the group.
+    ///
+    /// Files that share storage with another file or are smaller than `waste_min_size` don't count.
+    pub fn wasted(&self) -> u64 {
+        self.files.iter()
+            .filter(|file| !file.shares_storage && file.read_size >= self.waste_min_size )
+            .map(|file| file.read_size )
+            .sum::<u64>()
+            .saturating_sub(self.size())
+    }
+}
+
+/// How to order the files in each group of duplicates.
+///
+/// The first file is the one that is kept, such as by --reflink-duplicates.
+#[derive(Clone,Copy, Default, Debug, PartialEq,Eq, clap::ValueEnum)]
+pub enum GroupOrder {
+    /// By path.
+    #[default]
+    Path,
+    /// Oldest modification time first, as that is most likely the original.
+    Mtime,
+    /// Biggest first, which only differs from path when grouping ignores size.
+    Size,
+}
+
+/// Sort the files in each group, with ties ordered by path.
+pub fn sort_groups(groups: &mut [DuplicateGroup],  order: GroupOrder) {
+    for group in groups {
+        group.files.sort_by(|a, b| {
+            let by_path = || a.path.as_path().cmp(b.path.as_path());
+            match order {
+                GroupOrder::Path => by_path(),
+                GroupOrder::Mtime => a.modified.cmp(&b.modified).then_with(by_path),
+                GroupOrder::Size => b.read_size.cmp(&a.read_size).then_with(by_path),
+            }
+        });
+    }
+}
+
+/// Mark files that are stored in the same place on disk as an earlier file in their group,
+/// because they are hard links or reflinks (copy-on-write copies), and so don't waste space.
+///
+/// Files whose location cannot be found, such as on file systems or platforms that don't support
+/// it, are not marked.
+pub fn detect_shared_storage(groups: &mut [DuplicateGroup]) {
+    for group in groups {
+        let mut stored = Vec::with_capacity(group.files.len());
+        for file in &mut group.files {
+            let extents = match physical_extents(file.path.as_path()) {
+                Ok(extents) if !extents.is_empty() => extents,
+                _ => continue,
+            };
+            file.shares_storage = stored.contains(&extents);
+            if !file.shares_storage {
+                stored.push(extents);
+            }
+        }
+    }
+}
+
+/// Leave out groups where less than `min_waste` bytes could be freed,
+/// and put the groups where most can be freed first.
+///
+/// Does nothing if `min_waste` is 0.
+/// This needs to be redone after `detect_shared_storage()`, as that reduces what is wasted.
+pub fn retain_wasteful(groups: &mut Vec<DuplicateGroup>,  min_waste: u64) {
+    if min_waste != 0 {
+        groups.retain(|group| group.wasted() >= min_waste );
+        groups.sort_by_key(|group| std::cmp::Reverse(group.wasted()) );
+    }
+}
+
+/// How much two directories have in common.
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub struct DirectoryPair {
+    pub dirs: [String; 2],
+    /// Number of groups of identical files with files in both directories.
+    pub groups: u64,
+    /// Sum of the sizes of those groups.
+    pub bytes: u64,
+}
+
+/// Count how many groups of identical files each pair of directories have files from,
+/// sorted with the pairs sharing the most bytes first.
+///
+/// Each group is counted once for every pair of directories it has files in,
+/// even if one of the directories contains several of the files.
+pub fn directory_pairs(groups: &[DuplicateGroup]) -> Vec<DirectoryPair> {
+    let mut pairs = HashMap::<[&str; 2], (u64, u64)>::new();
+    let mut dirs = Vec::new();
+    for group in groups {
+        dirs.clear();
+        dirs.extend(group.files.iter().map(|file| file.dir.as_str() ));
+        dirs.sort_unstable();
+        dirs.dedup();
+        for (i, &a) in dirs.iter().enumerate() {
+            for &b in &dirs[i+1..] {
+                let (groups, bytes) = pairs.entry([a, b]).or_default();
+                *groups += 1;
+                *bytes += group.size();
+            }
+        }
+    }
+    let mut pairs = pairs.into_iter()
+            .map(|([a, b], (groups, bytes))| {
+                DirectoryPair { dirs: [a.to_string(), b.to_string()], groups, bytes }
+            })
+            .collect::<Vec<_>>();
+    pairs.sort_unstable_by(|a, b| {
+        b.bytes.cmp(&a.bytes)
+            .then(b.groups.cmp(&a.groups))
+            .then_with(|| a.dirs.cmp(&b.dirs) )
+    });
+    return pairs;
+}
+
+pub fn print_directory_pairs(pairs: &[DirectoryPair],  out: &mut dyn Write) -> io::Result<()> {
+    for pair in pairs {
+        writeln!(out, "{} and {} share {} groups of identical files ({:#})",
+                pair.dirs[0],
+                pair.dirs[1],
+                pair.groups,
+                Bytes(pair.bytes),
+        )?;
+    }
+    Ok(())
+}
+
+/// A file whose content has changed between scans.
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub struct Churn {
+    pub path: PrintablePath,
+    /// Number of different hashes the file has had.
+    pub hashes: u64,
+    /// Number of times it was hashed, which can be higher if it was changed back.
+    pub versions: u64,
+}
+
+pub fn print_churn(files: &[Churn],  out: &mut dyn Write) -> io::Result<()> {
+    for file in files {
+        writeln!(out, "{} different hashes in {} versions: {}",
+                file.hashes,
+                file.versions,
+                file.path,
+        )?;
+    }
+    Ok(())
+}
+
+/// Two files with different content that have identical regions, for --shared-chunks.
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub struct SharedChunks {
+    /// The printable path and size of a file with each content.
+    pub files: [(String, u64); 2],
+    /// The total size of the identical chunks.
+    pub shared: u64,
+}
+
+pub fn print_shared_chunks(pairs: &[SharedChunks],  out: &mut dyn Write) -> io::Result<()> {
+    for pair in pairs {
+        writeln!(out, "{:#} shared between", Bytes(pair.shared))?;
+        for (path, size) in &pair.files {
+            writeln!(out, "  {} ({:#})", path, Bytes(*size))?;
+        }
+    }
+    Ok(())
+}
+
+/// How the files under the roots differ from another database, for --diff-against.
+///
+/// The paths are sorted.
+#[derive(Clone, Default, Debug, PartialEq,Eq)]
+pub struct SnapshotDiff {
+    pub added: Vec<PrintablePath>,
+    pub removed: Vec<PrintablePath>,
+    /// Files whose hash has changed.
+    pub modified: Vec<PrintablePath>,
+    pub unchanged: usize,
+}
+
+/// Print how many files are in each category, and optionally which.
+pub fn print_diff(diff: &SnapshotDiff,  list: bool,  out: &mut dyn Write) -> io::Result<()> {
+    let categories = [
+        ("added", &diff.added),
+        ("removed", &diff.removed),
+        ("modified", &diff.modified),
+    ];
+    for (name, files) in categories {
+        writeln!(out, "{} files {}", files.len(), name)?;
+        if list {
+            for file in files {
+                writeln!(out, "  {}", file)?;
+            }
+        }
+    }
+    writeln!(out, "{} files unchanged", diff.unchanged)?;
+    Ok(())
+}
+
+/// The total size of the files in a directory and its subdirectories, for --du.
+#[derive(Clone, Default, Debug, PartialEq,Eq)]
+pub struct DirSize {
+    /// The printable directory, with trailing separator.
+    pub dir: String,
+    pub size: u64,
+    /// How much of the size is files that have a copy elsewhere.
+    pub duplicated: u64,
+    pub files: usize,
+}
+
+pub fn print_dir_sizes(dirs: &[DirSize],  out: &mut dyn Write) -> io::Result<()> {
+    for dir in dirs {
+        let size = format!("{:#}", Bytes(dir.size));
+        match dir.duplicated {
+            0 => writeln!(out, "{:>10}  {}", size, dir.dir)?,
+            duplicated => writeln!(out, "{:>10}  {} ({:#} duplicated)",
+                    size,
+                    dir.dir,
+                    Bytes(duplicated),
+            )?,
+        }
+    }
+    Ok(())
+}
+
+/// A file whose content is not in a reference database, for --only-new-vs.
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub struct NewFile {
+    pub path: PrintablePath,
+    pub size: u64,
+}
+
+/// Print the new files followed by a summary line.
+pub fn print_new_files(files: &[NewFile],  checked: usize,  out: &mut dyn Write)
+-> io::Result<()> {
+    for file in files {
+        writeln!(out, "{}", file.path)?;
+    }
+    writeln!(out, "{} of {} files with {:#} are not in the reference database",
+            files.len(),
+            checked,
+            Bytes(files.iter().map(|file| file.size ).sum()),
+    )
+}
+
+/// Directories that contain files with the same names, regardless of their content.
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub struct SameStructure {
+    /// Number of files in each directory.
+    pub files: usize,
+    /// The printable directories, with trailing separator.
+    pub dirs: Vec<String>,
+}
+
+pub fn print_same_structure(groups: &[SameStructure],  out: &mut dyn Write) -> io::Result<()> {
+    for group in groups {
+        writeln!(out, "{} directories with the same {} file names:",
+                group.dirs.len(),
+                group.files,
+        )?;
+        for dir in &group.dirs {
+            writeln!(out, "  {}", dir)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_hex(bytes: &[u8],  out: &mut String) {
+    for byte in bytes {
+        write!(out, "{:02x}", byte).unwrap();
+    }
+}
+
+/// Parse hexadecimal digits of either case.
+pub fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i+2)?, 16).ok() )
+        .collect()
+}
+
+/// Print each group of duplicates followed by a summary line.
+///
+/// `verbose` adds when files were created, where known.
+///
+/// If `survivor_marker` is given, the first file in each group is prefixed with it,
+/// and the others are indented to line up.
+pub fn print_duplicates(groups: &[DuplicateGroup],  verbose: bool,
+        survivor_marker: Option<&str>,  out: &mut dyn Write,
+) -> io::Result<()> {
+    let indent = match survivor_marker {
+        Some(marker) => " ".repeat(marker.chars().count() + 1),
+        None => "  ".to_string(),
+    };
+    let mut line = String::new();
+    let mut wasted = 0;
+    for group in groups {
+        line.clear();
+        write!(&mut line, "{} files of {:#} with hash ", group.files.len(), Bytes(group.size()))
+            .unwrap();
+        write_hex(&group.hash, &mut line);
+        if group.sampled_blocks != 0 {
+            write!(&mut line, " of {} sampled blocks (probably identical)", group.sampled_blocks)
+                .unwrap();
+        }
+        if group.normalized & NORMALIZED_LINE_ENDINGS != 0 {
+            line.push_str(" (ignoring line endings)");
+        }
+        if group.normalized & NORMALIZED_BOM != 0 {
+            line.push_str(" (ignoring byte order marks)");
+        }
+        writeln!(out, "{}:", line)?;
+        for (i, file) in group.files.iter().enumerate() {
+            line.clear();
+            match survivor_marker {
+                Some(marker) if i == 0 => write!(&mut line, "{} ", marker).unwrap(),
+                _ => line.push_str(&indent),
+            }
+            write!(&mut line, "{} {}", file.modified, file.path).unwrap();
+            if file.read_size != group.size() {
+                write!(&mut line, " ({:#})", Bytes(file.read_size)).unwrap();
+            }
+            if let (true, Some(created)) = (verbose, file.created) {
+                write!(&mut line, " (created {})", created).unwrap();
+            }
+            if let Some(ref tag) = file.tag {
+                write!(&mut line, " [{}]", tag).unwrap();
+            }
+            if file.shares_storage {
+                line.push_str(" (shares storage)");
+            }
+            writeln!(out, "{}", line)?;
+        }
+        wasted += group.wasted();
+    }
+    writeln!(out, "{} groups of duplicates, {:#} could be freed", groups.len(), Bytes(wasted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    fn group(size: u64,  paths: &[&str]) -> DuplicateGroup {
+        let files = paths.iter().map(|&path| {
+            let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+            DuplicateEntry {
+                path: PrintablePath::from(Path::new(path)),
+                dir: path[..path.len()-name.len()].to_string(),
+                created: None,
+                modified: PrintableTime::default(),
+                read_size: size,
+                tag: None,
+                shares_storage: false,
+            }
+        }).collect();
+        DuplicateGroup {
+            hash: vec![size as u8; 32],
+            sampled_blocks: 0,
+            normalized: 0,
+            files,
+            waste_min_size: 0,
+        }
+    }
+
+    #[test]
+    fn count_directory_pairs() {
+        let pairs = directory_pairs(&[
+            group(10, &["/a/1", "/b/1", "/c/1"]),
+            group(20, &["/a/2", "/b/2"]),
+            group(5, &["/a/3", "/a/4"]),
+            group(100, &["/c/5", "/d/5", "/d/6"]),
+        ]);
+        let summary = pairs.iter()
+                .map(|pair| {
+                    (pair.dirs[0].as_str(), pair.dirs[1].as_str(), pair.groups, pair.bytes)
+                })
+                .collect::<Vec<_>>();
+        assert_eq!(summary, [
+            ("/c/", "/d/", 1, 100),
+            ("/a/", "/b/", 2, 30),
+            ("/a/", "/c/", 1, 10),
+            ("/b/", "/c/", 1, 10),
+        ]);
+    }
+
+    #[test]
+    fn order_and_mark_survivor() {
+        let mut groups = [group(10, &["/c", "/a", "/b"])];
+        let time = |time: &str| time.parse::<PrintableTime>().unwrap();
+        groups[0].files[0].modified = time("2020-01-01 00:00:00");
+        groups[0].files[1].modified = time("2021-01-01 00:00:00");
+        groups[0].files[2].modified = time("2020-01-01 00:00:00");
+        sort_groups(&mut groups, GroupOrder::Mtime);
+        let paths = |groups: &[DuplicateGroup]| {
+            groups[0].files.iter().map(|file| file.path.to_string() ).collect::<Vec<_>>()
+        };
+        assert_eq!(paths(&groups), ["/b", "/c", "/a"]);
+        sort_groups(&mut groups, GroupOrder::Path);
+        assert_eq!(paths(&groups), ["/a", "/b", "/c"]);
+
+        let mut printed = Vec::new();
+        print_duplicates(&groups, false, Some("KEEP"), &mut printed).unwrap();
+        let printed = String::from_utf8(printed).unwrap();
+        let lines = printed.lines().skip(1).take(3).collect::<Vec<_>>();
+        assert_eq!(lines, [
+            "KEEP 2021-01-01 00:00:00 /a",
+            "     2020-01-01 00:00:00 /b",
+            "     2020-01-01 00:00:00 /c",
+        ]);
+    }
+
+    #[test]
+    fn shared_storage_is_not_wasted() {
+        let mut groups = vec![
+            group(10, &["/a/1", "/b/1"]),
+            group(20, &["/a/2", "/b/2"]),
+            group(30, &["/a/3", "/b/3", "/c/3"]),
+        ];
+        retain_wasteful(&mut groups, 20);
+        let sizes = groups.iter().map(DuplicateGroup::size ).collect::<Vec<_>>();
+        assert_eq!(sizes, [30, 20]);
+        groups[0].files[2].shares_storage = true;
+        groups[1].files[1].shares_storage = true;
+        retain_wasteful(&mut groups, 20);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].wasted(), 30);
+    }
+
+    #[cfg(target_os="linux")]
+    #[test]
+    fn hard_links_share_storage() {
+        let dir = std::env::temp_dir().join(format!("decopy-extents-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let content = vec![1u8; 20_000];
+        std::fs::write(path("a"), &content).unwrap();
+        std::fs::write(path("b"), &content).unwrap();
+        std::fs::hard_link(path("a"), path("c")).unwrap();
+        let mut groups = [group(20_000, &[&path("a"), &path("b"), &path("c")])];
+        detect_shared_storage(&mut groups);
+        let supported = crate::extents::physical_extents(Path::new(&path("a"))).is_ok();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let shared = groups[0].files.iter().map(|file| file.shares_storage ).collect::<Vec<_>>();
+        if supported {
+            assert_eq!(shared, [false, false, true]);
+            assert_eq!(groups[0].wasted(), 20_000);
+        } else {
+            assert_eq!(shared, [false, false, false]);
+        }
+    }
+}
@@ -17,44 +17,103 @@ pub use crate::available_buffers::AvailableBuffers;
 pub use crate::bytes::Bytes;
 pub use crate::path_decoding::PrintablePath;
 pub use crate::time::PrintableTime;
+use crate::chunking::Chunk;
+use crate::hash::HashAlgorithm;
+use crate::open_files::OpenForWriting;
+use crate::pseudo_fs::PseudoFilesystems;
+use crate::output::{EventLog, JsonLine};
+use crate::thread_info::{LogLevel, ThreadInfo};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, hash_map::Entry};
 use std::fmt::{self, Debug, Formatter};
 use std::io;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::{Arc, Condvar, Mutex, mpsc};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use fxhash::FxBuildHasher;
+use sha2::{Digest, Sha256};
 
 #[derive(Clone, Debug, PartialEq,Eq,Hash)]
 pub struct UnreadFile {
     pub path: Arc<PrintablePath>,
     pub modified: PrintableTime,
+    /// When the file was created, if the platform and file system record it.
+    pub created: Option<PrintableTime>,
     pub size: u64,
+    /// Device and inode number, if available.
+    pub inode: Option<(u64, u64)>,
 }
 
 #[derive(Clone, Debug)]
 pub enum ToRead {
     File(UnreadFile),
     Directory(Arc<PrintablePath>),
+    /// A file from the database that should be hashed again even if unchanged.
+    Recorded(Arc<PrintablePath>),
+}
+
+/// Which entries in the read queue a reader thread takes.
+#[derive(Clone,Copy, Debug, PartialEq,Eq)]
+pub enum ReadKind {
+    Any,
+    /// Only directories, when they are read by separate threads.
+    Directories,
+    /// Everything except directories.
+    Files,
 }
 
 #[derive(Default)]
 pub struct ReadQueue {
+    /// Files to read, and directories unless `separate_dirs` is set.
     pub queue: Vec<ToRead>,
+    /// Directories to read, when `separate_dirs` is set.
+    pub dirs: Vec<Arc<PrintablePath>>,
+    /// Whether directories and files are read by different threads. (--dir-threads)
+    pub separate_dirs: bool,
     pub stop_now: bool,
     pub working: u32,
 }
+impl ReadQueue {
+    pub fn push(&mut self,  to_read: ToRead) {
+        match to_read {
+            ToRead::Directory(dir) if self.separate_dirs => self.dirs.push(dir),
+            to_read => self.queue.push(to_read),
+        }
+    }
+
+    pub fn pop(&mut self,  kind: ReadKind) -> Option<ToRead> {
+        match kind {
+            ReadKind::Directories => self.dirs.pop().map(ToRead::Directory),
+            ReadKind::Files => self.queue.pop(),
+            ReadKind::Any => self.queue.pop().or_else(|| self.dirs.pop().map(ToRead::Directory) ),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty() && self.dirs.is_empty()
+    }
+}
 impl Debug for ReadQueue {
     fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
         fmtr.debug_struct("ReadQueue")
             .field("queue_length", &self.queue.len())
+            .field("dirs_length", &self.dirs.len())
+            .field("separate_dirs", &self.separate_dirs)
             .field("stop_now", &self.stop_now)
             .field("working", &self.working)
             .finish()
     }
 }
 
+/// What a reader sends to the hasher of a file.
+///
+/// The reader closes the channel when it reaches the end of the file,
+/// so a file is complete only if the channel is closed without an error being sent.
+/// A file that is empty, or that was truncated before the first read,
+/// is then stored with the hash of no bytes.
 #[derive(Debug)]
 pub enum FilePart {
     /// A vector that is always fully initialized.
@@ -64,9 +123,23 @@ pub enum FilePart {
 
 #[derive(Default)]
 pub struct HashQueue {
-    pub queue: Vec<(UnreadFile, mpsc::Receiver<FilePart>)>,
+    /// With the flag that is set when the file should be skipped.
+    pub queue: Vec<(UnreadFile, mpsc::Receiver<FilePart>, Arc<AtomicBool>)>,
     pub stop_now: bool,
     pub stop_when_empty: bool,
+    /// Park hasher threads until only this many are hashing, with --auto-scale.
+    pub max_hashers: Option<usize>,
+    /// Hasher threads that are started and not parked.
+    pub running_hashers: usize,
+}
+impl HashQueue {
+    /// Whether a hasher thread should park if this many would be running, with --auto-scale.
+    ///
+    /// Parked threads are unparked when the scan ends, so that they can quit.
+    pub fn too_many_hashers(&self,  running: usize) -> bool {
+        !self.stop_now && !self.stop_when_empty
+        && self.max_hashers.is_some_and(|max| running > max )
+    }
 }
 impl Debug for HashQueue {
     fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
@@ -74,15 +147,50 @@ impl Debug for HashQueue {
             .field("queue_length", &self.queue.len())
             .field("stop_now", &self.stop_now)
             .field("stop_when_empty", &self.stop_when_empty)
+            .field("max_hashers", &self.max_hashers)
+            .field("running_hashers", &self.running_hashers)
             .finish()
     }
 }
 
-#[derive(Clone,Copy)]
-struct Hex<const N: usize>([u8; N]);
-impl<const N: usize> Debug for Hex<N> {
+/// The hash of the content of a file, which is 32 or 64 bytes depending on the algorithm.
+///
+/// The bytes are stored inline, so that it can be copied and compared like an array.
+#[derive(Clone,Copy, PartialEq,Eq,Hash, PartialOrd,Ord)]
+pub struct FileHash {
+    length: u8,
+    bytes: [u8; 64],
+}
+impl FileHash {
+    /// Get a hash of a supported length.
+    pub fn new(hash: &[u8]) -> Option<Self> {
+        if hash.len() != 32 && hash.len() != 64 {
+            return None;
+        }
+        let mut bytes = [0; 64];
+        bytes[..hash.len()].copy_from_slice(hash);
+        return Some(FileHash { length: hash.len() as u8, bytes });
+    }
+}
+impl From<[u8; 32]> for FileHash {
+    fn from(hash: [u8; 32]) -> Self {
+        FileHash::new(&hash).unwrap()
+    }
+}
+impl From<[u8; 64]> for FileHash {
+    fn from(hash: [u8; 64]) -> Self {
+        FileHash::new(&hash).unwrap()
+    }
+}
+impl Deref for FileHash {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.length as usize]
+    }
+}
+impl Debug for FileHash {
     fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
-        for &byte in &self.0 {
+        for &byte in self.iter() {
             write!(fmtr, "{:02x}", byte)?;
         }
         Ok(())
@@ -93,76 +201,1205 @@ impl<const N: usize> Debug for Hex<N> {
 pub struct HashedFile {
     pub path: Arc<PrintablePath>,
     pub modified: PrintableTime,
+    /// When the file was created, if available.
+    pub created: Option<PrintableTime>,
     pub apparent_size: u64,
     pub read_size: u64,
-    pub hash: [u8; 32],
+    pub hash: FileHash,
+    /// 0 if the whole file was hashed, otherwise how many blocks were sampled.
+    pub sampled_blocks: u16,
+    /// Device and inode number, if available.
+    pub inode: Option<(u64, u64)>,
+    /// How the content was changed before hashing, as `NORMALIZED_*` bits, or 0 if not.
+    pub normalized: u8,
+    /// The content-defined chunks of the file with --cdc, otherwise empty.
+    pub chunks: Vec<Chunk>,
+    /// The part of the file that was hashed with --range, or None if it was the whole file.
+    pub range: Option<ByteRange>,
 }
 impl Debug for HashedFile {
     fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
         fmtr.debug_struct("HashedFile")
             .field("path", &self.path)
             .field("modified", &self.modified)
+            .field("created", &self.created)
             .field("apparent_size", &Bytes(self.apparent_size))
             .field("read_size", &Bytes(self.read_size))
-            .field("hash", &Hex(self.hash))
+            .field("hash", &self.hash)
+            .field("sampled_blocks", &self.sampled_blocks)
+            .field("inode", &self.inode)
+            .field("normalized", &self.normalized)
+            .field("chunks", &self.chunks.len())
+            .field("range", &self.range)
             .finish()
     }
 }
 
+/// Finds previously hashed files somewhere else than in memory, such as in the database.
+pub trait LookupPrevious: Send {
+    /// Get the modification time and size of a file when it was hashed,
+    /// and remember that it was found.
+    fn check(&mut self,  path: &PrintablePath) -> Option<(PrintableTime, u64)>;
+    /// Forget that a file was found, as it was deleted before it could be read.
+    fn uncheck(&mut self,  path: &PrintablePath);
+    /// Forget that any file was found, so that the next traversal decides what is pruned.
+    fn uncheck_all(&mut self);
+    /// Get the previously hashed files that have not been checked.
+    fn not_checked(&mut self) -> Vec<Arc<PrintablePath>>;
+}
+
 #[derive(Default)]
 pub struct PreviouslyRead {
     files: HashMap<Arc<PrintablePath>, (UnreadFile, AtomicBool), FxBuildHasher>,
+    /// Used instead of `files` if set, with --previous-lookup database.
+    lookup: Option<Mutex<Box<dyn LookupPrevious>>>,
 }
 impl PreviouslyRead {
     pub fn insert(&mut self,  file: UnreadFile) {
         self.files.insert(file.path.clone(), (file, AtomicBool::new(false)));
     }
+    /// Look up files in `lookup` instead of keeping them in memory.
+    ///
+    /// `paths()` and `files()` are then empty.
+    pub fn use_lookup(&mut self,  lookup: Box<dyn LookupPrevious>) {
+        self.lookup = Some(Mutex::new(lookup));
+    }
     pub fn check_unchanged(&self,  file: &UnreadFile) -> bool {
-        if let Some((ref info, ref still_exists)) = self.files.get(&file.path) {
+        // inode is not compared, as it is not stored for files hashed by older versions
+        self.check_previous(file) == Some((file.modified, file.size))
+    }
+    /// Get the modification time and size of the file when it was previously hashed,
+    /// and mark it as found.
+    pub fn check_previous(&self,  file: &UnreadFile) -> Option<(PrintableTime, u64)> {
+        if let Some(ref lookup) = self.lookup {
+            lookup.lock().unwrap().check(&file.path)
+        } else if let Some((ref info, ref still_exists)) = self.files.get(&file.path) {
             still_exists.store(true, Ordering::SeqCst);
-            info == file
+            Some((info.modified, info.size))
         } else {
-            false
+            None
         }
     }
-    pub fn get_not_found(&self) -> impl Iterator<Item=&Arc<PrintablePath>> {
+    /// Undo `check_unchanged()` for a file that was deleted before it could be read,
+    /// so that it is pruned.
+    pub fn vanished(&self,  path: &PrintablePath) {
+        if let Some(ref lookup) = self.lookup {
+            lookup.lock().unwrap().uncheck(path);
+        } else if let Some((_, ref still_exists)) = self.files.get(path) {
+            still_exists.store(false, Ordering::SeqCst);
+        }
+    }
+    /// Undo `check_previous()` for all files, after --prescan has traversed the roots,
+    /// so that files deleted before the scan finds them are pruned.
+    pub fn forget_found(&self) {
+        if let Some(ref lookup) = self.lookup {
+            lookup.lock().unwrap().uncheck_all();
+        } else {
+            for (_, still_exists) in self.files.values() {
+                still_exists.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+    pub fn paths(&self) -> impl Iterator<Item=&Arc<PrintablePath>> {
+        self.files.keys()
+    }
+    pub fn files(&self) -> impl Iterator<Item=&UnreadFile> {
+        self.files.values().map(|(ref file, _)| file )
+    }
+    pub fn get_not_found(&self) -> Vec<Arc<PrintablePath>> {
+        if let Some(ref lookup) = self.lookup {
+            return lookup.lock().unwrap().not_checked();
+        }
         self.files.values().filter_map(|(ref file, ref exists)| {
             match exists.load(Ordering::Acquire) {
                 true => None,
-                false => Some(&file.path),
+                false => Some(file.path.clone()),
             }
-        })
+        }).collect()
     }
 }
 impl Debug for PreviouslyRead {
     fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
         fmtr.debug_struct("PreviouslyRead")
             .field("files", &self.files.len())
+            .field("lookup", &self.lookup.is_some())
+            .finish()
+    }
+}
+
+/// A directory that has been read, for --fast-incremental.
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub struct ScannedDir {
+    pub path: Arc<PrintablePath>,
+    /// Modification time of the directory itself.
+    pub modified: PrintableTime,
+    /// From `dir_signature()`.
+    pub signature: [u8; 32],
+}
+
+/// Hash the paths of the files and subdirectories in a directory,
+/// and the size and modification time of the files.
+///
+/// Directories have no size and modification time.
+pub fn dir_signature<'a>(children: impl Iterator<Item=(&'a str, Option<(u64, PrintableTime)>)>)
+-> [u8; 32] {
+    let mut children = children.collect::<Vec<_>>();
+    children.sort_unstable_by_key(|&(path, _)| path );
+    let mut hasher = Sha256::new();
+    for (path, file) in children {
+        hasher.update(path.as_bytes());
+        match file {
+            Some((size, modified)) => {
+                hasher.update(size.to_le_bytes());
+                hasher.update(modified.to_string().as_bytes());
+            }
+            None => hasher.update(b"/"),
+        }
+        // paths cannot contain NUL
+        hasher.update([0]);
+    }
+    return hasher.finalize().into();
+}
+
+/// Directories from previous scans and what they contained, for --fast-incremental.
+#[derive(Default)]
+pub struct PreviousDirs {
+    dirs: HashMap<Arc<PrintablePath>, ScannedDir, FxBuildHasher>,
+    /// Files and subdirectories by parent directory.
+    children: HashMap<PathBuf, Vec<ToRead>, FxBuildHasher>,
+}
+impl PreviousDirs {
+    pub fn new(dirs: Vec<ScannedDir>,  previously_read: &PreviouslyRead) -> Self {
+        let mut children = HashMap::<PathBuf, Vec<ToRead>, FxBuildHasher>::default();
+        for file in previously_read.files() {
+            if let Some(parent) = file.path.as_path().parent() {
+                children.entry(parent.to_path_buf()).or_default().push(ToRead::File(file.clone()));
+            }
+        }
+        for dir in &dirs {
+            if let Some(parent) = dir.path.as_path().parent() {
+                let child = ToRead::Directory(dir.path.clone());
+                children.entry(parent.to_path_buf()).or_default().push(child);
+            }
+        }
+        let dirs = dirs.into_iter().map(|dir| (dir.path.clone(), dir) ).collect();
+        PreviousDirs { dirs, children }
+    }
+
+    /// Get what a directory contained when it was last read, if its modification time is the
+    /// same, and what is known about its contents hasn't changed since.
+    pub fn unchanged(&self,  dir: &PrintablePath,  modified: PrintableTime)
+    -> Option<(&ScannedDir, &[ToRead])> {
+        let previous = self.dirs.get(dir)?;
+        if previous.modified != modified {
+            return None;
+        }
+        let children = self.children.get(dir.as_path()).map_or(&[][..], Vec::as_slice);
+        let signature = dir_signature(children.iter().map(|child| match child {
+            ToRead::File(file) => (file.path.as_str(), Some((file.size, file.modified))),
+            ToRead::Directory(dir) | ToRead::Recorded(dir) => (dir.as_str(), None),
+        }));
+        match signature == previous.signature {
+            true => Some((previous, children)),
+            false => None,
+        }
+    }
+}
+impl Debug for PreviousDirs {
+    fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
+        fmtr.debug_struct("PreviousDirs")
+            .field("dirs", &self.dirs.len())
+            .finish()
+    }
+}
+
+/// A hash from a previous scan that can be reused for a file with the same inode.
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub struct CachedHash {
+    pub modified: PrintableTime,
+    pub apparent_size: u64,
+    pub read_size: u64,
+    pub hash: FileHash,
+    pub normalized: u8,
+}
+
+/// Hashes from previous scans by device and inode number,
+/// to not reread files that have been moved or renamed.
+#[derive(Default)]
+pub struct InodeCache {
+    files: HashMap<(u64, u64), CachedHash, FxBuildHasher>,
+}
+impl InodeCache {
+    pub fn insert(&mut self,  inode: (u64, u64),  cached: CachedHash) {
+        self.files.insert(inode, cached);
+    }
+    /// Get the previous hash if the file has the same inode, size and modification time.
+    pub fn get(&self,  file: &UnreadFile) -> Option<&CachedHash> {
+        let cached = self.files.get(&file.inode?)?;
+        match cached.modified == file.modified && cached.apparent_size == file.size {
+            true => Some(cached),
+            false => None,
+        }
+    }
+}
+impl Debug for InodeCache {
+    fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
+        fmtr.debug_struct("InodeCache")
+            .field("files", &self.files.len())
+            .finish()
+    }
+}
+
+/// What is known about a file found during this scan.
+#[derive(Clone, Debug)]
+enum SeenInode {
+    /// The first file found with the inode is being read,
+    /// and these other paths have the same inode.
+    Hashing(UnreadFile, Vec<UnreadFile>),
+    /// The file has been hashed, so other paths can reuse the hash.
+    Hashed(HashedFile),
+}
+
+/// What `SeenInodes::claim()` found.
+#[derive(Clone, Debug, PartialEq,Eq)]
+pub enum Claimed {
+    /// The file should be read and hashed.
+    First(UnreadFile),
+    /// The file is being read through another path, and will be sent when it has been hashed.
+    Waiting,
+    /// The file has already been hashed through another path.
+    Hashed(HashedFile),
+}
+
+/// Paths of files and directories queued in this scan, to not read any twice
+/// with --dedup-traversal.
+///
+/// This keeps every path in memory until the scan is done.
+#[derive(Default)]
+pub struct QueuedPaths {
+    paths: Mutex<HashSet<Arc<PrintablePath>, FxBuildHasher>>,
+}
+impl QueuedPaths {
+    /// Remember the path, and return whether it was new.
+    pub fn first_time(&self,  path: &Arc<PrintablePath>) -> bool {
+        self.paths.lock().unwrap().insert(path.clone())
+    }
+}
+impl Debug for QueuedPaths {
+    fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
+        fmtr.debug_struct("QueuedPaths")
+            .field("paths", &self.paths.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// Files that are only read once another file with the same size has been found,
+/// with --skip-unique-sizes.
+///
+/// A file can only have a duplicate if another file has the same size,
+/// so files that are alone with their size are never read.
+#[derive(Default)]
+pub struct SizeGroups {
+    /// The first file with each size, until another one is found and it is taken.
+    sizes: Mutex<HashMap<u64, Option<UnreadFile>, FxBuildHasher>>,
+}
+impl SizeGroups {
+    /// Add a file, and get the files that should be read now:
+    /// Nothing if it's the first with its size,
+    /// both it and the first one if it's the second, and only it after that.
+    pub fn add(&self,  file: UnreadFile) -> Vec<UnreadFile> {
+        let mut sizes = self.sizes.lock().unwrap();
+        match sizes.entry(file.size) {
+            Entry::Vacant(entry) => {
+                entry.insert(Some(file));
+                Vec::new()
+            }
+            Entry::Occupied(mut entry) => match entry.get_mut().take() {
+                Some(first) => vec![first, file],
+                None => vec![file],
+            },
+        }
+    }
+
+    /// Record a file with this size that will be stored without being added,
+    /// such as an unchanged file from the previous scan,
+    /// and get the first file with the same size if it's waiting to be read.
+    pub fn found(&self,  size: u64) -> Option<UnreadFile> {
+        self.sizes.lock().unwrap().insert(size, None).flatten()
+    }
+
+    /// Get the number of files and bytes not read because no other file has the same size.
+    pub fn unique(&self) -> (usize, u64) {
+        let sizes = self.sizes.lock().unwrap();
+        let unique = sizes.values().flatten();
+        return (unique.clone().count(), unique.map(|file| file.size ).sum());
+    }
+}
+impl Debug for SizeGroups {
+    fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
+        fmtr.debug_struct("SizeGroups")
+            .field("sizes", &self.sizes.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// Files found during this scan by device and inode number, so that files reached through
+/// multiple roots or hard links are only read once.
+///
+/// This is only used with several roots or --dedup-traversal,
+/// as it takes a lock for every file and keeps the hash of every file until the scan is done.
+///
+/// Files with the same inode but a different size or modification time, such as when the file
+/// is modified during the scan, are read again.
+#[derive(Default)]
+pub struct SeenInodes {
+    inodes: Mutex<HashMap<(u64, u64), SeenInode, FxBuildHasher>>,
+}
+impl SeenInodes {
+    /// Get the hashed file for another path to the same inode.
+    fn alias(hashed: &HashedFile,  file: UnreadFile) -> HashedFile {
+        HashedFile {
+            path: file.path,
+            modified: file.modified,
+            created: file.created,
+            apparent_size: file.size,
+            inode: file.inode,
+            // they are stored by the hash of the whole file, so once is enough
+            chunks: Vec::new(),
+            ..hashed.clone()
+        }
+    }
+
+    /// Check if a file has already been found through another path.
+    pub fn claim(&self,  file: UnreadFile) -> Claimed {
+        let Some(inode) = file.inode else {
+            return Claimed::First(file);
+        };
+        let mut inodes = self.inodes.lock().unwrap();
+        match inodes.get_mut(&inode) {
+            None => {
+                inodes.insert(inode, SeenInode::Hashing(file.clone(), Vec::new()));
+                Claimed::First(file)
+            }
+            Some(SeenInode::Hashing(first, waiting))
+            if first.size == file.size && first.modified == file.modified => {
+                waiting.push(file);
+                Claimed::Waiting
+            }
+            Some(SeenInode::Hashed(hashed))
+            if hashed.apparent_size == file.size && hashed.modified == file.modified => {
+                Claimed::Hashed(Self::alias(hashed, file))
+            }
+            Some(_) => Claimed::First(file),
+        }
+    }
+
+    /// Record the hash of a claimed file,
+    /// and get the other paths to it that were found while it was read.
+    pub fn hashed(&self,  file: &HashedFile) -> Vec<HashedFile> {
+        let Some(inode) = file.inode else {
+            return Vec::new();
+        };
+        let mut inodes = self.inodes.lock().unwrap();
+        let Some(seen) = inodes.get_mut(&inode) else {
+            return Vec::new();
+        };
+        let waiting = match seen {
+            SeenInode::Hashing(first, waiting) if first.path == file.path => {
+                std::mem::take(waiting)
+            }
+            _ => return Vec::new(),
+        };
+        // the chunks are not needed for the other paths
+        *seen = SeenInode::Hashed(HashedFile { chunks: Vec::new(),  ..file.clone() });
+        return waiting.into_iter().map(|other| Self::alias(file, other) ).collect();
+    }
+
+    /// Forget a claimed file that could not be read or hashed,
+    /// and get the other paths to it, which should be tried separately.
+    pub fn failed(&self,  file: &UnreadFile) -> Vec<UnreadFile> {
+        let Some(inode) = file.inode else {
+            return Vec::new();
+        };
+        let mut inodes = self.inodes.lock().unwrap();
+        match inodes.get(&inode) {
+            Some(SeenInode::Hashing(first, _)) if first.path == file.path => {}
+            _ => return Vec::new(),
+        }
+        match inodes.remove(&inode) {
+            Some(SeenInode::Hashing(_, waiting)) => waiting,
+            _ => unreachable!(),
+        }
+    }
+}
+impl Debug for SeenInodes {
+    fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
+        fmtr.debug_struct("SeenInodes")
+            .field("inodes", &self.inodes.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// Files that are being read and hashed, with a flag for skipping each of them,
+/// with --control-stdin.
+///
+/// Files are added when they are queued for hashing, and removed when the hasher is done.
+/// The flag is queued with the file, so the same path can be in progress more than once.
+#[derive(Default)]
+pub struct InProgress {
+    /// In the order they were started.
+    files: Mutex<Vec<InProgressFile>>,
+}
+/// The path, and whether it should be skipped.
+type InProgressFile = (Arc<PrintablePath>, Arc<AtomicBool>);
+impl InProgress {
+    /// Add a file and get the flag that is set when it should be skipped.
+    pub fn start(&self,  path: &Arc<PrintablePath>) -> Arc<AtomicBool> {
+        let skip = Arc::new(AtomicBool::new(false));
+        self.files.lock().unwrap().push((path.clone(), skip.clone()));
+        return skip;
+    }
+
+    /// Remove the file that got this flag from `start()`.
+    pub fn finish(&self,  skip: &Arc<AtomicBool>) {
+        let mut files = self.files.lock().unwrap();
+        if let Some(index) = files.iter().position(|(_, flag)| Arc::ptr_eq(flag, skip) ) {
+            files.remove(index);
+        }
+    }
+
+    /// Skip a file if it is in progress, and return whether it was.
+    pub fn skip(&self,  path: &PrintablePath) -> bool {
+        let files = self.files.lock().unwrap();
+        let mut skipped = false;
+        for (_, skip) in files.iter().filter(|(in_progress, _)| **in_progress == *path ) {
+            skipped |= !skip.swap(true, Ordering::Relaxed);
+        }
+        return skipped;
+    }
+
+    /// Skip the file that has been in progress the longest, and return its path.
+    pub fn skip_oldest(&self) -> Option<Arc<PrintablePath>> {
+        let files = self.files.lock().unwrap();
+        let (path, skip) = files.iter().find(|(_, skip)| !skip.load(Ordering::Relaxed) )?;
+        skip.store(true, Ordering::Relaxed);
+        return Some(path.clone());
+    }
+}
+impl Debug for InProgress {
+    fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
+        fmtr.debug_struct("InProgress")
+            .field("files", &self.files.lock().unwrap().len())
             .finish()
     }
 }
 
+/// Counts files where the number of bytes read differed from the size reported by stat,
+/// to detect if the file system reports stale sizes or if files are changing during the scan.
+#[derive(Default, Debug)]
+pub struct SizeMismatches {
+    hashed: AtomicU64,
+    mismatched: AtomicU64,
+    /// The file with the biggest difference, and its apparent and read size.
+    worst: Mutex<Option<(Arc<PrintablePath>, u64, u64)>>,
+}
+impl SizeMismatches {
+    /// Warn if at least this many files differ ...
+    const MIN_FILES: u64 = 5;
+    /// ... and they make up at least this percentage of the hashed files.
+    const MIN_PERCENT: u64 = 1;
+
+    pub fn record(&self,  file: &UnreadFile,  read_size: u64) {
+        self.hashed.fetch_add(1, Ordering::Relaxed);
+        if read_size == file.size {
+            return;
+        }
+        self.mismatched.fetch_add(1, Ordering::Relaxed);
+        let mut worst = self.worst.lock().unwrap();
+        let is_worse = match *worst {
+            Some((_, apparent, read)) => file.size.abs_diff(read_size) > apparent.abs_diff(read),
+            None => true,
+        };
+        if is_worse {
+            *worst = Some((file.path.clone(), file.size, read_size));
+        }
+    }
+
+    /// Describe the mismatches if there are enough of them to be suspicious.
+    pub fn summary(&self) -> Option<String> {
+        let hashed = self.hashed.load(Ordering::Relaxed);
+        let mismatched = self.mismatched.load(Ordering::Relaxed);
+        if mismatched < Self::MIN_FILES  ||  mismatched*100 < hashed*Self::MIN_PERCENT {
+            return None;
+        }
+        let worst = self.worst.lock().unwrap();
+        let (path, apparent, read) = worst.as_ref()?;
+        Some(format!(concat!(
+                "{} of {} hashed files had a different size when read than reported.\n",
+                "The file system might be reporting stale sizes, or files are changing during the scan.\n",
+                "Biggest difference: {} has apparent size {:#} but {:#} was read",
+            ),
+            mismatched,
+            hashed,
+            path,
+            Bytes(*apparent),
+            Bytes(*read),
+        ))
+    }
+}
+
+/// Number of files and bytes found by `--prescan`.
+#[derive(Default, Debug)]
+pub struct FileTotals {
+    files: AtomicU64,
+    bytes: AtomicU64,
+}
+impl FileTotals {
+    pub fn add(&self,  size: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+    }
+    pub fn files(&self) -> u64 {
+        self.files.load(Ordering::Relaxed)
+    }
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// Criteria for which files to hash.
+#[derive(Clone, Default, Debug)]
+pub struct Filters {
+    /// Only hash files modified before this time.
+    pub modified_before: Option<PrintableTime>,
+    /// Only hash files modified after this time.
+    pub modified_after: Option<PrintableTime>,
+    /// Skip files that sort before this path.
+    pub resume_from: Option<Arc<PrintablePath>>,
+    /// Only hash files owned by this user id.
+    pub owner: Option<u32>,
+    /// Only hash files owned by this group id.
+    pub group: Option<u32>,
+}
+impl Filters {
+    pub fn accepts(&self,  file: &UnreadFile) -> bool {
+        self.rejects(file).is_none()
+    }
+
+    /// Get which filter excludes the file, if any.
+    pub fn rejects(&self,  file: &UnreadFile) -> Option<&'static str> {
+        if let Some(ref from) = self.resume_from {
+            if file.path.as_path() < from.as_path() {
+                return Some("before --resume-from");
+            }
+        }
+        if let Some(before) = self.modified_before {
+            if file.modified >= before {
+                return Some("modified after --older-than");
+            }
+        }
+        if let Some(after) = self.modified_after {
+            if file.modified <= after {
+                return Some("modified before --newer-than");
+            }
+        }
+        None
+    }
+
+    /// Whether a file with these user and group ids should be hashed.
+    pub fn accepts_owner(&self,  uid: u32,  gid: u32) -> bool {
+        if let Some(owner) = self.owner {
+            if owner != uid {
+                return false;
+            }
+        }
+        if let Some(group) = self.group {
+            if group != gid {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Whether a directory could contain any accepted files.
+    pub fn accepts_dir(&self,  dir: &PrintablePath) -> bool {
+        match self.resume_from {
+            Some(ref from) => dir.as_path() >= from.as_path() || from.starts_with(dir.as_path()),
+            None => true,
+        }
+    }
+}
+impl fmt::Display for Filters {
+    fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
+        let mut separator = "";
+        if let Some(before) = self.modified_before {
+            write!(fmtr, "modified before {}", before)?;
+            separator = ", ";
+        }
+        if let Some(after) = self.modified_after {
+            write!(fmtr, "{}modified after {}", separator, after)?;
+            separator = ", ";
+        }
+        if let Some(ref from) = self.resume_from {
+            write!(fmtr, "{}resume from {}", separator, from)?;
+            separator = ", ";
+        }
+        if let Some(owner) = self.owner {
+            write!(fmtr, "{}owned by user {}", separator, owner)?;
+            separator = ", ";
+        }
+        if let Some(group) = self.group {
+            write!(fmtr, "{}owned by group {}", separator, group)?;
+            separator = ", ";
+        }
+        if separator.is_empty() {
+            fmtr.write_str("none")?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the id of the user with this name, or `None` if there is no such user.
+///
+/// This asks the C library, so that users from LDAP and other sources configured in
+/// /etc/nsswitch.conf are found too.
+#[cfg(target_os="linux")]
+pub fn user_id(name: &str) -> io::Result<Option<u32>> {
+    lookup_id(name, |name, buffer| unsafe {
+        let mut entry = std::mem::zeroed::<libc::passwd>();
+        let mut found = std::ptr::null_mut();
+        let error = libc::getpwnam_r(name, &mut entry,
+                buffer.as_mut_ptr().cast(), buffer.len(), &mut found,
+        );
+        (error, (!found.is_null()).then_some(entry.pw_uid))
+    })
+}
+
+/// Get the id of the group with this name, or `None` if there is no such group.
+#[cfg(target_os="linux")]
+pub fn group_id(name: &str) -> io::Result<Option<u32>> {
+    lookup_id(name, |name, buffer| unsafe {
+        let mut entry = std::mem::zeroed::<libc::group>();
+        let mut found = std::ptr::null_mut();
+        let error = libc::getgrnam_r(name, &mut entry,
+                buffer.as_mut_ptr().cast(), buffer.len(), &mut found,
+        );
+        (error, (!found.is_null()).then_some(entry.gr_gid))
+    })
+}
+
+/// Call `get` with a buffer for the strings of the entry until the buffer is big enough.
+#[cfg(target_os="linux")]
+fn lookup_id(
+        name: &str,
+        mut get: impl FnMut(*const libc::c_char, &mut [u8]) -> (libc::c_int, Option<u32>),
+) -> io::Result<Option<u32>> {
+    // names cannot contain NUL, so there is no such user or group
+    let Ok(name) = std::ffi::CString::new(name) else {
+        return Ok(None);
+    };
+    let mut buffer = vec![0u8; 1024];
+    loop {
+        match get(name.as_ptr(), &mut buffer) {
+            (0, id) => return Ok(id),
+            (libc::ERANGE, _) if buffer.len() < 1 << 20 => buffer.resize(buffer.len() * 2, 0),
+            (error, _) => return Err(io::Error::from_raw_os_error(error)),
+        }
+    }
+}
+
+/// Bit of `HashedFile.normalized` for CRLF and CR line endings having been replaced with LF.
+pub const NORMALIZED_LINE_ENDINGS: u8 = 1;
+/// Bit of `HashedFile.normalized` for files hashed without any byte order mark at the start.
+pub const NORMALIZED_BOM: u8 = 2;
+
+/// Size of the blocks read from files when only a sample of them is hashed.
+pub const SAMPLE_BLOCK_SIZE: u64 = 64*1024;
+
+/// Whether only a sample of `blocks` blocks of a file of `size` bytes will be hashed.
+///
+/// Files that are not bigger than the sample are always read completely.
+pub fn is_sampled(size: u64,  blocks: u16) -> bool {
+    blocks != 0 && size > SAMPLE_BLOCK_SIZE * blocks as u64
+}
+
+/// Get the sorted start offsets of the blocks to hash when sampling a file.
+///
+/// The start, middle and end of the file are always included,
+/// and any remaining blocks are placed pseudo-randomly with the size as seed,
+/// so that files with the same size are always sampled at the same offsets.
+pub fn sample_offsets(size: u64,  blocks: u16) -> Vec<u64> {
+    assert!(is_sampled(size, blocks), "file of {} bytes should not be sampled", size);
+    let last = size - SAMPLE_BLOCK_SIZE;
+    let mut offsets = vec![0, last, last/2];
+    offsets.truncate(blocks.into());
+    let mut state = size | 1; // xorshift must not start at zero
+    while offsets.len() < blocks.into() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let offset = state % (last+1);
+        if !offsets.contains(&offset) {
+            offsets.push(offset);
+        }
+    }
+    offsets.sort_unstable();
+    return offsets;
+}
+
+/// A part of files to hash instead of all of it, with --range.
+#[derive(Clone,Copy, Debug, PartialEq,Eq,Hash)]
+pub struct ByteRange {
+    pub start: u64,
+    /// Exclusive.
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn length(self) -> u64 {
+        self.end - self.start
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
+        write!(fmtr, "{}:{}", self.start, self.end)
+    }
+}
+
+/// Parses `START:END`, where both are a number of bytes with an optional unit.
+impl FromStr for ByteRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let parse = |part: &str| match part.bytes().all(|b| b.is_ascii_digit() ) {
+            true => part.parse::<u64>().map_err(|e| format!("{}: {}", part, e) ),
+            false => part.parse::<Bytes>()
+                .map(Bytes::as_u64)
+                .map_err(|e| format!("{}: {}", part, e) ),
+        };
+        let Some((start, end)) = s.split_once(':') else {
+            return Err("expected START:END".to_string());
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start >= end {
+            return Err(format!("the range {}:{} is empty", start, end));
+        }
+        return Ok(ByteRange { start, end });
+    }
+}
+
 #[derive(Debug)]
 pub struct Shared {
     pub previously_read: PreviouslyRead,
+    pub inode_cache: InodeCache,
+    /// Files found in this scan, to not read the same file through different paths,
+    /// with several roots or --dedup-traversal.
+    pub seen_inodes: Option<SeenInodes>,
+    /// Files being read or hashed, which can be skipped with --control-stdin.
+    pub in_progress: Option<Arc<InProgress>>,
     pub to_read: Mutex<ReadQueue>,
     pub reader_waker: Condvar,
     pub to_hash: Mutex<HashQueue>,
     pub hasher_waker: Condvar,
+    /// Wakes parked hasher threads, when --auto-scale raises `max_hashers` or the scan ends.
+    pub hasher_unparker: Condvar,
     pub buffers: AvailableBuffers,
     pub finished: Mutex<mpsc::Sender<HashedFile>>,
+    pub size_mismatches: SizeMismatches,
+    pub prescanned: FileTotals,
+    /// Files queued for reading.
+    pub discovered: FileTotals,
+    /// Files hashed or found in --inode-cache.
+    pub hashed: FileTotals,
+    /// Files that were deleted after being queued but before being read.
+    pub vanished: FileTotals,
+    /// Files that were bigger than the max buffer size, and so were read in several buffers.
+    pub split_reads: FileTotals,
+    pub filters: Filters,
+    /// Hash only this many blocks of bigger files, or 0 to always hash everything.
+    pub sample_blocks: u16,
+    /// Which hash function to hash files with, from --hash-algorithm.
+    pub hash_algorithm: HashAlgorithm,
+    /// Hash files of at least this size on several threads, with --parallel-file.
+    pub parallel_file: Option<u64>,
+    /// Hash text files as if all line endings were \n.
+    pub normalize_text: bool,
+    /// Hash text files without any byte order mark at the start, with --strip-bom.
+    pub strip_bom: bool,
+    /// Log why each file is or isn't hashed, with --explain.
+    pub explain: bool,
+    /// Also split files into content-defined chunks and hash those, with --cdc.
+    pub cdc: bool,
+    /// Hash only this part of every file, with --range.
+    pub range: Option<ByteRange>,
+    /// Read files without going through the page cache, or drop them from it afterwards.
+    pub direct_io: bool,
+    /// Tell the OS that files are read sequentially, and drop them from the page cache afterwards.
+    pub fadvise: bool,
+    /// Read files this many bytes at a time instead of based on their size, with --read-chunk.
+    pub read_chunk: Option<usize>,
+    /// Skip directories that are unchanged since the previous scan.
+    pub fast_incremental: bool,
+    /// Defer files that other processes have open for writing, with --skip-open-files.
+    pub open_for_writing: Option<OpenForWriting>,
+    /// Skip directories on these unless --allow-pseudofs.
+    pub pseudo_filesystems: Option<PseudoFilesystems>,
+    /// Skip paths that have already been queued, with --dedup-traversal.
+    pub queued_paths: Option<QueuedPaths>,
+    /// Don't read files until another file with the same size is found,
+    /// with --skip-unique-sizes.
+    pub size_groups: Option<SizeGroups>,
+    pub previous_dirs: PreviousDirs,
+    /// Directories read in this scan, to be stored for the next.
+    pub scanned_dirs: Mutex<Vec<ScannedDir>>,
+    /// --events-ndjson
+    pub events: Arc<EventLog>,
 }
 
 impl Shared {
     pub fn new(buffers: AvailableBuffers,  finished: mpsc::Sender<HashedFile>) -> Self {
         Shared {
             previously_read: PreviouslyRead::default(),
+            inode_cache: InodeCache::default(),
+            seen_inodes: None,
+            in_progress: None,
             to_read: Mutex::new(ReadQueue::default()),
             reader_waker: Condvar::new(),
             to_hash: Mutex::new(HashQueue::default()),
             hasher_waker: Condvar::new(),
+            hasher_unparker: Condvar::new(),
             buffers,
             finished: Mutex::new(finished),
+            size_mismatches: SizeMismatches::default(),
+            prescanned: FileTotals::default(),
+            discovered: FileTotals::default(),
+            hashed: FileTotals::default(),
+            vanished: FileTotals::default(),
+            split_reads: FileTotals::default(),
+            filters: Filters::default(),
+            sample_blocks: 0,
+            hash_algorithm: HashAlgorithm::default(),
+            parallel_file: None,
+            normalize_text: false,
+            strip_bom: false,
+            explain: false,
+            cdc: false,
+            range: None,
+            direct_io: false,
+            fadvise: false,
+            read_chunk: None,
+            fast_incremental: false,
+            open_for_writing: None,
+            pseudo_filesystems: Some(PseudoFilesystems::default()),
+            queued_paths: None,
+            size_groups: None,
+            previous_dirs: PreviousDirs::default(),
+            scanned_dirs: Mutex::new(Vec::new()),
+            events: Arc::new(EventLog::default()),
+        }
+    }
+
+    /// Get the flag that is set when a file should be skipped, which only --control-stdin does.
+    pub fn start_file(&self,  path: &Arc<PrintablePath>) -> Arc<AtomicBool> {
+        match &self.in_progress {
+            Some(in_progress) => in_progress.start(path),
+            None => Arc::default(),
+        }
+    }
+
+    /// Stop listing a file as in progress, after it has been hashed or failed.
+    pub fn finish_file(&self,  skip: &Arc<AtomicBool>) {
+        if let Some(in_progress) = &self.in_progress {
+            in_progress.finish(skip);
+        }
+    }
+
+    /// Forget a file that could not be read or hashed,
+    /// and get the other paths to the same inode that were waiting for it.
+    pub fn inode_failed(&self,  file: &UnreadFile) -> Vec<UnreadFile> {
+        match &self.seen_inodes {
+            Some(seen) => seen.failed(file),
+            None => Vec::new(),
+        }
+    }
+
+    /// Queue files that were waiting for another path to the same inode to be hashed,
+    /// after that failed.
+    pub fn read_separately(&self,  files: Vec<UnreadFile>) {
+        if files.is_empty() {
+            return;
+        }
+        let mut lock = self.to_read.lock().unwrap();
+        for file in files {
+            self.discovered.add(file.size);
+            lock.push(ToRead::File(file));
+        }
+        drop(lock);
+        self.reader_waker.notify_all();
+    }
+
+    /// Add a file or directory to the read queue and wake a thread that can read it.
+    pub fn queue_to_read(&self,  to_read: ToRead) {
+        let mut lock = self.to_read.lock().unwrap();
+        lock.push(to_read);
+        let separate_dirs = lock.separate_dirs;
+        drop(lock);
+        // with --dir-threads, the woken thread might not take this kind of entry
+        match separate_dirs {
+            true => self.reader_waker.notify_all(),
+            false => self.reader_waker.notify_one(),
+        }
+    }
+
+    /// Write an event created by `self.events.event()`, and log if that fails.
+    pub fn send_event(&self,  event: JsonLine,  thread_info: &ThreadInfo) {
+        if let Err(e) = self.events.write(event) {
+            let message = format!("Cannot write event, so stopping them: {}", e);
+            thread_info.log_message(LogLevel::Error, message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_offsets_are_deterministic_and_inside_file() {
+        let size = 100*SAMPLE_BLOCK_SIZE + 7;
+        let offsets = sample_offsets(size, 10);
+        assert_eq!(offsets, sample_offsets(size, 10));
+        assert_eq!(offsets.len(), 10);
+        assert_eq!(offsets[0], 0);
+        assert_eq!(offsets[9], size - SAMPLE_BLOCK_SIZE);
+        assert!(offsets.windows(2).all(|pair| pair[0] < pair[1] ));
+        assert_eq!(sample_offsets(size, 2), [0, size - SAMPLE_BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn vanished_files_are_not_found() {
+        let file = UnreadFile {
+            path: Arc::new(PrintablePath::from(std::path::Path::new("/gone"))),
+            modified: PrintableTime::default(),
+            created: None,
+            size: 1,
+            inode: None,
+        };
+        let mut read = PreviouslyRead::default();
+        read.insert(file.clone());
+        assert!(read.check_unchanged(&file));
+        assert_eq!(read.get_not_found(), []);
+        read.vanished(&file.path);
+        assert_eq!(read.get_not_found(), std::slice::from_ref(&file.path));
+        // found by --prescan but deleted before the scan
+        assert!(read.check_unchanged(&file));
+        read.forget_found();
+        assert_eq!(read.get_not_found(), [file.path]);
+    }
+
+    #[cfg(target_os="linux")]
+    #[test]
+    fn find_user_and_group_ids() {
+        assert_eq!(user_id("root").unwrap(), Some(0));
+        assert_eq!(group_id("root").unwrap(), Some(0));
+        assert_eq!(user_id("no such user").unwrap(), None);
+        assert_eq!(group_id("no such group").unwrap(), None);
+        assert_eq!(user_id("root\0").unwrap(), None);
+    }
+
+    #[test]
+    fn unchanged_dirs() {
+        let path = |p: &str| Arc::new(PrintablePath::from(std::path::Path::new(p)));
+        let file = |size| UnreadFile {
+            path: path("/d/file"),
+            modified: PrintableTime::default(),
+            created: None,
+            size,
+            inode: None,
+        };
+        let signature = dir_signature([
+            ("/d/file", Some((10, PrintableTime::default()))),
+            ("/d/sub", None),
+        ].into_iter());
+        let modified = PrintableTime::from_timestamp(1_000_000);
+        let dirs = |file_size| {
+            let mut read = PreviouslyRead::default();
+            read.insert(file(file_size));
+            let dirs = vec![
+                ScannedDir { path: path("/d"), modified, signature },
+                ScannedDir { path: path("/d/sub"), modified, signature: [0; 32] },
+            ];
+            PreviousDirs::new(dirs, &read)
+        };
+
+        let unchanged = dirs(10);
+        let (_, children) = unchanged.unchanged(&path("/d"), modified).unwrap();
+        assert_eq!(children.len(), 2);
+        assert!(unchanged.unchanged(&path("/d"), PrintableTime::default()).is_none());
+        assert!(unchanged.unchanged(&path("/e"), modified).is_none());
+        assert!(dirs(11).unchanged(&path("/d"), modified).is_none());
+    }
+
+    #[test]
+    fn same_inode_is_hashed_once() {
+        let file = |p: &str,  size| UnreadFile {
+            path: Arc::new(PrintablePath::from(std::path::Path::new(p))),
+            modified: PrintableTime::default(),
+            created: None,
+            size,
+            inode: Some((1, 2)),
+        };
+        let seen = SeenInodes::default();
+        assert_eq!(seen.claim(file("/a/x", 10)), Claimed::First(file("/a/x", 10)));
+        assert_eq!(seen.claim(file("/b/x", 10)), Claimed::Waiting);
+        // modified since
+        assert_eq!(seen.claim(file("/c/x", 11)), Claimed::First(file("/c/x", 11)));
+        let no_inode = UnreadFile { inode: None, ..file("/d/x", 10) };
+        assert_eq!(seen.claim(no_inode.clone()), Claimed::First(no_inode));
+
+        let hashed = HashedFile {
+            path: file("/a/x", 10).path,
+            modified: PrintableTime::default(),
+            created: None,
+            apparent_size: 10,
+            read_size: 10,
+            hash: [7; 32].into(),
+            sampled_blocks: 0,
+            inode: Some((1, 2)),
+            normalized: 0,
+            chunks: Vec::new(),
+            range: None,
+        };
+        let others = seen.hashed(&hashed);
+        assert_eq!(others.len(), 1);
+        assert_eq!(others[0].path, file("/b/x", 10).path);
+        assert_eq!(others[0].hash, hashed.hash);
+        match seen.claim(file("/e/x", 10)) {
+            Claimed::Hashed(other) => assert_eq!(other.path, file("/e/x", 10).path),
+            claimed => panic!("expected hashed, got {:?}", claimed),
         }
+        assert!(seen.hashed(&hashed).is_empty());
+
+        let other_inode = |p| UnreadFile { inode: Some((1, 3)), ..file(p, 5) };
+        assert_eq!(seen.claim(other_inode("/a/y")), Claimed::First(other_inode("/a/y")));
+        assert_eq!(seen.claim(other_inode("/b/y")), Claimed::Waiting);
+        assert_eq!(seen.failed(&other_inode("/b/y")), []);
+        assert_eq!(seen.failed(&other_inode("/a/y")), [other_inode("/b/y")]);
+        assert_eq!(seen.claim(other_inode("/c/y")), Claimed::First(other_inode("/c/y")));
+    }
+
+    #[test]
+    fn resume_from_skips_earlier_paths() {
+        let path = |path: &str| PrintablePath::from(std::path::Path::new(path));
+        let filters = Filters {
+            resume_from: Some(Arc::new(path("/b/c/d"))),
+            ..Default::default()
+        };
+        assert!(filters.accepts_dir(&path("/b")));
+        assert!(filters.accepts_dir(&path("/b/c")));
+        assert!(!filters.accepts_dir(&path("/b/a")));
+        assert!(filters.accepts_dir(&path("/b/c/e")));
+        assert!(filters.accepts_dir(&path("/c")));
+        let file = |p: &str| UnreadFile {
+            path: Arc::new(path(p)),
+            modified: PrintableTime::default(),
+            created: None,
+            size: 0,
+            inode: None,
+        };
+        assert!(!filters.accepts(&file("/b/c/a")));
+        assert!(filters.accepts(&file("/b/c/d")));
+        assert!(filters.accepts(&file("/b/d")));
+        assert_eq!(filters.rejects(&file("/a")), Some("before --resume-from"));
+        assert_eq!(filters.rejects(&file("/c")), None);
+    }
+
+    #[test]
+    fn small_files_are_not_sampled() {
+        assert!(!is_sampled(4*SAMPLE_BLOCK_SIZE, 4));
+        assert!(is_sampled(4*SAMPLE_BLOCK_SIZE + 1, 4));
+        assert!(!is_sampled(u64::MAX, 0));
+    }
+
+    #[test]
+    fn queued_paths_are_only_new_once() {
+        let queued = QueuedPaths::default();
+        let path = Arc::new(PrintablePath::from(PathBuf::from("/a/b")));
+        assert!(queued.first_time(&path));
+        assert!(!queued.first_time(&Arc::new(PrintablePath::from(PathBuf::from("/a/b")))));
+        assert!(queued.first_time(&Arc::new(PrintablePath::from(PathBuf::from("/a")))));
+    }
+
+    #[test]
+    fn only_read_files_with_the_same_size() {
+        let file = |p: &str,  size| UnreadFile {
+            path: Arc::new(PrintablePath::from(PathBuf::from(p))),
+            modified: PrintableTime::default(),
+            created: None,
+            size,
+            inode: None,
+        };
+        let sizes = SizeGroups::default();
+        assert_eq!(sizes.add(file("/a", 10)), []);
+        assert_eq!(sizes.add(file("/b", 20)), []);
+        assert_eq!(sizes.add(file("/c", 10)), [file("/a", 10), file("/c", 10)]);
+        assert_eq!(sizes.add(file("/d", 10)), [file("/d", 10)]);
+        assert_eq!(sizes.add(file("/e", 30)), []);
+        assert_eq!(sizes.unique(), (2, 50));
+        // unchanged since the previous scan
+        assert_eq!(sizes.found(30), Some(file("/e", 30)));
+        assert_eq!(sizes.found(40), None);
+        assert_eq!(sizes.add(file("/f", 40)), [file("/f", 40)]);
+        assert_eq!(sizes.unique(), (1, 20));
+    }
+
+    #[test]
+    fn skip_files_in_progress() {
+        let path = |p: &str| Arc::new(PrintablePath::from(std::path::Path::new(p)));
+        let in_progress = InProgress::default();
+        assert_eq!(in_progress.skip_oldest(), None);
+        let first = in_progress.start(&path("/a"));
+        let second = in_progress.start(&path("/b"));
+        assert_eq!(in_progress.skip_oldest(), Some(path("/a")));
+        assert!(first.load(Ordering::Relaxed));
+        assert!(!in_progress.skip(&path("/a")));
+        assert!(in_progress.skip(&path("/b")));
+        assert!(second.load(Ordering::Relaxed));
+        assert_eq!(in_progress.skip_oldest(), None);
+        in_progress.finish(&first);
+        assert!(!in_progress.skip(&path("/a")));
+        assert!(!in_progress.skip(&path("/c")));
+
+        // the same path queued twice
+        let again = in_progress.start(&path("/b"));
+        in_progress.finish(&second);
+        assert!(!again.load(Ordering::Relaxed));
+        assert!(in_progress.skip(&path("/b")));
+        assert!(again.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn separate_dirs_in_read_queue() {
+        let path = |p: &str| Arc::new(PrintablePath::from(std::path::Path::new(p)));
+        let is_dir = |to_read: Option<ToRead>| match to_read {
+            Some(ToRead::Directory(_)) => Some(true),
+            Some(_) => Some(false),
+            None => None,
+        };
+        let mut queue = ReadQueue { separate_dirs: true, ..Default::default() };
+        queue.push(ToRead::Directory(path("/a")));
+        queue.push(ToRead::Recorded(path("/a/b")));
+        assert_eq!(is_dir(queue.pop(ReadKind::Files)), Some(false));
+        assert_eq!(is_dir(queue.pop(ReadKind::Files)), None);
+        assert!(!queue.is_empty());
+        assert_eq!(is_dir(queue.pop(ReadKind::Directories)), Some(true));
+        assert!(queue.is_empty());
+
+        let mut queue = ReadQueue::default();
+        queue.push(ToRead::Directory(path("/a")));
+        queue.push(ToRead::Recorded(path("/a/b")));
+        assert_eq!(is_dir(queue.pop(ReadKind::Any)), Some(false));
+        assert_eq!(is_dir(queue.pop(ReadKind::Any)), Some(true));
+        assert!(queue.is_empty());
     }
 }
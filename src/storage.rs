@@ -12,80 +12,848 @@
  * You should have received a copy of the GNU General Public License along with Decopy.
  * If not, see <https://www.gnu.org/licenses/>.
  */
+use crate::disk_image::exit;
+use crate::hash::HashAlgorithm;
+use crate::output::{EventLog, JsonLine, ResultSink, TeeSink, is_stream, seconds};
 
+use crate::report::*;
 use crate::shared::*;
+use crate::thread_info::{LogLevel::*, LogMessage};
 
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
 use std::mem::ManuallyDrop;
-use std::path::Path;
+use std::path::{MAIN_SEPARATOR, Path};
 use std::sync::{Arc, mpsc};
+use std::thread;
 use std::time::{Duration, Instant};
 
-use rusqlite::{Connection, Statement};
+use fxhash::FxBuildHasher;
+use rusqlite::{Connection, ErrorCode, OpenFlags, OptionalExtension, Statement};
+use rusqlite::{Transaction, TransactionBehavior, types::Value};
+use sha2::{Digest, Sha256};
+
+/// How the hash is stored in the `hash` column.
+#[derive(Clone,Copy, Default, Debug, PartialEq,Eq, clap::ValueEnum)]
+pub enum HashStorage {
+    /// Raw bytes, which uses half the space.
+    #[default]
+    Blob,
+    /// Lowercase hexadecimal text, which is readable in the sqlite3 CLI.
+    Hex,
+}
+
+/// How much to do to make sure the database is on disk when it's closed.
+#[derive(Clone,Copy, Default, Debug, PartialEq,Eq, clap::ValueEnum)]
+pub enum Durability {
+    /// Rely on SQLite having written each transaction when it was committed.
+    #[default]
+    Fast,
+    /// Also move everything from a write-ahead log into the database file,
+    /// and flush the file and its directory to disk after closing it.
+    Safe,
+}
+
+/// Whether SQLite shrinks the database file when data is deleted.
+#[derive(Clone,Copy, Debug, PartialEq,Eq, clap::ValueEnum)]
+pub enum AutoVacuum {
+    /// Keep freed pages for reuse until the database is vacuumed.
+    None,
+    /// Move pages to truncate the file after every transaction.
+    Full,
+    /// Only truncate the file when `PRAGMA incremental_vacuum` is run.
+    Incremental,
+}
+
+impl AutoVacuum {
+    fn pragma_value(self) -> &'static str {
+        match self {
+            AutoVacuum::None => "NONE",
+            AutoVacuum::Full => "FULL",
+            AutoVacuum::Incremental => "INCREMENTAL",
+        }
+    }
+}
+
+/// Check that `size` is a page size SQLite supports.
+pub fn valid_page_size(size: u32) -> bool {
+    size.is_power_of_two()  &&  (512..=65536).contains(&size)
+}
+
+#[derive(Clone, Debug)]
+pub struct StorageOptions {
+    pub hash_storage: HashStorage,
+    /// Stored with every file hashed.
+    pub tag: Option<String>,
+    /// Must match the algorithm the database was created with.
+    pub hash_algorithm: HashAlgorithm,
+    /// The roots of the scan, to store which one each file was found under,
+    /// or empty to not store that.
+    pub record_roots: Vec<Arc<PrintablePath>>,
+    /// Where --image files are mounted, and the path of the image to store their files under,
+    /// as the mount points are temporary.
+    pub images: Vec<(Arc<PrintablePath>, Arc<PrintablePath>)>,
+    /// Ignored for in-memory databases.
+    pub durability: Durability,
+    /// Only applied when creating the database, and must be a valid page size.
+    pub page_size: Option<u32>,
+    /// Only applied when creating the database.
+    pub auto_vacuum: Option<AutoVacuum>,
+    /// How long SQLite waits for other connections to finish writing before returning busy.
+    pub busy_timeout: Duration,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        StorageOptions {
+            hash_storage: HashStorage::default(),
+            tag: None,
+            hash_algorithm: HashAlgorithm::default(),
+            record_roots: Vec::new(),
+            images: Vec::new(),
+            durability: Durability::default(),
+            page_size: None,
+            auto_vacuum: None,
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+        }
+    }
+}
+
+/// The default for `StorageOptions::busy_timeout`, which is also the default of --busy-timeout.
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Finds previously hashed files by querying the database for each file,
+/// with --previous-lookup database.
+///
+/// It has its own read-only connection, so that readers don't wait for the one that stores files,
+/// and remembers the checked files in a temporary table.
+///
+/// Errors are logged, and the file is then treated as not previously hashed.
+#[derive(Debug)]
+pub struct DatabaseLookup {
+    connection: Connection,
+    /// The range of paths under each root, from `Sqlite::path_range()`.
+    roots: Vec<(Vec<u8>, Vec<u8>, Arc<PrintablePath>)>,
+    /// Files sampled with a different number of blocks are ignored.
+    sample_blocks: u16,
+    /// Files that could not be remembered as checked, which must not be pruned.
+    failed: HashSet<Vec<u8>, FxBuildHasher>,
+    messages: mpsc::Sender<LogMessage>,
+}
+
+impl DatabaseLookup {
+    fn log_error(&self,  message: String) {
+        let _ = self.messages.send(LogMessage::new(Error, message));
+    }
+}
+
+impl LookupPrevious for DatabaseLookup {
+    fn check(&mut self,  path: &PrintablePath) -> Option<(PrintableTime, u64)> {
+        // remember files that are not in the database too, as the storer might add them later
+        let checked = retry_while_busy(&self.messages, || {
+            self.connection.prepare_cached("INSERT OR IGNORE INTO checked (path) VALUES (?1)")?
+                .execute((path.as_bytes(),))
+        });
+        if let Err(e) = checked {
+            self.log_error(format!("Cannot remember that {} was found: {}", path, e));
+            self.failed.insert(path.as_bytes().unwrap_or_default().to_vec());
+            return None;
+        }
+        let row = retry_while_busy(&self.messages, || {
+            self.connection.prepare_cached("
+                    SELECT modified, apparent_size FROM hashed
+                    WHERE path = ?1 AND sampled_blocks IN (0, ?2)"
+            )?.query_row((path.as_bytes(), self.sample_blocks), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?))
+            }).optional()
+        });
+        match row {
+            Ok(row) => {
+                let (modified, size) = row?;
+                return Some((modified.parse().expect("parse date-time"), size));
+            }
+            Err(e) => {
+                self.log_error(format!("Cannot look up {} in the database: {}", path, e));
+                return None;
+            }
+        }
+    }
+
+    fn uncheck(&mut self,  path: &PrintablePath) {
+        let result = retry_while_busy(&self.messages, || {
+            self.connection.prepare_cached("DELETE FROM checked WHERE path = ?1")?
+                .execute((path.as_bytes(),))
+        });
+        if let Err(e) = result {
+            self.log_error(format!("Cannot forget that {} was found: {}", path, e));
+        }
+    }
+
+    fn uncheck_all(&mut self) {
+        let result = retry_while_busy(&self.messages, || {
+            self.connection.execute("DELETE FROM checked", ())
+        });
+        if let Err(e) = result {
+            self.log_error(format!("Cannot forget the files found by the prescan: {}", e));
+        }
+    }
+
+    fn not_checked(&mut self) -> Vec<Arc<PrintablePath>> {
+        let result = retry_while_busy(&self.messages, || {
+            let mut stmt = self.connection.prepare("
+                    SELECT path FROM hashed
+                    WHERE (path = ?3 OR path BETWEEN ?1 AND ?2) AND sampled_blocks IN (0, ?4)
+                    AND path NOT IN (SELECT path FROM checked)"
+            )?;
+            let mut not_checked = Vec::new();
+            for (start, after, root) in &self.roots {
+                let params = (start, after, root.as_bytes(), self.sample_blocks);
+                let rows = stmt.query_map(params, |row| row.get::<_, Vec<u8>>(0) )?;
+                for path in rows {
+                    let path = path?;
+                    if !self.failed.contains(&path) {
+                        not_checked.push(Arc::new(PrintablePath::try_from(path).unwrap()));
+                    }
+                }
+            }
+            Ok(not_checked)
+        });
+        return result.unwrap_or_else(|e| {
+            self.log_error(format!("Cannot get the files that were not found, so not pruning: {}",
+                    e,
+            ));
+            Vec::new()
+        });
+    }
+}
+
+/// Statements to upgrade the schema of databases created by older versions,
+/// where the index is the `user_version` they upgrade from.
+///
+/// New databases are created directly from schema.sql,
+/// so the final result of these must match it.
+const MIGRATIONS: &[&str] = &[
+    // 0 -> 1: allow storing the hash as hex
+    "DROP INDEX IF EXISTS hashed_path;
+    DROP INDEX IF EXISTS hashed_dir;
+    DROP INDEX IF EXISTS hashed_name;
+    DROP INDEX IF EXISTS hashed_hash;
+    ALTER TABLE hashed RENAME TO old_hashed;
+    CREATE TABLE hashed (
+        path BLOB PRIMARY KEY NOT NULL,
+        printable_dir TEXT NOT NULL,
+        printable_name TEXT NOT NULL,
+        printable_path TEXT NOT NULL GENERATED ALWAYS
+            AS (printable_dir || printable_name) VIRTUAL,
+        modified TEXT NOT NULL CHECK(length(modified)=19),
+        apparent_size UNSIGNED INTEGER NOT NULL,
+        read_size UNSIGNED INTEGER NOT NULL,
+        hash BLOB NOT NULL CHECK(
+            typeof(hash)='blob' AND length(hash)=32
+            OR typeof(hash)='text' AND length(hash)=64
+        ),
+        hash_hex TEXT NOT NULL GENERATED ALWAYS
+            AS (CASE typeof(hash) WHEN 'text' THEN upper(hash) ELSE hex(hash) END) VIRTUAL
+    ) WITHOUT ROWID;
+    INSERT INTO hashed
+        (path, printable_dir, printable_name, modified, apparent_size, read_size, hash)
+        SELECT path, printable_dir, printable_name, modified, apparent_size, read_size, hash
+        FROM old_hashed;
+    DROP TABLE old_hashed;",
+    // 1 -> 2: --sample
+    "ALTER TABLE hashed ADD COLUMN sampled_blocks UNSIGNED INTEGER NOT NULL DEFAULT 0;",
+    // 2 -> 3: history for --churn, starting with the current version of every file
+    "CREATE TABLE history (
+        path BLOB NOT NULL,
+        modified TEXT NOT NULL,
+        hash_hex TEXT NOT NULL,
+        sampled_blocks UNSIGNED INTEGER NOT NULL,
+        PRIMARY KEY (path, modified, hash_hex, sampled_blocks)
+    ) WITHOUT ROWID;
+    INSERT INTO history (path, modified, hash_hex, sampled_blocks)
+        SELECT path, modified, hash_hex, sampled_blocks FROM hashed;",
+    // 3 -> 4: --inode-cache
+    "ALTER TABLE hashed ADD COLUMN device INTEGER;
+    ALTER TABLE hashed ADD COLUMN inode INTEGER;",
+    // 4 -> 5: --tag
+    "ALTER TABLE hashed ADD COLUMN tag TEXT;",
+    // 5 -> 6: the duplicates view and its index are created by schema.sql,
+    // but drop any old view with that name so that it gets the current definition.
+    "DROP VIEW IF EXISTS duplicates;",
+    // 6 -> 7: --fast-incremental
+    "CREATE TABLE dirs (
+        path BLOB PRIMARY KEY NOT NULL,
+        modified TEXT NOT NULL CHECK(length(modified)=19),
+        signature BLOB NOT NULL CHECK(length(signature)=32)
+    ) WITHOUT ROWID;",
+    // 7 -> 8: --normalize-text, which also changes the duplicates view
+    "ALTER TABLE hashed ADD COLUMN normalized UNSIGNED INTEGER NOT NULL DEFAULT 0;
+    DROP VIEW IF EXISTS duplicates;",
+    // 8 -> 9: record the hash algorithm, which was always SHA-256 before
+    "CREATE TABLE metadata (
+        key TEXT PRIMARY KEY NOT NULL,
+        value TEXT NOT NULL
+    ) WITHOUT ROWID;
+    INSERT INTO metadata (key, value) VALUES ('hash_algorithm', 'sha256'), ('hash_length', '32');",
+    // 9 -> 10: --record-root
+    "ALTER TABLE hashed ADD COLUMN root BLOB;",
+    // 10 -> 11: --cdc
+    "CREATE TABLE chunks (
+        file_hash TEXT NOT NULL,
+        offset UNSIGNED INTEGER NOT NULL,
+        length UNSIGNED INTEGER NOT NULL,
+        hash BLOB NOT NULL CHECK(length(hash)=32),
+        PRIMARY KEY (file_hash, offset)
+    ) WITHOUT ROWID;
+    CREATE INDEX chunks_hash ON chunks (hash);",
+    // 11 -> 12: creation time
+    "ALTER TABLE hashed ADD COLUMN created TEXT CHECK(length(created)=19);",
+    // 12 -> 13: allow 64-byte hashes for --hash-algorithm sha512.
+    // Renaming the table also moves the view and trigger on it,
+    // so drop those and let schema.sql create them again, with the indexes.
+    "DROP VIEW IF EXISTS duplicates;
+    DROP TRIGGER IF EXISTS hashed_history;
+    DROP INDEX IF EXISTS hashed_path;
+    DROP INDEX IF EXISTS hashed_dir;
+    DROP INDEX IF EXISTS hashed_name;
+    DROP INDEX IF EXISTS hashed_hash;
+    DROP INDEX IF EXISTS hashed_inode;
+    DROP INDEX IF EXISTS hashed_hash_hex;
+    ALTER TABLE hashed RENAME TO old_hashed;
+    CREATE TABLE hashed (
+        path BLOB PRIMARY KEY NOT NULL,
+        printable_dir TEXT NOT NULL,
+        printable_name TEXT NOT NULL,
+        printable_path TEXT NOT NULL GENERATED ALWAYS
+            AS (printable_dir || printable_name) VIRTUAL,
+        modified TEXT NOT NULL CHECK(length(modified)=19),
+        apparent_size UNSIGNED INTEGER NOT NULL,
+        read_size UNSIGNED INTEGER NOT NULL,
+        hash BLOB NOT NULL CHECK(
+            typeof(hash)='blob' AND length(hash) IN (32, 64)
+            OR typeof(hash)='text' AND length(hash) IN (64, 128)
+        ),
+        hash_hex TEXT NOT NULL GENERATED ALWAYS
+            AS (CASE typeof(hash) WHEN 'text' THEN upper(hash) ELSE hex(hash) END) VIRTUAL,
+        sampled_blocks UNSIGNED INTEGER NOT NULL DEFAULT 0,
+        device INTEGER,
+        inode INTEGER,
+        tag TEXT,
+        normalized UNSIGNED INTEGER NOT NULL DEFAULT 0,
+        root BLOB,
+        created TEXT CHECK(length(created)=19)
+    ) WITHOUT ROWID;
+    INSERT INTO hashed (path, printable_dir, printable_name, modified, apparent_size, read_size,
+            hash, sampled_blocks, device, inode, tag, normalized, root, created)
+        SELECT path, printable_dir, printable_name, modified, apparent_size, read_size,
+            hash, sampled_blocks, device, inode, tag, normalized, root, created
+        FROM old_hashed;
+    DROP TABLE old_hashed;",
+];
+
+/// How many more times to try something that failed because another connection is writing.
+///
+/// SQLite waits for the busy timeout before failing, so this is mostly for when it gives up
+/// right away to avoid a deadlock, but also lets a long write by another program finish.
+const BUSY_RETRIES: u32 = 10;
+
+/// Run `operation` again while it fails because another connection is writing,
+/// up to `BUSY_RETRIES` times, and log a warning once if it has to wait.
+fn retry_while_busy<T>(
+        messages: &mpsc::Sender<LogMessage>,
+        mut operation: impl FnMut() -> rusqlite::Result<T>,
+) -> rusqlite::Result<T> {
+    let mut retries = 0;
+    loop {
+        match operation() {
+            Err(rusqlite::Error::SqliteFailure(e, _))
+            if e.code == ErrorCode::DatabaseBusy  &&  retries < BUSY_RETRIES => {
+                if retries == 0 {
+                    let message = "the database is locked by another program, waiting for it";
+                    let _ = messages.send(LogMessage::new(Warning, message.to_string()));
+                }
+                retries += 1;
+                thread::sleep(Duration::from_millis(100));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Start a transaction that will write, and keep trying while another connection is writing.
+///
+/// Exits if the database stays locked, so that nothing is lost by a panic in the storage thread
+/// and the user is told why.
+fn write_transaction<'a>(connection: &'a Connection,  messages: &mpsc::Sender<LogMessage>)
+-> Transaction<'a> {
+    let started = retry_while_busy(messages, || {
+        Transaction::new_unchecked(connection, TransactionBehavior::Immediate)
+    });
+    match started {
+        Ok(transaction) => return transaction,
+        Err(e) => {
+            eprintln!("Cannot write to the database: {}", e);
+            eprintln!("Another program might be writing to it, try a longer --busy-timeout.");
+            exit(2);
+        }
+    }
+}
+
+/// Delete the chunks of content that no file in the database has anymore,
+/// after files have been deleted.
+fn delete_unused_chunks(transaction: &Transaction) {
+    transaction.execute(
+            "DELETE FROM chunks WHERE file_hash NOT IN (SELECT hash_hex FROM hashed)",
+            (),
+    ).expect("delete unused chunks");
+}
 
 #[derive(Debug)]
 pub struct Sqlite {
     connection: ManuallyDrop<Connection>,
     hashed_rx: mpsc::Receiver<HashedFile>,
-    messages: mpsc::Sender<String>,
+    messages: mpsc::Sender<LogMessage>,
+    options: StorageOptions,
+    events: Arc<EventLog>,
+    sink: TeeSink,
 }
 
 impl Drop for Sqlite {
+    /// Errors are printed instead of logged, as logs are no longer shown when this runs.
     fn drop(&mut self) {
         let connection = unsafe { ManuallyDrop::take(&mut self.connection) };
-        connection.close().expect("closing database");
+        let path = match self.options.durability {
+            Durability::Safe => connection.path().map(Path::to_path_buf),
+            Durability::Fast => None,
+        };
+        if path.is_some() {
+            // the number of log and checkpointed pages are irrelevant once it succeeds
+            match connection.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |row| {
+                row.get::<_, u32>(0)
+            }) {
+                Ok(0) => {}
+                Ok(_) => eprintln!("Warning: Cannot move everything from the write-ahead log \
+                                    into the database, as another program is using it"),
+                Err(e) => eprintln!("Error: Cannot checkpoint the database: {}", e),
+            }
+        }
+        if let Err((_, e)) = connection.close() {
+            eprintln!("Error: Cannot close the database: {}", e);
+        }
+        if let Some(path) = path {
+            if let Err(e) = fs::File::open(&path).and_then(|file| file.sync_all() ) {
+                eprintln!("Error: Cannot flush the database to disk: {}", e);
+            }
+            // make sure a newly created database file is in its directory
+            #[cfg(unix)]
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty() ) {
+                if let Err(e) = fs::File::open(dir).and_then(|dir| dir.sync_all() ) {
+                    eprintln!("Error: Cannot flush the directory of the database to disk: {}", e);
+                }
+            }
+        }
     }
 }
 
 impl Sqlite {
     /// Open the database read-write, or exit on failure.
+    ///
+    /// Also exits if the database was created with another hash algorithm.
     pub fn open(
             path: &Path,
+            options: StorageOptions,
             hashed_rx: mpsc::Receiver<HashedFile>,
-            messages: mpsc::Sender<String>,
+            messages: mpsc::Sender<LogMessage>,
     ) -> Self {
+        if is_stream(path) {
+            eprintln!("Cannot use {} as database, as SQLite needs a regular file, not a pipe.",
+                    PrintablePath::from(path),
+            );
+            eprintln!("Use --output with --format to stream the results instead.");
+            exit(2);
+        }
         let connection = Connection::open(path)
                 .expect("open database");
         let db = Self {
             connection: ManuallyDrop::new(connection),
             hashed_rx,
             messages,
+            options,
+            events: Arc::new(EventLog::default()),
+            sink: TeeSink::default(),
         };
-        db.prepare();
+        if let Err(e) = db.prepare() {
+            eprintln!("Cannot use {}: {}", PrintablePath::from(path), e);
+            exit(2);
+        }
+        if let Err(e) = db.check_hash_algorithm() {
+            eprintln!("Cannot use {}: {}", PrintablePath::from(path), e);
+            exit(2);
+        }
         return db;
     }
 
     /// Open the database read-write, or exit on failure.
-    pub fn new_in_memory(hashed_rx: mpsc::Receiver<HashedFile>,  messages: mpsc::Sender<String>)
-    -> Self {
+    pub fn new_in_memory(
+            options: StorageOptions,
+            hashed_rx: mpsc::Receiver<HashedFile>,
+            messages: mpsc::Sender<LogMessage>,
+    ) -> Self {
         let connection = Connection::open_in_memory()
                 .expect("create in-memory database");
         let db = Self {
             connection: ManuallyDrop::new(connection),
             hashed_rx,
             messages,
+            // there is nothing to write to disk
+            options: StorageOptions { durability: Durability::Fast, ..options },
+            events: Arc::new(EventLog::default()),
+            sink: TeeSink::default(),
         };
-        db.prepare();
+        db.prepare().expect("create in-memory database");
         return db;
     }
 
-    fn prepare(&self) {
-        let transaction = concat!("BEGIN\n;", include_str!("../schema.sql"), "COMMIT;\n");
-        self.connection.execute_batch(transaction).expect("create schema");
+    /// Set up the connection and create or upgrade the schema.
+    ///
+    /// Changes wait for other programs that are writing to the database, like all writes do.
+    fn prepare(&self) -> Result<(), String> {
+        self.connection.busy_timeout(self.options.busy_timeout)
+                .map_err(|e| format!("cannot set busy timeout: {}", e) )?;
+        let existing = self.connection.query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='hashed'",
+                (),
+                |row| row.get::<_, u32>(0),
+        ).map_err(|e| format!("cannot check for existing table: {}", e) )? != 0;
+        if existing {
+            let version = self.connection.pragma_query_value(None, "user_version", |row| {
+                row.get::<_, usize>(0)
+            }).map_err(|e| format!("cannot get schema version: {}", e) )?;
+            if version > MIGRATIONS.len() {
+                return Err(format!(
+                        "it has schema version {}, but this version only supports up to {}",
+                        version,
+                        MIGRATIONS.len(),
+                ));
+            }
+            if self.options.page_size.is_some() || self.options.auto_vacuum.is_some() {
+                let message = "not changing page size or auto_vacuum of an existing database";
+                let _ = self.messages.send(LogMessage::new(Warning, message.to_string()));
+            }
+            for (from, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+                let message = format!("upgrading database schema to version {}", from+1);
+                let _ = self.messages.send(LogMessage::new(Info, message));
+                let migration = format!("{}\nPRAGMA user_version = {};\n", migration, from+1);
+                self.write_batch(&migration).map_err(|e| {
+                    format!("cannot upgrade schema to version {}: {}", from+1, e)
+                })?;
+            }
+        } else {
+            // these must be set before any table is created
+            if let Some(page_size) = self.options.page_size {
+                self.connection.pragma_update(None, "page_size", page_size)
+                        .map_err(|e| format!("cannot set page size: {}", e) )?;
+            }
+            if let Some(auto_vacuum) = self.options.auto_vacuum {
+                self.connection.pragma_update(None, "auto_vacuum", auto_vacuum.pragma_value())
+                        .map_err(|e| format!("cannot set auto_vacuum: {}", e) )?;
+            }
+        }
+        // lets other programs read the database while it's being written to,
+        // and must be after the page size is set
+        retry_while_busy(&self.messages, || {
+            self.connection.pragma_update(None, "journal_mode", "WAL")
+        }).map_err(|e| format!("cannot enable WAL: {}", e) )?;
+        let schema = format!("{}\nPRAGMA user_version = {};\n",
+                include_str!("../schema.sql"),
+                MIGRATIONS.len(),
+        );
+        self.write_batch(&schema).map_err(|e| format!("cannot create schema: {}", e) )?;
+        let algorithm = self.options.hash_algorithm;
+        retry_while_busy(&self.messages, || self.connection.execute(
+                "INSERT OR IGNORE INTO metadata (key, value)
+                VALUES ('hash_algorithm', ?1), ('hash_length', ?2)",
+                (algorithm.name(), algorithm.length().to_string()),
+        )).map_err(|e| format!("cannot record hash algorithm: {}", e) )?;
+        return Ok(());
     }
 
-    pub fn get_previously_read(&mut self,
-            absolute_path: &PrintablePath,
-            preivously_read: &mut PreviouslyRead,
-    ) {
-        // LIKE and BLOB appear not to work for BLOB,
-        // and are probably vulnerable to injection anyway.
-        // Therefore BETWEEN must be used,
-        // which requires finding the next path after all sub-paths of the prefix.
-        let Some(start) = absolute_path.as_bytes() else {
-            let _ = self.messages.send("cache is ignored for non-UTF8 paths on Windows".to_string());
-            return;
+    /// Run SQL statements in one transaction, after waiting for other writers.
+    fn write_batch(&self,  sql: &str) -> rusqlite::Result<()> {
+        let transaction = retry_while_busy(&self.messages, || {
+            Transaction::new_unchecked(&self.connection, TransactionBehavior::Immediate)
+        })?;
+        transaction.execute_batch(sql)?;
+        return transaction.commit();
+    }
+
+    /// Check that the database was created with the hash algorithm in the options,
+    /// so that hashes from different algorithms are never stored together.
+    pub fn check_hash_algorithm(&self) -> Result<(), String> {
+        let get = |key: &str| self.connection.query_row(
+                "SELECT value FROM metadata WHERE key = ?1",
+                (key,),
+                |row| row.get::<_, String>(0),
+        ).expect("get metadata");
+        let (algorithm, length) = (get("hash_algorithm"), get("hash_length"));
+        let expected = self.options.hash_algorithm;
+        if algorithm != expected.name() || length != expected.length().to_string() {
+            return Err(format!("the database has {} hashes of {} bytes, not {}",
+                    algorithm,
+                    length,
+                    expected.name(),
+            ));
+        }
+        return Ok(());
+    }
+
+    /// Open another database read-only, and check that it has the current schema version
+    /// and the same hash algorithm as this one.
+    fn open_other(&self,  path: &Path) -> Result<Connection, String> {
+        let printable = PrintablePath::from(path);
+        let other = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .map_err(|e| format!("Cannot open {}: {}", printable, e) )?;
+        let version = other.pragma_query_value(None, "user_version", |row| {
+            row.get::<_, usize>(0)
+        }).map_err(|e| format!("Cannot get schema version of {}: {}", printable, e) )?;
+        if version != MIGRATIONS.len() {
+            return Err(format!("{} has schema version {} but must have {}; scan with it to upgrade",
+                    printable,
+                    version,
+                    MIGRATIONS.len(),
+            ));
+        }
+        let algorithm = self.options.hash_algorithm;
+        let other_algorithm = other.query_row(
+                "SELECT value FROM metadata WHERE key = 'hash_algorithm'",
+                (),
+                |row| row.get::<_, String>(0),
+        ).expect("get hash algorithm");
+        if other_algorithm != algorithm.name() {
+            return Err(format!("{} has {} hashes, not {}",
+                    printable,
+                    other_algorithm,
+                    algorithm.name(),
+            ));
+        }
+        return Ok(other);
+    }
+
+    /// Copy the files from another database into this one, to find duplicates across them.
+    ///
+    /// The other database is opened read-only, so it must have the current schema version,
+    /// and it cannot be merged if it has hashes from another algorithm.
+    /// Files with a path that is already in this database replace the existing file,
+    /// so files with the same path on different disks are only counted once.
+    /// Returns how many files were copied, and how many of them replaced a file.
+    pub fn merge_from(&mut self,  path: &Path) -> Result<(usize, usize), String> {
+        let printable = PrintablePath::from(path);
+        let other = self.open_other(path)?;
+        let hex_length = self.options.hash_algorithm.length() * 2;
+        let other_lengths = other.query_row(
+                "SELECT COUNT(*) FROM hashed WHERE length(hash_hex) != ?1",
+                (hex_length,),
+                |row| row.get::<_, u64>(0),
+        ).expect("count hashes of other lengths");
+        if other_lengths != 0 {
+            return Err(format!("{} has {} hashes that are not {}, so it cannot be merged",
+                    printable,
+                    other_lengths,
+                    self.options.hash_algorithm.name(),
+            ));
+        }
+
+        let mut select = other.prepare("
+                SELECT path, printable_dir, printable_name, modified, apparent_size, read_size,
+                    hash, sampled_blocks, device, inode, tag, normalized, root, created
+                FROM hashed"
+        ).expect("create SELECT statement");
+        let transaction = write_transaction(&self.connection, &self.messages);
+        let count = |transaction: &Transaction| transaction.query_row(
+                "SELECT COUNT(*) FROM hashed", (), |row| row.get::<_, usize>(0),
+        ).expect("count files");
+        let before = count(&transaction);
+        let mut insert = transaction.prepare("
+                INSERT OR REPLACE INTO hashed
+                (path, printable_dir, printable_name, modified, apparent_size, read_size,
+                    hash, sampled_blocks, device, inode, tag, normalized, root, created)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)"
+        ).expect("create INSERT statement");
+        let mut rows = select.query(()).expect("get files");
+        let mut copied = 0;
+        while let Some(row) = rows.next().expect("get next file") {
+            let values = (0..14)
+                    .map(|column| row.get::<_, Value>(column) )
+                    .collect::<Result<Vec<Value>, _>>()
+                    .expect("get collumns");
+            insert.execute(rusqlite::params_from_iter(values)).expect("insert file");
+            copied += 1;
+        }
+        insert.finalize().expect("finalize insert statement");
+        let replaced = before + copied - count(&transaction);
+        transaction.commit().expect("commit merged files");
+        return Ok((copied, replaced));
+    }
+
+    /// Compare the files under `roots` with the files under them in another database,
+    /// such as a copy of this one from before the scan.
+    ///
+    /// Files are modified if the hash or how it was computed differs.
+    pub fn diff_against(&self,  path: &Path,  roots: &[Arc<PrintablePath>])
+    -> Result<SnapshotDiff, String> {
+        let other = self.open_other(path)?;
+        let files_under_roots = |connection: &Connection| {
+            let mut files = BTreeMap::<Vec<u8>, (String, u16, u8)>::new();
+            let mut stmt = connection.prepare("
+                    SELECT path, hash_hex, sampled_blocks, normalized FROM hashed
+                    WHERE path = ?3 OR path BETWEEN ?1 AND ?2"
+            ).expect("create SELECT statement");
+            for root in roots {
+                let Some((start, after)) = self.path_range(root) else {
+                    continue;
+                };
+                let rows = stmt.query_map((&start, &after, root.as_bytes()), |row| {
+                    Ok((row.get(0)?, (row.get(1)?, row.get(2)?, row.get(3)?)))
+                }).expect("get files under root");
+                for row in rows {
+                    let (path, file) = row.expect("get file");
+                    files.insert(path, file);
+                }
+            }
+            return files;
         };
-        let mut after = Vec::from(start);
+        let mut before = files_under_roots(&other);
+        let now = files_under_roots(&self.connection);
+
+        let mut diff = SnapshotDiff::default();
+        let printable = |path: Vec<u8>| PrintablePath::try_from(path).unwrap();
+        for (path, file) in now {
+            match before.remove(&path) {
+                Some(previous) if previous == file => diff.unchanged += 1,
+                Some(_) => diff.modified.push(printable(path)),
+                None => diff.added.push(printable(path)),
+            }
+        }
+        diff.removed = before.into_keys().map(printable).collect();
+        return Ok(diff);
+    }
+
+    /// Find the files under `roots` whose content is not in another database,
+    /// by looking for a file there with the same hash and size.
+    ///
+    /// Returns the new files sorted by path, and how many files were checked.
+    pub fn find_new_files(&self,  reference: &Path,  roots: &[Arc<PrintablePath>])
+    -> Result<(Vec<NewFile>, usize), String> {
+        let other = self.open_other(reference)?;
+        let mut stmt = other.prepare("SELECT DISTINCT hash_hex, read_size FROM hashed")
+                .expect("create SELECT statement");
+        let existing = stmt.query_map((), |row| Ok((row.get(0)?, row.get(1)?)) )
+                .expect("get hashes")
+                .map(|row| row.expect("get hash") )
+                .collect::<HashSet<(String, u64), FxBuildHasher>>();
+
+        let mut stmt = self.connection.prepare("
+                SELECT path, hash_hex, read_size FROM hashed
+                WHERE path = ?3 OR path BETWEEN ?1 AND ?2"
+        ).expect("create SELECT statement");
+        let mut new = Vec::new();
+        let mut checked = 0;
+        for root in roots {
+            let Some((start, after)) = self.path_range(root) else {
+                continue;
+            };
+            let rows = stmt.query_map((&start, &after, root.as_bytes()), |row| {
+                Ok((row.get::<_, Vec<u8>>(0)?, row.get(1)?, row.get(2)?))
+            }).expect("get files under root");
+            for row in rows {
+                let (path, hash_hex, size) = row.expect("get file");
+                checked += 1;
+                if !existing.contains(&(hash_hex, size)) {
+                    new.push(NewFile { path: PrintablePath::try_from(path).unwrap(), size });
+                }
+            }
+        }
+        new.sort_by(|a, b| a.path.as_str().cmp(b.path.as_str()) );
+        return Ok((new, checked));
+    }
+
+    /// Sum the apparent size of the files in each directory under `roots` and its subdirectories,
+    /// and how much of that is files with a copy anywhere in the database.
+    ///
+    /// Only directories at most `max_depth` levels below a root are included,
+    /// and they are sorted by size, biggest first.
+    pub fn directory_sizes(&self,  roots: &[Arc<PrintablePath>],  max_depth: Option<usize>)
+    -> Vec<DirSize> {
+        let mut stmt = self.connection.prepare("
+                SELECT printable_dir, apparent_size, (
+                    SELECT COUNT(*) > 1 FROM hashed AS other
+                    WHERE other.hash_hex = file.hash_hex
+                    AND other.read_size = file.read_size
+                    AND other.sampled_blocks = file.sampled_blocks
+                    AND other.normalized = file.normalized
+                )
+                FROM hashed AS file
+                WHERE path = ?3 OR path BETWEEN ?1 AND ?2"
+        ).expect("create SELECT statement");
+        let mut dirs = HashMap::<String, DirSize>::new();
+        for root in roots {
+            let Some((start, after)) = self.path_range(root) else {
+                continue;
+            };
+            let mut root_dir = root.as_str().to_string();
+            if !root_dir.ends_with(MAIN_SEPARATOR) {
+                root_dir.push(MAIN_SEPARATOR);
+            }
+            let rows = stmt.query_map((&start, &after, root.as_bytes()), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?, row.get::<_, bool>(2)?))
+            }).expect("get files under root");
+            for row in rows {
+                let (dir, size, duplicated) = row.expect("get file");
+                let Some(relative) = dir.strip_prefix(&root_dir) else {
+                    continue; // the root is a file
+                };
+                // the root and every directory between it and the file
+                let ends = relative.match_indices(MAIN_SEPARATOR).map(|(i, _)| i+1 );
+                let levels = max_depth.map_or(usize::MAX, |depth| depth+1 );
+                let ancestors = std::iter::once(0).chain(ends).take(levels);
+                for end in ancestors {
+                    let path = &dir[..root_dir.len()+end];
+                    let dir = dirs.entry(path.to_string()).or_insert_with(|| DirSize {
+                        dir: path.to_string(),
+                        ..Default::default()
+                    });
+                    dir.size += size;
+                    dir.files += 1;
+                    if duplicated {
+                        dir.duplicated += size;
+                    }
+                }
+            }
+        }
+        let mut dirs = dirs.into_values().collect::<Vec<_>>();
+        dirs.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.dir.cmp(&b.dir) ) );
+        return dirs;
+    }
+
+    /// Get the range of paths that are inside a directory, but not the directory itself.
+    ///
+    /// LIKE and BLOB appear not to work for BLOB,
+    /// and are probably vulnerable to injection anyway.
+    /// Therefore BETWEEN must be used,
+    /// which requires finding the next path after all sub-paths of the prefix.
+    /// The prefix ends with a path separator so that /a/bc is not inside /a/b.
+    fn path_range(&self,  absolute_path: &PrintablePath) -> Option<(Vec<u8>, Vec<u8>)> {
+        let Some(path) = absolute_path.as_bytes() else {
+            let message = "cache is ignored for non-UTF8 paths on Windows".to_string();
+            let _ = self.messages.send(LogMessage::new(Warning, message));
+            return None;
+        };
+        let mut start = Vec::from(path);
+        if !start.ends_with(&[MAIN_SEPARATOR as u8]) {
+            start.push(MAIN_SEPARATOR as u8);
+        }
+        let mut after = start.clone();
         for i in (0..after.len()).rev() {
             if after[i] == 255 {
                 after.pop();
@@ -94,12 +862,114 @@ impl Sqlite {
                 break;
             }
         }
+        return Some((start, after));
+    }
+
+    /// Get the directories read by previous scans with --fast-incremental, under a root.
+    pub fn get_previous_dirs(&self,  root: &PrintablePath,  dirs: &mut Vec<ScannedDir>) {
+        let Some((start, after)) = self.path_range(root) else {
+            return;
+        };
+        let mut stmt = self.connection.prepare("
+                SELECT path, modified, signature FROM dirs
+                WHERE path = ?3 OR path BETWEEN ?1 AND ?2"
+        ).expect("create SELECT statement");
+        let rows = stmt.query_map((start, after, root.as_bytes()), |row| {
+            let path: Vec<u8> = row.get(0).expect("get path collumn");
+            let modified = row.get::<_, String>(1)
+                    .expect("get modified collumn")
+                    .parse::<PrintableTime>()
+                    .expect("parse date-time");
+            let signature: Vec<u8> = row.get(2).expect("get signature collumn");
+            Ok(ScannedDir {
+                    path: Arc::new(PrintablePath::try_from(path).unwrap()),
+                    modified,
+                    signature: signature.try_into().expect("signature is 32 bytes"),
+            })
+        }).expect("get previously read directories under root");
+        dirs.extend(rows.map(|row| row.expect("get mapped row") ));
+    }
+
+    /// Replace the directories stored under the roots with those read by this scan.
+    pub fn store_dirs(&mut self,  roots: &[Arc<PrintablePath>],  dirs: &[ScannedDir]) {
+        let ranges = roots.iter()
+                .filter_map(|root| Some((self.path_range(root)?, root.as_bytes())) )
+                .collect::<Vec<_>>();
+        let transaction = write_transaction(&self.connection, &self.messages);
+        for ((start, after), root) in ranges {
+            transaction.execute(
+                    "DELETE FROM dirs WHERE path = ?3 OR path BETWEEN ?1 AND ?2",
+                    (start, after, root),
+            ).expect("delete directories under root");
+        }
+        let mut statement = transaction.prepare("INSERT OR REPLACE INTO dirs
+                (path, modified, signature) VALUES (?1, ?2, ?3)"
+        ).expect("create INSERT OR REPLACE statement");
+        for dir in dirs {
+            if let Some(path) = dir.path.as_bytes() {
+                let modified = dir.modified.to_string();
+                statement.execute((path, modified, &dir.signature[..])).expect("insert directory");
+            }
+        }
+        statement.finalize().expect("finalize insert statement");
+        transaction.commit().expect("commit directories");
+    }
+
+    /// Remove a file or directory and everything in it from the database, for --forget.
+    ///
+    /// Returns how many files were removed.
+    pub fn forget(&mut self,  prefix: &PrintablePath) -> usize {
+        return self.delete_under(prefix, true);
+    }
+
+    /// Remove the files stored from a previous scan of an --image, as it is scanned again,
+    /// but not the image file itself.
+    ///
+    /// Returns how many files were removed.
+    pub fn forget_image_contents(&mut self,  image: &PrintablePath) -> usize {
+        return self.delete_under(image, false);
+    }
+
+    fn delete_under(&mut self,  prefix: &PrintablePath,  including_prefix: bool) -> usize {
+        let Some((start, after)) = self.path_range(prefix) else {
+            return 0;
+        };
+        let params = (&start, &after, prefix.as_bytes(), including_prefix);
+        let transaction = write_transaction(&self.connection, &self.messages);
+        let removed = transaction.execute(
+                "DELETE FROM hashed WHERE (path = ?3 AND ?4) OR path BETWEEN ?1 AND ?2",
+                params,
+        ).expect("delete files");
+        transaction.execute(
+                "DELETE FROM dirs WHERE (path = ?3 AND ?4) OR path BETWEEN ?1 AND ?2",
+                params,
+        ).expect("delete directories");
+        transaction.execute(
+                "DELETE FROM history WHERE (path = ?3 AND ?4) OR path BETWEEN ?1 AND ?2",
+                params,
+        ).expect("delete history");
+        delete_unused_chunks(&transaction);
+        transaction.commit().expect("commit deletes");
+        return removed;
+    }
+
+    /// Files that were only sampled are ignored unless `sample_blocks` matches,
+    /// so that they get fully hashed when not sampling.
+    pub fn get_previously_read(&mut self,
+            absolute_path: &PrintablePath,
+            sample_blocks: u16,
+            preivously_read: &mut PreviouslyRead,
+    ) {
+        let Some((start, after)) = self.path_range(absolute_path) else {
+            return;
+        };
 
         let mut stmt = self.connection.prepare("
                 SELECT path, modified, apparent_size
-                FROM hashed WHERE path BETWEEN ?1 AND ?2"
+                FROM hashed WHERE (path = ?4 OR path BETWEEN ?1 AND ?2)
+                AND sampled_blocks IN (0, ?3)"
         ).expect("create SELECT statement");
-        let files = stmt.query_map((start, after), |row | {
+        let files = stmt.query_map((start, after, sample_blocks, absolute_path.as_bytes()), |row | {
             let path: Vec<u8> = row.get(0).expect("get path collumn");
             let path = Arc::new(PrintablePath::try_from(path).unwrap());
             let modified = row.get::<_, String>(1)
@@ -109,7 +979,9 @@ impl Sqlite {
             Ok(UnreadFile {
                     path,
                     modified,
+                    created: None,
                     size: row.get(2).expect("get size collumn"),
+                    inode: None,
             })
         }).expect("get previously hashed files under root");
         for file in files {
@@ -118,11 +990,111 @@ impl Sqlite {
         }
     }
 
+    /// Look up previously hashed files under the roots in the database file as they are found,
+    /// instead of loading them into memory with `get_previously_read()`.
+    ///
+    /// Fails if the database is in memory.
+    pub fn lookup_previous(&self,  roots: &[Arc<PrintablePath>],  sample_blocks: u16)
+    -> Result<DatabaseLookup, String> {
+        let Some(path) = self.connection.path().filter(|path| *path != Path::new(":memory:") )
+        else {
+            return Err("the database is in memory".to_string());
+        };
+        let connection = self.open_other(path)?;
+        // in a temporary file, so that memory use doesn't grow with the number of files
+        connection.execute_batch("
+                PRAGMA temp_store = FILE;
+                CREATE TEMP TABLE checked (path BLOB PRIMARY KEY NOT NULL) WITHOUT ROWID;"
+        ).map_err(|e| format!("Cannot create temporary table: {}", e) )?;
+        connection.busy_timeout(self.options.busy_timeout)
+            .map_err(|e| format!("Cannot set busy timeout: {}", e) )?;
+        let roots = roots.iter().filter_map(|root| {
+            let (start, after) = self.path_range(root)?;
+            Some((start, after, root.clone()))
+        }).collect();
+        return Ok(DatabaseLookup {
+            connection,
+            roots,
+            sample_blocks,
+            failed: HashSet::default(),
+            messages: self.messages.clone(),
+        });
+    }
+
+    /// Get all fully hashed files with a known inode, under any root.
+    pub fn get_inode_cache(&mut self,  cache: &mut InodeCache) {
+        let mut stmt = self.connection.prepare("
+                SELECT device, inode, modified, apparent_size, read_size, hash_hex, normalized
+                FROM hashed WHERE inode IS NOT NULL AND sampled_blocks = 0"
+        ).expect("create SELECT statement");
+        let files = stmt.query_map((), |row| {
+            let device: i64 = row.get(0).expect("get device collumn");
+            let inode: i64 = row.get(1).expect("get inode collumn");
+            let modified = row.get::<_, String>(2)
+                    .expect("get modified collumn")
+                    .parse::<PrintableTime>()
+                    .expect("parse date-time");
+            let hash_hex: String = row.get(5).expect("get hash_hex collumn");
+            let hash = parse_hex(&hash_hex).expect("parse hash_hex");
+            Ok(((device as u64, inode as u64), CachedHash {
+                    modified,
+                    apparent_size: row.get(3).expect("get apparent_size collumn"),
+                    read_size: row.get(4).expect("get read_size collumn"),
+                    hash: FileHash::new(&hash).expect("hash has a supported length"),
+                    normalized: row.get(6).expect("get normalized collumn"),
+            }))
+        }).expect("get files with inode");
+        for file in files {
+            let (inode, cached) = file.expect("get mapped row");
+            cache.insert(inode, cached);
+        }
+    }
+
+    /// Write an event for every commit of hashed files.
+    pub fn set_events(&mut self,  events: Arc<EventLog>) {
+        self.events = events;
+    }
+
+    /// Also write hashed files to these sinks as they are stored.
+    pub fn set_sink(&mut self,  sink: TeeSink) {
+        self.sink = sink;
+    }
+
     pub fn save_hashed(&mut self,  insert_interval: Duration) {
-        fn insert_hashed(statement: &mut Statement,  insert: HashedFile) {
+        fn insert_hashed(statement: &mut Statement,  chunk_statement: &mut Statement,
+                mut insert: HashedFile,  options: &StorageOptions,
+                sink: &mut TeeSink,  messages: &mpsc::Sender<LogMessage>,
+        ) {
+            let path = insert.path.clone();
+            let image = options.images.iter()
+                    .find(|(mount_point, _)| path.as_path().starts_with(mount_point.as_path()) );
+            // the mount point is temporary
+            if let Some((mount_point, image)) = image {
+                let in_image = path.as_path().strip_prefix(mount_point.as_path()).unwrap();
+                insert.path = Arc::new(PrintablePath::from(image.as_path().join(in_image)));
+                // the loop device can be used by another image next time
+                insert.inode = None;
+            }
+            if !sink.is_empty() {
+                if let Err(e) = sink.add(&insert) {
+                    let message = format!("Cannot write hashed file to output: {}", e);
+                    let _ = messages.send(LogMessage::new(Error, message));
+                }
+            }
             let printable_path = insert.path.as_str();
             let name = Path::new(printable_path).file_name().unwrap_or_default().to_str().unwrap();
             let dir = &printable_path[..printable_path.len()-name.len()]; // with trailing slash
+            // the roots don't overlap, so there is at most one
+            let root = options.record_roots.iter()
+                    .find(|root| insert.path.as_path().starts_with(root.as_path()) );
+            let hash = match options.hash_storage {
+                HashStorage::Blob => Value::Blob(insert.hash.to_vec()),
+                HashStorage::Hex => {
+                    let mut hex = String::with_capacity(insert.hash.len()*2);
+                    write_hex(&insert.hash, &mut hex);
+                    Value::Text(hex)
+                }
+            };
             statement.insert(params!(
                     insert.path.as_bytes(),
                     dir,
@@ -130,35 +1102,84 @@ impl Sqlite {
                     insert.modified.to_string(),
                     insert.apparent_size,
                     insert.read_size,
-                    insert.hash,
+                    hash,
+                    insert.sampled_blocks,
+                    insert.inode.map(|(device, _)| device as i64 ),
+                    insert.inode.map(|(_, inode)| inode as i64 ),
+                    &options.tag,
+                    insert.normalized,
+                    root.and_then(|root| root.as_bytes() ),
+                    insert.created.map(|created| created.to_string() ),
             )).expect("insert hash");
+            if !insert.chunks.is_empty() {
+                let mut file_hash = String::with_capacity(insert.hash.len()*2);
+                write_hex(&insert.hash, &mut file_hash);
+                file_hash.make_ascii_uppercase();
+                for chunk in &insert.chunks {
+                    chunk_statement.execute(params!(
+                            &file_hash,
+                            chunk.offset,
+                            chunk.length,
+                            &chunk.hash[..],
+                    )).expect("insert chunk");
+                }
+            }
         }
+        let options = self.options.clone();
         while let Ok(file) = self.hashed_rx.recv() {
             let oldest = Instant::now();
             let mut files = 1u32;
-            let transaction = self.connection.transaction().expect("start transaction");
+            let transaction = write_transaction(&self.connection, &self.messages);
             let mut statement = transaction.prepare("INSERT OR REPLACE INTO HASHED
-                    (path, printable_dir, printable_name, modified, apparent_size, read_size, hash)
-                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+                    (path, printable_dir, printable_name, modified, apparent_size, read_size, hash,
+                        sampled_blocks, device, inode, tag, normalized, root, created)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)"
             ).expect("create INSERT OR REPLACE statement");
-            insert_hashed(&mut statement, file);
+            let mut chunk_statement = transaction.prepare("INSERT OR IGNORE INTO chunks
+                    (file_hash, offset, length, hash) VALUES (?1, ?2, ?3, ?4)"
+            ).expect("create INSERT statement for chunks");
+            let mut insert = |file| insert_hashed(
+                    &mut statement,
+                    &mut chunk_statement,
+                    file,
+                    &options,
+                    &mut self.sink,
+                    &self.messages,
+            );
+            insert(file);
             let mut timeout = insert_interval;
             while let Ok(file) = self.hashed_rx.recv_timeout(timeout) {
                 files += 1;
-                insert_hashed(&mut statement, file);
+                insert(file);
                 timeout = match insert_interval.checked_sub(Instant::elapsed(&oldest)) {
                     Some(next) => next,
                     None => break,
                 };
             }
-            let _ = self.messages.send(format!("committing {} hashed files", files));
+            let message = format!("committing {} hashed files", files);
+            let _ = self.messages.send(LogMessage::new(Info, message));
             statement.finalize().expect("finalize insert statement");
+            chunk_statement.finalize().expect("finalize chunk insert statement");
+            let committing = Instant::now();
             transaction.commit().expect("commit inserts");
+            if self.events.is_enabled() {
+                let event = self.events.event("commit")
+                        .number("files", files)
+                        .number("duration", seconds(committing.elapsed()));
+                if let Err(e) = self.events.write(event) {
+                    let message = format!("Cannot write event, so stopping them: {}", e);
+                    let _ = self.messages.send(LogMessage::new(Error, message));
+                }
+            }
+        }
+        if let Err(e) = self.sink.finish() {
+            let message = format!("Cannot write hashed file to output: {}", e);
+            let _ = self.messages.send(LogMessage::new(Error, message));
         }
     }
 
     pub fn store_roots(&mut self,  roots: &[Arc<PrintablePath>]) {
-        let transaction = self.connection.transaction().expect("start transaction");
+        let transaction = write_transaction(&self.connection, &self.messages);
         let mut statement = transaction.prepare("INSERT OR REPLACE INTO ROOTS
                 (path, printable_path) VALUES (?1, ?2)"
         ).expect("create INSERT OR REPLACE statement");
@@ -167,18 +1188,1185 @@ impl Sqlite {
         }).sum::<usize>();
         statement.finalize().expect("finalize insert statement");
         transaction.commit().expect("commit inserts");
-        let _ = self.messages.send(format!("inserted {} roots", inserted));
+        let message = format!("inserted {} roots", inserted);
+        let _ = self.messages.send(LogMessage::new(Info, message));
+    }
+
+    /// Get all files with the same content as at least one other file,
+    /// ordered by hash and then path.
+    pub fn find_duplicate_groups(&self,  options: &GroupingOptions) -> Vec<DuplicateGroup> {
+        // hash_hex is used so that hashes stored as blob and hex are grouped together
+        let mut group_by = String::from("hash_hex, sampled_blocks, normalized");
+        if !options.ignore_size {
+            group_by.push_str(", read_size");
+        }
+        if options.names == NameFilter::Same {
+            group_by.push_str(", printable_name");
+        }
+        if options.within_tag {
+            // NULL is never IN anything
+            group_by.push_str(", ifnull(tag, '')");
+        }
+        // ?1 is the tag to filter by, if any
+        let filter = match options.tag {
+            Some(_) => "tag = ?1",
+            None => "TRUE",
+        };
+        let mut having = String::from("COUNT(*) > 1");
+        if options.names == NameFilter::Different {
+            having.push_str(" AND COUNT(DISTINCT printable_name) > 1");
+        }
+        let query = format!("SELECT hash_hex, read_size, path, modified, printable_name,
+                    sampled_blocks, printable_dir, tag, normalized, created
+                FROM hashed
+                WHERE {2} AND ({0}) IN (SELECT {0} FROM hashed WHERE {2} GROUP BY {0} HAVING {1})
+                ORDER BY {0}, path",
+                group_by,
+                having,
+                filter,
+        );
+        let mut stmt = self.connection.prepare(&query).expect("create SELECT statement");
+        let rows = stmt.query_map(rusqlite::params_from_iter(&options.tag), |row| {
+            let hash_hex: String = row.get(0).expect("get hash_hex collumn");
+            let hash = parse_hex(&hash_hex).expect("parse hash_hex");
+            let read_size: u64 = row.get(1).expect("get read_size collumn");
+            let path: Vec<u8> = row.get(2).expect("get path collumn");
+            let modified = row.get::<_, String>(3)
+                    .expect("get modified collumn")
+                    .parse::<PrintableTime>()
+                    .expect("parse date-time");
+            let name: String = row.get(4).expect("get printable_name collumn");
+            let sampled_blocks: u16 = row.get(5).expect("get sampled_blocks collumn");
+            let dir: String = row.get(6).expect("get printable_dir collumn");
+            let tag: Option<String> = row.get(7).expect("get tag collumn");
+            let normalized: u8 = row.get(8).expect("get normalized collumn");
+            let created = row.get::<_, Option<String>>(9)
+                    .expect("get created collumn")
+                    .map(|created| created.parse::<PrintableTime>().expect("parse date-time") );
+            let path = PrintablePath::try_from(path).unwrap();
+            Ok((hash, name, sampled_blocks, normalized, DuplicateEntry {
+                    path, dir, modified, created, read_size, tag, shares_storage: false,
+            }))
+        }).expect("get duplicate files");
+
+        let mut groups = Vec::<DuplicateGroup>::new();
+        let mut group_name = String::new();
+        for row in rows {
+            let (hash, name, sampled_blocks, normalized, file) = row.expect("get mapped row");
+            match groups.last_mut() {
+                Some(group) if group.hash == hash
+                && group.sampled_blocks == sampled_blocks
+                && group.normalized == normalized
+                && (options.ignore_size || group.files[0].read_size == file.read_size)
+                && (options.names != NameFilter::Same || group_name == name)
+                && (!options.within_tag || group.files[0].tag == file.tag) => {
+                    group.files.push(file);
+                }
+                _ => {
+                    let files = vec![file];
+                    groups.push(DuplicateGroup {
+                        hash, sampled_blocks, normalized, files,
+                        waste_min_size: options.waste_min_size,
+                    });
+                    group_name = name;
+                },
+            }
+        }
+        retain_wasteful(&mut groups, options.min_waste);
+        return groups;
+    }
+
+    /// Write every file in the database as a JSON array of objects,
+    /// one row at a time so that big databases don't have to fit in memory.
+    ///
+    /// Paths that are not UTF-8 or contain control characters are written in their printable
+    /// form, with `path_is_lossy` set to true.
+    /// Returns how many files were written.
+    pub fn export_json(&self,  out: &mut dyn Write) -> io::Result<u64> {
+        let mut stmt = self.connection.prepare("
+                SELECT path, modified, apparent_size, read_size, hash_hex, sampled_blocks
+                FROM hashed
+                ORDER BY path"
+        ).expect("create SELECT statement");
+        let mut rows = stmt.query(()).expect("get files");
+        out.write_all(b"[")?;
+        let mut files = 0;
+        while let Some(row) = rows.next().expect("get next file") {
+            let path: Vec<u8> = row.get(0).expect("get path collumn");
+            let path = PrintablePath::try_from(path).unwrap();
+            let modified: String = row.get(1).expect("get modified collumn");
+            let apparent_size: u64 = row.get(2).expect("get apparent_size collumn");
+            let read_size: u64 = row.get(3).expect("get read_size collumn");
+            let hash_hex: String = row.get(4).expect("get hash_hex collumn");
+            let sampled_blocks: u16 = row.get(5).expect("get sampled_blocks collumn");
+            let object = JsonLine::plain()
+                    .string("path", path.as_str())
+                    .boolean("path_is_lossy", !path.is_printable())
+                    .string("modified", &modified)
+                    .number("apparent_size", apparent_size)
+                    .number("read_size", read_size)
+                    .string("hash", &hash_hex.to_ascii_lowercase())
+                    .number("sampled_blocks", sampled_blocks);
+            let separator = if files == 0 {"\n"} else {",\n"};
+            write!(out, "{}{}", separator, object.finish().trim_end())?;
+            files += 1;
+        }
+        out.write_all(b"\n]\n")?;
+        return Ok(files);
+    }
+
+    /// Get the `limit` files that have had the most different hashes,
+    /// ignoring hashes from --sample.
+    pub fn find_churn(&self,  limit: usize) -> Vec<Churn> {
+        let mut stmt = self.connection.prepare("
+                SELECT path, COUNT(DISTINCT hash_hex) AS hashes, COUNT(*)
+                FROM history WHERE sampled_blocks = 0
+                GROUP BY path HAVING hashes > 1
+                ORDER BY hashes DESC, path
+                LIMIT ?1"
+        ).expect("create SELECT statement");
+        let rows = stmt.query_map((limit,), |row| {
+            let path: Vec<u8> = row.get(0).expect("get path collumn");
+            Ok(Churn {
+                path: PrintablePath::try_from(path).unwrap(),
+                hashes: row.get(1).expect("get hash count"),
+                versions: row.get(2).expect("get version count"),
+            })
+        }).expect("get files with changed hashes");
+        return rows.map(|row| row.expect("get mapped row") ).collect();
+    }
+
+    /// Get the files other than `except` with the given whole-file hash and size.
+    pub fn find_copies(&self,  hash: &[u8],  read_size: u64,  except: &PrintablePath)
+    -> Vec<PrintablePath> {
+        let mut hash_hex = String::with_capacity(hash.len()*2);
+        write_hex(hash, &mut hash_hex);
+        hash_hex.make_ascii_uppercase();
+        let mut stmt = self.connection.prepare("
+                SELECT path FROM hashed
+                WHERE hash_hex = ?1 AND read_size = ?2 AND sampled_blocks = 0 AND normalized = 0
+                AND path IS NOT ?3
+                ORDER BY path"
+        ).expect("create SELECT statement");
+        let rows = stmt.query_map(params!(hash_hex, read_size, except.as_bytes()), |row| {
+            let path: Vec<u8> = row.get(0).expect("get path collumn");
+            Ok(PrintablePath::try_from(path).unwrap())
+        }).expect("get files with the same hash");
+        return rows.map(|row| row.expect("get mapped row") ).collect();
+    }
+
+    /// Get the `limit` pairs of files with different content that have the most bytes
+    /// in identical chunks from --cdc.
+    ///
+    /// Chunks of files that are no longer in the database are ignored.
+    pub fn find_shared_chunks(&self,  limit: usize) -> Vec<SharedChunks> {
+        let mut stmt = self.connection.prepare("
+                WITH whole AS (
+                    SELECT hash_hex FROM hashed WHERE sampled_blocks = 0 AND normalized = 0
+                )
+                SELECT a.file_hash, b.file_hash, SUM(a.length) AS shared
+                FROM chunks AS a JOIN chunks AS b ON a.hash = b.hash AND a.file_hash < b.file_hash
+                WHERE a.file_hash IN (SELECT hash_hex FROM whole)
+                AND b.file_hash IN (SELECT hash_hex FROM whole)
+                GROUP BY a.file_hash, b.file_hash
+                ORDER BY shared DESC
+                LIMIT ?1"
+        ).expect("create SELECT statement");
+        let mut file = self.connection.prepare("
+                SELECT printable_path, read_size FROM hashed
+                WHERE hash_hex = ?1 AND sampled_blocks = 0 AND normalized = 0
+                ORDER BY path
+                LIMIT 1"
+        ).expect("create SELECT statement");
+        let rows = stmt.query_map((limit,), |row| {
+            let a: String = row.get(0).expect("get file_hash collumn");
+            let b: String = row.get(1).expect("get file_hash collumn");
+            Ok((a, b, row.get::<_, u64>(2).expect("get shared bytes")))
+        }).expect("get files with shared chunks");
+        let mut pairs = Vec::new();
+        for row in rows {
+            let (a, b, shared) = row.expect("get mapped row");
+            let mut find = |hash: &str| file.query_row((hash,), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?))
+            }).expect("get file with chunks");
+            pairs.push(SharedChunks { files: [find(&a), find(&b)], shared });
+        }
+        return pairs;
     }
 
-    pub fn prune(&mut self,  read: &PreviouslyRead) {
-        let transaction = self.connection.transaction().expect("start transaction");
+    /// Get the `limit` groups of directories that contain files with the same names,
+    /// ignoring their content and subdirectories, with the most files first.
+    ///
+    /// Directories with only one file are ignored, as those are too likely to match by chance.
+    pub fn find_same_structure(&self,  limit: usize) -> Vec<SameStructure> {
+        let mut stmt = self.connection.prepare("
+                SELECT printable_dir, printable_name FROM hashed
+                ORDER BY printable_dir, printable_name"
+        ).expect("create SELECT statement");
+        let rows = stmt.query_map((), |row| {
+            let dir: String = row.get(0).expect("get printable_dir collumn");
+            let name: String = row.get(1).expect("get printable_name collumn");
+            Ok((dir, name))
+        }).expect("get file names");
+
+        // hash the sorted names of each directory, with the number of names
+        let mut by_names = HashMap::<([u8; 32], usize), Vec<String>>::new();
+        let mut add = |dir: String,  hasher: Sha256,  files: usize| {
+            if files > 1 {
+                let hash = hasher.finalize().into();
+                by_names.entry((hash, files)).or_default().push(dir);
+            }
+        };
+        let mut current = (String::new(), Sha256::new(), 0);
+        for row in rows {
+            let (dir, name) = row.expect("get mapped row");
+            if dir != current.0 {
+                let previous = std::mem::replace(&mut current, (dir, Sha256::new(), 0));
+                add(previous.0, previous.1, previous.2);
+            }
+            // names cannot contain NUL
+            current.1.update(name.as_bytes());
+            current.1.update([0]);
+            current.2 += 1;
+        }
+        add(current.0, current.1, current.2);
+
+        let mut groups = by_names.into_iter()
+                .filter(|(_, dirs)| dirs.len() > 1 )
+                .map(|((_, files), dirs)| SameStructure { files, dirs } )
+                .collect::<Vec<_>>();
+        groups.sort_unstable_by(|a, b| b.files.cmp(&a.files).then_with(|| a.dirs.cmp(&b.dirs) ));
+        groups.truncate(limit);
+        return groups;
+    }
+
+    /// Remove files that were not found by the scan, from `PreviouslyRead::get_not_found()`.
+    pub fn prune(&mut self,  not_found: &[Arc<PrintablePath>]) {
+        let transaction = write_transaction(&self.connection, &self.messages);
         let mut statement = transaction.prepare("DELETE FROM hashed WHERE path = ?1")
             .expect("create INSERT OR REPLACE statement");
-        let removed = read.get_not_found()
-            .map(|file| statement.execute((file.as_bytes(),)).expect("delete row") )
+        let mut history = transaction.prepare("DELETE FROM history WHERE path = ?1")
+            .expect("create DELETE statement");
+        let removed = not_found.iter()
+            .map(|file| {
+                history.execute((file.as_bytes(),)).expect("delete history");
+                statement.execute((file.as_bytes(),)).expect("delete row")
+            })
             .sum::<usize>();
         statement.finalize().expect("finalize delete statement");
+        history.finalize().expect("finalize delete statement");
+        delete_unused_chunks(&transaction);
         transaction.commit().expect("commit deletes");
-        let _ = self.messages.send(format!("pruned {} files", removed));
+        let message = format!("pruned {} files", removed);
+        let _ = self.messages.send(LogMessage::new(Info, message));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunking::Chunk;
+
+    fn in_memory_with(files: &[(&str, u64, [u8; 32])],  options: StorageOptions) -> Sqlite {
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        let (messages, _) = mpsc::channel();
+        let mut db = Sqlite::new_in_memory(options, hashed_rx, messages);
+        for &(path, read_size, hash) in files {
+            hashed_tx.send(HashedFile {
+                path: Arc::new(PrintablePath::from(Path::new(path))),
+                modified: PrintableTime::default(),
+                created: None,
+                apparent_size: read_size,
+                read_size,
+                hash: hash.into(),
+                sampled_blocks: 0,
+                inode: None,
+                normalized: 0,
+                chunks: Vec::new(),
+                range: None,
+            }).unwrap();
+        }
+        drop(hashed_tx);
+        db.save_hashed(Duration::from_secs(1));
+        return db;
+    }
+
+    #[test]
+    fn export_as_json() {
+        let files = [("/b", 2, [0xab; 32]), ("/a\nb", 1, [1; 32])];
+        let db = in_memory_with(&files, StorageOptions::default());
+        let mut out = Vec::new();
+        assert_eq!(db.export_json(&mut out).unwrap(), 2);
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4, "{}", out);
+        assert_eq!((lines[0], lines[3]), ("[", "]"));
+        assert!(lines[1].contains(r#""path_is_lossy":true"#), "{}", lines[1]);
+        let b = format!(concat!(r#"{{"path":"/b","#,
+                r#""path_is_lossy":false,"modified":"{}","apparent_size":2,"read_size":2,"#,
+                r#""hash":"{}","sampled_blocks":0}}"#),
+                PrintableTime::default(),
+                "ab".repeat(32),
+        );
+        assert_eq!(lines[2], b);
+        // an empty database is an empty array
+        let mut out = Vec::new();
+        let empty = in_memory_with(&[], StorageOptions::default());
+        assert_eq!(empty.export_json(&mut out).unwrap(), 0);
+        assert_eq!(String::from_utf8(out).unwrap(), "[\n]\n");
+    }
+
+    #[test]
+    fn copies_of_one_file() {
+        let db = in_memory_with(&[
+                ("/b", 10, [1; 32]),
+                ("/a", 10, [1; 32]),
+                ("/c", 10, [2; 32]),
+                ("/d", 11, [1; 32]),
+        ], StorageOptions::default());
+        let copies = |path: &str| {
+            db.find_copies(&[1; 32], 10, &PrintablePath::from(Path::new(path)))
+                    .iter().map(|path| path.to_string() ).collect::<Vec<_>>()
+        };
+        assert_eq!(copies("/a"), ["/b"]);
+        assert_eq!(copies("/new"), ["/a", "/b"]);
+    }
+
+    #[test]
+    fn creation_time() {
+        let db = in_memory_with(&[("/a", 10, [1; 32]), ("/b", 10, [1; 32])], Default::default());
+        db.connection.execute("UPDATE hashed SET created = '2023-01-02 03:04:05' WHERE path = ?1",
+                (b"/a",),
+        ).unwrap();
+        let groups = db.find_duplicate_groups(&GroupingOptions::default());
+        let created = "2023-01-02 03:04:05".parse::<PrintableTime>().unwrap();
+        assert_eq!(groups[0].files[0].created, Some(created));
+        assert_eq!(groups[0].files[1].created, None);
+        let mut printed = Vec::new();
+        print_duplicates(&groups, true, None, &mut printed).unwrap();
+        let printed = String::from_utf8(printed).unwrap();
+        assert!(printed.contains(" /a (created 2023-01-02 03:04:05)\n"), "{}", printed);
+        assert!(printed.contains(" /b\n"), "{}", printed);
+    }
+
+    #[test]
+    fn same_hash_different_size_is_not_grouped() {
+        let db = in_memory_with(&[
+            ("/a/file", 10, [1; 32]),
+            ("/b/truncated", 5, [1; 32]),
+            ("/c/file", 10, [1; 32]),
+            ("/d/unique", 10, [2; 32]),
+        ], StorageOptions::default());
+        let groups = db.find_duplicate_groups(&GroupingOptions::default());
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].files.len(), 2);
+        assert_eq!(groups[0].files[0].path.as_str(), "/a/file");
+        assert_eq!(groups[0].files[1].path.as_str(), "/c/file");
+        assert_eq!(groups[0].wasted(), 10);
+
+        let options = GroupingOptions { ignore_size: true, ..Default::default() };
+        let groups = db.find_duplicate_groups(&options);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].files.len(), 3);
+        assert_eq!(groups[0].wasted(), 15);
+    }
+
+    #[test]
+    fn min_waste() {
+        let db = in_memory_with(&[
+            ("/a", 10, [1; 32]),
+            ("/b", 10, [1; 32]),
+            ("/c", 20, [2; 32]),
+            ("/d", 20, [2; 32]),
+            ("/e", 5, [3; 32]),
+            ("/f", 5, [3; 32]),
+            ("/g", 5, [3; 32]),
+        ], StorageOptions::default());
+        let options = GroupingOptions { min_waste: 10, ..Default::default() };
+        let groups = db.find_duplicate_groups(&options);
+        let wasted = groups.iter().map(DuplicateGroup::wasted).collect::<Vec<u64>>();
+        assert_eq!(wasted, [20, 10, 10]);
+        assert_eq!(groups[0].files[0].path.as_str(), "/c");
+
+        let options = GroupingOptions { min_waste: 11, ..Default::default() };
+        assert_eq!(db.find_duplicate_groups(&options).len(), 1);
+    }
+
+    #[test]
+    fn waste_min_size() {
+        let db = in_memory_with(&[
+            ("/a", 10, [1; 32]),
+            ("/b", 10, [1; 32]),
+            ("/c", 5, [2; 32]),
+            ("/d", 5, [2; 32]),
+        ], StorageOptions::default());
+        let options = GroupingOptions { waste_min_size: 10, ..Default::default() };
+        let groups = db.find_duplicate_groups(&options);
+        let wasted = groups.iter().map(DuplicateGroup::wasted).collect::<Vec<u64>>();
+        assert_eq!(wasted, [10, 0]);
+
+        let with_min_waste = GroupingOptions { min_waste: 1, ..options };
+        assert_eq!(db.find_duplicate_groups(&with_min_waste).len(), 1);
+
+        // with --ignore-size-in-grouping, only the small copies are not counted
+        let mut group = groups[0].clone();
+        group.files[1].read_size = 4;
+        assert_eq!(group.wasted(), 0);
+    }
+
+    #[test]
+    fn merge_databases() {
+        let dir = std::env::temp_dir();
+        let path = |n: u32| dir.join(format!("decopy-merge-{}-{}.sqlite", std::process::id(), n));
+        for (n, file) in [(1, "/disk1/file"), (2, "/disk2/copy")] {
+            let (hashed_tx, hashed_rx) = mpsc::channel();
+            let options = StorageOptions::default();
+            let mut db = Sqlite::open(&path(n), options, hashed_rx, mpsc::channel().0);
+            hashed_tx.send(HashedFile {
+                path: Arc::new(PrintablePath::from(Path::new(file))),
+                modified: PrintableTime::default(),
+                created: None,
+                apparent_size: 10,
+                read_size: 10,
+                hash: [1; 32].into(),
+                sampled_blocks: 0,
+                inode: None,
+                normalized: 0,
+                chunks: Vec::new(),
+                range: None,
+            }).unwrap();
+            drop(hashed_tx);
+            db.save_hashed(Duration::from_secs(1));
+        }
+        let set_version = |version: usize| {
+            let connection = Connection::open(path(2)).unwrap();
+            connection.pragma_update(None, "user_version", version).unwrap();
+        };
+        set_version(MIGRATIONS.len() - 1);
+
+        let mut combined = in_memory_with(&[], StorageOptions::default());
+        assert_eq!(combined.merge_from(&path(1)), Ok((1, 0)));
+        assert!(combined.merge_from(&path(2)).is_err());
+        set_version(MIGRATIONS.len());
+        assert_eq!(combined.merge_from(&path(2)), Ok((1, 0)));
+        // the same paths
+        assert_eq!(combined.merge_from(&path(1)), Ok((1, 1)));
+        std::fs::remove_file(path(1)).unwrap();
+        std::fs::remove_file(path(2)).unwrap();
+        let groups = combined.find_duplicate_groups(&GroupingOptions::default());
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].files[1].path.as_str(), "/disk2/copy");
+    }
+
+    #[test]
+    fn look_up_previous_in_database() {
+        let path = std::env::temp_dir()
+                .join(format!("decopy-lookup-{}.sqlite", std::process::id()));
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        let mut db = Sqlite::open(&path, StorageOptions::default(), hashed_rx, mpsc::channel().0);
+        for file in ["/root/found", "/root/gone", "/other/file"] {
+            hashed_tx.send(HashedFile {
+                path: Arc::new(PrintablePath::from(Path::new(file))),
+                modified: PrintableTime::default(),
+                created: None,
+                apparent_size: 10,
+                read_size: 10,
+                hash: [1; 32].into(),
+                sampled_blocks: 0,
+                inode: None,
+                normalized: 0,
+                chunks: Vec::new(),
+                range: None,
+            }).unwrap();
+        }
+        drop(hashed_tx);
+        db.save_hashed(Duration::from_secs(1));
+
+        let root = Arc::new(PrintablePath::from(Path::new("/root")));
+        let mut lookup = db.lookup_previous(&[root], 0).unwrap();
+        let found = PrintablePath::from(Path::new("/root/found"));
+        assert_eq!(lookup.check(&found), Some((PrintableTime::default(), 10)));
+        assert_eq!(lookup.check(&PrintablePath::from(Path::new("/root/new"))), None);
+        let not_checked = lookup.not_checked();
+        assert_eq!(not_checked.len(), 1);
+        assert_eq!(not_checked[0].as_str(), "/root/gone");
+        // deleted before it was read
+        lookup.uncheck(&found);
+        assert_eq!(lookup.not_checked().len(), 2);
+        lookup.check(&found);
+        lookup.uncheck_all();
+        assert_eq!(lookup.not_checked().len(), 2);
+        drop(lookup);
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn locked_database_during_lookup() {
+        let path = std::env::temp_dir()
+                .join(format!("decopy-lookup-locked-{}.sqlite", std::process::id()));
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        let (messages_tx, messages_rx) = mpsc::channel();
+        let options = StorageOptions { busy_timeout: Duration::ZERO, ..Default::default() };
+        let mut db = Sqlite::open(&path, options, hashed_rx, messages_tx);
+        // readers are not blocked by writers in WAL mode
+        db.connection.pragma_update(None, "journal_mode", "DELETE").unwrap();
+        hashed_tx.send(HashedFile {
+            path: Arc::new(PrintablePath::from(Path::new("/root/file"))),
+            modified: PrintableTime::default(),
+            created: None,
+            apparent_size: 10,
+            read_size: 10,
+            hash: [1; 32].into(),
+            sampled_blocks: 0,
+            inode: None,
+            normalized: 0,
+            chunks: Vec::new(),
+            range: None,
+        }).unwrap();
+        drop(hashed_tx);
+        db.save_hashed(Duration::from_secs(1));
+
+        let root = Arc::new(PrintablePath::from(Path::new("/root")));
+        let mut lookup = db.lookup_previous(&[root], 0).unwrap();
+        db.connection.execute_batch("BEGIN EXCLUSIVE").unwrap();
+        let file = PrintablePath::from(Path::new("/root/file"));
+        assert_eq!(lookup.check(&file), None);
+        assert!(messages_rx.try_iter().any(|message| message.level == Error ));
+        db.connection.execute_batch("COMMIT").unwrap();
+        // it is stored again as it was not found
+        assert!(lookup.not_checked().is_empty());
+        drop(lookup);
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn safe_durability() {
+        let path = std::env::temp_dir()
+                .join(format!("decopy-durability-{}.sqlite", std::process::id()));
+        let options = StorageOptions { durability: Durability::Safe, ..Default::default() };
+        let db = Sqlite::open(&path, options, mpsc::channel().1, mpsc::channel().0);
+        db.connection.pragma_update(None, "journal_mode", "WAL").unwrap();
+        db.connection.execute_batch("INSERT INTO hashed (path, printable_dir, printable_name,
+                modified, apparent_size, read_size, hash)
+                VALUES (x'2f61', '/', 'a', '2023-01-02 03:04:05', 1, 1, zeroblob(32))
+        ").unwrap();
+        // the last connection to close checkpoints, so this is what makes it matter
+        let other = Connection::open(&path).unwrap();
+        other.query_row("SELECT COUNT(*) FROM hashed", (), |_| Ok(()) ).unwrap();
+        drop(db);
+        // nothing is left in the log for a crash to lose
+        let wal = path.with_extension("sqlite-wal");
+        assert!(std::fs::metadata(&wal).map_or(true, |wal| wal.len() == 0 ));
+        // as if the log was lost in a crash
+        let copy = path.with_extension("copy.sqlite");
+        std::fs::copy(&path, &copy).unwrap();
+        drop(other);
+        let connection = Connection::open(&copy).unwrap();
+        let files = connection.query_row("SELECT COUNT(*) FROM hashed", (), |row| {
+            row.get::<_, u32>(0)
+        }).unwrap();
+        assert_eq!(files, 1);
+        drop(connection);
+        for extension in ["sqlite-wal", "sqlite-shm", "copy.sqlite-wal", "copy.sqlite-shm"] {
+            let _ = std::fs::remove_file(path.with_extension(extension));
+        }
+        std::fs::remove_file(&copy).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn waits_for_other_writers() {
+        let path = std::env::temp_dir()
+                .join(format!("decopy-busy-{}.sqlite", std::process::id()));
+        let (messages_tx, messages_rx) = mpsc::channel();
+        // so that SQLite gives up right away and the retrying can be seen
+        let options = StorageOptions { busy_timeout: Duration::ZERO, ..Default::default() };
+        let mut db = Sqlite::open(&path, options, mpsc::channel().1, messages_tx);
+        let journal_mode = db.connection.pragma_query_value(None, "journal_mode", |row| {
+            row.get::<_, String>(0)
+        }).unwrap();
+        assert_eq!(journal_mode, "wal");
+        let other = Connection::open(&path).unwrap();
+        other.execute_batch("BEGIN IMMEDIATE").unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            other.execute_batch("COMMIT").unwrap();
+        });
+        assert_eq!(db.forget(&PrintablePath::from(Path::new("/a"))), 0);
+        writer.join().unwrap();
+        assert_eq!(messages_rx.try_iter().filter(|message| message.level == Warning ).count(), 1);
+        drop(db);
+        for extension in ["sqlite-wal", "sqlite-shm"] {
+            let _ = std::fs::remove_file(path.with_extension(extension));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn creating_schema_waits_for_other_writers() {
+        let path = std::env::temp_dir()
+                .join(format!("decopy-busy-schema-{}.sqlite", std::process::id()));
+        drop(Sqlite::open(&path, StorageOptions::default(), mpsc::channel().1, mpsc::channel().0));
+        let other = Connection::open(&path).unwrap();
+        other.execute_batch("BEGIN IMMEDIATE").unwrap();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            other.execute_batch("COMMIT").unwrap();
+        });
+        let (messages_tx, messages_rx) = mpsc::channel();
+        let options = StorageOptions { busy_timeout: Duration::ZERO, ..Default::default() };
+        let db = Sqlite::open(&path, options, mpsc::channel().1, messages_tx);
+        writer.join().unwrap();
+        assert_eq!(messages_rx.try_iter().filter(|message| message.level == Warning ).count(), 1);
+        drop(db);
+        for extension in ["sqlite-wal", "sqlite-shm"] {
+            let _ = std::fs::remove_file(path.with_extension(extension));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn page_size_and_auto_vacuum() {
+        let options = StorageOptions {
+            page_size: Some(16384),
+            auto_vacuum: Some(AutoVacuum::Incremental),
+            ..Default::default()
+        };
+        let db = Sqlite::new_in_memory(options, mpsc::channel().1, mpsc::channel().0);
+        let get = |pragma| db.connection.pragma_query_value(None, pragma, |row| {
+            row.get::<_, u32>(0)
+        }).unwrap();
+        assert_eq!((get("page_size"), get("auto_vacuum")), (16384, 2));
+        assert!(valid_page_size(512) && valid_page_size(65536));
+        assert!(!valid_page_size(256) && !valid_page_size(5000) && !valid_page_size(1<<17));
+    }
+
+    #[test]
+    fn shared_chunks() {
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        let mut db = Sqlite::new_in_memory(StorageOptions::default(), hashed_rx, mpsc::channel().0);
+        let chunk = |offset, length, hash| Chunk { offset, length, hash: [hash; 32] };
+        for (path, hash, chunks) in [
+            ("/a", 1, vec![chunk(0, 10, 1), chunk(10, 20, 2)]),
+            ("/b", 2, vec![chunk(0, 5, 3), chunk(5, 20, 2)]),
+            ("/c", 3, vec![chunk(0, 10, 4)]),
+        ] {
+            hashed_tx.send(HashedFile {
+                path: Arc::new(PrintablePath::from(Path::new(path))),
+                modified: PrintableTime::default(),
+                created: None,
+                apparent_size: chunks.iter().map(|chunk| chunk.length ).sum(),
+                read_size: chunks.iter().map(|chunk| chunk.length ).sum(),
+                hash: [hash; 32].into(),
+                sampled_blocks: 0,
+                inode: None,
+                normalized: 0,
+                chunks,
+                range: None,
+            }).unwrap();
+        }
+        drop(hashed_tx);
+        db.save_hashed(Duration::from_secs(1));
+        let pairs = db.find_shared_chunks(10);
+        assert_eq!(pairs, [SharedChunks {
+            files: [("/a".to_string(), 30), ("/b".to_string(), 25)],
+            shared: 20,
+        }]);
+
+        // deleted files leave no history or chunks behind
+        let count = |db: &Sqlite,  table: &str| db.connection.query_row(
+                &format!("SELECT COUNT(*) FROM {}", table), (), |row| row.get::<_, u32>(0),
+        ).unwrap();
+        assert_eq!((count(&db, "history"), count(&db, "chunks")), (3, 5));
+        db.prune(&[Arc::new(PrintablePath::from(Path::new("/a")))]);
+        assert_eq!((count(&db, "history"), count(&db, "chunks")), (2, 3));
+        assert!(db.find_shared_chunks(10).is_empty());
+        assert_eq!(db.forget(&PrintablePath::from(Path::new("/b"))), 1);
+        assert_eq!((count(&db, "history"), count(&db, "chunks")), (1, 1));
+    }
+
+    #[test]
+    fn same_structure() {
+        let db = in_memory_with(&[
+            ("/a/x", 1, [1; 32]),
+            ("/a/y", 2, [2; 32]),
+            ("/b/x", 3, [3; 32]),
+            ("/b/y", 4, [4; 32]),
+            ("/c/x", 5, [5; 32]),
+            ("/c/y", 6, [6; 32]),
+            ("/c/z", 7, [7; 32]),
+            ("/d/x", 8, [8; 32]),
+            ("/e/x", 9, [9; 32]),
+            ("/f/xy", 10, [10; 32]),
+            ("/g/x", 11, [11; 32]),
+            ("/g/y", 12, [12; 32]),
+            ("/g/z", 13, [13; 32]),
+        ], StorageOptions::default());
+        let groups = db.find_same_structure(10);
+        assert_eq!(groups, [
+            SameStructure { files: 3, dirs: vec!["/c/".to_string(), "/g/".to_string()] },
+            SameStructure { files: 2, dirs: vec!["/a/".to_string(), "/b/".to_string()] },
+        ]);
+        assert_eq!(db.find_same_structure(1).len(), 1);
+    }
+
+    #[test]
+    fn forget_prefix() {
+        let mut db = in_memory_with(&[
+            ("/a/b", 1, [1; 32]),
+            ("/a/b/c", 2, [2; 32]),
+            ("/a/b/d/e", 3, [3; 32]),
+            ("/a/bc", 4, [4; 32]),
+            ("/a/c", 5, [5; 32]),
+        ], StorageOptions::default());
+        assert_eq!(db.forget(&PrintablePath::from(Path::new("/a/b"))), 3);
+        let left = db.connection.prepare("SELECT path FROM hashed ORDER BY path").unwrap()
+            .query_map((), |row| row.get(0) ).unwrap()
+            .collect::<Result<Vec<Vec<u8>>, _>>().unwrap();
+        assert_eq!(left, [b"/a/bc".to_vec(), b"/a/c".to_vec()]);
+        assert_eq!(db.forget(&PrintablePath::from(Path::new("/a/b/"))), 0);
+    }
+
+    #[test]
+    fn image_files_are_stored_under_the_image() {
+        let path = |path: &str| Arc::new(PrintablePath::from(Path::new(path)));
+        let options = StorageOptions {
+            images: vec![(path("/tmp/decopy-disk.img-AbCdEf"), path("/images/disk.img"))],
+            ..StorageOptions::default()
+        };
+        let mut db = in_memory_with(&[
+            ("/images/disk.img", 1, [1; 32]),
+            ("/images/disk.img/old", 2, [2; 32]),
+            ("/tmp/decopy-disk.img-AbCdEf/a/b", 3, [3; 32]),
+            ("/tmp/decopy-disk.img-AbCdEfG", 4, [4; 32]),
+        ], options);
+        let paths = |db: &Sqlite| db.connection.prepare("SELECT path FROM hashed ORDER BY path")
+            .unwrap()
+            .query_map((), |row| row.get(0) ).unwrap()
+            .collect::<Result<Vec<Vec<u8>>, _>>().unwrap();
+        assert_eq!(paths(&db), [
+            b"/images/disk.img".to_vec(),
+            b"/images/disk.img/a/b".to_vec(),
+            b"/images/disk.img/old".to_vec(),
+            b"/tmp/decopy-disk.img-AbCdEfG".to_vec(),
+        ]);
+        // the image itself is kept
+        assert_eq!(db.forget_image_contents(&path("/images/disk.img")), 2);
+        assert_eq!(paths(&db), [
+            b"/images/disk.img".to_vec(),
+            b"/tmp/decopy-disk.img-AbCdEfG".to_vec(),
+        ]);
+    }
+
+    #[test]
+    fn duplicates_view() {
+        let db = in_memory_with(&[
+            ("/a", 10, [1; 32]),
+            ("/b", 10, [1; 32]),
+            ("/c", 10, [1; 32]),
+            ("/d", 9, [1; 32]),
+            ("/e", 10, [2; 32]),
+        ], StorageOptions::default());
+        let groups = db.connection.prepare("SELECT files, wasted, paths FROM duplicates").unwrap()
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)) ).unwrap()
+            .collect::<Result<Vec<(u32, u64, String)>, _>>().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].0, 3);
+        assert_eq!(groups[0].1, 20);
+        let mut paths = groups[0].2.lines().collect::<Vec<_>>();
+        paths.sort_unstable();
+        assert_eq!(paths, ["/a", "/b", "/c"]);
+    }
+
+    #[test]
+    fn hex_and_blob_are_grouped_together() {
+        let hex = StorageOptions { hash_storage: HashStorage::Hex, ..Default::default() };
+        let db = in_memory_with(&[("/a", 3, [0xab; 32]), ("/b", 3, [0xab; 32])], hex);
+        let stored: String = db.connection.query_row(
+                "SELECT hash FROM hashed WHERE path = ?1", (b"/a",), |row| row.get(0)
+        ).unwrap();
+        assert_eq!(stored, "ab".repeat(32));
+        db.connection.execute(
+                "INSERT INTO hashed (path, printable_dir, printable_name, modified,
+                apparent_size, read_size, hash) VALUES (?1, '/', 'c', ?2, 3, 3, ?3)",
+                (b"/c", PrintableTime::default().to_string(), [0xab; 32]),
+        ).unwrap();
+        let groups = db.find_duplicate_groups(&GroupingOptions::default());
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].hash, vec![0xab; 32]);
+        assert_eq!(groups[0].files.len(), 3);
+    }
+
+    #[test]
+    fn sampled_are_not_grouped_with_full() {
+        let mut db = in_memory_with(&[
+            ("/a", 1_000_000, [1; 32]),
+            ("/b", 1_000_000, [1; 32]),
+            ("/c", 1_000_000, [1; 32]),
+            ("/d", 1_000_000, [1; 32]),
+        ], StorageOptions::default());
+        db.connection.execute("UPDATE hashed SET sampled_blocks = 4 WHERE path >= ?1", (b"/c",))
+            .unwrap();
+        let groups = db.find_duplicate_groups(&GroupingOptions::default());
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].sampled_blocks, 0);
+        assert_eq!(groups[0].files[1].path.as_str(), "/b");
+        assert_eq!(groups[1].sampled_blocks, 4);
+        assert_eq!(groups[1].files[0].path.as_str(), "/c");
+
+        let root = PrintablePath::from(Path::new("/"));
+        let sampled = |path: &str| UnreadFile {
+            path: Arc::new(PrintablePath::from(Path::new(path))),
+            modified: PrintableTime::default(),
+            created: None,
+            size: 1_000_000,
+            inode: None,
+        };
+        let mut read = PreviouslyRead::default();
+        db.get_previously_read(&root, 0, &mut read);
+        assert!(read.check_unchanged(&sampled("/a")));
+        assert!(!read.check_unchanged(&sampled("/c")));
+        let mut read = PreviouslyRead::default();
+        db.get_previously_read(&root, 4, &mut read);
+        assert!(read.check_unchanged(&sampled("/a")));
+        assert!(read.check_unchanged(&sampled("/c")));
+    }
+
+    #[test]
+    fn count_hash_changes() {
+        let mut db = in_memory_with(&[
+            ("/log", 1, [1; 32]),
+            ("/stable", 1, [1; 32]),
+        ], StorageOptions::default());
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        db.hashed_rx = hashed_rx;
+        let versions = [([2; 32], "2023-01-01 00:00:00"), ([1; 32], "2023-01-02 00:00:00")];
+        for (hash, modified) in versions {
+            hashed_tx.send(HashedFile {
+                path: Arc::new(PrintablePath::from(Path::new("/log"))),
+                modified: modified.parse().unwrap(),
+                created: None,
+                apparent_size: 1,
+                read_size: 1,
+                hash: hash.into(),
+                sampled_blocks: 0,
+                inode: None,
+                normalized: 0,
+                chunks: Vec::new(),
+                range: None,
+            }).unwrap();
+        }
+        drop(hashed_tx);
+        db.save_hashed(Duration::from_secs(1));
+        let churn = db.find_churn(10);
+        assert_eq!(churn.len(), 1);
+        assert_eq!(churn[0].path.as_str(), "/log");
+        assert_eq!(churn[0].hashes, 2);
+        assert_eq!(churn[0].versions, 3);
+    }
+
+    #[test]
+    fn find_moved_file_by_inode() {
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        let (messages, _) = mpsc::channel();
+        let mut db = Sqlite::new_in_memory(StorageOptions::default(), hashed_rx, messages);
+        let modified = "2023-01-02 03:04:05".parse::<PrintableTime>().unwrap();
+        for (path, inode) in [("/old", Some((1, u64::MAX))), ("/unknown", None)] {
+            hashed_tx.send(HashedFile {
+                path: Arc::new(PrintablePath::from(Path::new(path))),
+                modified,
+                created: None,
+                apparent_size: 10,
+                read_size: 9,
+                hash: [7; 32].into(),
+                sampled_blocks: 0,
+                inode,
+                normalized: 0,
+                chunks: Vec::new(),
+                range: None,
+            }).unwrap();
+        }
+        drop(hashed_tx);
+        db.save_hashed(Duration::from_secs(1));
+
+        let mut cache = InodeCache::default();
+        db.get_inode_cache(&mut cache);
+        let mut moved = UnreadFile {
+            path: Arc::new(PrintablePath::from(Path::new("/new"))),
+            modified,
+            created: None,
+            size: 10,
+            inode: Some((1, u64::MAX)),
+        };
+        let cached = cache.get(&moved).unwrap();
+        assert_eq!((cached.read_size, cached.hash), (9, [7; 32].into()));
+        moved.size = 11;
+        assert_eq!(cache.get(&moved), None);
+    }
+
+    #[test]
+    fn filter_and_group_by_tag() {
+        let tagged = |tag: &str| {
+            StorageOptions { tag: Some(tag.to_string()), ..Default::default() }
+        };
+        let mut db = in_memory_with(&[("/a/1", 1, [1; 32]), ("/a/2", 1, [2; 32])], tagged("a"));
+        let (hashed_tx, hashed_rx) = mpsc::channel();
+        db.hashed_rx = hashed_rx;
+        db.options = tagged("b");
+        for (path, hash) in [("/b/1", [1; 32]), ("/b/2", [2; 32]), ("/b/3", [2; 32])] {
+            hashed_tx.send(HashedFile {
+                path: Arc::new(PrintablePath::from(Path::new(path))),
+                modified: PrintableTime::default(),
+                created: None,
+                apparent_size: 1,
+                read_size: 1,
+                hash: hash.into(),
+                sampled_blocks: 0,
+                inode: None,
+                normalized: 0,
+                chunks: Vec::new(),
+                range: None,
+            }).unwrap();
+        }
+        drop(hashed_tx);
+        db.save_hashed(Duration::from_secs(1));
+
+        let paths = |options: GroupingOptions| {
+            db.find_duplicate_groups(&options).iter()
+                .map(|group| group.files.iter().map(|f| f.path.to_string() ).collect::<Vec<_>>() )
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(GroupingOptions::default()), [
+            vec!["/a/1", "/b/1"],
+            vec!["/a/2", "/b/2", "/b/3"],
+        ]);
+        let within = GroupingOptions { within_tag: true, ..Default::default() };
+        assert_eq!(paths(within), [vec!["/b/2", "/b/3"]]);
+        let only_a = GroupingOptions { tag: Some("a".to_string()), ..Default::default() };
+        assert_eq!(paths(only_a), Vec::<Vec<String>>::new());
+    }
+
+    #[test]
+    fn diff_against_other_database() {
+        let path = std::env::temp_dir()
+                .join(format!("decopy-diff-{}.sqlite", std::process::id()));
+        let before = in_memory_with(&[
+            ("/r/same", 1, [1; 32]),
+            ("/r/changed", 1, [2; 32]),
+            ("/r/gone", 1, [3; 32]),
+        ], StorageOptions::default());
+        before.connection.execute("VACUUM INTO ?1", (path.to_str().unwrap(),)).unwrap();
+
+        let db = in_memory_with(&[
+            ("/r/same", 1, [1; 32]),
+            ("/r/changed", 1, [4; 32]),
+            ("/r/new", 1, [3; 32]),
+            ("/other/new", 1, [3; 32]),
+        ], StorageOptions::default());
+        let roots = [Arc::new(PrintablePath::from(Path::new("/r")))];
+        let diff = db.diff_against(&path, &roots).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let paths = |paths: &[PrintablePath]| {
+            paths.iter().map(|path| path.to_string() ).collect::<Vec<_>>()
+        };
+        assert_eq!(paths(&diff.added), ["/r/new"]);
+        assert_eq!(paths(&diff.removed), ["/r/gone"]);
+        assert_eq!(paths(&diff.modified), ["/r/changed"]);
+        assert_eq!(diff.unchanged, 1);
+    }
+
+    #[test]
+    fn new_files_compared_to_reference() {
+        let path = std::env::temp_dir()
+                .join(format!("decopy-reference-{}.sqlite", std::process::id()));
+        let reference = in_memory_with(&[
+            ("/backup/a", 1, [1; 32]),
+            ("/backup/b", 2, [2; 32]),
+        ], StorageOptions::default());
+        reference.connection.execute("VACUUM INTO ?1", (path.to_str().unwrap(),)).unwrap();
+
+        let db = in_memory_with(&[
+            ("/r/copy", 1, [1; 32]),
+            ("/r/truncated", 1, [2; 32]),
+            ("/r/new", 3, [3; 32]),
+            ("/other/new", 3, [3; 32]),
+        ], StorageOptions::default());
+        let roots = [Arc::new(PrintablePath::from(Path::new("/r")))];
+        let (new, checked) = db.find_new_files(&path, &roots).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let new = new.iter().map(|file| (file.path.as_str(), file.size) ).collect::<Vec<_>>();
+        assert_eq!(new, [("/r/new", 3), ("/r/truncated", 1)]);
+        assert_eq!(checked, 3);
+    }
+
+    #[test]
+    fn sizes_of_directories() {
+        let db = in_memory_with(&[
+            ("/r/a/1", 10, [1; 32]),
+            ("/r/a/b/2", 20, [2; 32]),
+            ("/r/c/3", 40, [3; 32]),
+            ("/other/copy", 20, [2; 32]),
+        ], StorageOptions::default());
+        let roots = [Arc::new(PrintablePath::from(Path::new("/r")))];
+        let sizes = |max_depth| {
+            db.directory_sizes(&roots, max_depth).into_iter()
+                .map(|dir| (dir.dir, dir.size, dir.duplicated, dir.files) )
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sizes(None), [
+            ("/r/".to_string(), 70, 20, 3),
+            ("/r/c/".to_string(), 40, 0, 1),
+            ("/r/a/".to_string(), 30, 20, 2),
+            ("/r/a/b/".to_string(), 20, 20, 1),
+        ]);
+        assert_eq!(sizes(Some(0)), [("/r/".to_string(), 70, 20, 3)]);
+        assert_eq!(sizes(Some(1)).len(), 3);
+    }
+
+    #[test]
+    fn record_roots() {
+        let roots = ["/a/b", "/a/bc"].map(|root| Arc::new(PrintablePath::from(Path::new(root))) );
+        let options = StorageOptions { record_roots: roots.to_vec(), ..Default::default() };
+        let files = [("/a/b/1", 1, [1; 32]), ("/a/bc/1", 1, [1; 32]), ("/c/1", 1, [1; 32])];
+        let db = in_memory_with(&files, options);
+        let mut statement = db.connection.prepare("SELECT root FROM hashed ORDER BY path").unwrap();
+        let roots = statement.query_map((), |row| row.get::<_, Option<Vec<u8>>>(0) )
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(roots, [Some(b"/a/b".to_vec()), Some(b"/a/bc".to_vec()), None]);
+    }
+
+    #[test]
+    fn upgrade_original_schema() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("
+            CREATE TABLE hashed (
+                path BLOB PRIMARY KEY NOT NULL,
+                printable_dir TEXT NOT NULL,
+                printable_name TEXT NOT NULL,
+                printable_path TEXT NOT NULL GENERATED ALWAYS
+                    AS (printable_dir || printable_name) VIRTUAL,
+                modified TEXT NOT NULL CHECK(length(modified)=19),
+                apparent_size UNSIGNED INTEGER NOT NULL,
+                read_size UNSIGNED INTEGER NOT NULL,
+                hash BLOB NOT NULL CHECK(length(hash)=32),
+                hash_hex TEXT NOT NULL GENERATED ALWAYS
+                    AS (hex(hash)) VIRTUAL
+            ) WITHOUT ROWID;
+            CREATE UNIQUE INDEX hashed_path ON hashed (path ASC);
+            CREATE INDEX hashed_hash ON hashed (hash);
+            INSERT INTO hashed (path, printable_dir, printable_name, modified,
+                apparent_size, read_size, hash)
+                VALUES (x'2f61', '/', 'a', '2023-01-02 03:04:05', 1, 1, zeroblob(32));
+        ").unwrap();
+        let (_, hashed_rx) = mpsc::channel();
+        let (messages, _) = mpsc::channel();
+        let db = Sqlite {
+            connection: ManuallyDrop::new(connection),
+            hashed_rx,
+            messages,
+            options: StorageOptions::default(),
+            events: Arc::new(EventLog::default()),
+            sink: TeeSink::default(),
+        };
+        db.prepare().unwrap();
+        let version = db.connection.pragma_query_value(None, "user_version", |row| {
+            row.get::<_, usize>(0)
+        }).unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        let (hash_hex, modified): (String, String) = db.connection.query_row(
+                "SELECT hash_hex, modified FROM hashed", (), |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap();
+        assert_eq!(hash_hex, "00".repeat(32));
+        assert_eq!(modified, "2023-01-02 03:04:05");
+        let indexes: u32 = db.connection.query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type='index' AND tbl_name='hashed'",
+                (),
+                |row| row.get(0),
+        ).unwrap();
+        assert_eq!(indexes, 6);
+        db.check_hash_algorithm().unwrap();
+    }
+
+    #[test]
+    fn sha512_hashes() {
+        for hash_storage in [HashStorage::Blob, HashStorage::Hex] {
+            let options = StorageOptions {
+                hash_algorithm: HashAlgorithm::Sha512,
+                hash_storage,
+                ..Default::default()
+            };
+            let (hashed_tx, hashed_rx) = mpsc::channel();
+            let (messages, _) = mpsc::channel();
+            let mut db = Sqlite::new_in_memory(options, hashed_rx, messages);
+            for path in ["/a", "/b"] {
+                hashed_tx.send(HashedFile {
+                    path: Arc::new(PrintablePath::from(Path::new(path))),
+                    modified: PrintableTime::default(),
+                    created: None,
+                    apparent_size: 1,
+                    read_size: 1,
+                    hash: [0xcd; 64].into(),
+                    sampled_blocks: 0,
+                    inode: None,
+                    normalized: 0,
+                    chunks: Vec::new(),
+                    range: None,
+                }).unwrap();
+            }
+            drop(hashed_tx);
+            db.save_hashed(Duration::from_secs(1));
+            db.check_hash_algorithm().unwrap();
+            let groups = db.find_duplicate_groups(&GroupingOptions::default());
+            assert_eq!(groups.len(), 1);
+            assert_eq!(groups[0].hash, vec![0xcd; 64]);
+            let copies = db.find_copies(&[0xcd; 64], 1, &PrintablePath::from(Path::new("/a")));
+            assert_eq!(copies.len(), 1);
+        }
+    }
+
+    #[test]
+    fn other_hash_algorithm() {
+        let db = in_memory_with(&[], StorageOptions::default());
+        db.check_hash_algorithm().unwrap();
+        db.connection.execute("UPDATE metadata SET value = 'md5' WHERE key = 'hash_algorithm'", ())
+            .unwrap();
+        assert_eq!(
+                db.check_hash_algorithm(),
+                Err("the database has md5 hashes of 32 bytes, not sha256".to_string()),
+        );
+        // not changed when opened again
+        db.prepare().unwrap();
+        assert!(db.check_hash_algorithm().is_err());
+    }
+
+    #[test]
+    fn filter_by_name() {
+        let db = in_memory_with(&[
+            ("/a/same", 1, [1; 32]),
+            ("/b/same", 1, [1; 32]),
+            ("/c/other", 1, [1; 32]),
+            ("/a/x", 2, [2; 32]),
+            ("/b/x", 2, [2; 32]),
+            ("/a/y", 3, [3; 32]),
+            ("/b/z", 3, [3; 32]),
+        ], StorageOptions::default());
+
+        let same = GroupingOptions { names: NameFilter::Same, ..Default::default() };
+        let groups = db.find_duplicate_groups(&same);
+        let paths = groups.iter()
+                .map(|group| group.files.iter().map(|f| f.path.as_str() ).collect::<Vec<_>>() )
+                .collect::<Vec<_>>();
+        assert_eq!(paths, vec![vec!["/a/same", "/b/same"], vec!["/a/x", "/b/x"]]);
+
+        let different = GroupingOptions { names: NameFilter::Different, ..Default::default() };
+        let groups = db.find_duplicate_groups(&different);
+        let paths = groups.iter()
+                .map(|group| group.files.iter().map(|f| f.path.as_str() ).collect::<Vec<_>>() )
+                .collect::<Vec<_>>();
+        assert_eq!(paths, vec![vec!["/a/same", "/b/same", "/c/other"], vec!["/a/y", "/b/z"]]);
     }
 }
@@ -14,11 +14,13 @@
  */
 
 use crate::path_decoding::PrintablePath;
+use crate::time::PrintableTime;
 
-use std::fmt::{self, Debug, Formatter};
+use std::fmt::{self, Debug, Display, Formatter};
 use std::ops::Deref;
 use std::sync::{Arc, Mutex, mpsc::Sender};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::SystemTime;
 
 use arc_swap::ArcSwapOption;
 
@@ -31,7 +33,9 @@ pub enum ThreadState {
     WaitingForMemory = 2,
     Reading = 3,
     Hashing = 4,
-    Quit = 5,
+    /// A hasher thread that --auto-scale has stopped using for now.
+    Parked = 5,
+    Quit = 6,
 }
 pub use self::ThreadState::*;
 
@@ -44,12 +48,49 @@ impl TryFrom<usize> for ThreadState {
             2 => Ok(WaitingForMemory),
             3 => Ok(Reading),
             4 => Ok(Hashing),
-            5 => Ok(Quit),
+            5 => Ok(Parked),
+            6 => Ok(Quit),
             _ => Err(())
         }
     }
 }
 
+/// How serious a log message is, which decides where it ends up.
+#[derive(Clone,Copy, Default, Debug, PartialEq,Eq, PartialOrd,Ord, clap::ValueEnum)]
+pub enum LogLevel {
+    /// Details about what the program is doing, which are only shown when asked for.
+    Verbose,
+    /// Why each file was or wasn't hashed, which is only logged with --explain.
+    Explain,
+    #[default]
+    Info,
+    /// Something looks wrong, but the file was still hashed or skipped deliberately.
+    Warning,
+    /// A file or directory could not be read.
+    Error,
+}
+pub use self::LogLevel::*;
+
+#[derive(Clone, Debug)]
+pub struct LogMessage {
+    pub level: LogLevel,
+    /// When the message was created, which can be a while before it's printed.
+    pub time: PrintableTime,
+    pub text: String,
+}
+
+impl LogMessage {
+    pub fn new(level: LogLevel,  text: String) -> Self {
+        LogMessage { level, time: PrintableTime::from(SystemTime::now()), text }
+    }
+}
+
+impl Display for LogMessage {
+    fn fmt(&self,  fmtr: &mut Formatter) -> fmt::Result {
+        fmtr.write_str(&self.text)
+    }
+}
+
 #[repr(C, align(128))] // avoid false sharing
 pub struct ThreadInfo {
     thread_name: String,
@@ -57,18 +98,24 @@ pub struct ThreadInfo {
     // to all threads..
     // Therefore just wrap it in a mutex to make it work:
     // Logging should be rare, so performance is not an issue.
-    log_channel: Mutex<Sender<String>>,
+    log_channel: Mutex<Sender<LogMessage>>,
     processed_bytes: AtomicUsize,
+    /// `processed_bytes` when the current file was started.
+    file_start: AtomicUsize,
+    /// Size of the current file, or 0 if unknown.
+    file_size: AtomicU64,
     state: AtomicUsize,
     working_on: ArcSwapOption<PrintablePath>,
 }
 
 impl ThreadInfo {
-    pub fn new(thread_name: String,  log_channel: Sender<String>) -> ThreadInfo {
+    pub fn new(thread_name: String,  log_channel: Sender<LogMessage>) -> ThreadInfo {
         ThreadInfo {
             thread_name,
             log_channel: Mutex::new(log_channel),
             processed_bytes: AtomicUsize::new(0),
+            file_start: AtomicUsize::new(0),
+            file_size: AtomicU64::new(0),
             state: AtomicUsize::new(Idle as usize),
             working_on: ArcSwapOption::empty(),
         }
@@ -78,8 +125,8 @@ impl ThreadInfo {
         &self.thread_name
     }
 
-    pub fn log_message(&self,  message: String) {
-        self.log_channel.lock().unwrap().send(message).unwrap()
+    pub fn log_message(&self,  level: LogLevel,  message: String) {
+        self.log_channel.lock().unwrap().send(LogMessage::new(level, message)).unwrap()
     }
 
     pub fn processed_bytes(&self) -> usize {
@@ -89,6 +136,18 @@ impl ThreadInfo {
         self.processed_bytes.fetch_add(bytes, Ordering::Relaxed);
     }
 
+    /// Remember how big the file about to be processed is, to be able to show how much of it
+    /// is done.
+    pub fn start_file(&self,  size: u64) {
+        self.file_start.store(self.processed_bytes(), Ordering::Relaxed);
+        self.file_size.store(size, Ordering::Relaxed);
+    }
+    /// How many bytes of the current file have been processed, and its size.
+    pub fn file_progress(&self) -> (u64, u64) {
+        let done = self.processed_bytes().wrapping_sub(self.file_start.load(Ordering::Relaxed));
+        (done as u64, self.file_size.load(Ordering::Relaxed))
+    }
+
     pub fn state(&self) -> ThreadState {
         let number = self.state.load(Ordering::Relaxed);
         ThreadState::try_from(number).unwrap_or_else(|_| {
@@ -105,6 +164,9 @@ impl ThreadInfo {
             None => view(None),
         }
     }
+    pub fn working_on(&self) -> Option<Arc<PrintablePath>> {
+        self.working_on.load_full()
+    }
     pub fn set_working_on(&self,  path: Option<Arc<PrintablePath>>) {
         self.working_on.store(path);
     }
@@ -131,7 +193,7 @@ impl Debug for ThreadInfo {
     }
 }
 
-pub fn create_info_array(name_prefix: &str,  count: usize,  log_channel: Sender<String>)
+pub fn create_info_array(name_prefix: &str,  count: usize,  log_channel: Sender<LogMessage>)
 -> Arc<[ThreadInfo]> {
     let mut infos = Vec::with_capacity(count+1);
     for n in 1..=count {
@@ -141,3 +203,35 @@ pub fn create_info_array(name_prefix: &str,  count: usize,  log_channel: Sender<
     }
     infos.into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem;
+    use std::sync::mpsc;
+
+    /// Each thread updates the counters in its own `ThreadInfo` all the time,
+    /// so these must not be in the same 128 byte block as those of another thread.
+    /// (Some Intel CPUs fetch cache lines in pairs, so 64 is not enough.)
+    #[test]
+    fn adjacent_infos_dont_share_cache_lines() {
+        assert_eq!(mem::align_of::<ThreadInfo>(), 128);
+        assert_eq!(mem::size_of::<ThreadInfo>() % 128, 0);
+
+        let (log_channel, _) = mpsc::channel();
+        let infos = create_info_array("test", 2, log_channel);
+        let block = |info: &ThreadInfo| {
+            let counters = [
+                &info.processed_bytes as *const AtomicUsize as usize,
+                &info.state as *const AtomicUsize as usize,
+            ];
+            counters.map(|address| address / 128 )
+        };
+        let [first_bytes, first_state] = block(&infos[0]);
+        let [second_bytes, second_state] = block(&infos[1]);
+        assert_eq!(first_bytes, first_state);
+        assert_eq!(second_bytes, second_state);
+        assert_ne!(first_bytes, second_bytes);
+    }
+}
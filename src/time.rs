@@ -18,7 +18,7 @@
 use std::fmt::{Debug, Display, Formatter, Result as fmtResult};
 use std::num::NonZeroU8;
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 /// A type to display a `SystemTime` in a human-readable way.
 ///
@@ -158,6 +158,51 @@ impl PrintableTime {
         }
     }
 
+    /// Convert datetime to seconds since 1970-01-01 00:00:00.
+    ///
+    /// This is the inverse of `from_timestamp()`.
+    pub const fn to_timestamp(self) -> i64 {
+        // days_from_civil() from http://howardhinnant.github.io/date_algorithms.html
+        let (month, day) = (self.month.get() as i64, self.day.get() as i64);
+        let year = if month <= 2 {self.year as i64 - 1} else {self.year as i64};
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let day_of_year = (153*((month+9)%12) + 2)/5 + day-1;
+        let day_of_era = year_of_era*365 + year_of_era/4 - year_of_era/100 + day_of_year;
+        let days = era*146097 + day_of_era - 719468;
+        days*24*60*60 + self.hour as i64*60*60 + self.minute as i64*60 + self.second as i64
+    }
+
+    /// Add a duration, or return `None` if the result is after `PrintableTime::MAX`.
+    pub fn checked_add(self,  duration: Duration) -> Option<Self> {
+        let seconds = i64::try_from(duration.as_secs()).ok()?;
+        let timestamp = self.to_timestamp().checked_add(seconds)?;
+        match timestamp <= Self::MAX.to_timestamp() {
+            true => Some(Self::from_timestamp(timestamp)),
+            false => None,
+        }
+    }
+
+    /// Subtract a duration, or return `None` if the result is before `PrintableTime::MIN`.
+    pub fn checked_sub(self,  duration: Duration) -> Option<Self> {
+        let seconds = i64::try_from(duration.as_secs()).ok()?;
+        let timestamp = self.to_timestamp().checked_sub(seconds)?;
+        match timestamp >= Self::MIN.to_timestamp() {
+            true => Some(Self::from_timestamp(timestamp)),
+            false => None,
+        }
+    }
+
+    /// Add a duration, clamping to `PrintableTime::MAX`.
+    pub fn saturating_add(self,  duration: Duration) -> Self {
+        self.checked_add(duration).unwrap_or(Self::MAX)
+    }
+
+    /// Subtract a duration, clamping to `PrintableTime::MIN`.
+    pub fn saturating_sub(self,  duration: Duration) -> Self {
+        self.checked_sub(duration).unwrap_or(Self::MIN)
+    }
+
     /// Clamp the datetime to be between year 0 and year 9999
     pub const fn clamp_to_yyyy(self) -> Self {
         match self.year {
@@ -303,6 +348,60 @@ mod tests {
         assert_eq!(timestamp_to_date(-2041622064000), (i16::MIN, 1, 1, 0, 0, 0));
     }
 
+    #[test]
+    fn timestamp_round_trip() {
+        for timestamp in [
+                0, -1, 68214896, 951854402, 4107542400, -62167219201, -74790000000,
+        ] {
+            assert_eq!(PrintableTime::from_timestamp(timestamp).to_timestamp(), timestamp);
+        }
+        for limit in [PrintableTime::MAX, PrintableTime::MIN] {
+            assert_eq!(PrintableTime::from_timestamp(limit.to_timestamp()), limit);
+        }
+        let after_max = PrintableTime::MAX.to_timestamp() + 1;
+        assert_eq!(PrintableTime::from_timestamp(after_max), PrintableTime::MAX);
+    }
+
+    #[test]
+    fn add_and_subtract() {
+        const DAY: Duration = Duration::from_secs(24*60*60);
+        let new_years_eve = PrintableTime::new(2023, 12, 31, 23, 59, 30);
+        assert_eq!(
+                new_years_eve.checked_add(Duration::from_secs(45)),
+                Some(PrintableTime::new(2024, 1, 1, 0, 0, 15)),
+        );
+        assert_eq!(
+                PrintableTime::new(2024, 1, 1, 0, 0, 15).checked_sub(Duration::from_secs(45)),
+                Some(new_years_eve),
+        );
+        assert_eq!(
+                PrintableTime::new(2024, 2, 28, 12, 0, 0).checked_add(DAY),
+                Some(PrintableTime::new(2024, 2, 29, 12, 0, 0)),
+        );
+        assert_eq!(
+                PrintableTime::new(2023, 2, 28, 12, 0, 0).checked_add(DAY),
+                Some(PrintableTime::new(2023, 3, 1, 12, 0, 0)),
+        );
+        assert_eq!(
+                PrintableTime::new(2000, 3, 1, 0, 0, 0).checked_sub(DAY),
+                Some(PrintableTime::new(2000, 2, 29, 0, 0, 0)),
+        );
+        assert_eq!(
+                PrintableTime::new(1900, 3, 1, 0, 0, 0).checked_sub(DAY),
+                Some(PrintableTime::new(1900, 2, 28, 0, 0, 0)),
+        );
+        assert_eq!(
+                PrintableTime::new(1970, 1, 31, 0, 0, 0).checked_sub(DAY*31),
+                Some(PrintableTime::new(1969, 12, 31, 0, 0, 0)),
+        );
+
+        assert_eq!(PrintableTime::MAX.checked_add(Duration::from_secs(1)), None);
+        assert_eq!(PrintableTime::MIN.checked_sub(Duration::from_secs(1)), None);
+        assert_eq!(PrintableTime::default().checked_add(Duration::MAX), None);
+        assert_eq!(PrintableTime::default().saturating_add(Duration::MAX), PrintableTime::MAX);
+        assert_eq!(PrintableTime::default().saturating_sub(DAY*1000000000), PrintableTime::MIN);
+    }
+
     #[test]
     fn default() {
         assert_eq!(PrintableTime::default(), PrintableTime::from(SystemTime::UNIX_EPOCH));